@@ -1,7 +1,59 @@
 from .neighborhood_analysis import (neighbor_components,
                                     get_bbox,
                                     get_bbox_neighbors,
+                                    get_neighbors_shape,
                                     get_point_neighbors,
+                                    get_neighbors_batch,
+                                    get_neighbors_knn,
+                                    get_neighbors_mutual_knn,
+                                    get_neighbors_with_distances,
+                                    get_neighbor_counts,
+                                    nearest_neighbor_distances,
+                                    ripley_k,
+                                    cross_k,
+                                    pair_correlation,
+                                    g_function,
+                                    f_function,
+                                    clark_evans,
+                                    get_neighbors_weighted,
+                                    get_neighbors_annulus,
+                                    get_neighbors_adaptive,
+                                    get_neighbors_periodic,
+                                    get_neighbors_3d,
+                                    get_grid_neighbors,
+                                    get_cross_neighbors,
+                                    get_cross_neighbors_knn,
+                                    get_points_in_box,
+                                    get_points_in_boxes,
+                                    edges_to_neighbors,
+                                    neighbors_to_coo,
+                                    symmetrize_neighbors,
+                                    assert_symmetric,
+                                    neighbor_graph_stats,
+                                    subsample_neighbors,
+                                    coo_to_neighbors,
+                                    save_neighbors,
+                                    load_neighbors,
                                     comb_bootstrap,
+                                    comb_bootstrap_matrix,
+                                    adjust_pvalues,
+                                    neighbor_composition,
+                                    neighborhood_entropy,
+                                    neighborhood_clusters,
+                                    expression_bootstrap,
+                                    moran_i,
+                                    geary_c,
+                                    local_moran,
+                                    getis_ord,
+                                    join_counts,
+                                    assortativity,
+                                    mixing_score,
+                                    infiltration_score,
+                                    PointIndex,
+                                    PointIndexF32,
+                                    get_point_neighbors_f32,
                                     CellCombs,
+                                    CombsTable,
+                                    set_num_threads,
+                                    get_num_threads,
                                     )
\ No newline at end of file
@@ -1,19 +1,276 @@
-from typing import List, Tuple, Dict, Optional
+from typing import List, Tuple, Dict, Optional, Union, Set, Callable
 
+import numpy as np
+
+Points = Union[List[Tuple[float, float]], np.ndarray]
+Neighbors = Union[List[List[int]], Dict[int, List[int]], Tuple[np.ndarray, np.ndarray],
+                  Tuple[np.ndarray, np.ndarray, np.ndarray, int]]
+WeightedNeighbors = Union[List[List[Tuple[int, float]]], Dict[int, List[Tuple[int, float]]]]
+CombsResult = Union[List[Tuple[Tuple[str, str], Union[float, Dict[str, float]]]],
+                    Dict[Tuple[str, str], Union[float, Dict[str, float]]], 'CombsTable']
+
+def set_num_threads(n: int) -> None: ...
+def get_num_threads() -> int: ...
 def neighbor_components(neighbors: Dict[int, List[int]], types: Dict[int, str]) -> (List[int], List[str], List[List[int]]): ...
 
 def get_bbox(points_collections: List[List[Tuple[float, float]]]) -> List[Tuple[float, float, float, float]]: ...
 
-def get_point_neighbors(points: List[Tuple[float, float]], r: float, labels: Optional[List[int]] = None) -> List[List[int]]: ...
+def get_point_neighbors(points: Points, r: Optional[Union[float, List[float]]], labels: Optional[List[int]] = None,
+                        use_both: bool = False, exclude_self: bool = False,
+                        format: str = 'list', sort: Optional[str] = None,
+                        max_neighbors: Optional[int] = None, metric: str = 'euclidean',
+                        node_size: int = 64, query_indices: Optional[List[int]] = None,
+                        drop_invalid: bool = False, types: Optional[List[str]] = None,
+                        target_types: Optional[Set[str]] = None,
+                        handle_duplicates: str = 'keep',
+                        radius_by_type: Optional[Dict[str, float]] = None) -> Union[Neighbors, Tuple]: ...
+
+def get_point_neighbors_f32(points: Points, r: float, labels: Optional[List[int]] = None,
+                            exclude_self: bool = False) -> List[List[int]]: ...
+
+def get_neighbors_batch(points_collections: List[Points], r: float) -> List[List[List[int]]]: ...
+
+def get_neighbors_knn(points: Points, k: int, labels: Optional[List[int]] = None,
+                      exclude_self: bool = True) -> List[List[int]]: ...
+
+def get_neighbors_mutual_knn(points: Points, k: int, symmetrize: str = 'intersection',
+                             labels: Optional[List[int]] = None) -> List[List[int]]: ...
+
+def get_neighbors_with_distances(points: Points, r: float, labels: Optional[List[int]] = None,
+                                 exclude_self: bool = False) -> List[List[Tuple[int, float]]]: ...
+
+def get_neighbors_weighted(points: Points, r: float, kernel: str = 'gaussian', bandwidth: Optional[float] = None,
+                           include_self: bool = True) -> List[List[Tuple[int, float]]]: ...
+
+def get_neighbor_counts(points: Points, r: float, exclude_self: bool = False,
+                        histogram: bool = False) -> Union[np.ndarray, Tuple[np.ndarray, np.ndarray]]: ...
+
+def nearest_neighbor_distances(points: Points, k: int = 1) -> np.ndarray: ...
+
+def ripley_k(points: Points, radii: List[float],
+             area: Optional[Union[float, Tuple[float, float, float, float]]] = None,
+             types: Optional[List[str]] = None, correction: str = 'border') \
+        -> Union[Tuple[np.ndarray, np.ndarray], Dict[str, Tuple[np.ndarray, np.ndarray]]]: ...
+
+def cross_k(points: Points, types: List[str], type_a: str, type_b: str, radii: List[float],
+            area: Optional[Union[float, Tuple[float, float, float, float]]] = None, correction: str = 'border',
+            n_sim: Optional[int] = None, seed: Optional[int] = None) \
+        -> Union[np.ndarray, Tuple[np.ndarray, np.ndarray, np.ndarray]]: ...
+
+def pair_correlation(points: Points, r_max: float, n_bins: int, bandwidth: Optional[float] = None,
+                     types: Optional[List[str]] = None,
+                     area: Optional[Union[float, Tuple[float, float, float, float]]] = None,
+                     correction: str = 'border') \
+        -> Union[Tuple[np.ndarray, np.ndarray], Tuple[np.ndarray, Dict[Tuple[str, str], np.ndarray]]]: ...
+
+def g_function(points: Points, radii: List[float],
+               types: Optional[List[str]] = None) -> Union[np.ndarray, Dict[str, np.ndarray]]: ...
+
+def f_function(points: Points, radii: List[float], grid_resolution: float,
+               window: Optional[Tuple[float, float, float, float]] = None) -> np.ndarray: ...
+
+def clark_evans(points: Points, area: Optional[Union[float, Tuple[float, float, float, float]]] = None,
+                types: Optional[List[str]] = None,
+                correction: str = 'none') -> Union[Dict[str, float], Dict[str, Dict[str, float]]]: ...
+
+def get_neighbors_annulus(points: Points, r_min: float, r_max: float,
+                          labels: Optional[List[int]] = None) -> List[List[int]]: ...
+
+def get_neighbors_adaptive(points: Points, r_start: float, r_max: float, min_n: int,
+                           exclude_self: bool = False) -> Tuple[List[List[int]], List[float], List[int]]: ...
+
+def get_neighbors_periodic(points: Points, r: float, width: float, height: float,
+                           labels: Optional[List[int]] = None) -> List[List[int]]: ...
+
+def get_neighbors_3d(points: List[Tuple[float, float, float]], r: float,
+                     labels: Optional[List[int]] = None) -> List[List[int]]: ...
+
+def get_grid_neighbors(points: Points, spacing: float, layout: str = 'hex',
+                       tolerance: Optional[float] = None) -> Tuple[List[List[int]], List[int]]: ...
+
+def get_cross_neighbors(points_a: List[Tuple[float, float]], points_b: List[Tuple[float, float]],
+                        r: float) -> List[List[int]]: ...
+
+def get_cross_neighbors_knn(points_a: List[Tuple[float, float]], points_b: List[Tuple[float, float]],
+                            k: int) -> List[List[int]]: ...
+
+def get_points_in_box(points: Points, min_x: float, min_y: float, max_x: float, max_y: float) -> List[int]: ...
+
+def get_points_in_boxes(points: Points, boxes: List[Tuple[float, float, float, float]]) -> List[List[int]]: ...
 
 def get_bbox_neighbors(bbox_list: List[Tuple[float, float, float, float]],
                        expand: float = 1.0, scale: float = 1.0, labels: Optional[List[int]] = None) -> List[List[int]]: ...
 
-def comb_bootstrap(x_status: List[bool], y_status: List[bool], neighbors: Dict[int, List[int]],
-                   times: int = 500, ignore_self: bool = False) -> float: ...
+def get_neighbors_shape(shapes: List[List[Tuple[float, float]]], expand: float,
+                        labels: Optional[List[int]] = None) -> List[List[int]]: ...
+
+def edges_to_neighbors(edges: Union[List[Tuple[int, int]], Tuple[np.ndarray, np.ndarray]],
+                       n_points: int) -> List[List[int]]: ...
+
+def neighbors_to_coo(neighbors: Neighbors, n_points: int,
+                     points: Optional[Points] = None) -> Tuple[np.ndarray, np.ndarray, np.ndarray]: ...
+
+def symmetrize_neighbors(neighbors: Neighbors, mode: str = 'union') -> List[List[int]]: ...
+
+def assert_symmetric(neighbors: Neighbors) -> None: ...
+
+def neighbor_graph_stats(neighbors: Neighbors, directed: bool = False) -> Dict: ...
+
+def subsample_neighbors(neighbors: Neighbors, fraction: float, seed: Optional[int] = None) -> List[List[int]]: ...
+
+def coo_to_neighbors(row: np.ndarray, col: np.ndarray, n_points: int) -> List[List[int]]: ...
+
+def save_neighbors(path: str, neighbors: Neighbors) -> None: ...
+
+def load_neighbors(path: str) -> List[List[int]]: ...
+
+def comb_bootstrap(x_status: Union[List[bool], List[int], List[float]],
+                   y_status: Union[List[bool], List[int], List[float]], neighbors: Neighbors,
+                   times: int = 500, ignore_self: bool = False, *, seed: Optional[int] = None,
+                   return_detail: bool = False, symmetric: bool = False,
+                   groups: Optional[List[int]] = None, adaptive: bool = False, stop_alpha: float = 0.05,
+                   max_times: Optional[int] = None,
+                   on_zero_variance: str = 'warn', count: str = 'incidence',
+                   return_permutations: bool = False,
+                   weights: Optional[List[List[float]]] = None,
+                   method: str = 'permutation', shuffle: str = 'y',
+                   progress: Optional[Callable[[int, int], None]] = None) -> Union[float, Dict[str, float], Tuple]: ...
+
+def comb_bootstrap_matrix(marker_matrix: np.ndarray, neighbors: Neighbors, times: int = 500,
+                          ignore_self: bool = False, seed: Optional[int] = None,
+                          markers: Optional[List[str]] = None) -> Union[np.ndarray, Dict[Tuple[str, str], float]]: ...
+
+def neighbor_composition(types: Union[List[str], List[int], np.ndarray], neighbors: Neighbors,
+                         normalize: bool = False, ignore_self: bool = False, on_empty: str = 'zero') -> Tuple[np.ndarray, List[str]]: ...
+
+def neighborhood_entropy(types: Union[List[str], List[int], np.ndarray], neighbors: Neighbors, base: float = 2,
+                         ignore_self: bool = False, normalized: bool = False) -> np.ndarray: ...
+
+def neighborhood_clusters(types: Union[List[str], List[int], np.ndarray], neighbors: Neighbors, k: int,
+                          n_iter: int = 100, seed: Optional[int] = None,
+                          ignore_self: bool = False) -> Tuple[np.ndarray, np.ndarray, List[str]]: ...
+
+def adjust_pvalues(pvalues: List[float], method: str = 'fdr_bh') -> List[float]: ...
+
+def expression_bootstrap(x_values: List[float], y_values: List[float], neighbors: Neighbors, times: int = 500,
+                         stat: str = 'product', ignore_self: bool = False, seed: Optional[int] = None,
+                         ignore_nan: bool = False) -> Tuple[float, float]: ...
+
+def moran_i(values: List[float], neighbors: Union[Neighbors, WeightedNeighbors],
+            weights: Optional[List[List[float]]] = None, permutations: int = 0, seed: Optional[int] = None,
+            standardize: bool = True, ignore_nan: bool = False) -> Dict[str, float]: ...
+
+def geary_c(values: List[float], neighbors: Union[Neighbors, WeightedNeighbors],
+            weights: Optional[List[List[float]]] = None, permutations: int = 0, seed: Optional[int] = None,
+            standardize: bool = True, ignore_nan: bool = False) -> Dict[str, float]: ...
+
+def local_moran(values: List[float], neighbors: Union[Neighbors, WeightedNeighbors], permutations: int = 999,
+                seed: Optional[int] = None, weights: Optional[List[List[float]]] = None,
+                standardize: bool = True) -> Dict[str, Union[np.ndarray, List[Optional[str]]]]: ...
+
+def getis_ord(values: List[float], neighbors: Union[Neighbors, WeightedNeighbors], star: bool = True,
+              weights: Optional[List[List[float]]] = None, adjust: Optional[str] = None) -> Dict[str, np.ndarray]: ...
+
+def join_counts(types: Union[List[str], List[int]], neighbors: Neighbors, permutations: int = 999,
+                seed: Optional[int] = None,
+                on_zero_variance: str = 'warn') -> Dict[Tuple[str, str], Dict[str, float]]: ...
+
+def assortativity(types: Union[List[str], List[int]], neighbors: Neighbors, permutations: int = 999,
+                  seed: Optional[int] = None, on_zero_variance: str = 'warn') -> Dict[str, float]: ...
+
+def mixing_score(types: Union[List[str], List[int]], neighbors: Neighbors, type_a: str, type_b: str,
+                 normalize: str = 'b', permutations: int = 0, seed: Optional[int] = None,
+                 on_zero_variance: str = 'warn') -> Union[float, Tuple[float, float]]: ...
+
+def infiltration_score(types: Union[List[str], List[int]], neighbors: Neighbors, type_a: str, type_b: str,
+                       permutations: int = 0, seed: Optional[int] = None,
+                       on_zero_variance: str = 'warn') -> Union[float, Tuple[float, float]]: ...
+
+class PointIndex:
+    def __init__(self, points: Points, node_size: int = 64): ...
+
+    def within(self, r: float) -> List[List[int]]: ...
+
+    def within_of(self, indices: List[int], r: float) -> List[List[int]]: ...
+
+    def knn(self, k: int, exclude_self: bool = True) -> List[List[int]]: ...
+
+class PointIndexF32:
+    def __init__(self, points: Points): ...
+
+    def within(self, r: float) -> List[List[int]]: ...
+
+    def within_of(self, indices: List[int], r: float) -> List[List[int]]: ...
+
+class CombsTable:
+    def to_json(self, path: str) -> None: ...
+
+    def to_csv(self, path: str) -> None: ...
+
+    @staticmethod
+    def from_json(path: str) -> CombsTable: ...
+
+    def records(self) -> List[Dict[str, Union[Tuple[str, str], float]]]: ...
 
 class CellCombs:
-    def __init__(self, types: List[str], order: bool = False): ...
+    def __init__(self, types: Union[List[str], List[int], np.ndarray], order: bool = False,
+                 categories: Optional[List[str]] = None): ...
+
+    def bootstrap(self, types: Union[List[str], List[int], np.ndarray], neighbors: Union[Neighbors, WeightedNeighbors],
+                  times: int = 500, pval: float = 0.05,
+                  method: str = 'pval', ignore_self: bool = False, *,
+                  require_symmetric: bool = False,
+                  groups: Optional[List[int]] = None,
+                  blocks: Optional[List[int]] = None,
+                  progress: Optional[Callable[[int, int], None]] = None,
+                  seed: Optional[int] = None,
+                  return_type: str = 'list',
+                  adjust: Optional[str] = None,
+                  selected_combs: Optional[List[Tuple[str, str]]] = None,
+                  categories: Optional[List[str]] = None,
+                  on_zero_variance: str = 'warn',
+                  stat: str = 'mean',
+                  min_cells: Optional[int] = None,
+                  drop_rare: bool = False,
+                  pseudocount: float = 1.0,
+                  allow_unknown: bool = False,
+                  histogram: Optional[int] = None) -> Union[CombsResult, Tuple[CombsResult, CombsResult]]: ...
+
+    def bootstrap_batch(self, types_list: List[Union[List[str], List[int], np.ndarray]], neighbors_list: List[Neighbors],
+                        times: int = 500, pval: float = 0.05, method: str = 'pval', ignore_self: bool = False, *,
+                        seed: Optional[int] = None, return_type: str = 'list', adjust: Optional[str] = None,
+                        selected_combs: Optional[List[Tuple[str, str]]] = None,
+                        categories: Optional[List[str]] = None, parallel: bool = True,
+                        meta: bool = False,
+                        on_zero_variance: str = 'warn',
+                        stat: str = 'mean',
+                        pseudocount: float = 1.0) -> Union[List[CombsResult], Tuple[List[CombsResult], CombsResult]]: ...
+
+    def bootstrap_marker(self, types: Union[List[str], List[int], np.ndarray], neighbors: Neighbors,
+                         marker_status: List[bool], focal_type: str, marker: str, *, times: int = 500,
+                         pval: float = 0.05, method: str = 'pval', ignore_self: bool = False,
+                         require_symmetric: bool = False,
+                         groups: Optional[List[int]] = None,
+                         blocks: Optional[List[int]] = None,
+                         progress: Optional[Callable[[int, int], None]] = None,
+                         seed: Optional[int] = None,
+                         return_type: str = 'list',
+                         adjust: Optional[str] = None,
+                         selected_combs: Optional[List[Tuple[str, str]]] = None,
+                         categories: Optional[List[str]] = None,
+                         on_zero_variance: str = 'warn',
+                         stat: str = 'mean',
+                         min_cells: Optional[int] = None,
+                         drop_rare: bool = False,
+                         pseudocount: float = 1.0,
+                         allow_unknown: bool = False,
+                         histogram: Optional[int] = None) -> Union[CombsResult, Tuple[CombsResult, CombsResult]]: ...
+
+    def cell_scores(self, types: Union[List[str], List[int], np.ndarray], neighbors: Neighbors, target_type: str,
+                    times: int = 500, *, seed: Optional[int] = None, ignore_self: bool = False,
+                    categories: Optional[List[str]] = None) -> np.ndarray: ...
 
-    def bootstrap(self, types: List[str], neighbors: List[List[int]], times: int = 500, pval: float = 0.05,
-                  method: str = 'pval', ignore_self: bool = False) -> List[Tuple[Tuple[str, str], float]]: ...
+    def bootstrap_ci(self, types: Union[List[str], List[int], np.ndarray], neighbors: Neighbors, n_boot: int = 1000,
+                     ci: float = 0.95, *, seed: Optional[int] = None, ignore_self: bool = False, stat: str = 'mean',
+                     return_type: str = 'list', categories: Optional[List[str]] = None) \
+            -> Union[List[Tuple[Tuple[str, str], Tuple[float, float]]], Dict[Tuple[str, str], Tuple[float, float]]]: ...
@@ -0,0 +1,81 @@
+use crate::neighbors::sq_dist;
+
+fn cross(o: &(f64, f64), a: &(f64, f64), b: &(f64, f64)) -> f64 {
+    (a.0 - o.0) * (b.1 - o.1) - (a.1 - o.1) * (b.0 - o.0)
+}
+
+fn on_segment(p: &(f64, f64), a: &(f64, f64), b: &(f64, f64)) -> bool {
+    p.0 >= a.0.min(b.0) && p.0 <= a.0.max(b.0) && p.1 >= a.1.min(b.1) && p.1 <= a.1.max(b.1)
+}
+
+pub fn segments_intersect(a: &(f64, f64), b: &(f64, f64), c: &(f64, f64), d: &(f64, f64)) -> bool {
+    let d1 = cross(c, d, a);
+    let d2 = cross(c, d, b);
+    let d3 = cross(a, b, c);
+    let d4 = cross(a, b, d);
+    if ((d1 > 0.0 && d2 < 0.0) || (d1 < 0.0 && d2 > 0.0))
+        && ((d3 > 0.0 && d4 < 0.0) || (d3 < 0.0 && d4 > 0.0))
+    {
+        return true;
+    }
+    // collinear cases
+    (d1 == 0.0 && on_segment(a, c, d))
+        || (d2 == 0.0 && on_segment(b, c, d))
+        || (d3 == 0.0 && on_segment(c, a, b))
+        || (d4 == 0.0 && on_segment(d, a, b))
+}
+
+pub fn point_segment_dist(p: &(f64, f64), a: &(f64, f64), b: &(f64, f64)) -> f64 {
+    let l2 = sq_dist(a, b);
+    if l2 == 0.0 {
+        return sq_dist(p, a).sqrt();
+    }
+    let t = (((p.0 - a.0) * (b.0 - a.0) + (p.1 - a.1) * (b.1 - a.1)) / l2).clamp(0.0, 1.0);
+    let proj = (a.0 + t * (b.0 - a.0), a.1 + t * (b.1 - a.1));
+    sq_dist(p, &proj).sqrt()
+}
+
+pub fn segments_dist(a: &(f64, f64), b: &(f64, f64), c: &(f64, f64), d: &(f64, f64)) -> f64 {
+    if segments_intersect(a, b, c, d) {
+        return 0.0;
+    }
+    point_segment_dist(a, c, d)
+        .min(point_segment_dist(b, c, d))
+        .min(point_segment_dist(c, a, b))
+        .min(point_segment_dist(d, a, b))
+}
+
+// ray casting, points on the boundary may go either way
+pub fn point_in_polygon(p: &(f64, f64), polygon: &[(f64, f64)]) -> bool {
+    let mut inside = false;
+    let n = polygon.len();
+    for i in 0..n {
+        let a = &polygon[i];
+        let b = &polygon[(i + 1) % n];
+        if (a.1 > p.1) != (b.1 > p.1) && p.0 < (b.0 - a.0) * (p.1 - a.1) / (b.1 - a.1) + a.0 {
+            inside = !inside;
+        }
+    }
+    inside
+}
+
+// the min distance between two polygons, 0 if they overlap
+pub fn polygons_dist(p: &[(f64, f64)], q: &[(f64, f64)]) -> f64 {
+    if p.len() > 2 && q.iter().any(|v| point_in_polygon(v, p)) {
+        return 0.0;
+    }
+    if q.len() > 2 && p.iter().any(|v| point_in_polygon(v, q)) {
+        return 0.0;
+    }
+    let mut dist = f64::INFINITY;
+    // the edges of a polygon are between each vertex and the next one, the last one closes the ring
+    for (a, b) in p.iter().zip(p.iter().cycle().skip(1)) {
+        for (c, d) in q.iter().zip(q.iter().cycle().skip(1)) {
+            dist = dist.min(segments_dist(a, b, c, d));
+            if dist == 0.0 {
+                return dist;
+            }
+        }
+    }
+    dist
+}
@@ -1,11 +1,17 @@
+mod geometry;
+mod neighbors;
+mod storage;
 mod utils;
 
+use geometry::*;
+use neighbors::*;
+use storage::*;
 use utils::*;
 
 use itertools::Itertools;
 use rand::seq::SliceRandom;
-use rand::thread_rng;
-use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
 
 use kdbush::KDBush;
 use counter::Counter;
@@ -14,21 +20,204 @@ use rstar::{RTree, RTreeObject, AABB};
 use spade::BoundingRect;
 
 // pyo3 dependencies
-use pyo3::exceptions::PyTypeError;
+use pyo3::exceptions::{PyIOError, PyRuntimeError, PyTypeError, PyValueError};
 use pyo3::prelude::*;
+use pyo3::types::{PyDict, PyTuple};
 use pyo3::wrap_pyfunction;
 
+// numpy dependencies
+use numpy::{IntoPyArray, PyReadonlyArray1, PyReadonlyArray2};
+
 #[pymodule]
 fn neighborhood_analysis(_py: Python, m: &PyModule) -> PyResult<()> {
     m.add_wrapped(wrap_pyfunction!(get_bbox))?;
     m.add_wrapped(wrap_pyfunction!(get_point_neighbors))?;
+    m.add_wrapped(wrap_pyfunction!(get_point_neighbors_f32))?;
+    m.add_wrapped(wrap_pyfunction!(get_neighbors_batch))?;
+    m.add_wrapped(wrap_pyfunction!(get_neighbors_knn))?;
+    m.add_wrapped(wrap_pyfunction!(get_neighbors_mutual_knn))?;
+    m.add_wrapped(wrap_pyfunction!(get_neighbors_with_distances))?;
+    m.add_wrapped(wrap_pyfunction!(get_neighbor_counts))?;
+    m.add_wrapped(wrap_pyfunction!(nearest_neighbor_distances))?;
+    m.add_wrapped(wrap_pyfunction!(ripley_k))?;
+    m.add_wrapped(wrap_pyfunction!(cross_k))?;
+    m.add_wrapped(wrap_pyfunction!(pair_correlation))?;
+    m.add_wrapped(wrap_pyfunction!(g_function))?;
+    m.add_wrapped(wrap_pyfunction!(f_function))?;
+    m.add_wrapped(wrap_pyfunction!(clark_evans))?;
+    m.add_wrapped(wrap_pyfunction!(get_neighbors_weighted))?;
+    m.add_wrapped(wrap_pyfunction!(get_neighbors_annulus))?;
+    m.add_wrapped(wrap_pyfunction!(get_neighbors_adaptive))?;
+    m.add_wrapped(wrap_pyfunction!(get_neighbors_periodic))?;
+    m.add_wrapped(wrap_pyfunction!(get_neighbors_3d))?;
+    m.add_wrapped(wrap_pyfunction!(get_grid_neighbors))?;
+    m.add_wrapped(wrap_pyfunction!(get_cross_neighbors))?;
+    m.add_wrapped(wrap_pyfunction!(get_cross_neighbors_knn))?;
+    m.add_wrapped(wrap_pyfunction!(get_points_in_box))?;
+    m.add_wrapped(wrap_pyfunction!(get_points_in_boxes))?;
     m.add_wrapped(wrap_pyfunction!(get_bbox_neighbors))?;
+    m.add_wrapped(wrap_pyfunction!(get_neighbors_shape))?;
+    m.add_wrapped(wrap_pyfunction!(edges_to_neighbors))?;
+    m.add_wrapped(wrap_pyfunction!(neighbors_to_coo))?;
+    m.add_wrapped(wrap_pyfunction!(symmetrize_neighbors))?;
+    m.add_wrapped(wrap_pyfunction!(assert_symmetric))?;
+    m.add_wrapped(wrap_pyfunction!(neighbor_graph_stats))?;
+    m.add_wrapped(wrap_pyfunction!(subsample_neighbors))?;
+    m.add_wrapped(wrap_pyfunction!(coo_to_neighbors))?;
+    m.add_wrapped(wrap_pyfunction!(save_neighbors))?;
+    m.add_wrapped(wrap_pyfunction!(load_neighbors))?;
+    m.add_class::<PointIndex>()?;
+    m.add_class::<PointIndexF32>()?;
     m.add_class::<CellCombs>()?;
+    m.add_class::<CombsTable>()?;
     m.add_wrapped(wrap_pyfunction!(comb_bootstrap))?;
+    m.add_wrapped(wrap_pyfunction!(comb_bootstrap_matrix))?;
+    m.add_wrapped(wrap_pyfunction!(adjust_pvalues))?;
+    m.add_wrapped(wrap_pyfunction!(neighbor_composition))?;
+    m.add_wrapped(wrap_pyfunction!(expression_bootstrap))?;
+    m.add_wrapped(wrap_pyfunction!(moran_i))?;
+    m.add_wrapped(wrap_pyfunction!(geary_c))?;
+    m.add_wrapped(wrap_pyfunction!(local_moran))?;
+    m.add_wrapped(wrap_pyfunction!(getis_ord))?;
+    m.add_wrapped(wrap_pyfunction!(join_counts))?;
+    m.add_wrapped(wrap_pyfunction!(assortativity))?;
+    m.add_wrapped(wrap_pyfunction!(mixing_score))?;
+    m.add_wrapped(wrap_pyfunction!(infiltration_score))?;
+    m.add_wrapped(wrap_pyfunction!(neighborhood_entropy))?;
+    m.add_wrapped(wrap_pyfunction!(neighborhood_clusters))?;
     m.add_wrapped(wrap_pyfunction!(neighbor_components))?;
+    m.add_wrapped(wrap_pyfunction!(set_num_threads))?;
+    m.add_wrapped(wrap_pyfunction!(get_num_threads))?;
+    Ok(())
+}
+
+
+fn extract_index_array(obj: &PyAny) -> PyResult<Vec<usize>> {
+    if let Ok(arr) = obj.extract::<PyReadonlyArray1<u32>>() {
+        return Ok(arr.as_array().iter().map(|v| *v as usize).collect());
+    }
+    if let Ok(arr) = obj.extract::<PyReadonlyArray1<u64>>() {
+        return Ok(arr.as_array().iter().map(|v| *v as usize).collect());
+    }
+    if let Ok(arr) = obj.extract::<PyReadonlyArray1<i64>>() {
+        return arr
+            .as_array()
+            .iter()
+            .map(|v| {
+                if *v < 0 {
+                    Err(PyValueError::new_err(format!(
+                        "Index should be non-negative, got {}.",
+                        v
+                    )))
+                } else {
+                    Ok(*v as usize)
+                }
+            })
+            .collect();
+    }
+    obj.extract::<Vec<usize>>()
+}
+
+// points can be a list of tuple or a numpy array in shape of (n, 2)
+fn extract_points(py: Python, points: &PyObject) -> PyResult<Vec<(f64, f64)>> {
+    if let Ok(arr) = points.extract::<PyReadonlyArray2<f64>>(py) {
+        check_points_shape(arr.shape())?;
+        return Ok(arr.as_array().outer_iter().map(|p| (p[0], p[1])).collect());
+    }
+    if let Ok(arr) = points.extract::<PyReadonlyArray2<f32>>(py) {
+        check_points_shape(arr.shape())?;
+        return Ok(arr
+            .as_array()
+            .outer_iter()
+            .map(|p| (p[0] as f64, p[1] as f64))
+            .collect());
+    }
+
+    match points.extract(py) {
+        Ok(data) => Ok(data),
+        Err(_) => Err(PyTypeError::new_err(
+            "Can't resolve `points`, should be list of tuple or numpy array in shape of (n, 2).",
+        )),
+    }
+}
+
+// keep float32 points in single precision, other inputs are downcast
+fn extract_points_f32(py: Python, points: &PyObject) -> PyResult<Vec<[f32; 2]>> {
+    if let Ok(arr) = points.extract::<PyReadonlyArray2<f32>>(py) {
+        check_points_shape(arr.shape())?;
+        return Ok(arr.as_array().outer_iter().map(|p| [p[0], p[1]]).collect());
+    }
+    Ok(extract_points(py, points)?
+        .into_iter()
+        .map(|p| [p.0 as f32, p.1 as f32])
+        .collect())
+}
+
+fn check_points_shape(shape: &[usize]) -> PyResult<()> {
+    if shape[1] != 2 {
+        return Err(PyValueError::new_err(format!(
+            "`points` should be in shape of (n, 2), got {:?}.",
+            shape
+        )));
+    }
     Ok(())
 }
 
+// pyo3 0.13 has no type for UserWarning, the category is looked up from builtins
+fn warn_user(py: Python, msg: &str) -> PyResult<()> {
+    let category = py.import("builtins")?.getattr("UserWarning")?;
+    PyErr::warn(py, category, msg, 1)
+}
+
+// neighbors can be a list of list, a dict of list, a tuple of (indptr, indices) in CSR layout
+// or a tuple of (row, col, data, n_points) in COO layout, the data is ignored.
+// A tuple is always read as a sparse layout, the neighbors of each cell should be given as a list.
+fn extract_neighbors(py: Python, neighbors: &PyObject) -> PyResult<Vec<Vec<usize>>> {
+    if let Ok(sparse) = neighbors.cast_as::<PyTuple>(py) {
+        let result = match sparse.len() {
+            2 => {
+                let indptr = extract_index_array(sparse.get_item(0))?;
+                let indices = extract_index_array(sparse.get_item(1))?;
+                csr_to_neighbors(&indptr, &indices)
+            }
+            4 => {
+                let row = extract_index_array(sparse.get_item(0))?;
+                let col = extract_index_array(sparse.get_item(1))?;
+                let n: usize = sparse.get_item(3).extract()?;
+                coo_to_graph(&row, &col, n)
+            }
+            n => Err(format!(
+                "A tuple of neighbors should be (indptr, indices) or (row, col, data, n_points), \
+                got a tuple of length {}.",
+                n
+            )),
+        };
+        return match result {
+            Ok(data) => Ok(data),
+            Err(msg) => Err(PyValueError::new_err(msg)),
+        };
+    }
+
+    if let Ok(data) = neighbors.extract(py) {
+        return Ok(data);
+    }
+
+    // the key is the cell index, missing keys have no neighbors
+    match neighbors.extract::<HashMap<usize, Vec<usize>>>(py) {
+        Ok(data) => {
+            let n = data.keys().max().map_or(0, |v| v + 1);
+            let mut result = vec![vec![]; n];
+            for (k, v) in data {
+                result[k] = v;
+            }
+            Ok(result)
+        }
+        Err(_) => Err(PyTypeError::new_err(
+            "Can't resolve `neighbors`, should be a list of list, a dict of list, \
+            a tuple of (indptr, indices) or a tuple of (row, col, data, n_points).",
+        )),
+    }
+}
 
 #[pyfunction]
 pub fn neighbor_components(neighbors: HashMap<usize, Vec<usize>>, types: HashMap<usize, &str>)
@@ -83,378 +272,5801 @@ pub fn get_bbox(points_collections: Vec<Vec<(f64, f64)>>) -> Vec<(f64, f64, f64,
     bbox
 }
 
-/// get_point_neighbors(points, r)
+/// get_point_neighbors(points, r, labels=None, use_both=False, exclude_self=False, format="list", sort=None,
+///                     max_neighbors=None, metric="euclidean", node_size=64, query_indices=None,
+///                     drop_invalid=False, types=None, target_types=None, handle_duplicates="keep",
+///                     radius_by_type=None)
 /// --
 ///
 /// A utility function to search for point neighbors using kd-tree
 ///
 /// Args:
-///     points: List[tuple(float, float)]; Two dimension points
-///     r: float; The search radius
+///     points: List[tuple(float, float)] or np.ndarray; Two dimension points,
+///             a C-contiguous float64 array is used without copying
+///     r: float or List[float]; The search radius, or the search radius of each point, can be None with `radius_by_type`
+///     labels: List[int]; The labels of points, if given, the neighbors will be returned as labels
+///     use_both: bool (False); Only work with radius of each point,
+///               if True, point j is a neighbor of point i when their distance is within r[i] + r[j]
+///     exclude_self: bool (False); Whether to exclude the point itself from its neighbors,
+///                   equals to `ignore_self=True` in the bootstrap functions
+///     format: str ("list"); "list", "csr" or "edges"
+///     sort: str (None); "index" to sort the neighbors by index, "distance" to sort from the nearest to the farthest,
+///           points at the same distance are ordered by index. If None, the order is decided by the kd-tree.
+///     max_neighbors: int (None); Keep only the nearest neighbors within radius if there are more than this number
+///     metric: str ("euclidean"); "euclidean", "manhattan" or "chebyshev", points on the boundary are included.
+///             The `sort` and `max_neighbors` always use euclidean distance.
+///     node_size: int (64); The node size of kd-tree, larger is faster to build, smaller is faster to query
+///     query_indices: List[int] (None); Only search for neighbors of these points, the neighbors still
+///                    index into all the points
+///     drop_invalid: bool (False); Points with NaN or infinite coordinates raise ValueError by default,
+///                   if True, these points are dropped
+///     types: List[str] (None); The type of each point
+///     target_types: Set[str] (None); Only points of these types are indexed and can be neighbors, require `types`
+///     handle_duplicates: str ("keep"); How to handle points with exactly the same coordinates,
+///                        "keep" does nothing, "warn" emits a warning listing the duplicate groups,
+///                        "merge" replaces the duplicates in neighbors with the first one of each group
+///     radius_by_type: Dict[str, float] (None); The search radius of each type, require `types`,
+///                     if given, point i uses the radius of its type and `r` is ignored
 ///
 /// Return:
-///     A list of neighbors' index, return as the order of the input
+///     A list of neighbors' index, return as the order of the input (or the order of query_indices).
+///     If format is "csr", return a tuple of two numpy arrays (indptr, indices), the neighbors of point i
+///     are indices[indptr[i]:indptr[i + 1]]. If format is "edges", return a tuple of two numpy arrays
+///     (source, target) of undirected edges with source < target, self-loops are removed.
+///     The arrays are uint32 if the number allows, otherwise uint64.
+///     If drop_invalid is True, return a tuple of (neighbors, kept), the neighbors index into the kept points,
+///     and kept is the list of original index of the kept points.
+///     If handle_duplicates is "merge", the mapping from each point to its representative point is appended
+///     to the returned tuple, eg. (neighbors, mapping) or (neighbors, kept, mapping).
 ///
 #[pyfunction]
-pub fn get_point_neighbors(points: Vec<(f64, f64)>, r: f64, labels: Option<Vec<usize>>) -> Vec<Vec<usize>> {
-    let mut has_labels = false;
-    let labels: Vec<usize> = match labels {
-        Some(data) => {
-            has_labels = true;
-            data
+#[allow(clippy::too_many_arguments)]
+pub fn get_point_neighbors(
+    py: Python,
+    points: PyObject,
+    r: PyObject,
+    labels: Option<Vec<usize>>,
+    use_both: Option<bool>,
+    exclude_self: Option<bool>,
+    format: Option<&str>,
+    sort: Option<&str>,
+    max_neighbors: Option<usize>,
+    metric: Option<&str>,
+    node_size: Option<usize>,
+    query_indices: Option<Vec<usize>>,
+    drop_invalid: Option<bool>,
+    types: Option<Vec<&str>>,
+    target_types: Option<PyObject>,
+    handle_duplicates: Option<&str>,
+    radius_by_type: Option<HashMap<String, f64>>,
+) -> PyResult<PyObject> {
+    // a C-contiguous float64 array is borrowed without copying
+    let array = points.extract::<PyReadonlyArray2<f64>>(py).ok();
+    let mut points = match array.as_ref().map(|arr| (arr.shape(), arr.as_slice())) {
+        Some((shape, Ok(coords))) => {
+            check_points_shape(shape)?;
+            Points::Flat(coords)
         }
-        None => vec![0],
+        _ => Points::Owned(extract_points(py, &points)?),
     };
 
-    let tree = KDBush::create(points.to_owned(), kdbush::DEFAULT_NODE_SIZE); // make an index
-    let result: HashMap<usize, Vec<usize>> = points
-        .par_iter()
-        .enumerate()
-        .map(|(i, p)| {
-            let mut neighbors: Vec<usize> = vec![];
-            tree.within(p.0, p.1, r, |id| neighbors.push(id));
-            (i, neighbors)
-        })
-        .collect();
-
-    let count = points.len();
-    let mut neighbors = vec![];
-    if has_labels {
-        for i in 0..count {
-            neighbors.push(result.get(&i).unwrap().clone().iter().map(
-                |t| { labels[*t] }
-            ).collect())
-        }
-    } else {
-        for i in 0..count {
-            neighbors.push(result.get(&i).unwrap().clone())
+    if let Some(t) = &types {
+        if t.len() != points.size() {
+            return Err(PyValueError::new_err(format!(
+                "The length of `types` ({}) should match the length of `points` ({}).",
+                t.len(),
+                points.size()
+            )));
         }
     }
 
-    neighbors
-}
+    let mut radii: Vec<f64> = match &radius_by_type {
+        Some(map) => match &types {
+            Some(t) => {
+                let mut radii = Vec::with_capacity(t.len());
+                for c in t {
+                    match map.get(*c) {
+                        Some(data) => radii.push(*data),
+                        None => {
+                            return Err(PyValueError::new_err(format!(
+                                "Type '{}' is not found in `radius_by_type`.",
+                                c
+                            )));
+                        }
+                    }
+                }
+                radii
+            }
+            None => {
+                return Err(PyValueError::new_err(
+                    "`types` is required when `radius_by_type` is given.",
+                ));
+            }
+        },
+        None => match r.extract::<f64>(py) {
+            Ok(data) => vec![data; points.size()],
+            Err(_) => match r.extract(py) {
+                Ok(data) => data,
+                Err(_) => {
+                    return Err(PyTypeError::new_err(
+                        "Can't resolve `r`, should be a float or list of float.",
+                    ));
+                }
+            },
+        },
+    };
 
-// customize object to insert in to R-tree
-struct Rect {
-    minx: f64,
-    miny: f64,
-    maxx: f64,
-    maxy: f64,
-    index: usize,
-}
+    if radii.len() != points.size() {
+        return Err(PyValueError::new_err(format!(
+            "The length of `r` ({}) should match the length of `points` ({}).",
+            radii.len(),
+            points.size()
+        )));
+    }
 
-impl Rect {
-    fn new(bbox: (f64, f64, f64, f64), index: usize) -> Rect {
-        Rect {
-            minx: bbox.0,
-            miny: bbox.1,
-            maxx: bbox.2,
-            maxy: bbox.3,
-            index,
+    let mut is_target: Option<Vec<bool>> = match target_types {
+        Some(target) => {
+            let target: HashSet<String> = match target.extract(py) {
+                Ok(data) => data,
+                Err(_) => match target.extract::<Vec<String>>(py) {
+                    Ok(data) => data.into_iter().collect(),
+                    Err(_) => {
+                        return Err(PyTypeError::new_err(
+                            "Can't resolve `target_types`, should be set or list of string.",
+                        ));
+                    }
+                },
+            };
+            match &types {
+                Some(t) => Some(t.iter().map(|c| target.contains(*c)).collect()),
+                None => {
+                    return Err(PyValueError::new_err(
+                        "`types` is required when `target_types` is given.",
+                    ));
+                }
+            }
         }
-    }
-}
+        None => None,
+    };
 
-impl RTreeObject for Rect {
-    type Envelope = AABB<[f64; 2]>;
+    let drop_invalid = match drop_invalid {
+        Some(data) => data,
+        None => false,
+    };
 
-    fn envelope(&self) -> Self::Envelope {
-        AABB::from_corners([self.minx, self.miny], [self.maxx, self.maxy])
+    let invalid = invalid_points(&points);
+    if !invalid.is_empty() && !drop_invalid {
+        return Err(PyValueError::new_err(format!(
+            "Points at index {:?} have NaN or infinite coordinates.",
+            invalid
+        )));
     }
-}
-
-/// get_bbox_neighbors(bbox_list, expand=1.0, scale=1.0)
-/// --
-///
-/// A utility function to search for bbox neighbors using r-tree
-///
-/// Args:
-///     bbox_list: List[tuple(float, float, float, float)]; The minimum bounding box of any polygon
-///               (minx, miny, maxx, maxy)
-///     expand: float; The expand unit
-///     scale: float; The scale fold number
-///
-/// Return:
-///     A list of neighbors' index, return as the order of the input
-///
-#[pyfunction]
-pub fn get_bbox_neighbors(
-    bbox_list: Vec<(f64, f64, f64, f64)>,
-    expand: Option<f64>,
-    scale: Option<f64>,
-    labels: Option<Vec<usize>>,
-) -> Vec<Vec<usize>> {
-    let mut expand_na: bool = true;
-    let expand: f64 = match expand {
-        Some(data) => {
-            expand_na = false;
-            data
+    // map the original index to the index after dropping invalid points
+    let mut new_index: Vec<Option<usize>> = (0..points.size()).map(Some).collect();
+    let kept: Vec<usize> = (0..points.size())
+        .filter(|i| invalid.binary_search(i).is_err())
+        .collect();
+    let mut labels = labels;
+    if !invalid.is_empty() {
+        for i in &invalid {
+            new_index[*i] = None;
         }
-        None => 0.0,
+        for (n, i) in kept.iter().enumerate() {
+            new_index[*i] = Some(n);
+        }
+        points = Points::Owned(kept.iter().map(|i| points.point(*i)).collect());
+        radii = kept.iter().map(|i| radii[*i]).collect();
+        labels = labels.map(|l| kept.iter().map(|i| l[*i]).collect());
+        is_target = is_target.map(|t| kept.iter().map(|i| t[*i]).collect());
+    }
+
+    let use_both = match use_both {
+        Some(data) => data,
+        None => false,
     };
 
-    let scale: f64 = match scale {
+    let exclude_self = match exclude_self {
         Some(data) => data,
-        None => 1.0,
+        None => false,
     };
 
-    let mut has_labels = false;
-    let labels: Vec<usize> = match labels {
-        Some(data) => {
-            has_labels = true;
-            data
-        }
-        None => vec![0],
+    let format = match format {
+        Some(data) => data,
+        None => "list",
     };
+    if format != "list" && format != "csr" && format != "edges" {
+        return Err(PyValueError::new_err(format!(
+            "Unknown format `{}`, should be 'list', 'csr' or 'edges'.",
+            format
+        )));
+    }
 
-    let aabb: Vec<Rect> = bbox_list
-        .par_iter()
-        .enumerate()
-        .map(|(i, b)| Rect::new(b.to_owned(), i))
-        .collect();
-    let tree: RTree<Rect> = RTree::<Rect>::bulk_load(aabb);
-    let search_aabb: Vec<Rect> = {
-        if expand_na == false {
-            let expand_aabb: Vec<Rect> = bbox_list
-                .par_iter()
-                .enumerate()
-                .map(|(i, b)| {
-                    Rect::new((b.0 - expand, b.1 - expand, b.2 + expand, b.3 + expand), i)
-                })
-                .collect();
-            expand_aabb
-        } else {
-            let scale_aabb: Vec<Rect> = bbox_list
-                .par_iter()
-                .enumerate()
-                .map(|(i, b)| {
-                    let xexpand: f64 = (b.2 - b.0) * (scale - 1.0);
-                    let yexpand: f64 = (b.3 - b.1) * (scale - 1.0);
-                    Rect::new(
-                        (b.0 - xexpand, b.1 - yexpand, b.2 + xexpand, b.3 + yexpand),
+    let sort = match sort {
+        Some(data) => data,
+        None => "none",
+    };
+    if sort != "none" && sort != "index" && sort != "distance" {
+        return Err(PyValueError::new_err(format!(
+            "Unknown sort `{}`, should be 'index' or 'distance'.",
+            sort
+        )));
+    }
+
+    let metric = match metric {
+        Some(data) => data,
+        None => "euclidean",
+    };
+    if metric != "euclidean" && metric != "manhattan" && metric != "chebyshev" {
+        return Err(PyValueError::new_err(format!(
+            "Unknown metric `{}`, should be 'euclidean', 'manhattan' or 'chebyshev'.",
+            metric
+        )));
+    }
+
+    let node_size = extract_node_size(node_size)?;
+
+    let query: Vec<usize> = match query_indices {
+        Some(data) => {
+            if format == "edges" {
+                return Err(PyValueError::new_err(
+                    "`query_indices` can't be used with format 'edges'.",
+                ));
+            }
+            let mut query = vec![];
+            for i in &data {
+                if *i >= new_index.len() {
+                    return Err(PyValueError::new_err(format!(
+                        "Index {} in `query_indices` is out of range for {} points.",
                         i,
-                    )
-                })
-                .collect();
-            scale_aabb
+                        new_index.len()
+                    )));
+                }
+                match new_index[*i] {
+                    Some(n) => query.push(n),
+                    None => {
+                        return Err(PyValueError::new_err(format!(
+                            "Index {} in `query_indices` is an invalid point.",
+                            i
+                        )));
+                    }
+                }
+            }
+            query
         }
+        None => (0..points.size()).collect(),
     };
-    let result: HashMap<usize, Vec<usize>> = search_aabb
-        .par_iter()
-        .map(|rect| {
-            let envelop = rect.envelope();
-            let search_result: Vec<&Rect> =
-                tree.locate_in_envelope_intersecting(&envelop).collect();
-            let neighbors: Vec<usize> = search_result.iter().map(|r| r.index).collect();
-            (rect.index, neighbors)
-        })
-        .collect();
 
-    let count = bbox_list.len();
-    let mut neighbors = vec![];
-    if has_labels {
-        for i in 0..count {
-            neighbors.push(result.get(&i).unwrap().clone().iter().map(
-                |t| { labels[*t] }
-            ).collect())
+    let handle_duplicates = match handle_duplicates {
+        Some(data) => data,
+        None => "keep",
+    };
+    if handle_duplicates != "keep" && handle_duplicates != "warn" && handle_duplicates != "merge" {
+        return Err(PyValueError::new_err(format!(
+            "Unknown handle_duplicates `{}`, should be 'keep', 'warn' or 'merge'.",
+            handle_duplicates
+        )));
+    }
+    // the representative index of each point, duplicates are merged into the first one
+    let mut representative: Vec<usize> = (0..points.size()).collect();
+    if handle_duplicates != "keep" {
+        let groups = duplicate_groups(&points);
+        if !groups.is_empty() && handle_duplicates == "warn" {
+            warn_user(
+                py,
+                &format!("Points with duplicate coordinates: {:?}", groups),
+            )?;
         }
-    } else {
-        for i in 0..count {
-            neighbors.push(result.get(&i).unwrap().clone())
+        if handle_duplicates == "merge" {
+            for g in &groups {
+                for i in g {
+                    representative[*i] = g[0];
+                }
+            }
         }
     }
+    let merge = handle_duplicates == "merge";
 
-    neighbors
+    let max_r = radii.iter().cloned().fold(0.0, f64::max);
+    // only the target points are indexed, the ids in the tree are mapped back to the points
+    let targets: Option<Vec<usize>> = is_target.map(|t| (0..t.len()).filter(|i| t[*i]).collect());
+    let neighbors = py.allow_threads(|| {
+        // make an index, no tree if there are no targets or every point is dropped as invalid
+        let tree = match &targets {
+            Some(ids) => {
+                let target_points = Points::Owned(ids.iter().map(|i| points.point(*i)).collect());
+                build_tree(&target_points, node_size)
+            }
+            None => build_tree(&points, node_size),
+        };
+        let neighbors: Vec<Vec<usize>> = query
+            .par_iter()
+            .map(|i| {
+                let i = *i;
+                let p = &points.point(i);
+                let search_r = if use_both { radii[i] + max_r } else { radii[i] };
+                let mut neighbors: Vec<usize> = match &tree {
+                    Some(tree) => metric_candidates(tree, p, search_r, metric),
+                    None => vec![],
+                };
+                if let Some(ids) = &targets {
+                    neighbors.iter_mut().for_each(|id| *id = ids[*id]);
+                }
+                if use_both || metric != "euclidean" {
+                    neighbors.retain(|id| {
+                        let reach = if use_both {
+                            radii[i] + radii[*id]
+                        } else {
+                            radii[i]
+                        };
+                        within_metric(p, &points.point(*id), reach, metric)
+                    });
+                }
+                // remap the duplicates before excluding self, or the representative would list itself
+                if merge {
+                    neighbors = neighbors
+                        .into_iter()
+                        .map(|id| representative[id])
+                        .unique()
+                        .collect();
+                }
+                if exclude_self {
+                    neighbors.retain(|id| *id != representative[i]);
+                }
+                if let Some(n) = max_neighbors {
+                    cap_neighbors(&mut neighbors, p, &points, n);
+                }
+                sort_neighbors(&mut neighbors, p, &points, sort);
+                neighbors
+            })
+            .collect();
+
+        apply_labels(neighbors, labels)
+    });
+
+    let result = if format == "csr" {
+        let (indptr, indices) = neighbors_to_csr(&neighbors);
+        index_arrays_to_py(py, indptr, indices)
+    } else if format == "edges" {
+        let (source, target) = neighbors_to_edges(&neighbors);
+        index_arrays_to_py(py, source, target)
+    } else {
+        neighbors.to_object(py)
+    };
+
+    let mut extra: Vec<PyObject> = vec![];
+    if drop_invalid {
+        extra.push(kept.to_object(py));
+    }
+    if merge {
+        extra.push(representative.to_object(py));
+    }
+    if extra.is_empty() {
+        return Ok(result);
+    }
+    extra.insert(0, result);
+    Ok(PyTuple::new(py, extra).to_object(py))
 }
 
-/// comb_bootstrap(x_status, y_status, neighbors, times=500, ignore_self=False)
+/// get_point_neighbors_f32(points, r, labels=None, exclude_self=False)
 /// --
 ///
-/// Bootstrap between two types
+/// A utility function to search for point neighbors in single precision using r-tree
 ///
-/// If you want to test co-localization between protein X and Y, first determine if the cell is X-positive
-/// and/or Y-positive. True is considered as positive and will be counted.
+/// The coordinates are stored and compared as float32 without upcasting, which halves the memory
+/// of the points. float32 has about 7 significant digits, so the points that lie (almost) exactly
+/// at radius r may be included or excluded differently from `get_point_neighbors`.
 ///
 /// Args:
-///     x_status: List[bool]; If cell is type x
-///     y_status: List[bool]; If cell is type y
-///     neighbors: Dict[int, List[int]]; eg. {1:[4,5], 2:[6,7]}, cell at index 1 has neighbor cells from index 4 and 5
-///     times: int (500); How many times to perform bootstrap
-///     ignore_self: bool (False); Whether to consider self as a neighbor
+///     points: np.ndarray; float32 array in shape of (n, 2), other inputs will be downcast to float32
+///     r: float; The search radius
+///     labels: List[int]; The labels of points, if given, the neighbors will be returned as labels
+///     exclude_self: bool (False); Whether to exclude the point itself from its neighbors
 ///
 /// Return:
-///     The z-score for the spatial relationship between X and Y
+///     A list of neighbors' index sorted by index, return as the order of the input
 ///
 #[pyfunction]
-fn comb_bootstrap(
+pub fn get_point_neighbors_f32(
     py: Python,
-    x_status: PyObject,
-    y_status: PyObject,
-    neighbors: PyObject,
-    times: Option<usize>,
-    ignore_self: Option<bool>,
-) -> PyResult<f64> {
-    let x: Vec<bool> = match x_status.extract(py) {
-        Ok(data) => data,
-        Err(_) => {
-            return Err(PyTypeError::new_err(
-                "Can't resolve `x_status`, should be list of bool.",
-            ));
-        }
-    };
+    points: PyObject,
+    r: f32,
+    labels: Option<Vec<usize>>,
+    exclude_self: Option<bool>,
+) -> PyResult<Vec<Vec<usize>>> {
+    let points = extract_points_f32(py, &points)?;
+    let exclude_self = match exclude_self {
+        Some(data) => data,
+        None => false,
+    };
+
+    let neighbors = py.allow_threads(|| {
+        let tree = f32_tree(&points);
+        let query: Vec<usize> = (0..points.len()).collect();
+        let mut neighbors = within_neighbors_f32(&tree, &points, &query, r);
+        if exclude_self {
+            neighbors
+                .par_iter_mut()
+                .enumerate()
+                .for_each(|(i, neighs)| neighs.retain(|j| *j != i));
+        }
+        neighbors
+    });
+
+    Ok(apply_labels(neighbors, labels))
+}
+
+/// get_neighbors_batch(points_collections, r)
+/// --
+///
+/// A utility function to search for point neighbors of many ROIs in parallel using kd-tree
+///
+/// Args:
+///     points_collections: List[List[tuple(float, float)] or np.ndarray]; The points of each ROI
+///     r: float; The search radius
+///
+/// Return:
+///     A list of neighbors for each ROI, the same as calling `get_point_neighbors` on each ROI,
+///     an empty list for the ROI without points
+///
+#[pyfunction]
+pub fn get_neighbors_batch(
+    py: Python,
+    points_collections: Vec<PyObject>,
+    r: f64,
+) -> PyResult<Vec<Vec<Vec<usize>>>> {
+    let mut rois: Vec<Vec<(f64, f64)>> = vec![];
+    for points in &points_collections {
+        rois.push(extract_points(py, points)?);
+    }
+
+    Ok(py.allow_threads(|| {
+        rois.par_iter()
+            .map(|points| {
+                let tree = build_tree(points.to_owned(), kdbush::DEFAULT_NODE_SIZE);
+                tree.map_or_else(Vec::new, |tree| {
+                    let query: Vec<usize> = (0..points.len()).collect();
+                    within_neighbors(&tree, points, &query, r)
+                })
+            })
+            .collect()
+    }))
+}
+
+/// get_neighbors_knn(points, k, labels=None, exclude_self=True)
+/// --
+///
+/// A utility function to search for the k nearest neighbors of each point using kd-tree
+///
+/// Neighbors are sorted from the nearest to the farthest,
+/// points at the same distance are ordered by their index.
+///
+/// Args:
+///     points: List[tuple(float, float)] or np.ndarray; Two dimension points
+///     k: int; The number of neighbors to search for each point, must be smaller than the number of points
+///     labels: List[int]; The labels of points, if given, the neighbors will be returned as labels
+///     exclude_self: bool (True); Whether to exclude the point itself from its neighbors
+///
+/// Return:
+///     A list of neighbors' index, return as the order of the input
+///
+#[pyfunction]
+pub fn get_neighbors_knn(
+    py: Python,
+    points: PyObject,
+    k: usize,
+    labels: Option<Vec<usize>>,
+    exclude_self: Option<bool>,
+) -> PyResult<Vec<Vec<usize>>> {
+    let points = extract_points(py, &points)?;
+    if k >= points.len() {
+        return Err(PyValueError::new_err(format!(
+            "`k` should be smaller than the number of points, got k={} for {} points.",
+            k,
+            points.len()
+        )));
+    }
+
+    let exclude_self = match exclude_self {
+        Some(data) => data,
+        None => true,
+    };
+
+    let tree = KDBush::create(points.to_owned(), kdbush::DEFAULT_NODE_SIZE);
+    let neighbors = knn_neighbors(&tree, &points, k, exclude_self);
+
+    Ok(apply_labels(neighbors, labels))
+}
+
+/// get_neighbors_mutual_knn(points, k, symmetrize="intersection", labels=None)
+/// --
+///
+/// A utility function to build a symmetric k nearest neighbors graph
+///
+/// With "intersection", point i and j are neighbors only if they are in the k nearest neighbors of each other.
+/// With "union", point i and j are neighbors if either one is in the k nearest neighbors of the other.
+/// The point itself is not included, the neighbors are sorted by index.
+///
+/// Args:
+///     points: List[tuple(float, float)] or np.ndarray; Two dimension points
+///     k: int; The number of neighbors to search for each point, must be smaller than the number of points
+///     symmetrize: str ("intersection"); "intersection" or "union"
+///     labels: List[int]; The labels of points, if given, the neighbors will be returned as labels
+///
+/// Return:
+///     A list of neighbors' index, return as the order of the input
+///
+#[pyfunction]
+pub fn get_neighbors_mutual_knn(
+    py: Python,
+    points: PyObject,
+    k: usize,
+    symmetrize: Option<&str>,
+    labels: Option<Vec<usize>>,
+) -> PyResult<Vec<Vec<usize>>> {
+    let points = extract_points(py, &points)?;
+    if k >= points.len() {
+        return Err(PyValueError::new_err(format!(
+            "`k` should be smaller than the number of points, got k={} for {} points.",
+            k,
+            points.len()
+        )));
+    }
+
+    let union = match symmetrize {
+        None | Some("intersection") => false,
+        Some("union") => true,
+        Some(other) => {
+            return Err(PyValueError::new_err(format!(
+                "Unknown symmetrize `{}`, should be 'intersection' or 'union'.",
+                other
+            )));
+        }
+    };
+
+    let neighbors = py.allow_threads(|| {
+        let tree = KDBush::create(points.to_owned(), kdbush::DEFAULT_NODE_SIZE);
+        let knn = knn_neighbors(&tree, &points, k, true);
+        symmetrize_graph(&knn, union)
+    });
+
+    Ok(apply_labels(neighbors, labels))
+}
+
+/// get_neighbors_with_distances(points, r, labels=None, exclude_self=False)
+/// --
+///
+/// A utility function to search for point neighbors and their distances using kd-tree
+///
+/// Args:
+///     points: List[tuple(float, float)] or np.ndarray; Two dimension points
+///     r: float; The search radius
+///     labels: List[int]; The labels of points, if given, the neighbors will be returned as labels
+///     exclude_self: bool (False); Whether to exclude the point itself (at distance 0) from its neighbors
+///
+/// Return:
+///     A list of (neighbor's index, euclidean distance), return as the order of the input
+///
+#[pyfunction]
+pub fn get_neighbors_with_distances(
+    py: Python,
+    points: PyObject,
+    r: f64,
+    labels: Option<Vec<usize>>,
+    exclude_self: Option<bool>,
+) -> PyResult<Vec<Vec<(usize, f64)>>> {
+    let points = extract_points(py, &points)?;
+    let exclude_self = match exclude_self {
+        Some(data) => data,
+        None => false,
+    };
+
+    let tree = build_tree(points.to_owned(), kdbush::DEFAULT_NODE_SIZE);
+    let neighbors = tree.map_or_else(Vec::new, |tree| {
+        within_distances(&tree, &points, r, exclude_self)
+    });
+
+    Ok(match labels {
+        Some(labels) => neighbors
+            .into_iter()
+            .map(|neighs| neighs.iter().map(|(t, d)| (labels[*t], *d)).collect())
+            .collect(),
+        None => neighbors,
+    })
+}
+
+/// get_neighbors_weighted(points, r, kernel="gaussian", bandwidth=None, include_self=True)
+/// --
+///
+/// A utility function to search for point neighbors with distance kernel weights using kd-tree
+///
+/// The weight of a neighbor at distance d:
+///     gaussian: exp(-d^2 / (2 * bandwidth^2))
+///     linear: max(0, 1 - d / bandwidth)
+///     inverse: 1 / (1 + d / bandwidth)
+///
+/// Args:
+///     points: List[tuple(float, float)] or np.ndarray; Two dimension points
+///     r: float; The search radius
+///     kernel: str ("gaussian"); "gaussian", "linear" or "inverse"
+///     bandwidth: float (r); The bandwidth of the kernel, must be positive
+///     include_self: bool (True); Whether to include the point itself as neighbor with weight 1
+///
+/// Return:
+///     A list of (neighbor's index, weight), return as the order of the input
+///
+#[pyfunction]
+pub fn get_neighbors_weighted(
+    py: Python,
+    points: PyObject,
+    r: f64,
+    kernel: Option<&str>,
+    bandwidth: Option<f64>,
+    include_self: Option<bool>,
+) -> PyResult<Vec<Vec<(usize, f64)>>> {
+    let points = extract_points(py, &points)?;
+
+    let kernel = match kernel {
+        Some(data) => data,
+        None => "gaussian",
+    };
+    if kernel != "gaussian" && kernel != "linear" && kernel != "inverse" {
+        return Err(PyValueError::new_err(format!(
+            "Unknown kernel `{}`, should be 'gaussian', 'linear' or 'inverse'.",
+            kernel
+        )));
+    }
+
+    let bandwidth = match bandwidth {
+        Some(data) => data,
+        None => r,
+    };
+    if bandwidth.is_nan() || bandwidth <= 0.0 {
+        return Err(PyValueError::new_err(format!(
+            "`bandwidth` should be positive, got {}.",
+            bandwidth
+        )));
+    }
+
+    let include_self = match include_self {
+        Some(data) => data,
+        None => true,
+    };
+
+    let neighbors = py.allow_threads(|| {
+        let tree = build_tree(points.to_owned(), kdbush::DEFAULT_NODE_SIZE);
+        let neighbors = tree.map_or_else(Vec::new, |tree| {
+            within_distances(&tree, &points, r, !include_self)
+        });
+        neighbors
+            .into_par_iter()
+            .enumerate()
+            .map(|(i, neighs)| {
+                neighs
+                    .into_iter()
+                    .map(|(id, d)| {
+                        if id == i {
+                            (id, 1.0)
+                        } else {
+                            (id, kernel_weight(d, bandwidth, kernel))
+                        }
+                    })
+                    .collect()
+            })
+            .collect()
+    });
+
+    Ok(neighbors)
+}
+
+/// get_neighbor_counts(points, r, exclude_self=False, histogram=False)
+/// --
+///
+/// A utility function to count point neighbors using kd-tree without returning the neighbors
+///
+/// Args:
+///     points: List[tuple(float, float)] or np.ndarray; Two dimension points
+///     r: float; The search radius
+///     exclude_self: bool (False); Whether to exclude the point itself from its neighbors
+///     histogram: bool (False); Whether to return the histogram of counts
+///
+/// Return:
+///     A numpy array of the number of neighbors, return as the order of the input.
+///     If histogram is True, also return a numpy array that the i-th element is the number of points
+///     that have i neighbors.
+///
+#[pyfunction]
+pub fn get_neighbor_counts(
+    py: Python,
+    points: PyObject,
+    r: f64,
+    exclude_self: Option<bool>,
+    histogram: Option<bool>,
+) -> PyResult<PyObject> {
+    let points = extract_points(py, &points)?;
+
+    let exclude_self = match exclude_self {
+        Some(data) => data,
+        None => false,
+    };
+
+    let histogram = match histogram {
+        Some(data) => data,
+        None => false,
+    };
+
+    // the histogram is empty too if there are no points
+    let counts = py.allow_threads(|| {
+        let tree = build_tree(points.to_owned(), kdbush::DEFAULT_NODE_SIZE);
+        tree.map_or_else(Vec::new, |tree| {
+            within_counts(&tree, &points, r, exclude_self)
+        })
+    });
+
+    if histogram {
+        let mut hist: Vec<usize> = vec![0; counts.iter().cloned().max().map_or(0, |m| m + 1)];
+        for c in &counts {
+            hist[*c] += 1;
+        }
+        return Ok((counts.into_pyarray(py), hist.into_pyarray(py)).to_object(py));
+    }
+
+    Ok(counts.into_pyarray(py).to_object(py))
+}
+
+/// nearest_neighbor_distances(points, k=1)
+/// --
+///
+/// A utility function to get the distance from each point to its k-th nearest neighbor using kd-tree
+///
+/// Args:
+///     points: List[tuple(float, float)] or np.ndarray; Two dimension points
+///     k: int (1); The order of the neighbor, 1 means the nearest one
+///
+/// Return:
+///     A numpy array of distances, return as the order of the input, NaN if there are no more than k points
+///
+#[pyfunction]
+pub fn nearest_neighbor_distances(
+    py: Python,
+    points: PyObject,
+    k: Option<usize>,
+) -> PyResult<PyObject> {
+    let points = extract_points(py, &points)?;
+
+    let k = match k {
+        Some(data) => data,
+        None => 1,
+    };
+    if k == 0 {
+        return Err(PyValueError::new_err("`k` should be at least 1."));
+    }
+
+    let dists = py.allow_threads(|| {
+        let tree = build_tree(points.to_owned(), kdbush::DEFAULT_NODE_SIZE);
+        tree.map_or_else(Vec::new, |tree| kth_neighbor_distances(&tree, &points, k))
+    });
+
+    Ok(dists.into_pyarray(py).to_object(py))
+}
+
+/// ripley_k(points, radii, area=None, types=None, correction="border")
+/// --
+///
+/// Ripley's K and L functions of the points
+///
+/// The intensity is n / area. With the border correction, only the points at least r away from the border
+/// of the rectangle are counted as the centers of radius r. The L function is returned as L(r) - r,
+/// where L(r) = sqrt(K(r) / pi), so it's around 0 under complete spatial randomness.
+///
+/// Args:
+///     points: List[tuple(float, float)] or np.ndarray; Two dimension points
+///     radii: List[float]; The radii to compute K and L
+///     area: float or tuple(float, float, float, float) (None); The area of the study region,
+///           or the rectangle as (xmin, ymin, xmax, ymax). If None, the bounding box of the points.
+///           The border correction uses the bounding box of the points if it's a float.
+///     types: List[str] (None); The type of each point, if given, K and L are computed for
+///            the points of each type
+///     correction: str ("border"); "border" or "none"
+///
+/// Return:
+///     A tuple of two numpy arrays (K, L - r) in the order of radii, NaN if it's not defined
+///     (eg. fewer than 2 points, or no points are at least r away from the border).
+///     If types is given, a dict keyed by the types.
+///
+#[pyfunction]
+pub fn ripley_k(
+    py: Python,
+    points: PyObject,
+    radii: Vec<f64>,
+    area: Option<PyObject>,
+    types: Option<Vec<String>>,
+    correction: Option<&str>,
+) -> PyResult<PyObject> {
+    let points = extract_points(py, &points)?;
+    if points.len() < 2 {
+        // K is not defined without a pair of points, and the tree can't be built on no points
+        check_points_radii(&points, &radii)?;
+        parse_correction(correction)?;
+        let nan = || vec![f64::NAN; radii.len()].into_pyarray(py);
+        return match types {
+            None => Ok((nan(), nan()).to_object(py)),
+            Some(types) => {
+                check_types_len(&types, points.len())?;
+                let results = PyDict::new(py);
+                for t in types.iter().unique() {
+                    results.set_item(t, (nan(), nan()))?;
+                }
+                Ok(results.to_object(py))
+            }
+        };
+    }
+    let (area, window) = study_area(py, &points, &radii, area, correction)?;
+
+    let tree = py.allow_threads(|| KDBush::create(points.to_owned(), kdbush::DEFAULT_NODE_SIZE));
+    let r_max = radii.iter().cloned().fold(0.0, f64::max);
+    let k_and_l = |centers: &Vec<usize>, targets: &Vec<bool>| {
+        let k = PairDistances::new(&tree, &points, centers, targets, r_max, window)
+            .ripley_k(&radii, area);
+        let l: Vec<f64> = k
+            .iter()
+            .zip(radii.iter())
+            .map(|(k, r)| (k / std::f64::consts::PI).sqrt() - r)
+            .collect();
+        (k, l)
+    };
+
+    match types {
+        None => {
+            let centers: Vec<usize> = (0..points.len()).collect();
+            let targets = vec![true; points.len()];
+            let (k, l) = py.allow_threads(|| k_and_l(&centers, &targets));
+            Ok((k.into_pyarray(py), l.into_pyarray(py)).to_object(py))
+        }
+        Some(types) => {
+            check_types_len(&types, points.len())?;
+            let uni: Vec<&String> = types.iter().unique().sorted().collect();
+            let results = PyDict::new(py);
+            for t in uni {
+                let targets: Vec<bool> = types.iter().map(|c| c == t).collect();
+                let centers: Vec<usize> = (0..points.len()).filter(|i| targets[*i]).collect();
+                let (k, l) = py.allow_threads(|| k_and_l(&centers, &targets));
+                results.set_item(t, (k.into_pyarray(py), l.into_pyarray(py)))?;
+            }
+            Ok(results.to_object(py))
+        }
+    }
+}
+
+/// cross_k(points, types, type_a, type_b, radii, area=None, correction="border", n_sim=None, seed=None)
+/// --
+///
+/// The bivariate cross-K function from type a to type b
+///
+/// The type b points within r of each type a point are counted and normalized by the intensity
+/// of type b, n_b / area. Above pi * r^2 means that type b is attracted to type a at distance r,
+/// below means repulsion. The area and the edge correction are the same as `ripley_k`.
+///
+/// Args:
+///     points: List[tuple(float, float)] or np.ndarray; Two dimension points
+///     types: List[str]; The type of each point
+///     type_a: str; The type of the center points
+///     type_b: str; The type of the counted points
+///     radii: List[float]; The radii to compute K
+///     area: float or tuple(float, float, float, float) (None); The same as `ripley_k`
+///     correction: str ("border"); "border" or "none"
+///     n_sim: int (None); If given, the types are shuffled across the points for n_sim times
+///            to get the pointwise envelopes of K
+///     seed: int (None); The random seed of the shuffles
+///
+/// Return:
+///     A numpy array of K in the order of radii, NaN if it's not defined. If n_sim is given,
+///     a tuple of three numpy arrays (K, lower, upper), the lower and upper envelopes are
+///     the pointwise min and max of the shuffles.
+///
+#[pyfunction]
+#[allow(clippy::too_many_arguments)]
+pub fn cross_k(
+    py: Python,
+    points: PyObject,
+    types: Vec<String>,
+    type_a: &str,
+    type_b: &str,
+    radii: Vec<f64>,
+    area: Option<PyObject>,
+    correction: Option<&str>,
+    n_sim: Option<usize>,
+    seed: Option<u64>,
+) -> PyResult<PyObject> {
+    let points = extract_points(py, &points)?;
+    check_types_len(&types, points.len())?;
+    for t in &[type_a, type_b] {
+        if !types.iter().any(|c| c == t) {
+            return Err(PyValueError::new_err(format!(
+                "Type '{}' is not found in `types`.",
+                t
+            )));
+        }
+    }
+    let (area, window) = study_area(py, &points, &radii, area, correction)?;
+
+    let tree = py.allow_threads(|| KDBush::create(points.to_owned(), kdbush::DEFAULT_NODE_SIZE));
+    let r_max = radii.iter().cloned().fold(0.0, f64::max);
+    let k_cross = |types: &Vec<&str>| {
+        let centers: Vec<usize> = (0..types.len()).filter(|i| types[*i] == type_a).collect();
+        let targets: Vec<bool> = types.iter().map(|c| *c == type_b).collect();
+        PairDistances::new(&tree, &points, &centers, &targets, r_max, window).ripley_k(&radii, area)
+    };
+    let types: Vec<&str> = types.iter().map(|c| c.as_str()).collect();
+    let k = py.allow_threads(|| k_cross(&types));
+
+    let n_sim = match n_sim {
+        Some(data) => data,
+        None => return Ok(k.into_pyarray(py).to_object(py)),
+    };
+    let sims: Vec<Vec<f64>> = run_permutations(py, n_sim, &None, |i| {
+        let mut rng = perm_rng(seed, i);
+        let mut shuffle_types = types.to_owned();
+        shuffle_types.shuffle(&mut rng);
+        k_cross(&shuffle_types)
+    })?;
+    let envelope = |f: fn(f64, f64) -> f64, init: f64| -> Vec<f64> {
+        (0..radii.len())
+            .map(|j| sims.iter().map(|sim| sim[j]).fold(init, f))
+            .collect()
+    };
+    let lower = envelope(f64::min, f64::INFINITY);
+    let upper = envelope(f64::max, f64::NEG_INFINITY);
+    Ok((
+        k.into_pyarray(py),
+        lower.into_pyarray(py),
+        upper.into_pyarray(py),
+    )
+        .to_object(py))
+}
+
+/// pair_correlation(points, r_max, n_bins, bandwidth=None, types=None, area=None, correction="border")
+/// --
+///
+/// The pair correlation function g(r) of the points
+///
+/// The pairs within r_max are found by kd-tree and put into n_bins bins from 0 to r_max,
+/// the count of each bin is normalized by the annulus area and the intensity n / area,
+/// so g(r) is around 1 under complete spatial randomness and above 1 for clustering.
+/// The area and the edge correction are the same as `ripley_k`, with the border correction
+/// only the points at least the outer radius of the bin away from the border are counted.
+///
+/// Args:
+///     points: List[tuple(float, float)] or np.ndarray; Two dimension points
+///     r_max: float; The max distance
+///     n_bins: int; The number of bins
+///     bandwidth: float (None); If given, smooth the distances by the Epanechnikov kernel
+///                of this bandwidth at the bin centers instead of counting the bins
+///     types: List[str] (None); The type of each point, if given, g(r) is computed for each pair of types
+///     area: float or tuple(float, float, float, float) (None); The same as `ripley_k`
+///     correction: str ("border"); "border" or "none"
+///
+/// Return:
+///     A tuple of two numpy arrays (bin centers, g), NaN if it's not defined (eg. fewer than 2 points).
+///     If types is given, g is a dict keyed by the type pairs sorted by the names, eg. ('a', 'b') has
+///     the type a points as the centers and counts the type b points, ('a', 'a') is the g(r) of type a.
+///
+#[pyfunction]
+#[allow(clippy::too_many_arguments)]
+pub fn pair_correlation(
+    py: Python,
+    points: PyObject,
+    r_max: f64,
+    n_bins: usize,
+    bandwidth: Option<f64>,
+    types: Option<Vec<String>>,
+    area: Option<PyObject>,
+    correction: Option<&str>,
+) -> PyResult<PyObject> {
+    let points = extract_points(py, &points)?;
+    if !(r_max.is_finite() && r_max > 0.0) {
+        return Err(PyValueError::new_err("`r_max` should be positive."));
+    }
+    if n_bins == 0 {
+        return Err(PyValueError::new_err("`n_bins` should be at least 1."));
+    }
+    if let Some(h) = bandwidth {
+        if !(h.is_finite() && h > 0.0) {
+            return Err(PyValueError::new_err("`bandwidth` should be positive."));
+        }
+    }
+
+    let edges: Vec<f64> = (0..=n_bins)
+        .map(|i| r_max * i as f64 / n_bins as f64)
+        .collect();
+    let bin_centers: Vec<f64> = edges.windows(2).map(|e| (e[0] + e[1]) / 2.0).collect();
+    if points.len() < 2 {
+        // g(r) is not defined without a pair of points, and the tree can't be built on no points
+        check_points_radii(&points, &[r_max])?;
+        parse_correction(correction)?;
+        let nan = || vec![f64::NAN; n_bins].into_pyarray(py);
+        return match types {
+            None => Ok((bin_centers.into_pyarray(py), nan()).to_object(py)),
+            Some(types) => {
+                check_types_len(&types, points.len())?;
+                let uni: Vec<String> = types.iter().unique().sorted().cloned().collect();
+                let results = PyDict::new(py);
+                for pair in type_combs(&uni, false) {
+                    results.set_item(pair, nan())?;
+                }
+                Ok((bin_centers.into_pyarray(py), results).to_object(py))
+            }
+        };
+    }
+    let (area, window) = study_area(py, &points, &[r_max], area, correction)?;
+
+    let r_search = r_max + bandwidth.unwrap_or(0.0);
+    let tree = py.allow_threads(|| KDBush::create(points.to_owned(), kdbush::DEFAULT_NODE_SIZE));
+    let g = |centers: &Vec<usize>, targets: &Vec<bool>| {
+        PairDistances::new(&tree, &points, centers, targets, r_search, window)
+            .pair_correlation(&edges, bandwidth, area)
+    };
+
+    match types {
+        None => {
+            let centers: Vec<usize> = (0..points.len()).collect();
+            let targets = vec![true; points.len()];
+            let result = py.allow_threads(|| g(&centers, &targets));
+            Ok((bin_centers.into_pyarray(py), result.into_pyarray(py)).to_object(py))
+        }
+        Some(types) => {
+            check_types_len(&types, points.len())?;
+            let uni: Vec<String> = types.iter().unique().sorted().cloned().collect();
+            let results = PyDict::new(py);
+            for (a, b) in type_combs(&uni, false) {
+                let centers: Vec<usize> = (0..points.len()).filter(|i| types[*i] == a).collect();
+                let targets: Vec<bool> = types.iter().map(|c| *c == b).collect();
+                let result = py.allow_threads(|| g(&centers, &targets));
+                results.set_item((a, b), result.into_pyarray(py))?;
+            }
+            Ok((bin_centers.into_pyarray(py), results).to_object(py))
+        }
+    }
+}
+
+/// g_function(points, radii, types=None)
+/// --
+///
+/// The nearest neighbor distance distribution function G(r) of the points
+///
+/// G(r) is the fraction of the points that the nearest neighbor is within r, without edge correction.
+///
+/// Args:
+///     points: List[tuple(float, float)] or np.ndarray; Two dimension points
+///     radii: List[float]; The radii to compute G
+///     types: List[str] (None); The type of each point, if given, G is computed for the points of each type
+///            with the nearest neighbor of the same type
+///
+/// Return:
+///     A numpy array of G in the order of radii, NaN if there are fewer than 2 points.
+///     If types is given, a dict keyed by the types.
+///
+#[pyfunction]
+pub fn g_function(
+    py: Python,
+    points: PyObject,
+    radii: Vec<f64>,
+    types: Option<Vec<String>>,
+) -> PyResult<PyObject> {
+    let points = extract_points(py, &points)?;
+    check_points_radii(&points, &radii)?;
+
+    let g = |points: &Vec<(f64, f64)>| {
+        if points.len() < 2 {
+            return vec![f64::NAN; radii.len()];
+        }
+        let tree = KDBush::create(points.to_owned(), kdbush::DEFAULT_NODE_SIZE);
+        let mut dists = kth_neighbor_distances(&tree, points, 1);
+        dists.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        empirical_cdf(&dists, &radii)
+    };
+
+    match types {
+        None => {
+            let result = py.allow_threads(|| g(&points));
+            Ok(result.into_pyarray(py).to_object(py))
+        }
+        Some(types) => {
+            check_types_len(&types, points.len())?;
+            let results = PyDict::new(py);
+            for (t, type_points) in points_by_type(&points, &types) {
+                let result = py.allow_threads(|| g(&type_points));
+                results.set_item(t, result.into_pyarray(py))?;
+            }
+            Ok(results.to_object(py))
+        }
+    }
+}
+
+/// f_function(points, radii, grid_resolution, window=None)
+/// --
+///
+/// The empty space function F(r) of the points
+///
+/// The test locations are a regular grid with the spacing of `grid_resolution` from the lower left corner
+/// of the window, F(r) is the fraction of the test locations that the nearest point is within r,
+/// without edge correction.
+///
+/// Args:
+///     points: List[tuple(float, float)] or np.ndarray; Two dimension points
+///     radii: List[float]; The radii to compute F
+///     grid_resolution: float; The spacing of the test locations
+///     window: tuple(float, float, float, float) (None); The rectangle (xmin, ymin, xmax, ymax) of
+///             the test locations. If None, the bounding box of the points.
+///
+/// Return:
+///     A numpy array of F in the order of radii
+///
+#[pyfunction]
+pub fn f_function(
+    py: Python,
+    points: PyObject,
+    radii: Vec<f64>,
+    grid_resolution: f64,
+    window: Option<(f64, f64, f64, f64)>,
+) -> PyResult<PyObject> {
+    let points = extract_points(py, &points)?;
+    check_points_radii(&points, &radii)?;
+    if points.is_empty() {
+        return Err(PyValueError::new_err("`points` should not be empty."));
+    }
+    if !(grid_resolution.is_finite() && grid_resolution > 0.0) {
+        return Err(PyValueError::new_err(
+            "`grid_resolution` should be positive.",
+        ));
+    }
+    let (xmin, ymin, xmax, ymax) = match window {
+        Some(data) => data,
+        None => points_bbox(&points),
+    };
+    if !(xmax >= xmin && ymax >= ymin) {
+        return Err(PyValueError::new_err(
+            "`window` should be (xmin, ymin, xmax, ymax).",
+        ));
+    }
+
+    let result = py.allow_threads(|| {
+        // the small tolerance keeps the last row and column when the size is a multiple of the spacing
+        let nx = ((xmax - xmin) / grid_resolution + 1e-9).floor() as usize + 1;
+        let ny = ((ymax - ymin) / grid_resolution + 1e-9).floor() as usize + 1;
+        let grid: Vec<(f64, f64)> = (0..nx)
+            .cartesian_product(0..ny)
+            .map(|(i, j)| {
+                (
+                    xmin + i as f64 * grid_resolution,
+                    ymin + j as f64 * grid_resolution,
+                )
+            })
+            .collect();
+        let tree = KDBush::create(points.to_owned(), kdbush::DEFAULT_NODE_SIZE);
+        let mut dists: Vec<f64> = cross_knn_neighbors(&tree, &points, &grid, 1)
+            .iter()
+            .zip(grid.iter())
+            .map(|(neighs, p)| sq_dist(p, &points[neighs[0]]).sqrt())
+            .collect();
+        dists.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        empirical_cdf(&dists, &radii)
+    });
+
+    Ok(result.into_pyarray(py).to_object(py))
+}
+
+/// clark_evans(points, area=None, types=None, correction="none")
+/// --
+///
+/// The Clark-Evans aggregation index of the points
+///
+/// R is the ratio of the observed mean nearest neighbor distance to the expected one under complete
+/// spatial randomness, 0.5 / sqrt(n / area). R < 1 means clustering and R > 1 means dispersion,
+/// the z-score and the two-sided p-value are from the normal approximation.
+///
+/// Args:
+///     points: List[tuple(float, float)] or np.ndarray; Two dimension points
+///     area: float or tuple(float, float, float, float) (None); The area of the study region,
+///           or the rectangle as (xmin, ymin, xmax, ymax). If None, the bounding box of the points.
+///     types: List[str] (None); The type of each point, if given, the index is computed for the points
+///            of each type with the nearest neighbor of the same type
+///     correction: str ("none"); "none" or "donnelly", the Donnelly edge correction of the expected
+///                 distance and its variance, it uses the perimeter of the rectangle
+///                 (the bounding box of the points if `area` is a float)
+///
+/// Return:
+///     A dict of R, observed (the mean nearest neighbor distance), expected, zscore and pvalue,
+///     NaN if there are fewer than 2 points. If types is given, a dict keyed by the types.
+///
+#[pyfunction]
+pub fn clark_evans(
+    py: Python,
+    points: PyObject,
+    area: Option<PyObject>,
+    types: Option<Vec<String>>,
+    correction: Option<&str>,
+) -> PyResult<PyObject> {
+    let points = extract_points(py, &points)?;
+    check_points_radii(&points, &[])?;
+    let correction = match correction {
+        Some(data) => data,
+        None => "none",
+    };
+    if correction != "none" && correction != "donnelly" {
+        return Err(PyValueError::new_err(
+            "`correction` should be 'none' or 'donnelly'.",
+        ));
+    }
+    let (area, window) = parse_area(py, &points, area)?;
+    let perimeter = 2.0 * ((window.2 - window.0) + (window.3 - window.1));
+
+    let index = |points: &Vec<(f64, f64)>| -> PyResult<PyObject> {
+        let n = points.len() as f64;
+        let observed = if points.len() < 2 {
+            f64::NAN
+        } else {
+            let tree = KDBush::create(points.to_owned(), kdbush::DEFAULT_NODE_SIZE);
+            mean_f(&kth_neighbor_distances(&tree, points, 1))
+        };
+        let (expected, se) = if correction == "donnelly" {
+            (
+                0.5 * (area / n).sqrt() + (0.0514 + 0.041 / n.sqrt()) * perimeter / n,
+                (0.0703 * area / (n * n) + 0.037 * perimeter * (area / n.powi(5)).sqrt()).sqrt(),
+            )
+        } else {
+            (0.5 * (area / n).sqrt(), 0.26136 * area.sqrt() / n)
+        };
+        let z = (observed - expected) / se;
+        let result = PyDict::new(py);
+        result.set_item("R", observed / expected)?;
+        result.set_item("observed", observed)?;
+        result.set_item("expected", expected)?;
+        result.set_item("zscore", z)?;
+        result.set_item("pvalue", normal_pvalue(z))?;
+        Ok(result.to_object(py))
+    };
+
+    match types {
+        None => index(&points),
+        Some(types) => {
+            check_types_len(&types, points.len())?;
+            let results = PyDict::new(py);
+            for (t, type_points) in points_by_type(&points, &types) {
+                results.set_item(t, index(&type_points)?)?;
+            }
+            Ok(results.to_object(py))
+        }
+    }
+}
+
+// the points of each type sorted by the type names
+fn points_by_type<'a>(
+    points: &[(f64, f64)],
+    types: &'a [String],
+) -> Vec<(&'a String, Vec<(f64, f64)>)> {
+    types
+        .iter()
+        .unique()
+        .sorted()
+        .map(|t| {
+            let type_points = points
+                .iter()
+                .zip(types.iter())
+                .filter(|(_, c)| *c == t)
+                .map(|(p, _)| *p)
+                .collect();
+            (t, type_points)
+        })
+        .collect()
+}
+
+fn check_types_len(types: &[String], n: usize) -> PyResult<()> {
+    if types.len() != n {
+        return Err(PyValueError::new_err(format!(
+            "The length of `types` ({}) should match the length of `points` ({}).",
+            types.len(),
+            n
+        )));
+    }
+    Ok(())
+}
+
+// the area and the rectangle of the study region, the rectangle is the bounding box of the points
+// if `area` is None or a float
+fn parse_area(
+    py: Python,
+    points: &Vec<(f64, f64)>,
+    area: Option<PyObject>,
+) -> PyResult<(f64, (f64, f64, f64, f64))> {
+    let bbox = points_bbox(points);
+    let (area, window) = match area {
+        None => ((bbox.2 - bbox.0) * (bbox.3 - bbox.1), bbox),
+        Some(obj) => {
+            if let Ok(a) = obj.extract::<f64>(py) {
+                (a, bbox)
+            } else if let Ok(rect) = obj.extract::<(f64, f64, f64, f64)>(py) {
+                ((rect.2 - rect.0) * (rect.3 - rect.1), rect)
+            } else {
+                return Err(PyTypeError::new_err(
+                    "Can't resolve `area`, should be a float or a tuple of (xmin, ymin, xmax, ymax).",
+                ));
+            }
+        }
+    };
+    if !(area.is_finite() && area > 0.0) {
+        return Err(PyValueError::new_err(
+            "The area should be positive, got an empty study region.",
+        ));
+    }
+    Ok((area, window))
+}
+
+fn check_points_radii(points: &Vec<(f64, f64)>, radii: &[f64]) -> PyResult<()> {
+    if !invalid_points(points).is_empty() {
+        return Err(PyValueError::new_err(
+            "`points` should not contain NaN or infinite coordinates.",
+        ));
+    }
+    if radii.iter().any(|r| !(r.is_finite() && *r >= 0.0)) {
+        return Err(PyValueError::new_err(
+            "`radii` should be non-negative and finite.",
+        ));
+    }
+    Ok(())
+}
+
+// the rectangle (xmin, ymin, xmax, ymax) the border correction is applied to
+type Window = (f64, f64, f64, f64);
+
+// the area and the window of the border correction (None for no correction) of the K functions,
+// the window is the rectangle given by `area` or the bounding box of the points
+fn study_area(
+    py: Python,
+    points: &Vec<(f64, f64)>,
+    radii: &[f64],
+    area: Option<PyObject>,
+    correction: Option<&str>,
+) -> PyResult<(f64, Option<Window>)> {
+    check_points_radii(points, radii)?;
+    let border = parse_correction(correction)?;
+
+    let (area, window) = parse_area(py, points, area)?;
+    if border {
+        Ok((area, Some(window)))
+    } else {
+        Ok((area, None))
+    }
+}
+
+// whether the border correction is applied
+fn parse_correction(correction: Option<&str>) -> PyResult<bool> {
+    let correction = match correction {
+        Some(data) => data,
+        None => "border",
+    };
+    if correction != "border" && correction != "none" {
+        return Err(PyValueError::new_err(
+            "`correction` should be 'border' or 'none'.",
+        ));
+    }
+    Ok(correction == "border")
+}
+
+/// get_neighbors_annulus(points, r_min, r_max, labels=None)
+/// --
+///
+/// A utility function to search for point neighbors in a ring using kd-tree
+///
+/// A point is a neighbor if its distance d satisfies r_min <= d <= r_max,
+/// points exactly at r_min or r_max are included. When r_min is 0, it's the same as `get_point_neighbors`.
+///
+/// Args:
+///     points: List[tuple(float, float)] or np.ndarray; Two dimension points
+///     r_min: float; The inner radius of the ring
+///     r_max: float; The outer radius of the ring
+///     labels: List[int]; The labels of points, if given, the neighbors will be returned as labels
+///
+/// Return:
+///     A list of neighbors' index, return as the order of the input
+///
+#[pyfunction]
+pub fn get_neighbors_annulus(
+    py: Python,
+    points: PyObject,
+    r_min: f64,
+    r_max: f64,
+    labels: Option<Vec<usize>>,
+) -> PyResult<Vec<Vec<usize>>> {
+    let points = extract_points(py, &points)?;
+    if !(r_min.is_finite() && r_max.is_finite() && 0.0 <= r_min && r_min < r_max) {
+        return Err(PyValueError::new_err(format!(
+            "Require finite 0 <= r_min < r_max, got r_min={} and r_max={}.",
+            r_min, r_max
+        )));
+    }
+    let tree = build_tree(points.to_owned(), kdbush::DEFAULT_NODE_SIZE);
+    let neighbors = tree.map_or_else(Vec::new, |tree| {
+        annulus_neighbors(&tree, &points, r_min, r_max)
+    });
+
+    Ok(apply_labels(neighbors, labels))
+}
+
+// the neighbors, the radius reached and the points that are short of neighbors
+type AdaptiveNeighbors = (Vec<Vec<usize>>, Vec<f64>, Vec<usize>);
+
+/// get_neighbors_adaptive(points, r_start, r_max, min_n, exclude_self=False)
+/// --
+///
+/// A utility function to search for point neighbors with an expanding radius using kd-tree
+///
+/// Each point is queried at r_start, if there are less than min_n neighbors (the point itself is not counted),
+/// the radius is doubled until min_n neighbors are found or r_max is reached.
+///
+/// Args:
+///     points: List[tuple(float, float)] or np.ndarray; Two dimension points
+///     r_start: float; The initial search radius
+///     r_max: float; The max search radius
+///     min_n: int; The min number of neighbors
+///     exclude_self: bool (False); Whether to exclude the point itself from its neighbors
+///
+/// Return:
+///     A tuple of (neighbors, radii, unmet), radii is the search radius used for each point,
+///     unmet is the index of points that have less than min_n neighbors at r_max
+///
+#[pyfunction]
+pub fn get_neighbors_adaptive(
+    py: Python,
+    points: PyObject,
+    r_start: f64,
+    r_max: f64,
+    min_n: usize,
+    exclude_self: Option<bool>,
+) -> PyResult<AdaptiveNeighbors> {
+    let points = extract_points(py, &points)?;
+    if !(r_max.is_finite() && r_start > 0.0 && r_start <= r_max) {
+        return Err(PyValueError::new_err(format!(
+            "Require finite 0 < r_start <= r_max, got r_start={} and r_max={}.",
+            r_start, r_max
+        )));
+    }
+
+    let exclude_self = match exclude_self {
+        Some(data) => data,
+        None => false,
+    };
+
+    let result = py.allow_threads(|| {
+        let tree = build_tree(points.to_owned(), kdbush::DEFAULT_NODE_SIZE);
+        tree.map_or_else(Vec::new, |tree| {
+            adaptive_neighbors(&tree, &points, r_start, r_max, min_n)
+        })
+    });
+
+    let mut neighbors: Vec<Vec<usize>> = vec![];
+    let mut radii: Vec<f64> = vec![];
+    let mut unmet: Vec<usize> = vec![];
+    for (i, (mut neighs, r)) in result.into_iter().enumerate() {
+        if neighs.len() <= min_n {
+            unmet.push(i);
+        }
+        if exclude_self {
+            neighs.retain(|id| *id != i);
+        }
+        neighbors.push(neighs);
+        radii.push(r);
+    }
+
+    Ok((neighbors, radii, unmet))
+}
+
+/// get_neighbors_periodic(points, r, width, height, labels=None)
+/// --
+///
+/// A utility function to search for point neighbors with periodic boundary using kd-tree
+///
+/// The domain [0, width) x [0, height) is treated as a torus, points near one edge
+/// are neighbors of points near the opposite edge. The neighbors are sorted by index.
+///
+/// Args:
+///     points: List[tuple(float, float)] or np.ndarray; Two dimension points
+///     r: float; The search radius
+///     width: float; The width of the domain
+///     height: float; The height of the domain
+///     labels: List[int]; The labels of points, if given, the neighbors will be returned as labels
+///
+/// Return:
+///     A list of neighbors' index, return as the order of the input
+///
+#[pyfunction]
+pub fn get_neighbors_periodic(
+    py: Python,
+    points: PyObject,
+    r: f64,
+    width: f64,
+    height: f64,
+    labels: Option<Vec<usize>>,
+) -> PyResult<Vec<Vec<usize>>> {
+    let points = extract_points(py, &points)?;
+    if !(width.is_finite() && height.is_finite() && width > 0.0 && height > 0.0) {
+        return Err(PyValueError::new_err(format!(
+            "`width` and `height` should be finite and positive, got width={} and height={}.",
+            width, height
+        )));
+    }
+    if !(r.is_finite() && r >= 0.0) {
+        return Err(PyValueError::new_err(format!(
+            "`r` should be finite and non-negative, got {}.",
+            r
+        )));
+    }
+    let neighbors = py.allow_threads(|| {
+        let tree = build_tree(points.to_owned(), kdbush::DEFAULT_NODE_SIZE);
+        tree.map_or_else(Vec::new, |tree| {
+            periodic_neighbors(&tree, &points, r, width, height)
+        })
+    });
+
+    Ok(apply_labels(neighbors, labels))
+}
+
+/// get_neighbors_3d(points, r, labels=None)
+/// --
+///
+/// A utility function to search for 3d point neighbors using r-tree
+///
+/// A point is a neighbor if it's inside or on the sphere of radius r
+///
+/// Args:
+///     points: List[tuple(float, float, float)]; Three dimension points
+///     r: float; The search radius
+///     labels: List[int]; The labels of points, if given, the neighbors will be returned as labels
+///
+/// Return:
+///     A list of neighbors' index, return as the order of the input
+///
+#[pyfunction]
+pub fn get_neighbors_3d(
+    points: Vec<(f64, f64, f64)>,
+    r: f64,
+    labels: Option<Vec<usize>>,
+) -> Vec<Vec<usize>> {
+    let neighbors = sphere_neighbors(&points, r);
+
+    apply_labels(neighbors, labels)
+}
+
+/// get_grid_neighbors(points, spacing, layout="hex", tolerance=None)
+/// --
+///
+/// A utility function to snap points to a grid and search for the adjacent occupied bins
+///
+/// The hex grid is pointy-top (spots in a row are `spacing` apart, rows are shifted by half spacing),
+/// a bin has 6 adjacent bins. The square grid is axis-aligned, a bin has 4 adjacent bins.
+/// Bins at the edge simply have fewer neighbors.
+///
+/// Args:
+///     points: List[tuple(float, float)] or np.ndarray; Two dimension points
+///     spacing: float; The distance between the centers of adjacent bins
+///     layout: str ("hex"); "hex" or "square"
+///     tolerance: float (None); The max distance between a point and its bin center as a fraction of spacing,
+///                raise ValueError if any point is farther than it. If None, no check is performed.
+///
+/// Return:
+///     A tuple of (bin_neighbors, point_bins), bin_neighbors is a list of adjacent bins' index for each bin,
+///     point_bins is the bin index of each point
+///
+#[pyfunction]
+pub fn get_grid_neighbors(
+    py: Python,
+    points: PyObject,
+    spacing: f64,
+    layout: Option<&str>,
+    tolerance: Option<f64>,
+) -> PyResult<(Vec<Vec<usize>>, Vec<usize>)> {
+    let points = extract_points(py, &points)?;
+    if spacing.is_nan() || spacing <= 0.0 {
+        return Err(PyValueError::new_err(format!(
+            "`spacing` should be positive, got {}.",
+            spacing
+        )));
+    }
+
+    let hex = match layout {
+        None | Some("hex") => true,
+        Some("square") => false,
+        Some(other) => {
+            return Err(PyValueError::new_err(format!(
+                "Unknown layout `{}`, should be 'hex' or 'square'.",
+                other
+            )));
+        }
+    };
+
+    let mut cells: Vec<(i64, i64)> = vec![];
+    let mut cell_index: HashMap<(i64, i64), usize> = HashMap::new();
+    let mut point_bins: Vec<usize> = vec![];
+    for (i, p) in points.iter().enumerate() {
+        let (cell, center) = snap_to_grid(p, spacing, hex);
+        if let Some(t) = tolerance {
+            let offset = sq_dist(p, &center).sqrt();
+            if offset > t * spacing {
+                return Err(PyValueError::new_err(format!(
+                    "Point {} is {} away from its bin center, exceeds the tolerance {}.",
+                    i,
+                    offset,
+                    t * spacing
+                )));
+            }
+        }
+        let bin = *cell_index.entry(cell).or_insert_with(|| {
+            cells.push(cell);
+            cells.len() - 1
+        });
+        point_bins.push(bin);
+    }
+
+    Ok((grid_neighbors(&cells, hex), point_bins))
+}
+
+/// get_cross_neighbors(points_a, points_b, r)
+/// --
+///
+/// A utility function to search for neighbors from another set of points using kd-tree
+///
+/// Only points_b is indexed, every point in points_a is used as query.
+///
+/// Args:
+///     points_a: List[tuple(float, float)]; Two dimension points to query
+///     points_b: List[tuple(float, float)]; Two dimension points to search for
+///     r: float; The search radius
+///
+/// Return:
+///     A list of neighbors' index in points_b, return as the order of points_a,
+///     all empty if points_b is empty
+///
+#[pyfunction]
+pub fn get_cross_neighbors(
+    py: Python,
+    points_a: Vec<(f64, f64)>,
+    points_b: Vec<(f64, f64)>,
+    r: f64,
+) -> Vec<Vec<usize>> {
+    py.allow_threads(|| {
+        let tree = build_tree(points_b, kdbush::DEFAULT_NODE_SIZE);
+        tree.map_or_else(
+            || vec![vec![]; points_a.len()],
+            |tree| cross_within_neighbors(&tree, &points_a, r),
+        )
+    })
+}
+
+/// get_cross_neighbors_knn(points_a, points_b, k)
+/// --
+///
+/// A utility function to search for the k nearest neighbors from another set of points using kd-tree
+///
+/// Neighbors are sorted from the nearest to the farthest,
+/// points at the same distance are ordered by their index.
+///
+/// Args:
+///     points_a: List[tuple(float, float)]; Two dimension points to query
+///     points_b: List[tuple(float, float)]; Two dimension points to search for
+///     k: int; The number of neighbors to search for each point, must not exceed the number of points_b
+///
+/// Return:
+///     A list of neighbors' index in points_b, return as the order of points_a
+///
+#[pyfunction]
+pub fn get_cross_neighbors_knn(
+    py: Python,
+    points_a: Vec<(f64, f64)>,
+    points_b: Vec<(f64, f64)>,
+    k: usize,
+) -> PyResult<Vec<Vec<usize>>> {
+    if k > points_b.len() {
+        return Err(PyValueError::new_err(format!(
+            "`k` should not exceed the number of points_b, got k={} for {} points.",
+            k,
+            points_b.len()
+        )));
+    }
+
+    // k is 0 if there is no tree
+    Ok(py.allow_threads(|| {
+        let tree = build_tree(points_b.to_owned(), kdbush::DEFAULT_NODE_SIZE);
+        tree.map_or_else(
+            || vec![vec![]; points_a.len()],
+            |tree| cross_knn_neighbors(&tree, &points_b, &points_a, k),
+        )
+    }))
+}
+
+// use uint32 to save memory if all the values fit in
+fn index_arrays_to_py(py: Python, a: Vec<usize>, b: Vec<usize>) -> PyObject {
+    let wide = a.iter().chain(b.iter()).any(|v| *v > u32::MAX as usize);
+    (
+        index_array_to_py(py, a, wide),
+        index_array_to_py(py, b, wide),
+    )
+        .to_object(py)
+}
+
+fn index_array_to_py(py: Python, a: Vec<usize>, wide: bool) -> PyObject {
+    if wide {
+        let a: Vec<u64> = a.into_iter().map(|v| v as u64).collect();
+        return a.into_pyarray(py).to_object(py);
+    }
+    let a: Vec<u32> = a.into_iter().map(|v| v as u32).collect();
+    a.into_pyarray(py).to_object(py)
+}
+
+fn extract_node_size(node_size: Option<usize>) -> PyResult<u8> {
+    match node_size {
+        None => Ok(kdbush::DEFAULT_NODE_SIZE),
+        Some(data) => {
+            if data < 2 || data > u8::MAX as usize {
+                return Err(PyValueError::new_err(format!(
+                    "`node_size` should be in range of [2, {}], got {}.",
+                    u8::MAX,
+                    data
+                )));
+            }
+            Ok(data as u8)
+        }
+    }
+}
+
+fn check_box(b: &(f64, f64, f64, f64)) -> PyResult<()> {
+    if b.0 > b.2 || b.1 > b.3 {
+        return Err(PyValueError::new_err(format!(
+            "Invalid box {:?}, should be (min_x, min_y, max_x, max_y) with min <= max.",
+            b
+        )));
+    }
+    Ok(())
+}
+
+/// get_points_in_box(points, min_x, min_y, max_x, max_y)
+/// --
+///
+/// A utility function to search for points inside a rectangle using kd-tree
+///
+/// Points on the boundary are included.
+///
+/// Args:
+///     points: List[tuple(float, float)] or np.ndarray; Two dimension points
+///     min_x: float; The left of the rectangle
+///     min_y: float; The bottom of the rectangle
+///     max_x: float; The right of the rectangle
+///     max_y: float; The top of the rectangle
+///
+/// Return:
+///     A list of index of points inside the rectangle, sorted by index
+///
+#[pyfunction]
+pub fn get_points_in_box(
+    py: Python,
+    points: PyObject,
+    min_x: f64,
+    min_y: f64,
+    max_x: f64,
+    max_y: f64,
+) -> PyResult<Vec<usize>> {
+    let points = extract_points(py, &points)?;
+    let b = (min_x, min_y, max_x, max_y);
+    check_box(&b)?;
+    let mut inside: Vec<usize> = vec![];
+    if let Some(tree) = build_tree(points, kdbush::DEFAULT_NODE_SIZE) {
+        tree.range(b.0, b.1, b.2, b.3, |id| inside.push(id));
+    }
+    inside.sort_unstable();
+
+    Ok(inside)
+}
+
+/// get_points_in_boxes(points, boxes)
+/// --
+///
+/// A utility function to search for points inside each rectangle using kd-tree
+///
+/// Points on the boundary are included.
+///
+/// Args:
+///     points: List[tuple(float, float)] or np.ndarray; Two dimension points
+///     boxes: List[tuple(float, float, float, float)]; The rectangles (min_x, min_y, max_x, max_y)
+///
+/// Return:
+///     A list of index of points inside each rectangle, sorted by index, return as the order of boxes
+///
+#[pyfunction]
+pub fn get_points_in_boxes(
+    py: Python,
+    points: PyObject,
+    boxes: Vec<(f64, f64, f64, f64)>,
+) -> PyResult<Vec<Vec<usize>>> {
+    let points = extract_points(py, &points)?;
+    for b in &boxes {
+        check_box(b)?;
+    }
+    let tree = match build_tree(points, kdbush::DEFAULT_NODE_SIZE) {
+        Some(tree) => tree,
+        None => return Ok(vec![vec![]; boxes.len()]),
+    };
+    let result: Vec<Vec<usize>> = boxes
+        .par_iter()
+        .map(|b| {
+            let mut inside: Vec<usize> = vec![];
+            tree.range(b.0, b.1, b.2, b.3, |id| inside.push(id));
+            inside.sort_unstable();
+            inside
+        })
+        .collect();
+
+    Ok(result)
+}
+
+// customize object to insert in to R-tree
+struct Rect {
+    minx: f64,
+    miny: f64,
+    maxx: f64,
+    maxy: f64,
+    index: usize,
+}
+
+impl Rect {
+    fn new(bbox: (f64, f64, f64, f64), index: usize) -> Rect {
+        Rect {
+            minx: bbox.0,
+            miny: bbox.1,
+            maxx: bbox.2,
+            maxy: bbox.3,
+            index,
+        }
+    }
+}
+
+impl RTreeObject for Rect {
+    type Envelope = AABB<[f64; 2]>;
+
+    fn envelope(&self) -> Self::Envelope {
+        AABB::from_corners([self.minx, self.miny], [self.maxx, self.maxy])
+    }
+}
+
+/// get_bbox_neighbors(bbox_list, expand=1.0, scale=1.0)
+/// --
+///
+/// A utility function to search for bbox neighbors using r-tree
+///
+/// Args:
+///     bbox_list: List[tuple(float, float, float, float)]; The minimum bounding box of any polygon
+///               (minx, miny, maxx, maxy)
+///     expand: float; The expand unit
+///     scale: float; The scale fold number
+///
+/// Return:
+///     A list of neighbors' index, return as the order of the input
+///
+#[pyfunction]
+pub fn get_bbox_neighbors(
+    bbox_list: Vec<(f64, f64, f64, f64)>,
+    expand: Option<f64>,
+    scale: Option<f64>,
+    labels: Option<Vec<usize>>,
+) -> Vec<Vec<usize>> {
+    let mut expand_na: bool = true;
+    let expand: f64 = match expand {
+        Some(data) => {
+            expand_na = false;
+            data
+        }
+        None => 0.0,
+    };
+
+    let scale: f64 = match scale {
+        Some(data) => data,
+        None => 1.0,
+    };
+
+    let mut has_labels = false;
+    let labels: Vec<usize> = match labels {
+        Some(data) => {
+            has_labels = true;
+            data
+        }
+        None => vec![0],
+    };
+
+    let aabb: Vec<Rect> = bbox_list
+        .par_iter()
+        .enumerate()
+        .map(|(i, b)| Rect::new(b.to_owned(), i))
+        .collect();
+    let tree: RTree<Rect> = RTree::<Rect>::bulk_load(aabb);
+    let search_aabb: Vec<Rect> = {
+        if expand_na == false {
+            let expand_aabb: Vec<Rect> = bbox_list
+                .par_iter()
+                .enumerate()
+                .map(|(i, b)| {
+                    Rect::new((b.0 - expand, b.1 - expand, b.2 + expand, b.3 + expand), i)
+                })
+                .collect();
+            expand_aabb
+        } else {
+            let scale_aabb: Vec<Rect> = bbox_list
+                .par_iter()
+                .enumerate()
+                .map(|(i, b)| {
+                    let xexpand: f64 = (b.2 - b.0) * (scale - 1.0);
+                    let yexpand: f64 = (b.3 - b.1) * (scale - 1.0);
+                    Rect::new(
+                        (b.0 - xexpand, b.1 - yexpand, b.2 + xexpand, b.3 + yexpand),
+                        i,
+                    )
+                })
+                .collect();
+            scale_aabb
+        }
+    };
+    let result: HashMap<usize, Vec<usize>> = search_aabb
+        .par_iter()
+        .map(|rect| {
+            let envelop = rect.envelope();
+            let search_result: Vec<&Rect> =
+                tree.locate_in_envelope_intersecting(&envelop).collect();
+            let neighbors: Vec<usize> = search_result.iter().map(|r| r.index).collect();
+            (rect.index, neighbors)
+        })
+        .collect();
+
+    let count = bbox_list.len();
+    let mut neighbors = vec![];
+    if has_labels {
+        for i in 0..count {
+            neighbors.push(result.get(&i).unwrap().clone().iter().map(
+                |t| { labels[*t] }
+            ).collect())
+        }
+    } else {
+        for i in 0..count {
+            neighbors.push(result.get(&i).unwrap().clone())
+        }
+    }
+
+    neighbors
+}
+
+/// Constructor function
+///
+/// A reusable kd-tree index of points, build once and query with different parameters
+///
+/// Args:
+///     points: List[tuple(float, float)] or np.ndarray; Two dimension points, ValueError if it's empty
+///     node_size: int (64); The node size of kd-tree, larger is faster to build, smaller is faster to query
+///
+#[pyclass]
+struct PointIndex {
+    points: Vec<(f64, f64)>,
+    tree: KDBush,
+}
+
+#[pymethods]
+impl PointIndex {
+    #[new]
+    fn new(py: Python, points: PyObject, node_size: Option<usize>) -> PyResult<Self> {
+        let points = extract_points(py, &points)?;
+        let node_size = extract_node_size(node_size)?;
+        let tree = build_tree(points.to_owned(), node_size)
+            .ok_or_else(|| PyValueError::new_err("Can't build the index on no points."))?;
+        Ok(PointIndex { points, tree })
+    }
+
+    /// Search for neighbors of all points within radius
+    ///
+    /// Args:
+    ///     r: float; The search radius
+    ///
+    /// Return:
+    ///     A list of neighbors' index, return as the order of the input
+    ///
+    fn within(&self, py: Python, r: f64) -> Vec<Vec<usize>> {
+        let query: Vec<usize> = (0..self.points.len()).collect();
+        py.allow_threads(|| within_neighbors(&self.tree, &self.points, &query, r))
+    }
+
+    /// Search for neighbors of selected points within radius
+    ///
+    /// Args:
+    ///     indices: List[int]; The index of points to query
+    ///     r: float; The search radius
+    ///
+    /// Return:
+    ///     A list of neighbors' index, return as the order of the indices
+    ///
+    fn within_of(&self, py: Python, indices: Vec<usize>, r: f64) -> PyResult<Vec<Vec<usize>>> {
+        for i in &indices {
+            if *i >= self.points.len() {
+                return Err(PyValueError::new_err(format!(
+                    "Index {} is out of range for {} points.",
+                    i,
+                    self.points.len()
+                )));
+            }
+        }
+        Ok(py.allow_threads(|| within_neighbors(&self.tree, &self.points, &indices, r)))
+    }
+
+    /// Search for the k nearest neighbors of all points
+    ///
+    /// Args:
+    ///     k: int; The number of neighbors to search for each point
+    ///     exclude_self: bool (True); Whether to exclude the point itself from its neighbors
+    ///
+    /// Return:
+    ///     A list of neighbors' index, return as the order of the input
+    ///
+    fn knn(&self, py: Python, k: usize, exclude_self: Option<bool>) -> PyResult<Vec<Vec<usize>>> {
+        if k >= self.points.len() {
+            return Err(PyValueError::new_err(format!(
+                "`k` should be smaller than the number of points, got k={} for {} points.",
+                k,
+                self.points.len()
+            )));
+        }
+
+        let exclude_self = match exclude_self {
+            Some(data) => data,
+            None => true,
+        };
+
+        Ok(py.allow_threads(|| knn_neighbors(&self.tree, &self.points, k, exclude_self)))
+    }
+}
+
+/// PointIndex in single precision
+///
+/// The coordinates are kept as float32, see `get_point_neighbors_f32` for the precision implications
+///
+/// Args:
+///     points: np.ndarray; float32 array in shape of (n, 2), other inputs will be downcast to float32
+///
+#[pyclass]
+struct PointIndexF32 {
+    points: Vec<[f32; 2]>,
+    tree: RTree<PointF32>,
+}
+
+#[pymethods]
+impl PointIndexF32 {
+    #[new]
+    fn new(py: Python, points: PyObject) -> PyResult<Self> {
+        let points = extract_points_f32(py, &points)?;
+        let tree = f32_tree(&points);
+        Ok(PointIndexF32 { points, tree })
+    }
+
+    /// Search for neighbors of all points within radius
+    ///
+    /// Args:
+    ///     r: float; The search radius
+    ///
+    /// Return:
+    ///     A list of neighbors' index sorted by index, return as the order of the input
+    ///
+    fn within(&self, r: f32) -> Vec<Vec<usize>> {
+        let query: Vec<usize> = (0..self.points.len()).collect();
+        within_neighbors_f32(&self.tree, &self.points, &query, r)
+    }
+
+    /// Search for neighbors of selected points within radius
+    ///
+    /// Args:
+    ///     indices: List[int]; The index of points to query
+    ///     r: float; The search radius
+    ///
+    /// Return:
+    ///     A list of neighbors' index sorted by index, return as the order of the indices
+    ///
+    fn within_of(&self, indices: Vec<usize>, r: f32) -> PyResult<Vec<Vec<usize>>> {
+        for i in &indices {
+            if *i >= self.points.len() {
+                return Err(PyValueError::new_err(format!(
+                    "Index {} is out of range for {} points.",
+                    i,
+                    self.points.len()
+                )));
+            }
+        }
+        Ok(within_neighbors_f32(&self.tree, &self.points, &indices, r))
+    }
+}
+
+/// edges_to_neighbors(edges, n_points)
+/// --
+///
+/// A utility function to convert undirected edges to neighbors
+///
+/// Args:
+///     edges: List[tuple(int, int)] or tuple(np.ndarray, np.ndarray); The edges as pairs or as (source, target)
+///     n_points: int; The number of points
+///
+/// Return:
+///     A list of neighbors' index sorted by index, self-loops are removed
+///
+#[pyfunction]
+pub fn edges_to_neighbors(
+    py: Python,
+    edges: PyObject,
+    n_points: usize,
+) -> PyResult<Vec<Vec<usize>>> {
+    let (source, target): (Vec<usize>, Vec<usize>) = match edges.cast_as::<PyTuple>(py) {
+        Ok(arrays) if arrays.len() == 2 => (
+            extract_index_array(arrays.get_item(0))?,
+            extract_index_array(arrays.get_item(1))?,
+        ),
+        _ => match edges.extract::<Vec<(usize, usize)>>(py) {
+            Ok(data) => data.into_iter().unzip(),
+            Err(_) => {
+                return Err(PyTypeError::new_err(
+                    "Can't resolve `edges`, should be list of tuple or a tuple of (source, target).",
+                ));
+            }
+        },
+    };
+
+    match edges_to_graph(&source, &target, n_points) {
+        Ok(data) => Ok(data),
+        Err(msg) => Err(PyValueError::new_err(msg)),
+    }
+}
+
+/// neighbors_to_coo(neighbors, n_points, points=None)
+/// --
+///
+/// A utility function to convert neighbors to a sparse matrix in COO layout
+///
+/// The output can be passed to `scipy.sparse.coo_matrix((data, (row, col)), shape=(n_points, n_points))`
+///
+/// Args:
+///     neighbors: List[List[int]]; The neighbors, or a tuple in CSR or COO layout
+///     n_points: int; The number of points
+///     points: List[tuple(float, float)] or np.ndarray; If given, the data will be the distances between points
+///
+/// Return:
+///     A tuple of (row, col, data) as numpy arrays, data is filled with ones if no points are given.
+///     Append the number of points, eg. (row, col, data, n_points), to use it as the neighbors of the bootstrap functions
+///
+#[pyfunction]
+pub fn neighbors_to_coo(
+    py: Python,
+    neighbors: PyObject,
+    n_points: usize,
+    points: Option<PyObject>,
+) -> PyResult<PyObject> {
+    let neighbors = extract_neighbors(py, &neighbors)?;
+    if neighbors.len() > n_points {
+        return Err(PyValueError::new_err(format!(
+            "Got neighbors of {} points, but `n_points` is {}.",
+            neighbors.len(),
+            n_points
+        )));
+    }
+    let (row, col) = neighbors::neighbors_to_coo(&neighbors);
+    if let Some(j) = col.iter().find(|j| **j >= n_points) {
+        return Err(PyValueError::new_err(format!(
+            "Neighbor {} is out of range for {} points.",
+            j, n_points
+        )));
+    }
+
+    let data: Vec<f64> = match points {
+        Some(data) => {
+            let points = extract_points(py, &data)?;
+            if points.len() != n_points {
+                return Err(PyValueError::new_err(format!(
+                    "The length of `points` ({}) should be the same as `n_points` ({}).",
+                    points.len(),
+                    n_points
+                )));
+            }
+            row.iter()
+                .zip(col.iter())
+                .map(|(i, j)| sq_dist(&points[*i], &points[*j]).sqrt())
+                .collect()
+        }
+        None => vec![1.0; row.len()],
+    };
+
+    let wide = n_points > u32::MAX as usize;
+    Ok(PyTuple::new(
+        py,
+        vec![
+            index_array_to_py(py, row, wide),
+            index_array_to_py(py, col, wide),
+            data.into_pyarray(py).to_object(py),
+        ],
+    )
+    .to_object(py))
+}
+
+/// symmetrize_neighbors(neighbors, mode="union")
+/// --
+///
+/// A utility function to make the neighbors symmetric
+///
+/// Args:
+///     neighbors: List[List[int]]; The neighbors, or a tuple in CSR or COO layout
+///     mode: str ("union"); "union" to add the missing reverse neighbors,
+///           "intersection" to remove the neighbors that are not mutual
+///
+/// Return:
+///     A list of neighbors' index sorted by index
+///
+#[pyfunction]
+pub fn symmetrize_neighbors(
+    py: Python,
+    neighbors: PyObject,
+    mode: Option<&str>,
+) -> PyResult<Vec<Vec<usize>>> {
+    let neighbors = extract_neighbors(py, &neighbors)?;
+    let union = match mode {
+        Some("union") | None => true,
+        Some("intersection") => false,
+        Some(other) => {
+            return Err(PyValueError::new_err(format!(
+                "`mode` should be 'union' or 'intersection', got '{}'.",
+                other
+            )));
+        }
+    };
+
+    Ok(py.allow_threads(|| symmetrize_graph(&neighbors, union)))
+}
+
+/// assert_symmetric(neighbors)
+/// --
+///
+/// Raise ValueError if the neighbors are not symmetric
+///
+/// Args:
+///     neighbors: List[List[int]]; The neighbors, or a tuple in CSR or COO layout
+///
+#[pyfunction]
+pub fn assert_symmetric(py: Python, neighbors: PyObject) -> PyResult<()> {
+    let neighbors = extract_neighbors(py, &neighbors)?;
+    check_symmetric(py, &neighbors)
+}
+
+fn check_symmetric(py: Python, neighbors: &Vec<Vec<usize>>) -> PyResult<()> {
+    match py.allow_threads(|| first_asymmetric(neighbors)) {
+        Some((i, j)) => Err(PyValueError::new_err(format!(
+            "The neighbors are not symmetric, {} is a neighbor of {} but not vice versa.",
+            j, i
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// neighbor_graph_stats(neighbors, directed=False)
+/// --
+///
+/// A utility function to summarize the neighbor graph, self-loops are not counted
+///
+/// Args:
+///     neighbors: List[List[int]]; The neighbors, or a tuple in CSR or COO layout
+///     directed: bool (False); If False, the graph is treated as undirected,
+///               j is a neighbor of i when either of them is a neighbor of the other
+///
+/// Return:
+///     A dict with n_nodes, n_edges, mean_degree, median_degree, max_degree, n_isolated,
+///     and degree_hist as a tuple of two numpy arrays (degree, count) sorted by degree
+///
+#[pyfunction]
+pub fn neighbor_graph_stats(
+    py: Python,
+    neighbors: PyObject,
+    directed: Option<bool>,
+) -> PyResult<PyObject> {
+    let neighbors = extract_neighbors(py, &neighbors)?;
+    let directed = match directed {
+        Some(data) => data,
+        None => false,
+    };
+
+    let mut degrees = py.allow_threads(|| node_degrees(&neighbors, directed));
+    degrees.sort_unstable();
+    let n_nodes = degrees.len();
+    let total: usize = degrees.iter().sum();
+    let n_edges = if directed { total } else { total / 2 };
+    let (mean_degree, median_degree) = if n_nodes == 0 {
+        (0.0, 0.0)
+    } else if n_nodes % 2 == 1 {
+        (total as f64 / n_nodes as f64, degrees[n_nodes / 2] as f64)
+    } else {
+        (
+            total as f64 / n_nodes as f64,
+            (degrees[n_nodes / 2 - 1] + degrees[n_nodes / 2]) as f64 / 2.0,
+        )
+    };
+    let n_isolated = degrees.iter().filter(|d| **d == 0).count();
+
+    let mut hist_degree: Vec<usize> = vec![];
+    let mut hist_count: Vec<usize> = vec![];
+    for d in degrees.iter() {
+        if hist_degree.last() == Some(d) {
+            *hist_count.last_mut().unwrap() += 1;
+        } else {
+            hist_degree.push(*d);
+            hist_count.push(1);
+        }
+    }
+
+    let stats = PyDict::new(py);
+    stats.set_item("n_nodes", n_nodes)?;
+    stats.set_item("n_edges", n_edges)?;
+    stats.set_item("mean_degree", mean_degree)?;
+    stats.set_item("median_degree", median_degree)?;
+    stats.set_item("max_degree", degrees.last().cloned().unwrap_or(0))?;
+    stats.set_item("n_isolated", n_isolated)?;
+    stats.set_item(
+        "degree_hist",
+        index_arrays_to_py(py, hist_degree, hist_count),
+    )?;
+    Ok(stats.to_object(py))
+}
+
+/// subsample_neighbors(neighbors, fraction, seed=None)
+/// --
+///
+/// A utility function to build an approximate neighbor graph for exploratory analysis on large data
+///
+/// Each point randomly keeps a fraction of its neighbors, at least one is kept if there is any.
+/// The order of the kept neighbors is preserved, the result may not be symmetric.
+///
+/// Args:
+///     neighbors: List[List[int]]; The neighbors, or a tuple in CSR or COO layout
+///     fraction: float; The fraction of neighbors to keep, in range of (0, 1]
+///     seed: int (None); The random seed, the result is reproducible with the same seed
+///
+/// Return:
+///     A list of neighbors' index
+///
+#[pyfunction]
+pub fn subsample_neighbors(
+    py: Python,
+    neighbors: PyObject,
+    fraction: f64,
+    seed: Option<u64>,
+) -> PyResult<Vec<Vec<usize>>> {
+    let neighbors = extract_neighbors(py, &neighbors)?;
+    if !(fraction > 0.0 && fraction <= 1.0) {
+        return Err(PyValueError::new_err(format!(
+            "`fraction` should be in range of (0, 1], got {}.",
+            fraction
+        )));
+    }
+    let seed = match seed {
+        Some(data) => data,
+        None => rand::random(),
+    };
+
+    Ok(py.allow_threads(|| subsample_graph(&neighbors, fraction, seed)))
+}
+
+/// coo_to_neighbors(row, col, n_points)
+/// --
+///
+/// A utility function to convert a sparse matrix in COO layout to neighbors
+///
+/// Args:
+///     row: np.ndarray; The row index of each entry
+///     col: np.ndarray; The column index of each entry
+///     n_points: int; The number of points
+///
+/// Return:
+///     A list of neighbors' index sorted by index, the duplicated entries are merged
+///
+#[pyfunction]
+pub fn coo_to_neighbors(
+    py: Python,
+    row: PyObject,
+    col: PyObject,
+    n_points: usize,
+) -> PyResult<Vec<Vec<usize>>> {
+    let row = extract_index_array(row.as_ref(py))?;
+    let col = extract_index_array(col.as_ref(py))?;
+    match coo_to_graph(&row, &col, n_points) {
+        Ok(data) => Ok(data),
+        Err(msg) => Err(PyValueError::new_err(msg)),
+    }
+}
+
+/// save_neighbors(path, neighbors)
+/// --
+///
+/// Save neighbors to a compact binary file
+///
+/// Args:
+///     path: str; The path of the file
+///     neighbors: List[List[int]]; The neighbors, or a tuple in CSR or COO layout
+///
+#[pyfunction]
+pub fn save_neighbors(py: Python, path: &str, neighbors: PyObject) -> PyResult<()> {
+    let neighbors = extract_neighbors(py, &neighbors)?;
+    match write_neighbors(path, &neighbors) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(PyIOError::new_err(format!(
+            "Failed to save neighbors to {}: {}",
+            path, e
+        ))),
+    }
+}
+
+/// load_neighbors(path)
+/// --
+///
+/// Load neighbors from a file saved by `save_neighbors`
+///
+/// Args:
+///     path: str; The path of the file
+///
+/// Return:
+///     A list of neighbors' index
+///
+#[pyfunction]
+pub fn load_neighbors(path: &str) -> PyResult<Vec<Vec<usize>>> {
+    match read_neighbors(path) {
+        Ok(data) => Ok(data),
+        Err(e) if e.kind() == std::io::ErrorKind::InvalidData => Err(PyValueError::new_err(
+            format!("Failed to load neighbors from {}: {}", path, e),
+        )),
+        Err(e) => Err(PyIOError::new_err(format!(
+            "Failed to load neighbors from {}: {}",
+            path, e
+        ))),
+    }
+}
+
+/// get_neighbors_shape(shapes, expand, labels=None)
+/// --
+///
+/// A utility function to search for neighbors of cell shapes using r-tree
+///
+/// Two cells are neighbors if their polygons intersect or touch after dilated by `expand`,
+/// which means the distance between the two polygons is within 2 * expand.
+///
+/// Args:
+///     shapes: List[List[(float, float)]]; The vertices of polygon of each cell
+///     expand: float; The dilation distance
+///     labels: List[int]; The labels of shapes, if given, the neighbors will be returned as labels
+///
+/// Return:
+///     A list of neighbors' index sorted by index, return as the order of the input
+///
+#[pyfunction]
+pub fn get_neighbors_shape(
+    py: Python,
+    shapes: Vec<Vec<(f64, f64)>>,
+    expand: f64,
+    labels: Option<Vec<usize>>,
+) -> PyResult<Vec<Vec<usize>>> {
+    if expand.is_nan() || expand < 0.0 {
+        return Err(PyValueError::new_err(format!(
+            "`expand` should not be negative, got {}.",
+            expand
+        )));
+    }
+    for (i, shape) in shapes.iter().enumerate() {
+        if shape.is_empty() {
+            return Err(PyValueError::new_err(format!(
+                "Shape at index {} has no vertex.",
+                i
+            )));
+        }
+    }
+
+    let neighbors = py.allow_threads(|| {
+        // bounding box prefilter, only the candidates are tested by exact polygon distance
+        let aabb: Vec<Rect> = shapes
+            .par_iter()
+            .enumerate()
+            .map(|(i, shape)| {
+                let b = points_bbox(shape);
+                Rect::new((b.0 - expand, b.1 - expand, b.2 + expand, b.3 + expand), i)
+            })
+            .collect();
+        let envelopes: Vec<AABB<[f64; 2]>> = aabb.iter().map(|rect| rect.envelope()).collect();
+        let tree: RTree<Rect> = RTree::<Rect>::bulk_load(aabb);
+        envelopes
+            .par_iter()
+            .enumerate()
+            .map(|(i, envelope)| {
+                let mut neighbors: Vec<usize> = tree
+                    .locate_in_envelope_intersecting(envelope)
+                    .map(|rect| rect.index)
+                    .filter(|j| *j == i || polygons_dist(&shapes[i], &shapes[*j]) <= 2.0 * expand)
+                    .collect();
+                neighbors.sort_unstable();
+                neighbors
+            })
+            .collect()
+    });
+
+    Ok(apply_labels(neighbors, labels))
+}
+
+/// neighbor_composition(types, neighbors, normalize=False, ignore_self=False, on_empty="zero")
+/// --
+///
+/// The count of each type in the neighbors of each cell
+///
+/// Args:
+///     types: List[str] or List[int]; The type of all the cells
+///     neighbors: List[List[int]] or Dict[int, List[int]]; The neighbors, or a tuple in CSR or COO layout
+///     normalize: bool (False); Whether to normalize each row to the fractions
+///     ignore_self: bool (False); Whether to consider self as a neighbor
+///     on_empty: str ("zero"); The row of cells without neighbors, "zero" or "nan"
+///
+/// Return:
+///     A tuple of (matrix, types), the matrix is in shape of (n_cells, n_types),
+///     the columns follow the order of types, which is the order of their first appearance
+///
+#[pyfunction]
+fn neighbor_composition(
+    py: Python,
+    types: PyObject,
+    neighbors: PyObject,
+    normalize: Option<bool>,
+    ignore_self: Option<bool>,
+    on_empty: Option<&str>,
+) -> PyResult<PyObject> {
+    let (uni, codes) = extract_types(py, &types, &None)?;
+    let neighbors = extract_neighbors(py, &neighbors)?;
+    check_neighbors_range(&neighbors, codes.len())?;
+    let normalize = match normalize {
+        Some(data) => data,
+        None => false,
+    };
+    let ignore_self = match ignore_self {
+        Some(data) => data,
+        None => false,
+    };
+    let on_empty = match on_empty {
+        Some(data) => data,
+        None => "zero",
+    };
+    if on_empty != "zero" && on_empty != "nan" {
+        return Err(PyValueError::new_err(format!(
+            "`on_empty` should be 'zero' or 'nan', got '{}'.",
+            on_empty
+        )));
+    }
+
+    let n_types = uni.len();
+
+    let matrix: Vec<f64> = py.allow_threads(|| {
+        (0..codes.len())
+            .into_par_iter()
+            .map(|i| {
+                let mut neighs = neighbors.get(i).cloned().unwrap_or_default();
+                if ignore_self {
+                    neighs.retain(|j| *j != i);
+                }
+                let mut row = composition_row(&codes, &neighs, n_types);
+                if neighs.is_empty() {
+                    if on_empty == "nan" {
+                        row = vec![f64::NAN; n_types];
+                    }
+                } else if normalize {
+                    let total = neighs.len() as f64;
+                    row.iter_mut().for_each(|v| *v /= total);
+                }
+                row
+            })
+            .flatten()
+            .collect()
+    });
+
+    let matrix = matrix.into_pyarray(py).reshape([codes.len(), n_types])?;
+    Ok((matrix, uni).to_object(py))
+}
+
+/// neighborhood_entropy(types, neighbors, base=2, ignore_self=False, normalized=False)
+/// --
+///
+/// The Shannon entropy of the type composition in the neighbors of each cell
+///
+/// Args:
+///     types: List[str] or List[int]; The type of all the cells
+///     neighbors: List[List[int]] or Dict[int, List[int]]; The neighbors, or a tuple in CSR or COO layout
+///     base: float (2); The base of the logarithm
+///     ignore_self: bool (False); Whether to consider self as a neighbor
+///     normalized: bool (False); Whether to divide by log(k), k is the number of types of all the cells,
+///                 so the entropy is in [0, 1] regardless of the base, it's 0 if there is only one type
+///
+/// Return:
+///     A numpy array of the entropy of each cell, NaN for the cells without neighbors
+///
+#[pyfunction]
+fn neighborhood_entropy(
+    py: Python,
+    types: PyObject,
+    neighbors: PyObject,
+    base: Option<f64>,
+    ignore_self: Option<bool>,
+    normalized: Option<bool>,
+) -> PyResult<PyObject> {
+    let (uni, codes) = extract_types(py, &types, &None)?;
+    let neighbors = extract_neighbors(py, &neighbors)?;
+    check_neighbors_range(&neighbors, codes.len())?;
+    let base = match base {
+        Some(data) => data,
+        None => 2.0,
+    };
+    if !(base > 0.0 && base != 1.0) {
+        return Err(PyValueError::new_err(format!(
+            "`base` should be positive and not 1, got {}.",
+            base
+        )));
+    }
+    let ignore_self = match ignore_self {
+        Some(data) => data,
+        None => false,
+    };
+    let normalized = match normalized {
+        Some(data) => data,
+        None => false,
+    };
+
+    let n_types = uni.len();
+    let max_entropy = (n_types as f64).log(base);
+
+    let entropy: Vec<f64> = py.allow_threads(|| {
+        (0..codes.len())
+            .into_par_iter()
+            .map(|i| {
+                let mut neighs = neighbors.get(i).cloned().unwrap_or_default();
+                if ignore_self {
+                    neighs.retain(|j| *j != i);
+                }
+                let h = shannon_entropy(&composition_row(&codes, &neighs, n_types), base);
+                // with only one type, every neighborhood is pure and h is 0
+                if normalized && n_types > 1 {
+                    h / max_entropy
+                } else {
+                    h
+                }
+            })
+            .collect()
+    });
+
+    Ok(entropy.into_pyarray(py).to_object(py))
+}
+
+/// neighborhood_clusters(types, neighbors, k, n_iter=100, seed=None, ignore_self=False)
+/// --
+///
+/// Cluster the cells by the type composition of their neighbors, the cellular neighborhoods
+///
+/// The composition of each cell is normalized to the fractions, and clustered by k-means
+/// (Lloyd's algorithm from the k-means++ init).
+///
+/// Args:
+///     types: List[str] or List[int]; The type of all the cells
+///     neighbors: List[List[int]] or Dict[int, List[int]]; The neighbors, or a tuple in CSR or COO layout
+///     k: int; The number of clusters
+///     n_iter: int (100); The max number of iterations, at least 1, it stops early once no label changes
+///     seed: int (None); The random seed of the init, the result is identical across runs with the same seed
+///     ignore_self: bool (False); Whether to consider self as a neighbor
+///
+/// Return:
+///     A tuple of (labels, centroids, types), labels is a numpy array of the cluster of each cell,
+///     -1 for the cells without neighbors, which are not clustered. centroids is in shape of (k, n_types),
+///     the mean fractions of each cluster, the columns follow the order of types, which is the order of
+///     their first appearance
+///
+#[pyfunction]
+fn neighborhood_clusters(
+    py: Python,
+    types: PyObject,
+    neighbors: PyObject,
+    k: usize,
+    n_iter: Option<usize>,
+    seed: Option<u64>,
+    ignore_self: Option<bool>,
+) -> PyResult<PyObject> {
+    let (uni, codes) = extract_types(py, &types, &None)?;
+    let neighbors = extract_neighbors(py, &neighbors)?;
+    check_neighbors_range(&neighbors, codes.len())?;
+    let n_iter = match n_iter {
+        Some(data) => data,
+        None => 100,
+    };
+    if n_iter == 0 {
+        return Err(PyValueError::new_err("`n_iter` should be at least 1."));
+    }
+    let ignore_self = match ignore_self {
+        Some(data) => data,
+        None => false,
+    };
+
+    let n_types = uni.len();
+
+    // the fractions of the cells with neighbors
+    let rows: Vec<Option<Vec<f64>>> = py.allow_threads(|| {
+        (0..codes.len())
+            .into_par_iter()
+            .map(|i| {
+                let mut neighs = neighbors.get(i).cloned().unwrap_or_default();
+                if ignore_self {
+                    neighs.retain(|j| *j != i);
+                }
+                if neighs.is_empty() {
+                    return None;
+                }
+                let total = neighs.len() as f64;
+                let row = composition_row(&codes, &neighs, n_types);
+                Some(row.into_iter().map(|v| v / total).collect())
+            })
+            .collect()
+    });
+    let clustered: Vec<Vec<f64>> = rows.iter().flatten().cloned().collect();
+    if k == 0 || k > clustered.len() {
+        return Err(PyValueError::new_err(format!(
+            "`k` should be between 1 and the number of cells with neighbors ({}), got {}.",
+            clustered.len(),
+            k
+        )));
+    }
+
+    let (labels, centroids, _) = py.allow_threads(|| {
+        let mut rng = perm_rng(seed, 0);
+        kmeans(&clustered, k, n_iter, &mut rng)
+    });
+    let mut cluster_labels = labels.into_iter();
+    let labels: Vec<i64> = rows
+        .iter()
+        .map(|r| match r {
+            Some(_) => cluster_labels.next().unwrap() as i64,
+            None => -1,
+        })
+        .collect();
+
+    let centroids: Vec<f64> = centroids.into_iter().flatten().collect();
+    let centroids = centroids.into_pyarray(py).reshape([k, n_types])?;
+    Ok((labels.into_pyarray(py), centroids, uni).to_object(py))
+}
+
+// the number of permutations in each block of the adaptive bootstrap
+const ADAPTIVE_BLOCK: usize = 50;
+// the progress is reported after each block of permutations
+const PROGRESS_BLOCK: usize = 100;
+
+// run the permutations in blocks without the GIL, between the blocks the signals are checked
+// and progress is called with (done, total) if given, Ctrl-C or an exception from progress
+// aborts the run once the running block completes
+fn run_permutations<T, F>(
+    py: Python,
+    times: usize,
+    progress: &Option<PyObject>,
+    perm: F,
+) -> PyResult<Vec<T>>
+where
+    T: Send,
+    F: Fn(usize) -> T + Sync,
+{
+    let mut results = Vec::with_capacity(times);
+    while results.len() < times {
+        let start = results.len();
+        let end = (start + PROGRESS_BLOCK).min(times);
+        results.extend(
+            py.allow_threads(|| (start..end).into_par_iter().map(&perm).collect::<Vec<T>>()),
+        );
+        report_progress(py, progress, end, times)?;
+    }
+    Ok(results)
+}
+
+// the same as run_permutations, but each result is consumed in the order of the permutations
+// once its block completes, so only a block of results is kept
+fn stream_permutations<T, F, G>(
+    py: Python,
+    times: usize,
+    progress: &Option<PyObject>,
+    perm: F,
+    mut consume: G,
+) -> PyResult<()>
+where
+    T: Send,
+    F: Fn(usize) -> T + Sync,
+    G: FnMut(T),
+{
+    let mut done = 0;
+    while done < times {
+        let end = (done + PROGRESS_BLOCK).min(times);
+        let block: Vec<T> = py.allow_threads(|| (done..end).into_par_iter().map(&perm).collect());
+        block.into_iter().for_each(&mut consume);
+        report_progress(py, progress, end, times)?;
+        done = end;
+    }
+    Ok(())
+}
+
+// the signal handlers only run in the thread holding the GIL, so a KeyboardInterrupt is raised here
+fn report_progress(
+    py: Python,
+    progress: &Option<PyObject>,
+    done: usize,
+    total: usize,
+) -> PyResult<()> {
+    py.check_signals()?;
+    if let Some(callback) = progress {
+        callback.call1(py, (done, total))?;
+    }
+    Ok(())
+}
+
+/// comb_bootstrap(x_status, y_status, neighbors, times=500, ignore_self=False, *, seed=None, return_detail=False,
+///                symmetric=False, groups=None, adaptive=False, stop_alpha=0.05, max_times=None,
+///                on_zero_variance="warn", count="incidence", return_permutations=False, weights=None,
+///                method="permutation", shuffle="y", progress=None)
+/// --
+///
+/// Bootstrap between two types
+///
+/// If you want to test co-localization between protein X and Y, first determine if the cell is X-positive
+/// and/or Y-positive. True is considered as positive and will be counted.
+///
+/// Args:
+///     x_status: List[bool], List[int] or List[float]; If cell is type x, or the value of x in each cell
+///               (eg. the transcript counts), the numeric values are summed as x[i] * y[j] over the neighbors
+///     y_status: List[bool], List[int] or List[float]; If cell is type y, or the value of y in each cell
+///     neighbors: List[List[int]] or Dict[int, List[int]]; eg. {1:[4,5], 2:[6,7]}, cell at index 1 has neighbor cells from index 4 and 5,
+///                a tuple of (indptr, indices) in CSR layout or a tuple of (row, col, data, n_points) in COO layout
+///     times: int (500); How many times to perform bootstrap
+///     ignore_self: bool (False); Whether to consider self as a neighbor
+///     seed: int (None); The random seed, the result is identical across runs with the same seed
+///     return_detail: bool (False); Whether to return the details of the permutation test
+///     symmetric: bool (False); If False, count the x-positive cells with y-positive neighbors,
+///                the result depends on the direction when the neighbors are asymmetric.
+///                If True, the neighbors are made symmetric, and count the neighbor pairs that one is x-positive
+///                and the other is y-positive, each pair is counted once
+///     groups: List[int] (None); The group (eg. sample or ROI) of each cell, if given, the status is only shuffled within
+///             each group to keep the frequency of each group
+///     adaptive: bool (False); If True, run permutations in blocks of 50, and stop early once the lower bound of
+///               the 95% confidence interval of the running p-value is above `stop_alpha`
+///     stop_alpha: float (0.05); The threshold of p-value to stop early
+///     max_times: int (None); The max number of permutations when adaptive, default is `times`
+///     on_zero_variance: str ("warn"); When all the permutations give the same count (eg. y is all False),
+///                       the z-score is undefined, "raise" raises ValueError, "nan" returns NaN as the z-score,
+///                       "warn" returns NaN with a warning
+///     count: str ("incidence"); What to count, the permutations use the same mode.
+///            "incidence" counts the x-positive cells with y-positive neighbors as described in `symmetric`.
+///            "pairs" counts each unordered neighbor pair that one is x-positive and the other is y-positive
+///            at most once, which is the same as "incidence" with symmetric=True.
+///            "cells" counts the x-positive cells that have at least one y-positive neighbor.
+///     return_permutations: bool (False); Whether to return the count of each permutation
+///     weights: List[List[float]] (None); The weight of each neighbor in the same shape as neighbors,
+///              the weights can also be given in neighbors as a list of list of (index, weight).
+///              If given, sum the weights instead of counting, the weights stay with the edges when the status is shuffled.
+///              Only work with symmetric=False, count="incidence" and adaptive=False, the same for numeric status.
+///     method: str ("permutation"); "permutation" or "analytic". The analytic method computes the exact mean
+///             and variance of the count when y is shuffled (conditioning on the number of y-positive cells
+///             and the neighbors), no permutation is run and the p-value is from the normal approximation.
+///             Only work with symmetric=False, count="incidence", adaptive=False, return_permutations=False
+///             and shuffle="x" or "y".
+///     shuffle: str ("y"); Which status to shuffle in each permutation, "y", "x" or "both" (independently).
+///              The null distributions differ when x and y have different frequencies or spatial patterns.
+///     progress: Callable[[int, int], None] (None); Called with (done, total) after every 100 permutations
+///               (50 if adaptive), raise an exception (eg. KeyboardInterrupt) in it to abort the run,
+///               Ctrl-C also aborts the run between these blocks without a callback
+///
+/// Return:
+///     The z-score for the spatial relationship between X and Y.
+///     If return_detail or adaptive is True, return a dict with observed, perm_mean, perm_std, zscore, pvalue
+///     and times (the number of permutations actually run), the two-sided empirical p-value is
+///     (number of permutations at least as extreme + 1) / (times + 1).
+///     If return_permutations is True, return a tuple of (result, counts), counts is a numpy array
+///     of the count of each permutation.
+///
+#[pyfunction(
+    times = "None",
+    ignore_self = "None",
+    "*",
+    seed = "None",
+    return_detail = "None",
+    symmetric = "None",
+    groups = "None",
+    adaptive = "None",
+    stop_alpha = "None",
+    max_times = "None",
+    on_zero_variance = "None",
+    count = "None",
+    return_permutations = "None",
+    weights = "None",
+    method = "None",
+    shuffle = "None",
+    progress = "None"
+)]
+#[allow(clippy::too_many_arguments)]
+fn comb_bootstrap(
+    py: Python,
+    x_status: PyObject,
+    y_status: PyObject,
+    neighbors: PyObject,
+    times: Option<usize>,
+    ignore_self: Option<bool>,
+    seed: Option<u64>,
+    return_detail: Option<bool>,
+    symmetric: Option<bool>,
+    groups: Option<Vec<i64>>,
+    adaptive: Option<bool>,
+    stop_alpha: Option<f64>,
+    max_times: Option<usize>,
+    on_zero_variance: Option<&str>,
+    count: Option<&str>,
+    return_permutations: Option<bool>,
+    weights: Option<Vec<Vec<f64>>>,
+    method: Option<&str>,
+    shuffle: Option<&str>,
+    progress: Option<PyObject>,
+) -> PyResult<PyObject> {
+    let x = extract_status(py, &x_status, "x_status")?;
+    let y = extract_status(py, &y_status, "y_status")?;
+
+    if x.len() != y.len() {
+        return Err(PyValueError::new_err(format!(
+            "The length of `x_status` ({}) and `y_status` ({}) should be the same.",
+            x.len(),
+            y.len()
+        )));
+    }
+
+    let (neighbors_data, weights_data) = extract_weighted_neighbors(py, &neighbors, weights)?;
+    check_neighbors_range(&neighbors_data, x.len())?;
+
+    let times = match times {
+        Some(data) => data,
+        None => 500,
+    };
+
+    let ignore_self = match ignore_self {
+        Some(data) => data,
+        None => false,
+    };
+    let symmetric = match symmetric {
+        Some(data) => data,
+        None => false,
+    };
+    let count_mode = match count {
+        Some(data) => data,
+        None => "incidence",
+    };
+    if !["incidence", "pairs", "cells"].contains(&count_mode) {
+        return Err(PyValueError::new_err(format!(
+            "`count` should be 'incidence', 'pairs' or 'cells', got '{}'.",
+            count_mode
+        )));
+    }
+    let members = extract_group_members(groups, y.len())?;
+
+    let adaptive = match adaptive {
+        Some(data) => data,
+        None => false,
+    };
+    let stop_alpha = match stop_alpha {
+        Some(data) => data,
+        None => 0.05,
+    };
+    if !(stop_alpha > 0.0 && stop_alpha < 1.0) {
+        return Err(PyValueError::new_err(format!(
+            "`stop_alpha` should be in range of (0, 1), got {}.",
+            stop_alpha
+        )));
+    }
+    let max_times = match max_times {
+        Some(data) => data,
+        None => times,
+    };
+    let on_zero_variance = extract_on_zero_variance(on_zero_variance)?;
+
+    let return_detail = match return_detail {
+        Some(data) => data,
+        None => false,
+    };
+    let return_permutations = match return_permutations {
+        Some(data) => data,
+        None => false,
+    };
+    let method = match method {
+        Some(data) => data,
+        None => "permutation",
+    };
+    if method != "permutation" && method != "analytic" {
+        return Err(PyValueError::new_err(format!(
+            "`method` should be 'permutation' or 'analytic', got '{}'.",
+            method
+        )));
+    }
+
+    let shuffle = match shuffle {
+        Some(data) => data,
+        None => "y",
+    };
+    if !["y", "x", "both"].contains(&shuffle) {
+        return Err(PyValueError::new_err(format!(
+            "`shuffle` should be 'y', 'x' or 'both', got '{}'.",
+            shuffle
+        )));
+    }
+
+    if method == "analytic" {
+        if symmetric
+            || count_mode != "incidence"
+            || adaptive
+            || return_permutations
+            || shuffle == "both"
+        {
+            return Err(PyValueError::new_err(
+                "The analytic method only works with symmetric=False, count='incidence', adaptive=False, \
+                return_permutations=False and shuffle='x' or 'y'.",
+            ));
+        }
+        let weights = match weights_data {
+            Some(data) => data,
+            None => neighbors_data
+                .iter()
+                .map(|neighs| vec![1.0; neighs.len()])
+                .collect(),
+        };
+        let weighted: Vec<Vec<(usize, f64)>> = neighbors_data
+            .into_iter()
+            .zip(weights)
+            .map(|(neighs, w)| neighs.into_iter().zip(w).collect())
+            .collect();
+        let weighted = utils::remove_rep_weighted(weighted, ignore_self);
+        let x = x.values();
+        let y = y.values();
+        let real = comb_weight_neighbors(&x, &y, &weighted);
+        let members = match members {
+            Some(data) => data,
+            None => vec![(0..y.len()).collect()],
+        };
+        let (m, var) = if shuffle == "x" {
+            let loads: Vec<f64> = weighted
+                .iter()
+                .map(|neighs| neighs.iter().map(|(c, w)| w * y[*c]).sum())
+                .collect();
+            shuffle_moments(&loads, &x, &members)
+        } else {
+            let loads = neighbor_loads(&x, &weighted, y.len());
+            shuffle_moments(&loads, &y, &members)
+        };
+        let sd = var.sqrt();
+        let z = bootstrap_zscore(py, real, m, sd, on_zero_variance)?;
+        if return_detail {
+            return bootstrap_detail(py, real, m, sd, z, normal_pvalue(z), 0);
+        }
+        return Ok(z.to_object(py));
+    }
+
+    // the numeric markers are summed in the same way as the weights
+    let (x, y) = match (x, y, weights_data) {
+        (Status::Bool(x), Status::Bool(y), None) => (x, y),
+        (x, y, weights) => {
+            if symmetric || count_mode != "incidence" || adaptive {
+                return Err(PyValueError::new_err(
+                    "Weighted neighbors and numeric markers only work with symmetric=False, \
+                    count='incidence' and adaptive=False.",
+                ));
+            }
+            let weights = match weights {
+                Some(data) => data,
+                None => neighbors_data
+                    .iter()
+                    .map(|neighs| vec![1.0; neighs.len()])
+                    .collect(),
+            };
+            let weighted: Vec<Vec<(usize, f64)>> = neighbors_data
+                .into_iter()
+                .zip(weights)
+                .map(|(neighs, w)| neighs.into_iter().zip(w).collect())
+                .collect();
+            let weighted = utils::remove_rep_weighted(weighted, ignore_self);
+            let x = x.values();
+            let y = y.values();
+            let real = comb_weight_neighbors(&x, &y, &weighted);
+            let perm_values: Vec<f64> = run_permutations(py, times, &progress, |i| {
+                let mut rng = perm_rng(seed, i);
+                let (shuffle_x, shuffle_y) = shuffle_status(&x, &y, shuffle, &members, &mut rng);
+                comb_weight_neighbors(&shuffle_x, &shuffle_y, &weighted)
+            })?;
+
+            let m = mean_f(&perm_values);
+            let sd = std_f(&perm_values);
+            let z = bootstrap_zscore(py, real, m, sd, on_zero_variance)?;
+            let result = if return_detail {
+                let p = empirical_pvalue(real, &perm_values, m);
+                bootstrap_detail(py, real, m, sd, z, p, perm_values.len())?
+            } else {
+                z.to_object(py)
+            };
+            if return_permutations {
+                return Ok((result, perm_values.into_pyarray(py)).to_object(py));
+            }
+            return Ok(result);
+        }
+    };
+
+    let pairs = symmetric || count_mode == "pairs";
+    let neighbors_data = if symmetric || pairs {
+        symmetrize_graph(&neighbors_data, true)
+    } else {
+        neighbors_data
+    };
+    // counting cells needs all the neighbors of each cell
+    let neighbors = if count_mode == "cells" {
+        let mut neighbors = neighbors_data;
+        if ignore_self {
+            neighbors
+                .iter_mut()
+                .enumerate()
+                .for_each(|(i, neighs)| neighs.retain(|j| *j != i));
+        }
+        neighbors
+    } else {
+        utils::remove_rep_neighbors(neighbors_data, ignore_self)
+    };
+    let count = |x: &[bool], y: &[bool]| {
+        if count_mode == "cells" {
+            comb_count_cells(x, y, &neighbors)
+        } else if pairs {
+            comb_count_pairs(x, y, &neighbors)
+        } else {
+            comb_count_neighbors(x, y, &neighbors)
+        }
+    };
+    let real: f64 = count(&x, &y) as f64;
+
+    let perm_count = |i: usize| -> usize {
+        let mut rng = perm_rng(seed, i);
+        let (shuffle_x, shuffle_y) = shuffle_status(&x, &y, shuffle, &members, &mut rng);
+        count(&shuffle_x, &shuffle_y)
+    };
+    // the counts are only kept when they are needed, otherwise they are reduced on the fly
+    let perm_counts: Option<Vec<usize>> = if adaptive {
+        let mut perm_counts = vec![];
+        while perm_counts.len() < max_times {
+            let start = perm_counts.len();
+            let end = (start + ADAPTIVE_BLOCK).min(max_times);
+            perm_counts.extend(py.allow_threads(|| {
+                (start..end)
+                    .into_par_iter()
+                    .map(perm_count)
+                    .collect::<Vec<usize>>()
+            }));
+            report_progress(py, &progress, end, max_times)?;
+            if clearly_insignificant(real, &perm_counts, stop_alpha) {
+                break;
+            }
+        }
+        Some(perm_counts)
+    } else if return_detail || return_permutations {
+        Some(run_permutations(py, times, &progress, perm_count)?)
+    } else {
+        None
+    };
+    let sums = match &perm_counts {
+        Some(counts) => count_sums(counts),
+        None => {
+            let mut sums = (0, 0, 0);
+            stream_permutations(py, times, &progress, perm_count, |v| {
+                sums = add_count(sums, v)
+            })?;
+            sums
+        }
+    };
+
+    let (m, sd) = sums_mean_std(sums);
+    let z = bootstrap_zscore(py, real, m, sd, on_zero_variance)?;
+
+    let result = if return_detail || adaptive {
+        let counts: Vec<f64> = perm_counts.iter().flatten().map(|v| *v as f64).collect();
+        let p = empirical_pvalue(real, &counts, m);
+        bootstrap_detail(py, real, m, sd, z, p, counts.len())?
+    } else {
+        z.to_object(py)
+    };
+
+    match perm_counts {
+        Some(counts) if return_permutations => {
+            let counts: Vec<u64> = counts.into_iter().map(|v| v as u64).collect();
+            Ok((result, counts.into_pyarray(py)).to_object(py))
+        }
+        _ => Ok(result),
+    }
+}
+
+// the status of markers, bool for positive or negative, or numeric values like the transcript counts
+enum Status {
+    Bool(Vec<bool>),
+    Value(Vec<f64>),
+}
+
+impl Status {
+    fn len(&self) -> usize {
+        match self {
+            Status::Bool(v) => v.len(),
+            Status::Value(v) => v.len(),
+        }
+    }
+
+    fn values(&self) -> Vec<f64> {
+        match self {
+            Status::Bool(v) => v.iter().map(|s| *s as u8 as f64).collect(),
+            Status::Value(v) => v.to_owned(),
+        }
+    }
+}
+
+// try bool first, then int, then float
+fn extract_status(py: Python, status: &PyObject, name: &str) -> PyResult<Status> {
+    if let Ok(data) = status.extract::<Vec<bool>>(py) {
+        return Ok(Status::Bool(data));
+    }
+    if let Ok(data) = status.extract::<Vec<u32>>(py) {
+        return Ok(Status::Value(data.into_iter().map(|v| v as f64).collect()));
+    }
+    match status.extract::<Vec<f64>>(py) {
+        Ok(data) => Ok(Status::Value(data)),
+        Err(_) => Err(PyTypeError::new_err(format!(
+            "Can't resolve `{}`, should be list of bool, int or float.",
+            name
+        ))),
+    }
+}
+
+// the z-score is undefined when all the permutations are the same
+fn bootstrap_zscore(
+    py: Python,
+    real: f64,
+    m: f64,
+    sd: f64,
+    on_zero_variance: &str,
+) -> PyResult<f64> {
+    if sd != 0.0 {
+        return Ok((real - m) / sd);
+    }
+    let msg = format!(
+        "All the permutations give the same count {}, the z-score is undefined.",
+        m
+    );
+    undefined_zscore(py, msg, on_zero_variance)
+}
+
+// the same as bootstrap_zscore, the message names the pair
+fn pair_zscore(
+    py: Python,
+    pair: (&str, &str),
+    real: f64,
+    m: f64,
+    sd: f64,
+    on_zero_variance: &str,
+) -> PyResult<f64> {
+    if sd != 0.0 {
+        return Ok((real - m) / sd);
+    }
+    let msg = format!(
+        "All the permutations of ('{}', '{}') give the same count {}, the z-score is undefined.",
+        pair.0, pair.1, m
+    );
+    undefined_zscore(py, msg, on_zero_variance)
+}
+
+// "warn" by default, shared by all the permutation tests
+fn extract_on_zero_variance(on_zero_variance: Option<&str>) -> PyResult<&str> {
+    let on_zero_variance = match on_zero_variance {
+        Some(data) => data,
+        None => "warn",
+    };
+    if !["raise", "nan", "warn"].contains(&on_zero_variance) {
+        return Err(PyValueError::new_err(format!(
+            "`on_zero_variance` should be 'raise', 'nan' or 'warn', got '{}'.",
+            on_zero_variance
+        )));
+    }
+    Ok(on_zero_variance)
+}
+
+fn undefined_zscore(py: Python, msg: String, on_zero_variance: &str) -> PyResult<f64> {
+    match on_zero_variance {
+        "raise" => return Err(PyValueError::new_err(msg)),
+        "warn" => warn_user(py, &msg)?,
+        _ => {}
+    }
+    Ok(f64::NAN)
+}
+
+fn bootstrap_detail(
+    py: Python,
+    real: f64,
+    m: f64,
+    sd: f64,
+    z: f64,
+    pvalue: f64,
+    times: usize,
+) -> PyResult<PyObject> {
+    let detail = PyDict::new(py);
+    detail.set_item("observed", real)?;
+    detail.set_item("perm_mean", m)?;
+    detail.set_item("perm_std", sd)?;
+    detail.set_item("zscore", z)?;
+    detail.set_item("pvalue", pvalue)?;
+    detail.set_item("times", times)?;
+    Ok(detail.to_object(py))
+}
+
+// the neighbors and their weights, if any
+type WeightedNeighbors = (Vec<Vec<usize>>, Option<Vec<Vec<f64>>>);
+
+// neighbors can also be a list (or dict) of list of (index, weight), or the weights are given separately
+fn extract_weighted_neighbors(
+    py: Python,
+    neighbors: &PyObject,
+    weights: Option<Vec<Vec<f64>>>,
+) -> PyResult<WeightedNeighbors> {
+    if weights.is_none() {
+        let weighted: Option<Vec<Vec<(usize, f64)>>> = match neighbors.extract(py) {
+            Ok(data) => Some(data),
+            Err(_) => match neighbors.extract::<HashMap<usize, Vec<(usize, f64)>>>(py) {
+                Ok(data) => {
+                    let n = data.keys().max().map_or(0, |v| v + 1);
+                    let mut result = vec![vec![]; n];
+                    for (k, v) in data {
+                        result[k] = v;
+                    }
+                    Some(result)
+                }
+                Err(_) => None,
+            },
+        };
+        // an empty neighbors is resolved as unweighted
+        if let Some(data) = weighted {
+            if data.iter().any(|neighs| !neighs.is_empty()) {
+                let (neighbors, weights): (Vec<Vec<usize>>, Vec<Vec<f64>>) = data
+                    .into_iter()
+                    .map(|neighs| neighs.into_iter().unzip())
+                    .unzip();
+                return Ok((neighbors, Some(weights)));
+            }
+        }
+    }
+
+    let neighbors = extract_neighbors(py, neighbors)?;
+    if let Some(w) = &weights {
+        let same_shape = w.len() == neighbors.len()
+            && w.iter()
+                .zip(neighbors.iter())
+                .all(|(a, b)| a.len() == b.len());
+        if !same_shape {
+            return Err(PyValueError::new_err(
+                "`weights` should have the same shape as `neighbors`.",
+            ));
+        }
+    }
+    Ok((neighbors, weights))
+}
+
+/// set_num_threads(n)
+/// --
+///
+/// Set the number of threads of all the parallel functions
+///
+/// The thread pool is created at the first parallel call, so this should be called before that,
+/// otherwise it raises RuntimeError. If not set, the `RAYON_NUM_THREADS` environment variable is used,
+/// the default is the number of CPUs.
+///
+/// Args:
+///     n: int; The number of threads
+///
+#[pyfunction]
+pub fn set_num_threads(n: usize) -> PyResult<()> {
+    if n == 0 {
+        return Err(PyValueError::new_err("`n` should be at least 1."));
+    }
+    let pool = rayon::ThreadPoolBuilder::new().num_threads(n);
+    match pool.build_global() {
+        Ok(_) => Ok(()),
+        Err(_) => Err(PyRuntimeError::new_err(format!(
+            "Can't set the number of threads to {}, the thread pool is already initialized \
+            with {} threads, call set_num_threads before any parallel function.",
+            n,
+            rayon::current_num_threads()
+        ))),
+    }
+}
+
+/// get_num_threads()
+/// --
+///
+/// The number of threads of all the parallel functions
+///
+#[pyfunction]
+pub fn get_num_threads() -> usize {
+    rayon::current_num_threads()
+}
+
+/// adjust_pvalues(pvalues, method="fdr_bh")
+/// --
+///
+/// Multiple testing correction of p-values
+///
+/// Args:
+///     pvalues: List[float]; The raw p-values
+///     method: str ("fdr_bh"); "fdr_bh" for Benjamini-Hochberg or "bonferroni"
+///
+/// Return:
+///     The adjusted p-values in the same order, capped at 1, NaN p-values are kept as NaN
+///     and are not counted as tests
+///
+#[pyfunction]
+fn adjust_pvalues(pvalues: Vec<f64>, method: Option<&str>) -> PyResult<Vec<f64>> {
+    let method = match method {
+        Some(data) => data,
+        None => "fdr_bh",
+    };
+    if method != "fdr_bh" && method != "bonferroni" {
+        return Err(PyValueError::new_err(format!(
+            "`method` should be 'fdr_bh' or 'bonferroni', got '{}'.",
+            method
+        )));
+    }
+    Ok(utils::adjust_pvalues(&pvalues, method))
+}
+
+/// comb_bootstrap_matrix(marker_matrix, neighbors, times=500, ignore_self=False, seed=None, markers=None,
+///                       on_zero_variance="warn")
+/// --
+///
+/// Bootstrap between all pairs of markers
+///
+/// The permutations are shared by all the pairs, with the same seed, the entry (a, b) is the same as
+/// `comb_bootstrap(marker_matrix[:, a], marker_matrix[:, b], neighbors, times, ignore_self, seed, on_zero_variance)`.
+///
+/// Args:
+///     marker_matrix: np.ndarray; bool array in shape of (n_cells, n_markers), if the cell is positive of the marker
+///     neighbors: List[List[int]] or Dict[int, List[int]]; The neighbors, or a tuple in CSR or COO layout
+///     times: int (500); How many times to perform bootstrap
+///     ignore_self: bool (False); Whether to consider self as a neighbor
+///     seed: int (None); The random seed, the result is identical across runs with the same seed
+///     markers: List[str] (None); The names of markers, if given, return a dict keyed by the pair of markers
+///     on_zero_variance: str ("warn"); When all the permutations of a pair give the same count (eg. a marker
+///                       without positive cells), the z-score is undefined,
+///                       "raise" raises ValueError, "nan" returns NaN, "warn" returns NaN with a warning
+///
+/// Return:
+///     The z-score matrix in shape of (n_markers, n_markers), the row is x and the column is y
+///
+#[pyfunction]
+fn comb_bootstrap_matrix(
+    py: Python,
+    marker_matrix: PyObject,
+    neighbors: PyObject,
+    times: Option<usize>,
+    ignore_self: Option<bool>,
+    seed: Option<u64>,
+    markers: Option<Vec<String>>,
+    on_zero_variance: Option<&str>,
+) -> PyResult<PyObject> {
+    let matrix: Vec<Vec<bool>> = match marker_matrix.extract::<PyReadonlyArray2<bool>>(py) {
+        Ok(arr) => arr
+            .as_array()
+            .outer_iter()
+            .map(|row| row.to_vec())
+            .collect(),
+        Err(_) => match marker_matrix.extract(py) {
+            Ok(data) => data,
+            Err(_) => {
+                return Err(PyTypeError::new_err(
+                    "Can't resolve `marker_matrix`, should be a bool array in shape of (n_cells, n_markers).",
+                ));
+            }
+        },
+    };
+    let n = matrix.len();
+    let m = matrix.first().map_or(0, |row| row.len());
+    if matrix.iter().any(|row| row.len() != m) {
+        return Err(PyValueError::new_err(
+            "Every row of `marker_matrix` should have the same length.",
+        ));
+    }
+    if let Some(names) = &markers {
+        if names.len() != m {
+            return Err(PyValueError::new_err(format!(
+                "The length of `markers` ({}) should match the number of columns of `marker_matrix` ({}).",
+                names.len(),
+                m
+            )));
+        }
+    }
+
+    let neighbors_data: Vec<Vec<usize>> = extract_neighbors(py, &neighbors)?;
+    check_neighbors_range(&neighbors_data, n)?;
+
+    let times = match times {
+        Some(data) => data,
+        None => 500,
+    };
+
+    let ignore_self = match ignore_self {
+        Some(data) => data,
+        None => false,
+    };
+    let neighbors = utils::remove_rep_neighbors(neighbors_data, ignore_self);
+
+    let on_zero_variance = extract_on_zero_variance(on_zero_variance)?;
+
+    let identity: Vec<usize> = (0..n).collect();
+    let real = py.allow_threads(|| comb_count_matrix(&matrix, &identity, &neighbors));
+    // shuffle the index in the same way as comb_bootstrap shuffles y
+    let perm_counts: Vec<Vec<usize>> = run_permutations(py, times, &None, |i| {
+        let mut rng = perm_rng(seed, i);
+        let mut perm = identity.to_owned();
+        perm.shuffle(&mut rng);
+        comb_count_matrix(&matrix, &perm, &neighbors)
+    })?;
+
+    let stats: Vec<(f64, f64)> = py.allow_threads(|| {
+        (0..m * m)
+            .into_par_iter()
+            .map(|k| {
+                let counts: Vec<usize> = perm_counts.iter().map(|c| c[k]).collect();
+                sums_mean_std(count_sums(&counts))
+            })
+            .collect()
+    });
+    // the same zero variance handling as comb_bootstrap, the message names the pair of markers
+    let names: Vec<String> = match &markers {
+        Some(names) => names.to_owned(),
+        None => (0..m).map(|i| i.to_string()).collect(),
+    };
+    let mut z: Vec<f64> = Vec::with_capacity(m * m);
+    for (k, (mean, sd)) in stats.into_iter().enumerate() {
+        let pair = (names[k / m].as_str(), names[k % m].as_str());
+        z.push(pair_zscore(
+            py,
+            pair,
+            real[k] as f64,
+            mean,
+            sd,
+            on_zero_variance,
+        )?);
+    }
+
+    match markers {
+        Some(names) => {
+            let result = PyDict::new(py);
+            for (a, x) in names.iter().enumerate() {
+                for (b, y) in names.iter().enumerate() {
+                    result.set_item((x, y), z[a * m + b])?;
+                }
+            }
+            Ok(result.to_object(py))
+        }
+        None => Ok(z.into_pyarray(py).reshape([m, m])?.to_object(py)),
+    }
+}
+
+/// expression_bootstrap(x_values, y_values, neighbors, times=500, stat="product", ignore_self=False, seed=None,
+///                      ignore_nan=False, on_zero_variance="warn")
+/// --
+///
+/// Bootstrap between two continuous markers
+///
+/// The null distribution is built by permuting y across cells.
+///
+/// Args:
+///     x_values: List[float]; The expression of marker x of each cell
+///     y_values: List[float]; The expression of marker y of each cell
+///     neighbors: List[List[int]] or Dict[int, List[int]]; The neighbors, or a tuple in CSR or COO layout
+///     times: int (500); How many times to perform bootstrap
+///     stat: str ("product"); "product" is the sum of x[i] * y[j] over the neighbor pairs, each pair is counted
+///           once like `comb_bootstrap`. "pearson" is the pearson correlation between x[i] and the mean of y
+///           of all the neighbors of cell i, cells without neighbors are not counted.
+///     ignore_self: bool (False); Whether to consider self as a neighbor
+///     seed: int (None); The random seed, the result is identical across runs with the same seed
+///     ignore_nan: bool (False); NaN values raise ValueError by default, if True, the cells with NaN are excluded
+///     on_zero_variance: str ("warn"); When all the permutations give the same statistic (eg. y is constant),
+///                       the z-score is undefined, "raise" raises ValueError, "nan" returns NaN,
+///                       "warn" returns NaN with a warning
+///
+/// Return:
+///     A tuple of (z-score, p-value), the two-sided empirical p-value is
+///     (number of permutations at least as extreme + 1) / (times + 1)
+///
+#[pyfunction]
+#[allow(clippy::too_many_arguments)]
+fn expression_bootstrap(
+    py: Python,
+    x_values: Vec<f64>,
+    y_values: Vec<f64>,
+    neighbors: PyObject,
+    times: Option<usize>,
+    stat: Option<&str>,
+    ignore_self: Option<bool>,
+    seed: Option<u64>,
+    ignore_nan: Option<bool>,
+    on_zero_variance: Option<&str>,
+) -> PyResult<(f64, f64)> {
+    if x_values.len() != y_values.len() {
+        return Err(PyValueError::new_err(format!(
+            "The length of `x_values` ({}) and `y_values` ({}) should be the same.",
+            x_values.len(),
+            y_values.len()
+        )));
+    }
+    let mut neighbors_data: Vec<Vec<usize>> = extract_neighbors(py, &neighbors)?;
+    check_neighbors_range(&neighbors_data, x_values.len())?;
+    neighbors_data.resize(x_values.len(), vec![]);
+
+    let times = match times {
+        Some(data) => data,
+        None => 500,
+    };
+
+    let pearson = match stat {
+        Some("product") | None => false,
+        Some("pearson") => true,
+        Some(other) => {
+            return Err(PyValueError::new_err(format!(
+                "`stat` should be 'product' or 'pearson', got '{}'.",
+                other
+            )));
+        }
+    };
+
+    let ignore_self = match ignore_self {
+        Some(data) => data,
+        None => false,
+    };
+
+    let ignore_nan = match ignore_nan {
+        Some(data) => data,
+        None => false,
+    };
+
+    let on_zero_variance = extract_on_zero_variance(on_zero_variance)?;
+
+    let keep: Vec<bool> = x_values
+        .iter()
+        .zip(y_values.iter())
+        .map(|(x, y)| !x.is_nan() && !y.is_nan())
+        .collect();
+    let (x, y, neighbors_data) = if keep.iter().all(|k| *k) {
+        (x_values, y_values, neighbors_data)
+    } else if ignore_nan {
+        let x: Vec<f64> = (0..keep.len())
+            .filter(|i| keep[*i])
+            .map(|i| x_values[i])
+            .collect();
+        let y: Vec<f64> = (0..keep.len())
+            .filter(|i| keep[*i])
+            .map(|i| y_values[i])
+            .collect();
+        (x, y, induced_subgraph(&neighbors_data, &keep))
+    } else {
+        let nan_cells: Vec<usize> = (0..keep.len()).filter(|i| !keep[*i]).collect();
+        return Err(PyValueError::new_err(format!(
+            "Found NaN values at cells {:?}, set `ignore_nan=True` to exclude them.",
+            nan_cells
+        )));
+    };
+
+    let neighbors = if pearson {
+        let mut neighbors = neighbors_data;
+        if ignore_self {
+            neighbors
+                .iter_mut()
+                .enumerate()
+                .for_each(|(i, neighs)| neighs.retain(|j| *j != i));
+        }
+        neighbors
+    } else {
+        utils::remove_rep_neighbors(neighbors_data, ignore_self)
+    };
+    let compute_stat = |y: &Vec<f64>| {
+        if pearson {
+            pearson_stat(&x, y, &neighbors)
+        } else {
+            product_stat(&x, y, &neighbors)
+        }
+    };
+
+    let real = py.allow_threads(|| compute_stat(&y));
+    let perm_stats: Vec<f64> = run_permutations(py, times, &None, |i| {
+        let mut rng = perm_rng(seed, i);
+        let mut shuffle_y = y.to_owned();
+        shuffle_y.shuffle(&mut rng);
+        compute_stat(&shuffle_y)
+    })?;
+
+    let m = mean_f(&perm_stats);
+    let sd = std_f(&perm_stats);
+    // the pearson correlation of a constant y is NaN, so are all the permutations
+    let z = if sd.is_nan() {
+        let msg = "Every permutation gives a NaN statistic, the z-score is undefined.".to_string();
+        undefined_zscore(py, msg, on_zero_variance)?
+    } else {
+        bootstrap_zscore(py, real, m, sd, on_zero_variance)?
+    };
+    Ok((z, empirical_pvalue(real, &perm_stats, m)))
+}
+
+/// moran_i(values, neighbors, weights=None, permutations=0, seed=None, standardize=True, ignore_nan=False)
+/// --
+///
+/// Global Moran's I of a continuous marker over the neighbor graph
+///
+/// The self-loops are not counted. The z-score is from the expectation -1 / (n - 1) and
+/// the variance under the randomization assumption.
+///
+/// Args:
+///     values: List[float]; The value of each cell
+///     neighbors: List[List[int]] or Dict[int, List[int]]; The neighbors, or a tuple in CSR or COO layout,
+///                or the weighted neighbors as list of (index, weight)
+///     weights: List[List[float]] (None); The weight of each neighbor, in the same shape as `neighbors`,
+///              if None, the weights are from the weighted neighbors or all 1
+///     permutations: int (0); If larger than 0, the values are shuffled for this many times
+///                   to get the empirical p-value
+///     seed: int (None); The random seed of the permutations
+///     standardize: bool (True); Whether to row-standardize the weights so that the weights of
+///                  each cell sum to 1
+///     ignore_nan: bool (False); NaN values raise ValueError by default, if True, the cells with NaN are excluded
+///
+/// Return:
+///     A dict of I, expected, variance, zscore and pvalue (two-sided from the z-score). If permutations
+///     is larger than 0, perm_pvalue is the two-sided empirical p-value like `expression_bootstrap`.
+///
+#[pyfunction]
+#[allow(clippy::too_many_arguments)]
+pub fn moran_i(
+    py: Python,
+    values: Vec<f64>,
+    neighbors: PyObject,
+    weights: Option<Vec<Vec<f64>>>,
+    permutations: Option<usize>,
+    seed: Option<u64>,
+    standardize: Option<bool>,
+    ignore_nan: Option<bool>,
+) -> PyResult<PyObject> {
+    let data = autocorrelation_edges(py, values, &neighbors, weights, standardize, ignore_nan)?;
+    autocorrelation_result(
+        py,
+        "I",
+        &data,
+        moran_stat,
+        moran_moments,
+        permutations,
+        seed,
+    )
+}
+
+/// geary_c(values, neighbors, weights=None, permutations=0, seed=None, standardize=True, ignore_nan=False)
+/// --
+///
+/// Geary's C of a continuous marker over the neighbor graph
+///
+/// The inputs are the same as `moran_i`. C is around 1 without spatial autocorrelation, below 1 for
+/// the positive autocorrelation (where Moran's I is positive) and above 1 for the negative one.
+/// The z-score is from the expectation 1 and the variance under the randomization assumption.
+///
+/// Args:
+///     values: List[float]; The value of each cell
+///     neighbors: List[List[int]] or Dict[int, List[int]]; The same as `moran_i`
+///     weights: List[List[float]] (None); The same as `moran_i`
+///     permutations: int (0); If larger than 0, the values are shuffled for this many times
+///                   to get the empirical p-value
+///     seed: int (None); The random seed of the permutations
+///     standardize: bool (True); Whether to row-standardize the weights
+///     ignore_nan: bool (False); NaN values raise ValueError by default, if True, the cells with NaN are excluded
+///
+/// Return:
+///     A dict of C, expected, variance, zscore and pvalue, with perm_pvalue if permutations is larger than 0
+///
+#[pyfunction]
+#[allow(clippy::too_many_arguments)]
+pub fn geary_c(
+    py: Python,
+    values: Vec<f64>,
+    neighbors: PyObject,
+    weights: Option<Vec<Vec<f64>>>,
+    permutations: Option<usize>,
+    seed: Option<u64>,
+    standardize: Option<bool>,
+    ignore_nan: Option<bool>,
+) -> PyResult<PyObject> {
+    let data = autocorrelation_edges(py, values, &neighbors, weights, standardize, ignore_nan)?;
+    autocorrelation_result(
+        py,
+        "C",
+        &data,
+        geary_stat,
+        geary_moments,
+        permutations,
+        seed,
+    )
+}
+
+/// local_moran(values, neighbors, permutations=999, seed=None, weights=None, standardize=True)
+/// --
+///
+/// Local Moran's I (LISA) of each cell
+///
+/// I_i = z_i * sum_j(w_ij * z_j) / m2, where z is the deviation from the mean and m2 = sum(z^2) / n.
+/// The p-value is from the conditional permutation, the neighbors of cell i are drawn from all
+/// the other cells while the value of cell i is fixed, the cells run in parallel.
+///
+/// Args:
+///     values: List[float]; The value of each cell, NaN is not allowed
+///     neighbors: List[List[int]] or Dict[int, List[int]]; The same as `moran_i`
+///     permutations: int (999); How many times to draw the neighbors of each cell
+///     seed: int (None); The random seed, cell i uses seed + i
+///     weights: List[List[float]] (None); The same as `moran_i`
+///     standardize: bool (True); Whether to row-standardize the weights
+///
+/// Return:
+///     A dict of numpy arrays aligned with the cells, I is the local Moran's I, pvalue is the one-sided
+///     p-value of the side that I is more extreme, (number of permutations at least as extreme + 1) /
+///     (permutations + 1), and quadrant is a list of 'HH', 'LL', 'HL' or 'LH' of the cell value
+///     and the weighted mean of its neighbors against the mean. Cells without neighbors are NaN and None.
+///
+#[pyfunction]
+pub fn local_moran(
+    py: Python,
+    values: Vec<f64>,
+    neighbors: PyObject,
+    permutations: Option<usize>,
+    seed: Option<u64>,
+    weights: Option<Vec<Vec<f64>>>,
+    standardize: Option<bool>,
+) -> PyResult<PyObject> {
+    let permutations = match permutations {
+        Some(data) => data,
+        None => 999,
+    };
+    let (values, edges) =
+        autocorrelation_edges(py, values, &neighbors, weights, standardize, None)?;
+
+    let n = values.len();
+    let m = mean_f(&values);
+    let z: Vec<f64> = values.iter().map(|v| v - m).collect();
+    let m2 = z.iter().map(|v| v * v).sum::<f64>() / n as f64;
+    let mut cell_weights: Vec<Vec<(usize, f64)>> = vec![vec![]; n];
+    for (i, j, w) in edges {
+        cell_weights[i].push((j, w));
+    }
+
+    // the cells run in blocks like the permutations, so the signals are checked between them
+    let cells: Vec<(f64, f64, Option<&str>)> = run_permutations(py, n, &None, |i| {
+        let neighs = &cell_weights[i];
+        if neighs.is_empty() || n < 2 {
+            return (f64::NAN, f64::NAN, None);
+        }
+        let lag: f64 = neighs.iter().map(|(j, w)| w * z[*j]).sum();
+        let real = z[i] * lag / m2;
+        let mut stats = PermStats::new(real);
+        let mut rng = perm_rng(seed, i);
+        let k = neighs.len().min(n - 1);
+        for _ in 0..permutations {
+            // the other cells are 0..n - 1 with i skipped
+            let drawn = rand::seq::index::sample(&mut rng, n - 1, k);
+            let sim_lag: f64 = drawn
+                .iter()
+                .zip(neighs.iter())
+                .map(|(j, (_, w))| w * z[if j < i { j } else { j + 1 }])
+                .sum();
+            stats.push(z[i] * sim_lag / m2);
+        }
+        let pvalue = if permutations > 0 {
+            stats.directional_pvalue().0
+        } else {
+            f64::NAN
+        };
+        let quadrant = match (z[i] > 0.0, lag > 0.0) {
+            (true, true) => "HH",
+            (false, false) => "LL",
+            (true, false) => "HL",
+            (false, true) => "LH",
+        };
+        (real, pvalue, Some(quadrant))
+    })?;
+
+    let result = PyDict::new(py);
+    let local_i: Vec<f64> = cells.iter().map(|c| c.0).collect();
+    let pvalues: Vec<f64> = cells.iter().map(|c| c.1).collect();
+    let quadrants: Vec<Option<&str>> = cells.iter().map(|c| c.2).collect();
+    result.set_item("I", local_i.into_pyarray(py))?;
+    result.set_item("pvalue", pvalues.into_pyarray(py))?;
+    result.set_item("quadrant", quadrants)?;
+    Ok(result.to_object(py))
+}
+
+/// getis_ord(values, neighbors, star=True, weights=None, adjust=None)
+/// --
+///
+/// Getis-Ord Gi or Gi* of each cell, the hot spots and cold spots
+///
+/// The z-score of the weighted sum of the neighbor values against all the cells, the global sums are
+/// computed once and the cells run in parallel. Gi* counts the cell itself with weight 1, Gi excludes
+/// the cell from both the sum and the global mean and variance.
+///
+/// Args:
+///     values: List[float]; The value of each cell, NaN is not allowed
+///     neighbors: List[List[int]] or Dict[int, List[int]]; The same as `moran_i`
+///     star: bool (True); Whether to compute Gi* or Gi
+///     weights: List[List[float]] (None); The same as `moran_i`, not row-standardized
+///     adjust: str (None); The multiple testing correction across the cells, 'fdr_bh' or 'bonferroni'
+///
+/// Return:
+///     A dict of numpy arrays aligned with the cells, zscore is positive for the hot spots and negative
+///     for the cold spots, pvalue is two-sided, pvalue_adj is added if adjust is given. The cells without
+///     neighbors are NaN for Gi.
+///
+#[pyfunction]
+pub fn getis_ord(
+    py: Python,
+    values: Vec<f64>,
+    neighbors: PyObject,
+    star: Option<bool>,
+    weights: Option<Vec<Vec<f64>>>,
+    adjust: Option<&str>,
+) -> PyResult<PyObject> {
+    let star = match star {
+        Some(data) => data,
+        None => true,
+    };
+    if let Some(a) = adjust {
+        if a != "fdr_bh" && a != "bonferroni" {
+            return Err(PyValueError::new_err(format!(
+                "`adjust` should be 'fdr_bh' or 'bonferroni', got '{}'.",
+                a
+            )));
+        }
+    }
+    let (values, edges) =
+        autocorrelation_edges(py, values, &neighbors, weights, Some(false), None)?;
+
+    let n = values.len();
+    let mut cell_weights: Vec<Vec<(usize, f64)>> = vec![vec![]; n];
+    for (i, j, w) in edges {
+        cell_weights[i].push((j, w));
+    }
+    let zscores: Vec<f64> = py.allow_threads(|| {
+        let sum: f64 = values.iter().sum();
+        let sum_sq: f64 = values.iter().map(|v| v * v).sum();
+        cell_weights
+            .par_iter()
+            .enumerate()
+            .map(|(i, neighs)| {
+                let mut local: f64 = neighs.iter().map(|(j, w)| w * values[*j]).sum();
+                let mut w_sum: f64 = neighs.iter().map(|(_, w)| w).sum();
+                let mut w_sq: f64 = neighs.iter().map(|(_, w)| w * w).sum();
+                // Gi leaves the cell out of the global sums
+                let (m, total, total_sq) = if star {
+                    local += values[i];
+                    w_sum += 1.0;
+                    w_sq += 1.0;
+                    (n as f64, sum, sum_sq)
+                } else {
+                    let x = values[i];
+                    (n as f64 - 1.0, sum - x, sum_sq - x * x)
+                };
+                let mean = total / m;
+                let sd = (total_sq / m - mean * mean).sqrt();
+                let var = (m * w_sq - w_sum * w_sum) / (m - 1.0);
+                (local - w_sum * mean) / (sd * var.sqrt())
+            })
+            .collect()
+    });
+
+    let pvalues: Vec<f64> = zscores.iter().map(|z| normal_pvalue(*z)).collect();
+    let result = PyDict::new(py);
+    if let Some(a) = adjust {
+        // the NaN cells are not tested
+        let tested: Vec<usize> = (0..n).filter(|i| !pvalues[*i].is_nan()).collect();
+        let tested_pvalues: Vec<f64> = tested.iter().map(|i| pvalues[*i]).collect();
+        let mut adjusted = vec![f64::NAN; n];
+        for (i, p) in tested.iter().zip(utils::adjust_pvalues(&tested_pvalues, a)) {
+            adjusted[*i] = p;
+        }
+        result.set_item("pvalue_adj", adjusted.into_pyarray(py))?;
+    }
+    result.set_item("zscore", zscores.into_pyarray(py))?;
+    result.set_item("pvalue", pvalues.into_pyarray(py))?;
+    Ok(result.to_object(py))
+}
+
+/// join_counts(types, neighbors, permutations=999, seed=None, on_zero_variance="warn")
+/// --
+///
+/// Join count statistics of the cell types
+///
+/// The number of the undirected edges between each pair of types, the same type (AA, BB, ...)
+/// and the cross types (AB, ...). A directed graph is symmetrized first, j is a neighbor of i
+/// or i is a neighbor of j makes one edge, the self-loops are not counted.
+/// The null distribution is from shuffling the types.
+///
+/// Args:
+///     types: List[str] or List[int]; The type of each cell
+///     neighbors: List[List[int]] or Dict[int, List[int]]; The neighbors of each cell
+///     permutations: int (999); How many times to shuffle the types
+///     seed: int (None); The random seed, the result is identical across runs with the same seed
+///     on_zero_variance: str ("warn"); When all the permutations of a pair give the same count (eg. a type
+///                       without edges), the z-score is undefined,
+///                       "raise" raises ValueError, "nan" returns NaN, "warn" returns NaN with a warning
+///
+/// Return:
+///     A dict keyed by the pair of types (a, b) in sorted order, the value is a dict of observed,
+///     mean and std of the permutations, zscore and pvalue, the one-sided p-value of the side
+///     that the observed count is more extreme
+///
+#[pyfunction]
+pub fn join_counts(
+    py: Python,
+    types: PyObject,
+    neighbors: PyObject,
+    permutations: Option<usize>,
+    seed: Option<u64>,
+    on_zero_variance: Option<&str>,
+) -> PyResult<PyObject> {
+    let permutations = match permutations {
+        Some(data) => data,
+        None => 999,
+    };
+    if permutations == 0 {
+        return Err(PyValueError::new_err(
+            "`permutations` should be at least 1.",
+        ));
+    }
+    let on_zero_variance = extract_on_zero_variance(on_zero_variance)?;
+    let (names, codes) = extract_types(py, &types, &None)?;
+    let neighbors = extract_neighbors(py, &neighbors)?;
+    check_neighbors_range(&neighbors, codes.len())?;
+
+    // the types are renamed to the sorted order, so the pairs are (a, b) with a <= b
+    let mut order: Vec<usize> = (0..names.len()).collect();
+    order.sort_by(|a, b| names[*a].cmp(&names[*b]));
+    let mut rank = vec![0; names.len()];
+    for (r, i) in order.iter().enumerate() {
+        rank[*i] = r;
+    }
+    let types: Vec<usize> = codes.iter().map(|c| rank[*c]).collect();
+    let names: Vec<String> = order.iter().map(|i| names[*i].to_owned()).collect();
+    let k = names.len();
+
+    let (sources, targets) = neighbors_to_edges(&neighbors);
+    let real = join_count_stat(&types, &sources, &targets, k);
+    let mut stats: Vec<PermStats> = real.iter().map(|c| PermStats::new(*c as f64)).collect();
+    stream_permutations(
+        py,
+        permutations,
+        &None,
+        |i| {
+            let mut rng = perm_rng(seed, i);
+            let mut shuffle_types = types.to_owned();
+            shuffle_types.shuffle(&mut rng);
+            join_count_stat(&shuffle_types, &sources, &targets, k)
+        },
+        |counts| {
+            for (s, c) in stats.iter_mut().zip(counts) {
+                s.push(c as f64);
+            }
+        },
+    )?;
+
+    let result = PyDict::new(py);
+    for (a, b) in (0..k).flat_map(|a| (a..k).map(move |b| (a, b))) {
+        let s = &stats[a * k + b];
+        let sd = s.std();
+        let pair = (names[a].as_str(), names[b].as_str());
+        let z = pair_zscore(py, pair, s.real, s.mean, sd, on_zero_variance)?;
+        let record = PyDict::new(py);
+        record.set_item("observed", real[a * k + b])?;
+        record.set_item("mean", s.mean)?;
+        record.set_item("std", sd)?;
+        record.set_item("zscore", z)?;
+        record.set_item("pvalue", s.directional_pvalue().0)?;
+        result.set_item(pair, record)?;
+    }
+    Ok(result.to_object(py))
+}
+
+/// assortativity(types, neighbors, permutations=999, seed=None, on_zero_variance="warn")
+/// --
+///
+/// Newman's assortativity coefficient of the cell types over the neighbors
+///
+/// r = (sum_i e_ii - sum_i a_i^2) / (1 - sum_i a_i^2), e_ij is the fraction of the edge ends between
+/// type i and j and a_i is the fraction of the edge ends of type i. The graph is undirected as
+/// `join_counts`, the self-loops are not counted. r is 1 if the neighbors are always the same type,
+/// and negative if the neighbors prefer the other types.
+///
+/// Args:
+///     types: List[str] or List[int]; The type of each cell
+///     neighbors: List[List[int]] or Dict[int, List[int]]; The neighbors of each cell
+///     permutations: int (999); How many times to shuffle the types, 0 to skip the test
+///     seed: int (None); The random seed, the result is identical across runs with the same seed
+///     on_zero_variance: str ("warn"); When all the permutations give the same r, or r is NaN (eg. a single
+///                       type in the edges), the z-score is undefined,
+///                       "raise" raises ValueError, "nan" returns NaN, "warn" returns NaN with a warning
+///
+/// Return:
+///     A dict of r, and the mean and std of the permutations, zscore and the two-sided pvalue
+///     if permutations > 0
+///
+#[pyfunction]
+pub fn assortativity(
+    py: Python,
+    types: PyObject,
+    neighbors: PyObject,
+    permutations: Option<usize>,
+    seed: Option<u64>,
+    on_zero_variance: Option<&str>,
+) -> PyResult<PyObject> {
+    let permutations = match permutations {
+        Some(data) => data,
+        None => 999,
+    };
+    let on_zero_variance = extract_on_zero_variance(on_zero_variance)?;
+    let (names, types) = extract_types(py, &types, &None)?;
+    let neighbors = extract_neighbors(py, &neighbors)?;
+    check_neighbors_range(&neighbors, types.len())?;
+    let k = names.len();
 
-    let y: Vec<bool> = match y_status.extract(py) {
-        Ok(data) => data,
-        Err(_) => {
-            return Err(PyTypeError::new_err(
-                "Can't resolve `y_status`, should be list of bool.",
-            ));
-        }
+    let (sources, targets) = neighbors_to_edges(&neighbors);
+    if sources.is_empty() {
+        return Err(PyValueError::new_err(
+            "No edges are found between different cells.",
+        ));
+    }
+    let real = assortativity_stat(&join_count_stat(&types, &sources, &targets, k), k);
+    let result = PyDict::new(py);
+    result.set_item("r", real)?;
+    if permutations > 0 {
+        let perm_values: Vec<f64> = run_permutations(py, permutations, &None, |i| {
+            let mut rng = perm_rng(seed, i);
+            let mut shuffle_types = types.to_owned();
+            shuffle_types.shuffle(&mut rng);
+            assortativity_stat(&join_count_stat(&shuffle_types, &sources, &targets, k), k)
+        })?;
+        let m = mean_f(&perm_values);
+        let sd = std_f(&perm_values);
+        // r is NaN when the edges have a single type, in the data or in any permutation
+        let z = if real.is_nan() || sd.is_nan() {
+            let msg = "The assortativity is NaN, the z-score is undefined.".to_string();
+            undefined_zscore(py, msg, on_zero_variance)?
+        } else {
+            bootstrap_zscore(py, real, m, sd, on_zero_variance)?
+        };
+        result.set_item("mean", m)?;
+        result.set_item("std", sd)?;
+        result.set_item("zscore", z)?;
+        result.set_item("pvalue", empirical_pvalue(real, &perm_values, m))?;
+    }
+    Ok(result.to_object(py))
+}
+
+/// mixing_score(types, neighbors, type_a, type_b, normalize="b", permutations=0, seed=None,
+///              on_zero_variance="warn")
+/// --
+///
+/// The mixing score of two cell types (Keren et al. 2018)
+///
+/// The number of A-B edges divided by the number of B-B edges, eg. A is the tumor cells and B is
+/// the immune cells. The graph is undirected as `join_counts`, the self-loops are not counted.
+///
+/// Args:
+///     types: List[str] or List[int]; The type of each cell
+///     neighbors: List[List[int]] or Dict[int, List[int]]; The neighbors of each cell
+///     type_a: str; The type A
+///     type_b: str; The type B
+///     normalize: str ("b"); The denominator, "b" for the B-B edges, "a" for the A-A edges,
+///                "both" for the A-A and the B-B edges
+///     permutations: int (0); How many times to shuffle the types for the z-score
+///     seed: int (None); The random seed, the result is identical across runs with the same seed
+///     on_zero_variance: str ("warn"); When the denominator is 0, the score is undefined, and when no
+///                       permutation is left or all of them give the same score, the z-score is undefined,
+///                       "raise" raises ValueError, "nan" returns NaN, "warn" returns NaN with a warning
+///
+/// Return:
+///     The mixing score. If permutations > 0, a tuple of (score, zscore) against the shuffled types,
+///     the permutations with a zero denominator are skipped
+///
+#[pyfunction]
+#[allow(clippy::too_many_arguments)]
+pub fn mixing_score(
+    py: Python,
+    types: PyObject,
+    neighbors: PyObject,
+    type_a: &str,
+    type_b: &str,
+    normalize: Option<&str>,
+    permutations: Option<usize>,
+    seed: Option<u64>,
+    on_zero_variance: Option<&str>,
+) -> PyResult<PyObject> {
+    let normalize = match normalize {
+        Some(data) => data,
+        None => "b",
     };
+    if !["a", "b", "both"].contains(&normalize) {
+        return Err(PyValueError::new_err(format!(
+            "`normalize` should be 'a', 'b' or 'both', got '{}'.",
+            normalize
+        )));
+    }
+    let on_zero_variance = extract_on_zero_variance(on_zero_variance)?;
+    let (edges, a, b) = typed_edges(py, &types, &neighbors, type_a, type_b)?;
+    let k = edges.n_types;
+    edge_ratio_result(py, &edges, permutations, seed, on_zero_variance, |counts| {
+        let count = |x: usize, y: usize| counts[x.min(y) * k + x.max(y)] as f64;
+        let denominator = match normalize {
+            "a" => count(a, a),
+            "b" => count(b, b),
+            _ => count(a, a) + count(b, b),
+        };
+        (count(a, b), denominator)
+    })
+}
 
-    let neighbors_data: Vec<Vec<usize>> = match neighbors.extract(py) {
-        Ok(data) => data,
-        Err(_) => {
-            return Err(PyTypeError::new_err(
-                "Can't resolve `neighbors`, should be a dict.",
-            ));
+/// infiltration_score(types, neighbors, type_a, type_b, permutations=0, seed=None, on_zero_variance="warn")
+/// --
+///
+/// The infiltration score of type B into type A
+///
+/// The number of A-B edges divided by the number of all the edges of A, eg. A is the tumor cells
+/// and B is the immune cells. The graph is undirected as `join_counts`, the self-loops are not counted.
+///
+/// Args:
+///     types: List[str] or List[int]; The type of each cell
+///     neighbors: List[List[int]] or Dict[int, List[int]]; The neighbors of each cell
+///     type_a: str; The type A
+///     type_b: str; The type B
+///     permutations: int (0); How many times to shuffle the types for the z-score
+///     seed: int (None); The random seed, the result is identical across runs with the same seed
+///     on_zero_variance: str ("warn"); The same as `mixing_score`, the score is undefined if A has no edges
+///
+/// Return:
+///     The infiltration score. If permutations > 0, a tuple of (score, zscore), the same as `mixing_score`
+///
+#[pyfunction]
+#[allow(clippy::too_many_arguments)]
+pub fn infiltration_score(
+    py: Python,
+    types: PyObject,
+    neighbors: PyObject,
+    type_a: &str,
+    type_b: &str,
+    permutations: Option<usize>,
+    seed: Option<u64>,
+    on_zero_variance: Option<&str>,
+) -> PyResult<PyObject> {
+    let on_zero_variance = extract_on_zero_variance(on_zero_variance)?;
+    let (edges, a, b) = typed_edges(py, &types, &neighbors, type_a, type_b)?;
+    let k = edges.n_types;
+    edge_ratio_result(py, &edges, permutations, seed, on_zero_variance, |counts| {
+        let count = |x: usize, y: usize| counts[x.min(y) * k + x.max(y)] as f64;
+        (count(a, b), (0..k).map(|x| count(a, x)).sum::<f64>())
+    })
+}
+
+// the type of each cell and the undirected edges without self-loops, for the edge counts between types
+struct TypedEdges {
+    types: Vec<usize>,
+    n_types: usize,
+    sources: Vec<usize>,
+    targets: Vec<usize>,
+}
+
+// the typed edges and the index of type_a and type_b
+fn typed_edges(
+    py: Python,
+    types: &PyObject,
+    neighbors: &PyObject,
+    type_a: &str,
+    type_b: &str,
+) -> PyResult<(TypedEdges, usize, usize)> {
+    let (names, types) = extract_types(py, types, &None)?;
+    let neighbors = extract_neighbors(py, neighbors)?;
+    check_neighbors_range(&neighbors, types.len())?;
+    let mut index = vec![];
+    for (arg, t) in [("type_a", type_a), ("type_b", type_b)].iter() {
+        match names.iter().position(|name| name == t) {
+            Some(i) => index.push(i),
+            None => {
+                return Err(PyValueError::new_err(format!(
+                    "`{}` '{}' is not found in types.",
+                    arg, t
+                )));
+            }
         }
+    }
+    let (sources, targets) = neighbors_to_edges(&neighbors);
+    let edges = TypedEdges {
+        types,
+        n_types: names.len(),
+        sources,
+        targets,
     };
+    Ok((edges, index[0], index[1]))
+}
 
-    let times = match times {
+// the score is numerator / denominator of the join counts, with the z-score against the shuffled types
+fn edge_ratio_result<F>(
+    py: Python,
+    edges: &TypedEdges,
+    permutations: Option<usize>,
+    seed: Option<u64>,
+    on_zero_variance: &str,
+    ratio: F,
+) -> PyResult<PyObject>
+where
+    F: Fn(&Vec<usize>) -> (f64, f64) + Sync,
+{
+    let permutations = match permutations {
         Some(data) => data,
-        None => 500,
+        None => 0,
+    };
+    let score = |types: &Vec<usize>| {
+        let counts = join_count_stat(types, &edges.sources, &edges.targets, edges.n_types);
+        let (n, d) = ratio(&counts);
+        if d == 0.0 {
+            f64::NAN
+        } else {
+            n / d
+        }
     };
 
-    let ignore_self = match ignore_self {
+    let mut real = score(&edges.types);
+    if real.is_nan() {
+        let msg = "The denominator of the score is 0, the score is undefined.".to_string();
+        real = undefined_zscore(py, msg, on_zero_variance)?;
+    }
+    if permutations == 0 {
+        return Ok(real.to_object(py));
+    }
+    let perm_values: Vec<f64> = run_permutations(py, permutations, &None, |i| {
+        let mut rng = perm_rng(seed, i);
+        let mut shuffle_types = edges.types.to_owned();
+        shuffle_types.shuffle(&mut rng);
+        score(&shuffle_types)
+    })?;
+    let perm_values: Vec<f64> = perm_values.into_iter().filter(|v| !v.is_nan()).collect();
+    let z = if perm_values.is_empty() {
+        let msg =
+            "The denominator is 0 in all the permutations, the z-score is undefined.".to_string();
+        undefined_zscore(py, msg, on_zero_variance)?
+    } else {
+        let m = mean_f(&perm_values);
+        bootstrap_zscore(py, real, m, std_f(&perm_values), on_zero_variance)?
+    };
+    Ok((real, z).to_object(py))
+}
+
+type WeightedEdge = (usize, usize, f64);
+type WeightedEdges = Vec<WeightedEdge>;
+
+// the values and the weighted edges (i, j, w_ij) of the spatial autocorrelation, the self-loops
+// are not counted and the cells with NaN are excluded if ignore_nan
+fn autocorrelation_edges(
+    py: Python,
+    values: Vec<f64>,
+    neighbors: &PyObject,
+    weights: Option<Vec<Vec<f64>>>,
+    standardize: Option<bool>,
+    ignore_nan: Option<bool>,
+) -> PyResult<(Vec<f64>, WeightedEdges)> {
+    let (neighbors_data, weights_data) = extract_weighted_neighbors(py, neighbors, weights)?;
+    check_neighbors_range(&neighbors_data, values.len())?;
+
+    let standardize = match standardize {
+        Some(data) => data,
+        None => true,
+    };
+    let ignore_nan = match ignore_nan {
         Some(data) => data,
         None => false,
     };
-    let neighbors = utils::remove_rep_neighbors(neighbors_data, ignore_self);
-    let real: f64 = comb_count_neighbors(&x, &y, &neighbors) as f64;
-
-    let perm_counts: Vec<usize> = (0..times)
-        .into_par_iter()
-        .map(|_| {
-            let mut rng = thread_rng();
-            let mut shuffle_y = y.to_owned();
-            shuffle_y.shuffle(&mut rng);
-            let perm_result = comb_count_neighbors(&x, &shuffle_y, &neighbors);
-            perm_result
-        })
-        .collect();
 
-    let m = mean(&perm_counts);
-    let sd = std(&perm_counts);
+    let keep: Vec<bool> = values.iter().map(|v| !v.is_nan()).collect();
+    if !ignore_nan && keep.iter().any(|k| !*k) {
+        let nan_cells: Vec<usize> = (0..keep.len()).filter(|i| !keep[*i]).collect();
+        return Err(PyValueError::new_err(format!(
+            "Found NaN values at cells {:?}, set `ignore_nan=True` to exclude them.",
+            nan_cells
+        )));
+    }
+    // the index of the kept cells after the NaN cells are excluded
+    let mut new_index = vec![0; keep.len()];
+    let mut count = 0;
+    for (i, k) in keep.iter().enumerate() {
+        new_index[i] = count;
+        if *k {
+            count += 1;
+        }
+    }
+    let values: Vec<f64> = values.into_iter().filter(|v| !v.is_nan()).collect();
+
+    let mut edges: WeightedEdges = vec![];
+    let mut row_sums = vec![0.0; values.len()];
+    for (i, neighs) in neighbors_data.iter().enumerate() {
+        for (k, j) in neighs.iter().enumerate() {
+            if *j != i && keep[i] && keep[*j] {
+                let w = match &weights_data {
+                    Some(w) => w[i][k],
+                    None => 1.0,
+                };
+                edges.push((new_index[i], new_index[*j], w));
+                row_sums[new_index[i]] += w;
+            }
+        }
+    }
+    if standardize {
+        edges
+            .iter_mut()
+            .filter(|e| row_sums[e.0] > 0.0)
+            .for_each(|e| e.2 /= row_sums[e.0]);
+    }
+    if edges.iter().map(|e| e.2).sum::<f64>() == 0.0 {
+        return Err(PyValueError::new_err(
+            "The sum of the weights is 0, no neighbors are found.",
+        ));
+    }
+    Ok((values, edges))
+}
+
+// the statistic with the z-score from its moments, and the empirical p-value if permutations > 0
+fn autocorrelation_result(
+    py: Python,
+    name: &str,
+    data: &(Vec<f64>, WeightedEdges),
+    stat: fn(&[f64], &[WeightedEdge]) -> f64,
+    moments: fn(&[f64], &[WeightedEdge]) -> (f64, f64),
+    permutations: Option<usize>,
+    seed: Option<u64>,
+) -> PyResult<PyObject> {
+    let (values, edges) = data;
+    let permutations = match permutations {
+        Some(data) => data,
+        None => 0,
+    };
+
+    let (real, expected, variance) = py.allow_threads(|| {
+        let (expected, variance) = moments(values, edges);
+        (stat(values, edges), expected, variance)
+    });
+    let z = (real - expected) / variance.sqrt();
+    let result = PyDict::new(py);
+    result.set_item(name, real)?;
+    result.set_item("expected", expected)?;
+    result.set_item("variance", variance)?;
+    result.set_item("zscore", z)?;
+    result.set_item("pvalue", normal_pvalue(z))?;
+    if permutations > 0 {
+        let perm_values: Vec<f64> = run_permutations(py, permutations, &None, |i| {
+            let mut rng = perm_rng(seed, i);
+            let mut shuffle_values = values.to_owned();
+            shuffle_values.shuffle(&mut rng);
+            stat(&shuffle_values, edges)
+        })?;
+        let m = mean_f(&perm_values);
+        result.set_item("perm_pvalue", empirical_pvalue(real, &perm_values, m))?;
+    }
+    Ok(result.to_object(py))
+}
+
+fn extract_group_members(groups: Option<Vec<i64>>, n: usize) -> PyResult<Option<Vec<Vec<usize>>>> {
+    match groups {
+        Some(data) => {
+            check_labels_len("groups", data.len(), n)?;
+            Ok(Some(group_members(&data)))
+        }
+        None => Ok(None),
+    }
+}
+
+// the cells are shuffled together if they are in the same block, and the same group if groups is given
+fn extract_block_members(
+    groups: &Option<Vec<i64>>,
+    blocks: &Option<Vec<i64>>,
+    n: usize,
+) -> PyResult<Option<Vec<Vec<usize>>>> {
+    let blocks = match blocks {
+        Some(data) => data,
+        None => return extract_group_members(groups.to_owned(), n),
+    };
+    check_labels_len("blocks", blocks.len(), n)?;
+    let keys: Vec<(i64, i64)> = match groups {
+        Some(data) => {
+            check_labels_len("groups", data.len(), n)?;
+            data.iter().cloned().zip(blocks.iter().cloned()).collect()
+        }
+        None => blocks.iter().map(|b| (0, *b)).collect(),
+    };
+    Ok(Some(group_members(&keys)))
+}
+
+fn check_labels_len(name: &str, len: usize, n: usize) -> PyResult<()> {
+    if len != n {
+        return Err(PyValueError::new_err(format!(
+            "The length of `{}` ({}) should match the number of cells ({}).",
+            name, len, n
+        )));
+    }
+    Ok(())
+}
+
+// every cell with neighbors and every neighbor should be one of the n cells
+fn check_neighbors_range(neighbors: &[Vec<usize>], n: usize) -> PyResult<()> {
+    if neighbors.len() > n {
+        return Err(PyValueError::new_err(format!(
+            "Cell {} has neighbors, but there are only {} cells.",
+            neighbors.len() - 1,
+            n
+        )));
+    }
+    for (i, neighs) in neighbors.iter().enumerate() {
+        if let Some(j) = neighs.iter().find(|j| **j >= n) {
+            return Err(PyValueError::new_err(format!(
+                "Neighbor {} of cell {} is out of range for {} cells.",
+                j, i, n
+            )));
+        }
+    }
+    Ok(())
+}
+
+// the integer codes of cell types, like the codes of a pandas categorical
+fn extract_type_codes(obj: &PyAny) -> Option<Vec<i64>> {
+    if let Ok(arr) = obj.extract::<PyReadonlyArray1<i16>>() {
+        return Some(arr.as_array().iter().map(|v| *v as i64).collect());
+    }
+    if let Ok(arr) = obj.extract::<PyReadonlyArray1<i32>>() {
+        return Some(arr.as_array().iter().map(|v| *v as i64).collect());
+    }
+    if let Ok(arr) = obj.extract::<PyReadonlyArray1<i64>>() {
+        return Some(arr.as_array().to_vec());
+    }
+    obj.extract::<Vec<i64>>().ok()
+}
 
-    Ok((real - m) / sd)
+// the unique names of cell types in order of first appearance, and the index of each cell in the names
+fn extract_types(
+    py: Python,
+    types: &PyObject,
+    categories: &Option<Vec<String>>,
+) -> PyResult<(Vec<String>, Vec<usize>)> {
+    if let Ok(data) = types.extract::<Vec<&str>>(py) {
+        let mut names: Vec<String> = vec![];
+        let mut index: HashMap<&str, usize> = HashMap::new();
+        let codes = data
+            .into_iter()
+            .map(|t| {
+                *index.entry(t).or_insert_with(|| {
+                    names.push(t.to_string());
+                    names.len() - 1
+                })
+            })
+            .collect();
+        return Ok((names, codes));
+    }
+    let data = match extract_type_codes(types.as_ref(py)) {
+        Some(data) => data,
+        None => {
+            return Err(PyTypeError::new_err(
+                "Can't resolve `types`, should be list of string or int.",
+            ));
+        }
+    };
+    let mut names: Vec<String> = vec![];
+    let mut index: HashMap<i64, usize> = HashMap::new();
+    let mut codes = Vec::with_capacity(data.len());
+    for c in data {
+        if let Entry::Vacant(entry) = index.entry(c) {
+            let name = match categories {
+                Some(cats) => match if c >= 0 { cats.get(c as usize) } else { None } {
+                    Some(name) => name.to_owned(),
+                    None => {
+                        return Err(PyValueError::new_err(format!(
+                            "Code {} is out of range for {} categories.",
+                            c,
+                            cats.len()
+                        )));
+                    }
+                },
+                None => c.to_string(),
+            };
+            entry.insert(names.len());
+            names.push(name);
+        }
+        codes.push(index[&c]);
+    }
+    Ok((names, codes))
 }
 
 /// Constructor function
 ///
 /// Args:
-///     types: List[str]; All the type of cells in your research
-///     order: bool (False); If False, A->B and A<-B is the same
+///     types: List[str]; All the type of cells in your research, or the integer codes of the types
+///            (eg. the codes of a pandas categorical)
+///     order: bool (False); If False, A->B and A<-B is the same. If True, (A, B) only counts the B neighbors
+///            of A cells, which is different from (B, A) when the neighbors or the arrangement is asymmetric
+///     categories: List[str] (None); The name of each integer code, the code is the index in categories.
+///                 If not given, the name of a code is the code itself as string
 ///
 #[pyclass]
 struct CellCombs {
     #[pyo3(get)]
-    cell_types: PyObject,
+    cell_types: Vec<String>,
     #[pyo3(get)]
-    cell_combs: PyObject,
+    cell_combs: Vec<(String, String)>,
     #[pyo3(get)]
     order: bool,
 }
 
-unsafe impl Send for CellCombs {}
-
 #[pymethods]
 impl CellCombs {
     #[new]
-    fn new(py: Python, types: PyObject, order: Option<bool>) -> PyResult<Self> {
-        let types_data: Vec<&str> = match types.extract(py) {
-            Ok(data) => data,
-            Err(_) => {
-                return Err(PyTypeError::new_err(
-                    "Can't resolve `types`, should be list of string.",
-                ));
+    fn new(
+        py: Python,
+        types: PyObject,
+        order: Option<bool>,
+        categories: Option<Vec<String>>,
+    ) -> PyResult<Self> {
+        let (uni, _) = extract_types(py, &types, &categories)?;
+
+        let order_data: bool = match order {
+            Some(data) => data,
+            None => false,
+        };
+
+        Ok(CellCombs {
+            cell_combs: type_combs(&uni, order_data),
+            cell_types: uni,
+            order: order_data,
+        })
+    }
+
+    /// Bootstrap functions
+    ///
+    /// If method is 'pval', 1.0 means association, -1.0 means avoidance, 0.0 means insignificance.
+    /// The p-value is the smaller one of (number of permutations >= observed + 1) / (times + 1) and
+    /// (number of permutations <= observed + 1) / (times + 1), when both are the same it's 0.0.
+    /// If method is 'zscore', results is the exact z-score value.
+    /// If method is 'full', results is a dict of the observed mean count (observed), the number of the center
+    /// cells that are counted (n_centers), the number of cells of both types (n_cells),
+    /// the mean (perm_mean, the expected count) and
+    /// std (perm_std) of the permutations, the z-score (zscore),
+    /// the log2 fold change log2((observed + pseudocount) / (perm_mean + pseudocount)) (log2_fc),
+    /// the two-sided empirical p-value (pvalue),
+    /// (number of permutations at least as extreme + 1) / (times + 1), and the significance (sig) at `pval`,
+    /// which is the same as the 'pval' method.
+    ///
+    /// Args:
+    ///     types: List[str]; The type of all the cells, or the integer codes of the types
+    ///     neighbors: List[List[int]] or Dict[int, List[int]]; eg. {1:[4,5], 2:[6,7]}, cell at index 1 has neighbor cells from index 4 and 5,
+    ///                a tuple of (indptr, indices) in CSR layout or a tuple of (row, col, data, n_points) in COO layout.
+    ///                A cell missing from the dict keys has no neighbors, it's not counted as a center
+    ///                but can still be a neighbor of other cells. All the indices should be < len(types).
+    ///                The neighbors can also be a list (or dict) of list of (index, weight), eg. the distance weights,
+    ///                the weights of the neighbors are summed instead of counting,
+    ///                the weights stay with the edges when the types are shuffled.
+    ///     times: int (500); How many times to perform bootstrap
+    ///     pval: float (0.05); The threshold of p-value
+    ///     method: str ('pval'); 'pval', 'zscore' or 'full'
+    ///     ignore_self: bool (False); Whether to consider self as a neighbor
+    ///     require_symmetric: bool (False); Raise ValueError if the neighbors are not symmetric
+    ///     groups: List[int] (None); The group (eg. sample or ROI) of each cell, if given, the types are only
+    ///             shuffled within each group
+    ///     blocks: List[int] (None); The spatial block of each cell, if given, the types are only shuffled within
+    ///             each block (and group), this keeps the large-scale tissue architecture (eg. epidermis and dermis)
+    ///             in the permutations. A block with a single cell is never changed.
+    ///     progress: Callable[[int, int], None] (None); Called with (done, total) after every 100 permutations,
+    ///               raise an exception (eg. KeyboardInterrupt) in it to abort the run,
+    ///               Ctrl-C also aborts the run between these blocks without a callback
+    ///     seed: int (None); The random seed, the result is identical across runs with the same seed
+    ///     return_type: str ('list'); 'list', 'dict' or 'table'. 'table' returns a `CombsTable` of the statistics
+    ///                  of the 'full' method whatever the method is, which can be saved to JSON or CSV
+    ///     adjust: str (None); The multiple testing correction across all the combinations, 'fdr_bh' or
+    ///             'bonferroni', the adjusted p-values are compared with `pval`. For the 'full' method,
+    ///             the adjusted p-value is added as pvalue_adj. No effect on the 'zscore' method.
+    ///     selected_combs: List[Tuple[str, str]] (None); Only count and test these combinations, the order of
+    ///                     the pair doesn't matter if order is False
+    ///     categories: List[str] (None); The name of each integer code, the same as the constructor
+    ///     on_zero_variance: str ("warn"); When all the permutations of a pair give the same count (eg. a type
+    ///                       in a single cell), the z-score of 'zscore' and 'full' is undefined,
+    ///                       "raise" raises ValueError, "nan" returns NaN, "warn" returns NaN with a warning
+    ///     stat: str ('mean'); The statistic of each pair, the permutations use the same one.
+    ///           'mean' is the mean number of type B neighbors of the type A cells that have any,
+    ///           'sum' is the total number of type B neighbors of the type A cells,
+    ///           'frequency' is the fraction of the type A cells that have at least one type B neighbor.
+    ///           If order is False, both directions are counted together.
+    ///     min_cells: int (None); The pairs that either type has fewer than `min_cells` cells in `types`
+    ///                are not tested, the result is NaN. For the 'full' method, the number of cells of
+    ///                both types is given as n_cells.
+    ///     drop_rare: bool (False); Omit the pairs below `min_cells` instead of NaN
+    ///     pseudocount: float (1.0); Added to both the observed and the expected count of log2_fc,
+    ///                  should be finite and non-negative
+    ///     allow_unknown: bool (False); If False, raise ValueError for the types not in the constructor,
+    ///                    otherwise these cells are skipped as centers, they are still shuffled with
+    ///                    the other cells and can be neighbors but are not counted as any type
+    ///     histogram: int (None); The number of bins, eg. 20, to summarize the permutations of each pair,
+    ///                the bins are from the min to the max like numpy.histogram. The bins are counted as the
+    ///                permutations run, so if there are many distinct values, a value near the edge of a bin
+    ///                may be counted in the neighboring bin
+    ///
+    /// Return:
+    ///     List of tuples sorted by the type names, eg.(('a', 'b'), 1.0), the type a and type b has a relationship
+    ///     as association. If return_type is 'dict', a dict keyed by the type pairs, eg. {('a', 'b'): 1.0}.
+    ///     Every pair in `cell_combs` (or `selected_combs`) is in the result exactly once whatever the method is,
+    ///     the pairs not found in the data have a count of 0, only the rare pairs can be dropped by `drop_rare`.
+    ///     If histogram is set, a tuple of (results, histograms), the histograms are in the same format with
+    ///     a dict of counts, edges (length of bins + 1), min and max of the permutations for each pair,
+    ///     None for the rare pairs.
+    ///
+    #[args(
+        times = "None",
+        pval = "None",
+        method = "None",
+        ignore_self = "None",
+        "*",
+        require_symmetric = "None",
+        groups = "None",
+        blocks = "None",
+        progress = "None",
+        seed = "None",
+        return_type = "None",
+        adjust = "None",
+        selected_combs = "None",
+        categories = "None",
+        on_zero_variance = "None",
+        stat = "None",
+        min_cells = "None",
+        drop_rare = "None",
+        pseudocount = "None",
+        allow_unknown = "None",
+        histogram = "None"
+    )]
+    #[allow(clippy::too_many_arguments)]
+    fn bootstrap(
+        &self,
+        py: Python,
+        types: PyObject,
+        neighbors: PyObject,
+        times: Option<usize>,
+        pval: Option<f64>,
+        method: Option<&str>,
+        ignore_self: Option<bool>,
+        require_symmetric: Option<bool>,
+        groups: Option<Vec<i64>>,
+        blocks: Option<Vec<i64>>,
+        progress: Option<PyObject>,
+        seed: Option<u64>,
+        return_type: Option<&str>,
+        adjust: Option<&str>,
+        selected_combs: Option<Vec<(String, String)>>,
+        categories: Option<Vec<String>>,
+        on_zero_variance: Option<&str>,
+        stat: Option<&str>,
+        min_cells: Option<usize>,
+        drop_rare: Option<bool>,
+        pseudocount: Option<f64>,
+        allow_unknown: Option<bool>,
+        histogram: Option<usize>,
+    ) -> PyResult<PyObject> {
+        let options = CombsOptions::bootstrap(
+            times,
+            pval,
+            method,
+            ignore_self,
+            require_symmetric,
+            groups,
+            blocks,
+            progress,
+            seed,
+            return_type,
+            adjust,
+            selected_combs,
+            categories,
+            on_zero_variance,
+            stat,
+            min_cells,
+            drop_rare,
+            pseudocount,
+            allow_unknown,
+            histogram,
+        )?;
+        self.run_bootstrap(py, &types, &neighbors, &options)
+    }
+
+    /// Bootstrap of multiple ROIs
+    ///
+    /// Each ROI is the same as `bootstrap`, the ROI at index k uses seed + k * times (wrapping around 2^64)
+    /// as the random seed.
+    ///
+    /// Args:
+    ///     types_list: List[List[str]]; The type of all the cells in each ROI
+    ///     neighbors_list: List[Neighbors]; The neighbors of each ROI
+    ///     times: int (500); How many times to perform bootstrap
+    ///     pval: float (0.05); The threshold of p-value
+    ///     method: str ('pval'); 'pval', 'zscore' or 'full'
+    ///     ignore_self: bool (False); Whether to consider self as a neighbor
+    ///     seed: int (None); The random seed
+    ///     return_type: str ('list'); 'list', 'dict' or 'table', the same as `bootstrap`,
+    ///                  the meta z-scores are a list for 'table'
+    ///     adjust: str (None); The multiple testing correction within each ROI
+    ///     selected_combs: List[Tuple[str, str]] (None); Only count and test these combinations
+    ///     categories: List[str] (None); The name of each integer code
+    ///     parallel: bool (True); If True, run the ROIs in parallel, otherwise run the permutations
+    ///               of each ROI in parallel
+    ///     meta: bool (False); Whether to combine the z-scores of ROIs by Stouffer's method,
+    ///           sum(z) / sqrt(k) over the k ROIs that the z-score is defined, NaN if none
+    ///     on_zero_variance: str ("warn"); The same as `bootstrap`, the meta z-score always skips them
+    ///     stat: str ('mean'); 'mean', 'sum' or 'frequency', the same as `bootstrap`
+    ///     pseudocount: float (1.0); The same as `bootstrap`
+    ///
+    /// Return:
+    ///     List of the result of each ROI, if meta is True, a tuple of (results, meta z-scores)
+    ///
+    #[args(
+        times = "None",
+        pval = "None",
+        method = "None",
+        ignore_self = "None",
+        "*",
+        seed = "None",
+        return_type = "None",
+        adjust = "None",
+        selected_combs = "None",
+        categories = "None",
+        parallel = "None",
+        meta = "None",
+        on_zero_variance = "None",
+        stat = "None",
+        pseudocount = "None"
+    )]
+    #[allow(clippy::too_many_arguments)]
+    fn bootstrap_batch(
+        &self,
+        py: Python,
+        types_list: Vec<PyObject>,
+        neighbors_list: Vec<PyObject>,
+        times: Option<usize>,
+        pval: Option<f64>,
+        method: Option<&str>,
+        ignore_self: Option<bool>,
+        seed: Option<u64>,
+        return_type: Option<&str>,
+        adjust: Option<&str>,
+        selected_combs: Option<Vec<(String, String)>>,
+        categories: Option<Vec<String>>,
+        parallel: Option<bool>,
+        meta: Option<bool>,
+        on_zero_variance: Option<&str>,
+        stat: Option<&str>,
+        pseudocount: Option<f64>,
+    ) -> PyResult<PyObject> {
+        if types_list.len() != neighbors_list.len() {
+            return Err(PyValueError::new_err(format!(
+                "The length of `types_list` ({}) and `neighbors_list` ({}) should be the same.",
+                types_list.len(),
+                neighbors_list.len()
+            )));
+        }
+        let options = CombsOptions {
+            pseudocount: extract_pseudocount(pseudocount)?,
+            ..CombsOptions::new(
+                times,
+                pval,
+                method,
+                return_type,
+                adjust,
+                on_zero_variance,
+                stat,
+            )?
+        };
+        let ignore_self = match ignore_self {
+            Some(data) => data,
+            None => false,
+        };
+        let parallel = match parallel {
+            Some(data) => data,
+            None => true,
+        };
+        let meta = match meta {
+            Some(data) => data,
+            None => false,
+        };
+
+        let cellcombs = self.resolve_combs(&selected_combs)?;
+        let order = self.order;
+        let stat = options.stat;
+        let mut rois: Vec<(Vec<usize>, Vec<Vec<usize>>)> = vec![];
+        let mut roi_type_counts: Vec<Vec<usize>> = vec![];
+        for (types, neighbors) in types_list.iter().zip(neighbors_list.iter()) {
+            let types_data = self.resolve_types(py, types, &categories, false)?;
+            let neighbors_data = extract_neighbors(py, neighbors)?;
+            check_neighbors_range(&neighbors_data, types_data.len())?;
+            roi_type_counts.push(count_types(&types_data, self.cell_types.len()));
+            rois.push((types_data, center_neighbors(neighbors_data, ignore_self)));
+        }
+
+        let times = options.times;
+        let keep_values = options.full_stats();
+        let perm = |k: usize, i: usize| {
+            let (types_data, neighbors) = &rois[k];
+            let roi_seed = seed.map(|s| s.wrapping_add((k * times) as u64));
+            let mut rng = perm_rng(roi_seed, i);
+            let mut shuffle_types = types_data.to_owned();
+            shuffle_types.shuffle(&mut rng);
+            count_neighbors(
+                &shuffle_types,
+                neighbors,
+                None,
+                &cellcombs,
+                order,
+                stat,
+                None,
+            )
+        };
+        let mut stats: Vec<_> = py.allow_threads(|| {
+            rois.par_iter()
+                .map(|(types_data, neighbors)| {
+                    let real_data =
+                        count_neighbors(types_data, neighbors, None, &cellcombs, order, stat, None);
+                    let perm_stats = new_perm_stats(&real_data, keep_values);
+                    (real_data, perm_stats)
+                })
+                .collect()
+        });
+        // each block of permutations runs for all the ROIs, the signals are checked between the blocks
+        for start in (0..times).step_by(PROGRESS_BLOCK) {
+            let end = (start + PROGRESS_BLOCK).min(times);
+            let blocks: Vec<Vec<CombsCounts>> = py.allow_threads(|| {
+                if parallel {
+                    (0..rois.len())
+                        .into_par_iter()
+                        .map(|k| (start..end).map(|i| perm(k, i)).collect())
+                        .collect()
+                } else {
+                    (0..rois.len())
+                        .map(|k| (start..end).into_par_iter().map(|i| perm(k, i)).collect())
+                        .collect()
+                }
+            });
+            for ((_, perm_stats), block) in stats.iter_mut().zip(blocks.iter()) {
+                block
+                    .iter()
+                    .for_each(|perm_result| update_perm_stats(perm_stats, perm_result));
             }
+            report_progress(py, &None, end, times)?;
+        }
+
+        let mut results: Vec<PyObject> = vec![];
+        for ((real_data, perm_stats), type_counts) in stats.iter().zip(roi_type_counts.iter()) {
+            results.push(self.combs_result(
+                py,
+                &cellcombs,
+                type_counts,
+                real_data,
+                perm_stats,
+                &options,
+            )?);
+        }
+        if !meta {
+            return Ok(results.to_object(py));
+        }
+
+        let mut meta_results: Vec<((&str, &str), f64)> = cellcombs
+            .iter()
+            .map(|k| {
+                let zscores: Vec<f64> = stats
+                    .iter()
+                    .filter_map(|(_, perm_stats)| {
+                        let st = &perm_stats[k];
+                        let sd = st.std();
+                        if sd != 0.0 {
+                            Some((st.real - st.mean) / sd)
+                        } else {
+                            None
+                        }
+                    })
+                    .collect();
+                let z = if zscores.is_empty() {
+                    f64::NAN
+                } else {
+                    zscores.iter().sum::<f64>() / (zscores.len() as f64).sqrt()
+                };
+                (
+                    (self.cell_types[k.0].as_str(), self.cell_types[k.1].as_str()),
+                    z,
+                )
+            })
+            .collect();
+        meta_results.sort_by(|a, b| a.0.cmp(&b.0));
+        let meta_py = combs_to_py(py, meta_results, options.return_type)?;
+        Ok((results, meta_py).to_object(py))
+    }
+
+    /// Bootstrap with a type stratified by a marker
+    ///
+    /// The cells of `focal_type` are split into two types by `marker_status`, named as
+    /// focal_type + '+' + marker and focal_type + '-' + marker, eg. 'Tumor+Ki67' and 'Tumor-Ki67',
+    /// the other types are the same as the constructor. The results are the same as `bootstrap`
+    /// with these types, eg. (('Tumor+Ki67', 'CD8 T'), 1.0).
+    ///
+    /// Args:
+    ///     types: List[str]; The type of all the cells, or the integer codes of the types
+    ///     neighbors: List[List[int]] or Dict[int, List[int]]; The neighbors of each cell
+    ///     marker_status: List[bool]; Whether each cell is positive of the marker
+    ///     focal_type: str; The type to stratify
+    ///     marker: str; The name of the marker
+    ///     The other arguments are the same as `bootstrap`, eg. times, method or seed, and can only be given
+    ///     as keywords, the derived types can be used in selected_combs
+    ///
+    /// Return:
+    ///     The same as `bootstrap`
+    ///
+    #[args(
+        "*",
+        times = "None",
+        pval = "None",
+        method = "None",
+        ignore_self = "None",
+        require_symmetric = "None",
+        groups = "None",
+        blocks = "None",
+        progress = "None",
+        seed = "None",
+        return_type = "None",
+        adjust = "None",
+        selected_combs = "None",
+        categories = "None",
+        on_zero_variance = "None",
+        stat = "None",
+        min_cells = "None",
+        drop_rare = "None",
+        pseudocount = "None",
+        allow_unknown = "None",
+        histogram = "None"
+    )]
+    #[allow(clippy::too_many_arguments)]
+    fn bootstrap_marker(
+        &self,
+        py: Python,
+        types: PyObject,
+        neighbors: PyObject,
+        marker_status: Vec<bool>,
+        focal_type: String,
+        marker: String,
+        times: Option<usize>,
+        pval: Option<f64>,
+        method: Option<&str>,
+        ignore_self: Option<bool>,
+        require_symmetric: Option<bool>,
+        groups: Option<Vec<i64>>,
+        blocks: Option<Vec<i64>>,
+        progress: Option<PyObject>,
+        seed: Option<u64>,
+        return_type: Option<&str>,
+        adjust: Option<&str>,
+        selected_combs: Option<Vec<(String, String)>>,
+        categories: Option<Vec<String>>,
+        on_zero_variance: Option<&str>,
+        stat: Option<&str>,
+        min_cells: Option<usize>,
+        drop_rare: Option<bool>,
+        pseudocount: Option<f64>,
+        allow_unknown: Option<bool>,
+        histogram: Option<usize>,
+    ) -> PyResult<PyObject> {
+        let options = CombsOptions::bootstrap(
+            times,
+            pval,
+            method,
+            ignore_self,
+            require_symmetric,
+            groups,
+            blocks,
+            progress,
+            seed,
+            return_type,
+            adjust,
+            selected_combs,
+            categories,
+            on_zero_variance,
+            stat,
+            min_cells,
+            drop_rare,
+            pseudocount,
+            allow_unknown,
+            histogram,
+        )?;
+        if !self.cell_types.contains(&focal_type) {
+            return Err(PyValueError::new_err(format!(
+                "Unknown cell type in `focal_type`: {}.",
+                focal_type
+            )));
+        }
+        let (names, codes) = extract_types(py, &types, &options.categories)?;
+        if marker_status.len() != codes.len() {
+            return Err(PyValueError::new_err(format!(
+                "The length of `marker_status` ({}) should match the number of cells ({}).",
+                marker_status.len(),
+                codes.len()
+            )));
+        }
+
+        let positive = format!("{}+{}", focal_type, marker);
+        let negative = format!("{}-{}", focal_type, marker);
+        let labels: Vec<String> = codes
+            .iter()
+            .zip(marker_status.iter())
+            .map(|(c, status)| match (names[*c] == focal_type, status) {
+                (true, true) => positive.to_owned(),
+                (true, false) => negative.to_owned(),
+                _ => names[*c].to_owned(),
+            })
+            .collect();
+        // the focal type is replaced in place by the two derived types
+        let cell_types: Vec<String> = self
+            .cell_types
+            .iter()
+            .flat_map(|t| {
+                if *t == focal_type {
+                    vec![positive.to_owned(), negative.to_owned()]
+                } else {
+                    vec![t.to_owned()]
+                }
+            })
+            .collect();
+        let stratified = CellCombs {
+            cell_combs: type_combs(&cell_types, self.order),
+            cell_types,
+            order: self.order,
         };
 
-        let order_data: bool = match order {
+        // the types are names now, the codes are resolved above
+        let options = CombsOptions {
+            categories: None,
+            ..options
+        };
+        stratified.run_bootstrap(py, &labels.to_object(py), &neighbors, &options)
+    }
+
+    /// Per-cell enrichment scores
+    ///
+    /// The z-score of the number of `target_type` neighbors of each cell against the label permutations.
+    /// Cells with no neighbors or with the same count in all the permutations get NaN.
+    ///
+    /// Args:
+    ///     types: List[str]; The type of all the cells, or the integer codes of the types
+    ///     neighbors: List[List[int]] or Dict[int, List[int]]; The neighbors of each cell
+    ///     target_type: str; The type of the neighbors to count
+    ///     times: int (500); How many times to perform bootstrap
+    ///     seed: int (None); The random seed
+    ///     ignore_self: bool (False); Whether to consider self as a neighbor
+    ///     categories: List[str] (None); The name of each integer code, the same as the constructor
+    ///
+    /// Return:
+    ///     An array of the z-score of each cell
+    ///
+    #[args(
+        times = "None",
+        "*",
+        seed = "None",
+        ignore_self = "None",
+        categories = "None"
+    )]
+    #[allow(clippy::too_many_arguments)]
+    fn cell_scores(
+        &self,
+        py: Python,
+        types: PyObject,
+        neighbors: PyObject,
+        target_type: String,
+        times: Option<usize>,
+        seed: Option<u64>,
+        ignore_self: Option<bool>,
+        categories: Option<Vec<String>>,
+    ) -> PyResult<PyObject> {
+        let types_data = self.resolve_types(py, &types, &categories, false)?;
+        let mut neighbors_data: Vec<Vec<usize>> = extract_neighbors(py, &neighbors)?;
+        check_neighbors_range(&neighbors_data, types_data.len())?;
+        // the cells missing in the neighbors have no neighbors, so their scores are NaN
+        neighbors_data.resize(types_data.len(), vec![]);
+        if !self.cell_types.contains(&target_type) {
+            return Err(PyValueError::new_err(format!(
+                "Unknown cell type in `target_type`: {}.",
+                target_type
+            )));
+        }
+        let target = self.type_index(&target_type);
+
+        let times = match times {
+            Some(data) => data,
+            None => 500,
+        };
+        let ignore_self = match ignore_self {
             Some(data) => data,
             None => false,
         };
+        let neighbors = center_neighbors(neighbors_data, ignore_self);
 
-        let uni: Vec<&str> = types_data.into_iter().unique().collect();
-        let mut combs = vec![];
-
-        if order_data {
-            for i1 in uni.to_owned() {
-                for i2 in uni.to_owned() {
-                    combs.push((i1, i2));
+        let n = types_data.len();
+        let real = py.allow_threads(|| cell_target_counts(&types_data, &neighbors, target));
+        // the sum and the sum of squares of each cell, the permutations are not kept
+        let (mut s1, mut s2) = (vec![0.0; n], vec![0.0; n]);
+        stream_permutations(
+            py,
+            times,
+            &None,
+            |i| {
+                let mut rng = perm_rng(seed, i);
+                let mut shuffle_types = types_data.to_owned();
+                shuffle_types.shuffle(&mut rng);
+                cell_target_counts(&shuffle_types, &neighbors, target)
+            },
+            |counts| {
+                for (k, c) in counts.iter().enumerate() {
+                    s1[k] += c;
+                    s2[k] += c * c;
                 }
-            }
-        } else {
-            for (i1, e1) in uni.to_owned().iter().enumerate() {
-                for (i2, e2) in uni.to_owned().iter().enumerate() {
-                    if i2 >= i1 {
-                        combs.push((e1, e2));
-                    }
+            },
+        )?;
+        let scores: Vec<f64> = (0..n)
+            .map(|k| {
+                let m = s1[k] / times as f64;
+                let sd = (s2[k] / times as f64 - m * m).max(0.0).sqrt();
+                if neighbors[k].is_empty() || sd == 0.0 {
+                    f64::NAN
+                } else {
+                    (real[k] - m) / sd
                 }
-            }
-        }
+            })
+            .collect();
 
-        Ok(CellCombs {
-            cell_types: uni.to_object(py),
-            cell_combs: combs.to_object(py),
-            order: order_data,
-        })
+        Ok(scores.into_pyarray(py).to_object(py))
     }
 
-    /// Bootstrap functions
+    /// Confidence intervals of the statistic
     ///
-    /// If method is 'pval', 1.0 means association, -1.0 means avoidance, 0.0 means insignificance.
-    /// If method is 'zscore', results is the exact z-score value.
+    /// The center cells are resampled with replacement and the statistic of each pair is counted again,
+    /// the interval is the quantiles of the resampled statistics. If the center type of a pair
+    /// is missing in any resample, the bounds of the pair are NaN.
     ///
     /// Args:
-    ///     types: List[str]; The type of all the cells
-    ///     neighbors: List[List[int]]; eg. {1:[4,5], 2:[6,7]}, cell at index 1 has neighbor cells from index 4 and 5
-    ///     times: int (500); How many times to perform bootstrap
-    ///     pval: float (0.05); The threshold of p-value
-    ///     method: str ('pval'); 'pval' or 'zscore'
+    ///     types: List[str]; The type of all the cells, or the integer codes of the types
+    ///     neighbors: List[List[int]] or Dict[int, List[int]]; The neighbors of each cell
+    ///     n_boot: int (1000); How many times to resample
+    ///     ci: float (0.95); The confidence level
+    ///     seed: int (None); The random seed
     ///     ignore_self: bool (False); Whether to consider self as a neighbor
+    ///     stat: str ('mean'); 'mean', 'sum' or 'frequency', the same as `bootstrap`
+    ///     return_type: str ('list'); 'list' or 'dict'
+    ///     categories: List[str] (None); The name of each integer code, the same as the constructor
     ///
     /// Return:
-    ///     List of tuples, eg.(('a', 'b'), 1.0), the type a and type b has a relationship as association
+    ///     List of tuples sorted by the type names, eg.(('a', 'b'), (lower, upper)),
+    ///     if return_type is 'dict', a dict keyed by the type pairs
     ///
-    fn bootstrap(
+    #[args(
+        n_boot = "None",
+        ci = "None",
+        "*",
+        seed = "None",
+        ignore_self = "None",
+        stat = "None",
+        return_type = "None",
+        categories = "None"
+    )]
+    #[allow(clippy::too_many_arguments)]
+    fn bootstrap_ci(
         &self,
         py: Python,
         types: PyObject,
         neighbors: PyObject,
-        times: Option<usize>,
-        pval: Option<f64>,
-        method: Option<&str>,
+        n_boot: Option<usize>,
+        ci: Option<f64>,
+        seed: Option<u64>,
         ignore_self: Option<bool>,
+        stat: Option<&str>,
+        return_type: Option<&str>,
+        categories: Option<Vec<String>>,
     ) -> PyResult<PyObject> {
-        let types_data: Vec<&str> = match types.extract(py) {
-            Ok(data) => data,
-            Err(_) => {
-                return Err(PyTypeError::new_err(
-                    "Can't resolve `types`, should be list of string.",
-                ));
-            }
+        let types_data = self.resolve_types(py, &types, &categories, false)?;
+        let neighbors_data: Vec<Vec<usize>> = extract_neighbors(py, &neighbors)?;
+        check_neighbors_range(&neighbors_data, types_data.len())?;
+
+        let n_boot = match n_boot {
+            Some(data) => data,
+            None => 1000,
         };
-        let neighbors_data: Vec<Vec<usize>> = match neighbors.extract(py) {
-            Ok(data) => data,
-            Err(_) => {
-                return Err(PyTypeError::new_err(
-                    "Can't resolve `neighbors`, should be a list.",
-                ));
-            }
+        let ci = match ci {
+            Some(data) => data,
+            None => 0.95,
+        };
+        if !(ci > 0.0 && ci < 1.0) {
+            return Err(PyValueError::new_err(format!(
+                "`ci` should be in (0, 1), got {}.",
+                ci
+            )));
+        }
+        let ignore_self = match ignore_self {
+            Some(data) => data,
+            None => false,
         };
+        let options = CombsOptions::new(None, None, None, return_type, None, None, stat)?;
+        if options.return_type == "table" {
+            return Err(PyValueError::new_err(
+                "`return_type` should be 'list' or 'dict'.",
+            ));
+        }
+
+        let cellcombs = self.resolve_combs(&None)?;
+        let order = self.order;
+        let stat = options.stat;
+        let n_types = self.cell_types.len();
+        let neighbors = center_neighbors(neighbors_data, ignore_self);
+
+        // the statistic is undefined if no center cell of the pair is resampled
+        let resampled: Vec<HashMap<(usize, usize), f64>> =
+            run_permutations(py, n_boot, &None, |i| {
+                let mut rng = perm_rng(seed, i);
+                let (rs_types, rs_neighbors) = resample_centers(&types_data, &neighbors, &mut rng);
+                let rs_centers = &rs_types[types_data.len()..];
+                let centers = count_types(rs_centers, n_types);
+                count_neighbors(
+                    &rs_types,
+                    &rs_neighbors,
+                    None,
+                    &cellcombs,
+                    order,
+                    stat,
+                    Some(rs_centers),
+                )
+                .into_iter()
+                .map(|(k, v)| {
+                    let present = centers[k.0] > 0 || (!order && centers[k.1] > 0);
+                    (k, if present { v.0 } else { f64::NAN })
+                })
+                .collect()
+            })?;
+
+        let alpha = (1.0 - ci) / 2.0;
+        let mut results: Vec<(_, (f64, f64))> = cellcombs
+            .iter()
+            .map(|k| {
+                let mut values: Vec<f64> = resampled.iter().map(|r| r[k]).collect();
+                let bounds = if values.iter().any(|v| v.is_nan()) {
+                    (f64::NAN, f64::NAN)
+                } else {
+                    values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+                    (quantile(&values, alpha), quantile(&values, 1.0 - alpha))
+                };
+                (
+                    (self.cell_types[k.0].as_str(), self.cell_types[k.1].as_str()),
+                    bounds,
+                )
+            })
+            .collect();
+        results.sort_by(|a, b| a.0.cmp(&b.0));
+
+        combs_to_py(py, results, options.return_type)
+    }
+}
+
+impl CellCombs {
+    fn run_bootstrap(
+        &self,
+        py: Python,
+        types: &PyObject,
+        neighbors: &PyObject,
+        options: &CombsOptions,
+    ) -> PyResult<PyObject> {
+        let types_data =
+            self.resolve_types(py, types, &options.categories, options.allow_unknown)?;
+        let (neighbors_data, weights_data) = extract_weighted_neighbors(py, neighbors, None)?;
+        check_neighbors_range(&neighbors_data, types_data.len())?;
+
+        if options.require_symmetric {
+            check_symmetric(py, &neighbors_data)?;
+        }
+
+        let cellcombs = self.resolve_combs(&options.selected_combs)?;
+        let order = self.order;
+        let stat = options.stat;
+
+        // the weights stay with the edges when the types are shuffled
+        let weights = weights_data.map(|w| center_weights(&neighbors_data, w, options.ignore_self));
+        let neighbors = center_neighbors(neighbors_data, options.ignore_self);
+        let members = extract_block_members(&options.groups, &options.blocks, types_data.len())?;
+
+        // the rare pairs are not counted
+        let type_counts = count_types(&types_data, self.cell_types.len());
+        let counted: Vec<(usize, usize)> = cellcombs
+            .iter()
+            .cloned()
+            .filter(|k| !options.is_rare(k, &type_counts))
+            .collect();
+
+        let real_data = py.allow_threads(|| {
+            count_neighbors(
+                &types_data,
+                &neighbors,
+                weights.as_ref(),
+                &counted,
+                order,
+                stat,
+                None,
+            )
+        });
+
+        let mut perm_stats = new_perm_stats(&real_data, options.full_stats());
+        if let Some(bins) = options.histogram {
+            perm_stats
+                .values_mut()
+                .for_each(|st| st.histogram = Some(Histogram::new(bins)));
+        }
+        stream_permutations(
+            py,
+            options.times,
+            &options.progress,
+            |i| {
+                let mut rng = perm_rng(options.seed, i);
+                let mut shuffle_types = types_data.to_owned();
+                match &members {
+                    Some(m) => shuffle_within(&mut shuffle_types, m, &mut rng),
+                    None => shuffle_types.shuffle(&mut rng),
+                }
+                count_neighbors(
+                    &shuffle_types,
+                    &neighbors,
+                    weights.as_ref(),
+                    &counted,
+                    order,
+                    stat,
+                    None,
+                )
+            },
+            |perm_result| update_perm_stats(&mut perm_stats, &perm_result),
+        )?;
+
+        let result = self.combs_result(
+            py,
+            &cellcombs,
+            &type_counts,
+            &real_data,
+            &perm_stats,
+            options,
+        )?;
+        if options.histogram.is_none() {
+            return Ok(result);
+        }
+        let histograms = self.combs_histograms(py, &cellcombs, &perm_stats, options)?;
+        Ok((result, histograms).to_object(py))
+    }
+
+    fn type_index(&self, t: &str) -> usize {
+        self.cell_types.iter().position(|c| c == t).unwrap()
+    }
+
+    // the index of the type of each cell in cell_types, if allow_unknown,
+    // the unknown types are len(cell_types) that is not in any combination
+    fn resolve_types(
+        &self,
+        py: Python,
+        types: &PyObject,
+        categories: &Option<Vec<String>>,
+        allow_unknown: bool,
+    ) -> PyResult<Vec<usize>> {
+        let (names, codes) = extract_types(py, types, categories)?;
+        let unknown: Vec<&str> = names
+            .iter()
+            .filter(|t| !self.cell_types.contains(t))
+            .map(|t| t.as_str())
+            .collect();
+        if !unknown.is_empty() && !allow_unknown {
+            return Err(PyValueError::new_err(format!(
+                "Unknown cell types in `types`: {}, set allow_unknown=True to skip them.",
+                unknown.join(", ")
+            )));
+        }
+        let lookup: Vec<usize> = names
+            .iter()
+            .map(|t| match self.cell_types.iter().position(|c| c == t) {
+                Some(i) => i,
+                None => self.cell_types.len(),
+            })
+            .collect();
+        Ok(codes.into_iter().map(|c| lookup[c]).collect())
+    }
+
+    fn resolve_combs(
+        &self,
+        selected_combs: &Option<Vec<(String, String)>>,
+    ) -> PyResult<Vec<(usize, usize)>> {
+        match selected_combs {
+            Some(selected) => self.select_combs(selected),
+            None => Ok(self
+                .cell_combs
+                .iter()
+                .map(|(a, b)| (self.type_index(a), self.type_index(b)))
+                .collect()),
+        }
+    }
+
+    // the histogram of the permutations of each pair sorted by the names, None for the rare pairs
+    fn combs_histograms(
+        &self,
+        py: Python,
+        cellcombs: &[(usize, usize)],
+        perm_stats: &HashMap<(usize, usize), PermStats>,
+        options: &CombsOptions,
+    ) -> PyResult<PyObject> {
+        let mut histograms: Vec<((&str, &str), PyObject)> = vec![];
+        for k in cellcombs.iter() {
+            let names = (self.cell_types[k.0].as_str(), self.cell_types[k.1].as_str());
+            let hist = match perm_stats.get(k).and_then(|st| st.histogram.as_ref()) {
+                Some(hist) => {
+                    let (counts, edges) = hist.result();
+                    let record = PyDict::new(py);
+                    record.set_item("counts", counts)?;
+                    record.set_item("edges", edges)?;
+                    record.set_item("min", hist.min)?;
+                    record.set_item("max", hist.max)?;
+                    record.to_object(py)
+                }
+                None if options.drop_rare => continue,
+                None => py.None(),
+            };
+            histograms.push((names, hist));
+        }
+        histograms.sort_by(|a, b| a.0.cmp(&b.0));
+        combs_to_py(py, histograms, options.return_type)
+    }
+
+    // format the result of each combination by the method
+    fn combs_result(
+        &self,
+        py: Python,
+        cellcombs: &[(usize, usize)],
+        type_counts: &[usize],
+        real_data: &CombsCounts,
+        perm_stats: &HashMap<(usize, usize), PermStats>,
+        options: &CombsOptions,
+    ) -> PyResult<PyObject> {
+        let name =
+            |k: &(usize, usize)| (self.cell_types[k.0].as_str(), self.cell_types[k.1].as_str());
+        // the rare pairs are NaN, or dropped if drop_rare
+        let (rare, counted): (Vec<_>, Vec<_>) = cellcombs
+            .iter()
+            .cloned()
+            .partition(|k| options.is_rare(k, type_counts));
+        let cellcombs = &counted;
+        let rare = if options.drop_rare { vec![] } else { rare };
+
+        let sigs = pval_sigs(cellcombs, perm_stats, options);
+
+        if options.full_stats() {
+            let stats: Vec<(f64, f64, f64, f64)> = cellcombs
+                .iter()
+                .map(|k| {
+                    let st = &perm_stats[k];
+                    (st.real, st.mean, st.std(), st.empirical_pvalue())
+                })
+                .collect();
+            let pvalues: Vec<f64> = stats.iter().map(|s| s.3).collect();
+            let adjusted = match options.adjust {
+                Some(a) => utils::adjust_pvalues(&pvalues, a),
+                None => pvalues,
+            };
+
+            let table = options.return_type == "table";
+            let mut records: Vec<CombsRecord> = vec![];
+            let mut results: Vec<((&str, &str), PyObject)> = vec![];
+            let rows = cellcombs.iter().zip(stats).zip(adjusted).zip(sigs);
+            for (((k, (real, m, sd, p)), p_adj), sig) in rows {
+                let z = pair_zscore(py, name(k), real, m, sd, options.on_zero_variance)?;
+                if table {
+                    records.push(self.combs_record(k, real, m, z, p_adj, sig));
+                    continue;
+                }
+                let detail = PyDict::new(py);
+                detail.set_item("observed", real)?;
+                detail.set_item("n_centers", real_data[k].1)?;
+                detail.set_item("n_cells", (type_counts[k.0], type_counts[k.1]))?;
+                detail.set_item("perm_mean", m)?;
+                detail.set_item("perm_std", sd)?;
+                detail.set_item("zscore", z)?;
+                detail.set_item(
+                    "log2_fc",
+                    ((real + options.pseudocount) / (m + options.pseudocount)).log2(),
+                )?;
+                detail.set_item("pvalue", p)?;
+                if options.adjust.is_some() {
+                    detail.set_item("pvalue_adj", p_adj)?;
+                }
+                detail.set_item("sig", sig)?;
+                results.push((name(k), detail.to_object(py)));
+            }
+            for k in rare.iter() {
+                if table {
+                    let nan = f64::NAN;
+                    records.push(self.combs_record(k, nan, nan, nan, nan, 0.0));
+                    continue;
+                }
+                let detail = PyDict::new(py);
+                detail.set_item("observed", f64::NAN)?;
+                detail.set_item("n_centers", 0)?;
+                detail.set_item("n_cells", (type_counts[k.0], type_counts[k.1]))?;
+                detail.set_item("perm_mean", f64::NAN)?;
+                detail.set_item("perm_std", f64::NAN)?;
+                detail.set_item("zscore", f64::NAN)?;
+                detail.set_item("log2_fc", f64::NAN)?;
+                detail.set_item("pvalue", f64::NAN)?;
+                if options.adjust.is_some() {
+                    detail.set_item("pvalue_adj", f64::NAN)?;
+                }
+                detail.set_item("sig", 0.0)?;
+                results.push((name(k), detail.to_object(py)));
+            }
+            if table {
+                records.sort_by(|a, b| a.pair().cmp(&b.pair()));
+                return Ok(CombsTable { records }.into_py(py));
+            }
+            results.sort_by(|a, b| a.0.cmp(&b.0));
+            return combs_to_py(py, results, options.return_type);
+        }
+
+        let mut results: Vec<((&str, &str), f64)> = vec![];
+
+        if options.method == "pval" {
+            for (k, sig) in cellcombs.iter().zip(sigs) {
+                results.push((name(k), sig));
+            }
+        } else {
+            for k in cellcombs.iter() {
+                let st = &perm_stats[k];
+                let z = pair_zscore(
+                    py,
+                    name(k),
+                    st.real,
+                    st.mean,
+                    st.std(),
+                    options.on_zero_variance,
+                )?;
+                results.push((name(k), z));
+            }
+        }
+
+        for k in rare.iter() {
+            results.push((name(k), f64::NAN));
+        }
+
+        // sorted by the type names, the result is identical across runs with the same seed
+        results.sort_by(|a, b| a.0.cmp(&b.0));
+
+        combs_to_py(py, results, options.return_type)
+    }
+
+    fn combs_record(
+        &self,
+        k: &(usize, usize),
+        observed: f64,
+        expected: f64,
+        zscore: f64,
+        pvalue: f64,
+        sig: f64,
+    ) -> CombsRecord {
+        CombsRecord {
+            type1: self.cell_types[k.0].to_owned(),
+            type2: self.cell_types[k.1].to_owned(),
+            observed,
+            expected,
+            zscore,
+            pvalue,
+            sig,
+        }
+    }
+
+    // the selected combinations as the same key in cell_combs
+    fn select_combs(&self, selected: &Vec<(String, String)>) -> PyResult<Vec<(usize, usize)>> {
+        let unknown: Vec<&str> = selected
+            .iter()
+            .flat_map(|(a, b)| vec![a, b])
+            .filter(|t| !self.cell_types.contains(*t))
+            .map(|t| t.as_str())
+            .unique()
+            .collect();
+        if !unknown.is_empty() {
+            return Err(PyValueError::new_err(format!(
+                "Unknown cell types in `selected_combs`: {}.",
+                unknown.join(", ")
+            )));
+        }
+        let mut combs: Vec<(usize, usize)> = vec![];
+        for (a, b) in selected {
+            let comb = self
+                .cell_combs
+                .iter()
+                .find(|(c1, c2)| (c1 == a && c2 == b) || (!self.order && c1 == b && c2 == a))
+                .map(|(c1, c2)| (self.type_index(c1), self.type_index(c2)))
+                .unwrap();
+            if !combs.contains(&comb) {
+                combs.push(comb);
+            }
+        }
+        Ok(combs)
+    }
+}
+
+// the value and the number of observations of each pair of cell types
+type CombsCounts = HashMap<(usize, usize), (f64, usize)>;
+
+// added to both the observed and the expected count of log2_fc
+fn extract_pseudocount(pseudocount: Option<f64>) -> PyResult<f64> {
+    let pseudocount = match pseudocount {
+        Some(data) => data,
+        None => 1.0,
+    };
+    if !pseudocount.is_finite() || pseudocount < 0.0 {
+        return Err(PyValueError::new_err(format!(
+            "`pseudocount` should be a finite non-negative number, got {}.",
+            pseudocount
+        )));
+    }
+    Ok(pseudocount)
+}
+
+// the options of the bootstrap methods of CellCombs
+struct CombsOptions<'a> {
+    times: usize,
+    pval: f64,
+    method: &'a str,
+    ignore_self: bool,
+    require_symmetric: bool,
+    groups: Option<Vec<i64>>,
+    blocks: Option<Vec<i64>>,
+    progress: Option<PyObject>,
+    seed: Option<u64>,
+    return_type: &'a str,
+    adjust: Option<&'a str>,
+    selected_combs: Option<Vec<(String, String)>>,
+    categories: Option<Vec<String>>,
+    on_zero_variance: &'a str,
+    stat: &'a str,
+    min_cells: usize,
+    drop_rare: bool,
+    pseudocount: f64,
+    allow_unknown: bool,
+    histogram: Option<usize>,
+}
 
+impl<'a> CombsOptions<'a> {
+    // the other options are the defaults, the methods that take them set them after
+    fn new(
+        times: Option<usize>,
+        pval: Option<f64>,
+        method: Option<&'a str>,
+        return_type: Option<&'a str>,
+        adjust: Option<&'a str>,
+        on_zero_variance: Option<&'a str>,
+        stat: Option<&'a str>,
+    ) -> PyResult<Self> {
         let times = match times {
             Some(data) => data,
             None => 500,
@@ -469,81 +6081,363 @@ impl CellCombs {
             Some(data) => data,
             None => "pval",
         };
+        if !["pval", "zscore", "full"].contains(&method) {
+            return Err(PyValueError::new_err(format!(
+                "`method` should be 'pval', 'zscore' or 'full', got '{}'.",
+                method
+            )));
+        }
 
-        let ignore_self = match ignore_self {
+        let return_type = match return_type {
             Some(data) => data,
-            None => false,
+            None => "list",
         };
+        if !["list", "dict", "table"].contains(&return_type) {
+            return Err(PyValueError::new_err(format!(
+                "`return_type` should be 'list', 'dict' or 'table', got '{}'.",
+                return_type
+            )));
+        }
+        if let Some(a) = adjust {
+            if a != "fdr_bh" && a != "bonferroni" {
+                return Err(PyValueError::new_err(format!(
+                    "`adjust` should be 'fdr_bh' or 'bonferroni', got '{}'.",
+                    a
+                )));
+            }
+        }
 
-        let cellcombs: Vec<(&str, &str)> = match self.cell_combs.extract(py) {
-            Ok(data) => data,
-            Err(_) => return Err(PyTypeError::new_err("Resolve cell_combs failed.")),
+        let on_zero_variance = extract_on_zero_variance(on_zero_variance)?;
+
+        let stat = match stat {
+            Some(data) => data,
+            None => "mean",
         };
+        if !["mean", "sum", "frequency"].contains(&stat) {
+            return Err(PyValueError::new_err(format!(
+                "`stat` should be 'mean', 'sum' or 'frequency', got '{}'.",
+                stat
+            )));
+        }
 
-        let neighbors = utils::remove_rep_neighbors(neighbors_data, ignore_self);
+        Ok(CombsOptions {
+            times,
+            pval,
+            method,
+            ignore_self: false,
+            require_symmetric: false,
+            groups: None,
+            blocks: None,
+            progress: None,
+            seed: None,
+            return_type,
+            adjust,
+            selected_combs: None,
+            categories: None,
+            on_zero_variance,
+            stat,
+            min_cells: 0,
+            drop_rare: false,
+            pseudocount: 1.0,
+            allow_unknown: false,
+            histogram: None,
+        })
+    }
 
-        let real_data = count_neighbors(&types_data, &neighbors, &cellcombs, self.order);
+    // all the arguments of CellCombs.bootstrap, bootstrap_marker takes the same ones
+    #[allow(clippy::too_many_arguments)]
+    fn bootstrap(
+        times: Option<usize>,
+        pval: Option<f64>,
+        method: Option<&'a str>,
+        ignore_self: Option<bool>,
+        require_symmetric: Option<bool>,
+        groups: Option<Vec<i64>>,
+        blocks: Option<Vec<i64>>,
+        progress: Option<PyObject>,
+        seed: Option<u64>,
+        return_type: Option<&'a str>,
+        adjust: Option<&'a str>,
+        selected_combs: Option<Vec<(String, String)>>,
+        categories: Option<Vec<String>>,
+        on_zero_variance: Option<&'a str>,
+        stat: Option<&'a str>,
+        min_cells: Option<usize>,
+        drop_rare: Option<bool>,
+        pseudocount: Option<f64>,
+        allow_unknown: Option<bool>,
+        histogram: Option<usize>,
+    ) -> PyResult<Self> {
+        if histogram == Some(0) {
+            return Err(PyValueError::new_err(
+                "The number of bins of `histogram` should be positive.",
+            ));
+        }
 
-        let mut simulate_data = cellcombs
-            .iter()
-            .map(|comb| (comb.to_owned(), vec![]))
-            .collect::<HashMap<(&str, &str), Vec<f64>>>();
+        Ok(CombsOptions {
+            ignore_self: match ignore_self {
+                Some(data) => data,
+                None => false,
+            },
+            require_symmetric: match require_symmetric {
+                Some(data) => data,
+                None => false,
+            },
+            groups,
+            blocks,
+            progress,
+            seed,
+            selected_combs,
+            categories,
+            min_cells: match min_cells {
+                Some(data) => data,
+                None => 0,
+            },
+            drop_rare: match drop_rare {
+                Some(data) => data,
+                None => false,
+            },
+            pseudocount: extract_pseudocount(pseudocount)?,
+            allow_unknown: match allow_unknown {
+                Some(data) => data,
+                None => false,
+            },
+            histogram,
+            ..CombsOptions::new(
+                times,
+                pval,
+                method,
+                return_type,
+                adjust,
+                on_zero_variance,
+                stat,
+            )?
+        })
+    }
 
-        let all_data: Vec<HashMap<(&str, &str), f64>> = (0..times)
-            .into_par_iter()
-            .map(|_| {
-                let mut rng = thread_rng();
-                let mut shuffle_types = types_data.to_owned();
-                shuffle_types.shuffle(&mut rng);
-                let perm_result =
-                    count_neighbors(&shuffle_types, &neighbors, &cellcombs, self.order);
-                perm_result
-            })
-            .collect();
+    // the table has the same statistics as the 'full' method
+    fn full_stats(&self) -> bool {
+        self.method == "full" || self.return_type == "table"
+    }
 
-        for perm_result in all_data {
-            for (k, v) in perm_result.iter() {
-                simulate_data.get_mut(k).unwrap().push(*v);
-            }
+    // either type of the pair has fewer than min_cells cells
+    fn is_rare(&self, comb: &(usize, usize), type_counts: &[usize]) -> bool {
+        type_counts[comb.0] < self.min_cells || type_counts[comb.1] < self.min_cells
+    }
+}
+
+// the number of cells of each type
+fn count_types(types: &[usize], n_types: usize) -> Vec<usize> {
+    let mut counts = vec![0; n_types];
+    // the unknown types are not counted
+    for t in types.iter() {
+        if let Some(c) = counts.get_mut(*t) {
+            *c += 1;
         }
+    }
+    counts
+}
 
-        let mut results: Vec<((&str, &str), f64)> = vec![];
+// the counting needs all the neighbors of each center
+fn center_neighbors(neighbors: Vec<Vec<usize>>, ignore_self: bool) -> Vec<Vec<usize>> {
+    let mut neighbors = neighbors;
+    if ignore_self {
+        neighbors
+            .iter_mut()
+            .enumerate()
+            .for_each(|(i, neighs)| neighs.retain(|j| *j != i));
+    }
+    neighbors
+}
 
-        for (k, v) in simulate_data.iter() {
-            let real = real_data[k];
+// the weights of center_neighbors
+fn center_weights(
+    neighbors: &[Vec<usize>],
+    weights: Vec<Vec<f64>>,
+    ignore_self: bool,
+) -> Vec<Vec<f64>> {
+    if !ignore_self {
+        return weights;
+    }
+    weights
+        .into_iter()
+        .zip(neighbors.iter())
+        .enumerate()
+        .map(|(i, (w, neighs))| {
+            w.into_iter()
+                .zip(neighs.iter())
+                .filter(|(_, j)| **j != i)
+                .map(|(w, _)| w)
+                .collect()
+        })
+        .collect()
+}
 
-            if method == "pval" {
-                let mut gt: f64 = 0.0;
-                let mut lt: f64 = 0.0;
-                for i in v.iter() {
-                    if i >= &real {
-                        gt += 1.0
-                    }
-                    if i <= &real {
-                        lt += 1.0
-                    }
-                }
-                let gt: f64 = gt as f64 / (times.to_owned() as f64 + 1.0);
-                let lt: f64 = lt as f64 / (times.to_owned() as f64 + 1.0);
-                let dir: f64 = (gt < lt) as i32 as f64;
-                let udir: f64 = !(gt < lt) as i32 as f64;
-                let p: f64 = gt * dir + lt * udir;
-                let sig: f64 = (p < pval) as i32 as f64;
-                let sigv: f64 = sig * (dir - 0.5).signum();
-                results.push((k.to_owned(), sigv));
-            } else {
-                let m = mean_f(v);
-                let sd = std_f(v);
-                if sd != 0.0 {
-                    results.push((k.to_owned(), (real - m) / sd));
-                } else {
-                    results.push((k.to_owned(), 0.0));
+// the values of the permutations are only kept for the empirical p-value of the 'full' method
+fn new_perm_stats(
+    real_data: &CombsCounts,
+    keep_values: bool,
+) -> HashMap<(usize, usize), PermStats> {
+    real_data
+        .iter()
+        .map(|(k, v)| {
+            let mut st = PermStats::new(v.0);
+            if keep_values {
+                st.values = Some(vec![]);
+            }
+            (k.to_owned(), st)
+        })
+        .collect()
+}
+
+// the significance of the 'pval' method, the direction of the smaller one-sided p-value if it's
+// below `pval`, the correction is applied before the significance is assigned
+fn pval_sigs(
+    cellcombs: &[(usize, usize)],
+    perm_stats: &HashMap<(usize, usize), PermStats>,
+    options: &CombsOptions,
+) -> Vec<f64> {
+    let (pvalues, dirs): (Vec<f64>, Vec<f64>) = cellcombs
+        .iter()
+        .map(|k| perm_stats[k].directional_pvalue())
+        .unzip();
+    let pvalues = match options.adjust {
+        Some(a) => utils::adjust_pvalues(&pvalues, a),
+        None => pvalues,
+    };
+    pvalues
+        .iter()
+        .zip(dirs)
+        .map(|(p, dir)| (*p < options.pval) as i32 as f64 * dir)
+        .collect()
+}
+
+fn update_perm_stats(
+    perm_stats: &mut HashMap<(usize, usize), PermStats>,
+    perm_result: &CombsCounts,
+) {
+    for (k, v) in perm_result.iter() {
+        perm_stats.get_mut(k).unwrap().push(v.0);
+    }
+}
+
+// all the combinations of the types, (A, B) and (B, A) are the same if not order
+fn type_combs(types: &[String], order: bool) -> Vec<(String, String)> {
+    let mut combs = vec![];
+
+    if order {
+        for i1 in types.iter() {
+            for i2 in types.iter() {
+                combs.push((i1.to_owned(), i2.to_owned()));
+            }
+        }
+    } else {
+        for (i1, e1) in types.iter().enumerate() {
+            for (i2, e2) in types.iter().enumerate() {
+                if i2 >= i1 {
+                    combs.push((e1.to_owned(), e2.to_owned()));
                 }
             }
         }
+    }
+    combs
+}
+
+/// The result table of `CellCombs.bootstrap` with return_type='table'
+///
+/// One record per pair with the columns type1, type2, observed, expected (the mean of the permutations),
+/// zscore, pvalue (the adjusted p-value if adjust is given) and sig
+///
+#[pyclass]
+struct CombsTable {
+    records: Vec<CombsRecord>,
+}
+
+#[pymethods]
+impl CombsTable {
+    /// Save the table to a JSON file, a list of records. JSON has no NaN or infinity, they are saved
+    /// as the strings "NaN", "Infinity" and "-Infinity"
+    ///
+    /// Args:
+    ///     path: str; The path of the file
+    ///
+    fn to_json(&self, path: &str) -> PyResult<()> {
+        match write_combs_json(path, &self.records) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(PyIOError::new_err(format!(
+                "Failed to save the table to {}: {}",
+                path, e
+            ))),
+        }
+    }
+
+    /// Save the table to a CSV file with a header
+    ///
+    /// Args:
+    ///     path: str; The path of the file
+    ///
+    fn to_csv(&self, path: &str) -> PyResult<()> {
+        match write_combs_csv(path, &self.records) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(PyIOError::new_err(format!(
+                "Failed to save the table to {}: {}",
+                path, e
+            ))),
+        }
+    }
+
+    /// Load a table from a JSON file saved by `to_json`, or any list of records in standard JSON,
+    /// eg. saved by the json module with allow_nan=False, null is loaded as NaN
+    ///
+    /// Args:
+    ///     path: str; The path of the file
+    ///
+    #[staticmethod]
+    fn from_json(path: &str) -> PyResult<Self> {
+        match read_combs_json(path) {
+            Ok(records) => Ok(CombsTable { records }),
+            Err(e) if e.kind() == std::io::ErrorKind::InvalidData => Err(PyValueError::new_err(
+                format!("Failed to load the table from {}: {}", path, e),
+            )),
+            Err(e) => Err(PyIOError::new_err(format!(
+                "Failed to load the table from {}: {}",
+                path, e
+            ))),
+        }
+    }
 
-        let results_py = results.to_object(py);
+    /// The records of the table
+    ///
+    /// Return:
+    ///     A list of dict, eg. {'pair': ('a', 'b'), 'observed': 1.0, ...}
+    ///
+    fn records(&self, py: Python) -> PyResult<Vec<PyObject>> {
+        let mut records = vec![];
+        for r in self.records.iter() {
+            let record = PyDict::new(py);
+            record.set_item("pair", r.pair())?;
+            record.set_item("observed", r.observed)?;
+            record.set_item("expected", r.expected)?;
+            record.set_item("zscore", r.zscore)?;
+            record.set_item("pvalue", r.pvalue)?;
+            record.set_item("sig", r.sig)?;
+            records.push(record.to_object(py));
+        }
+        Ok(records)
+    }
+}
 
-        Ok(results_py)
+fn combs_to_py<T: ToPyObject>(
+    py: Python,
+    results: Vec<((&str, &str), T)>,
+    return_type: &str,
+) -> PyResult<PyObject> {
+    if return_type == "dict" {
+        let results_dict = PyDict::new(py);
+        for (k, v) in results {
+            results_dict.set_item(k, v)?;
+        }
+        return Ok(results_dict.to_object(py));
     }
+    Ok(results.to_object(py))
 }
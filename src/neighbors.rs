@@ -0,0 +1,934 @@
+use kdbush::{KDBush, PointReader};
+use rand::rngs::StdRng;
+use rand::seq::index::sample;
+use rand::SeedableRng;
+use rayon::prelude::*;
+use rstar::{RTree, RTreeObject, AABB};
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+
+// read access to the coordinates of points, whether they are owned or borrowed
+pub trait Coords: Sync {
+    fn size(&self) -> usize;
+    fn point(&self, i: usize) -> (f64, f64);
+}
+
+impl Coords for Vec<(f64, f64)> {
+    fn size(&self) -> usize {
+        self.len()
+    }
+    fn point(&self, i: usize) -> (f64, f64) {
+        self[i]
+    }
+}
+
+// the points are either owned, or borrowed from a C-contiguous array laid out as [x0, y0, x1, y1, ...]
+pub enum Points<'a> {
+    Owned(Vec<(f64, f64)>),
+    Flat(&'a [f64]),
+}
+
+impl Coords for Points<'_> {
+    fn size(&self) -> usize {
+        match self {
+            Points::Owned(points) => points.len(),
+            Points::Flat(coords) => coords.len() / 2,
+        }
+    }
+    fn point(&self, i: usize) -> (f64, f64) {
+        match self {
+            Points::Owned(points) => points[i],
+            Points::Flat(coords) => (coords[2 * i], coords[2 * i + 1]),
+        }
+    }
+}
+
+impl PointReader for &Points<'_> {
+    fn size_hint(&self) -> usize {
+        self.size()
+    }
+    fn visit_all<F>(&self, mut visitor: F)
+    where
+        F: FnMut(usize, f64, f64),
+    {
+        for i in 0..self.size() {
+            let p = self.point(i);
+            visitor(i, p.0, p.1);
+        }
+    }
+}
+
+// KDBush can't be built on no points, the callers map None to their empty result
+pub fn build_tree<P: PointReader>(points: P, node_size: u8) -> Option<KDBush> {
+    if points.size_hint() == 0 {
+        None
+    } else {
+        Some(KDBush::create(points, node_size))
+    }
+}
+
+pub fn invalid_points<P: Coords>(points: &P) -> Vec<usize> {
+    (0..points.size())
+        .filter(|i| {
+            let p = points.point(*i);
+            !(p.0.is_finite() && p.1.is_finite())
+        })
+        .collect()
+}
+
+// group the index of points with exactly the same coordinates, in the order of first appearance
+pub fn duplicate_groups<P: Coords>(points: &P) -> Vec<Vec<usize>> {
+    let mut groups: Vec<Vec<usize>> = vec![];
+    let mut seen: HashMap<(u64, u64), usize> = HashMap::new();
+    for i in 0..points.size() {
+        let p = points.point(i);
+        // adding 0.0 turns -0.0 into 0.0
+        let key = ((p.0 + 0.0).to_bits(), (p.1 + 0.0).to_bits());
+        let g = *seen.entry(key).or_insert_with(|| {
+            groups.push(vec![]);
+            groups.len() - 1
+        });
+        groups[g].push(i);
+    }
+    groups.into_iter().filter(|g| g.len() > 1).collect()
+}
+
+pub fn sq_dist(a: &(f64, f64), b: &(f64, f64)) -> f64 {
+    let dx = a.0 - b.0;
+    let dy = a.1 - b.1;
+    dx * dx + dy * dy
+}
+
+pub fn points_bbox(points: &Vec<(f64, f64)>) -> (f64, f64, f64, f64) {
+    let mut minx = f64::INFINITY;
+    let mut miny = f64::INFINITY;
+    let mut maxx = f64::NEG_INFINITY;
+    let mut maxy = f64::NEG_INFINITY;
+    for p in points {
+        minx = minx.min(p.0);
+        miny = miny.min(p.1);
+        maxx = maxx.max(p.0);
+        maxy = maxy.max(p.1);
+    }
+    (minx, miny, maxx, maxy)
+}
+
+pub fn within_metric(a: &(f64, f64), b: &(f64, f64), r: f64, metric: &str) -> bool {
+    let dx = (a.0 - b.0).abs();
+    let dy = (a.1 - b.1).abs();
+    match metric {
+        "manhattan" => dx + dy <= r,
+        "chebyshev" => dx.max(dy) <= r,
+        _ => dx * dx + dy * dy <= r * r,
+    }
+}
+
+// the candidates are searched in the smallest region that contains the metric ball
+pub fn metric_candidates(tree: &KDBush, p: &(f64, f64), r: f64, metric: &str) -> Vec<usize> {
+    let mut candidates: Vec<usize> = vec![];
+    if metric == "chebyshev" {
+        tree.range(p.0 - r, p.1 - r, p.0 + r, p.1 + r, |id| candidates.push(id));
+    } else {
+        tree.within(p.0, p.1, r, |id| candidates.push(id));
+    }
+    candidates
+}
+
+// the initial radius that is expected to contain k points if they are uniformly distributed
+fn init_knn_radius(points: &Vec<(f64, f64)>, k: usize) -> (f64, f64) {
+    let (minx, miny, maxx, maxy) = points_bbox(points);
+    let w = maxx - minx;
+    let h = maxy - miny;
+    let diag = (w * w + h * h).sqrt();
+    let r = (w * h * k as f64 / (std::f64::consts::PI * points.len() as f64)).sqrt();
+    let r = if r > 0.0 {
+        r
+    } else if diag > 0.0 {
+        diag / points.len() as f64
+    } else {
+        1.0
+    };
+
+    (r, diag)
+}
+
+pub fn knn_search(
+    tree: &KDBush,
+    points: &[(f64, f64)],
+    p: &(f64, f64),
+    cent: Option<usize>,
+    k: usize,
+    init_r: f64,
+    diag: f64,
+) -> Vec<usize> {
+    let mut r = init_r;
+    let mut candidates: Vec<usize>;
+    loop {
+        candidates = vec![];
+        tree.within(p.0, p.1, r, |id| {
+            if Some(id) != cent {
+                candidates.push(id)
+            }
+        });
+        if candidates.len() >= k || r >= diag {
+            break;
+        }
+        r *= 2.0;
+    }
+
+    let mut dists: Vec<(f64, usize)> = candidates
+        .into_iter()
+        .map(|id| (sq_dist(p, &points[id]), id))
+        .collect();
+    // ties are broken by the index to keep the result deterministic
+    dists.sort_by(|a, b| {
+        a.0.partial_cmp(&b.0)
+            .unwrap_or(Ordering::Equal)
+            .then(a.1.cmp(&b.1))
+    });
+    dists.into_iter().take(k).map(|(_, id)| id).collect()
+}
+
+pub fn knn_neighbors(
+    tree: &KDBush,
+    points: &Vec<(f64, f64)>,
+    k: usize,
+    exclude_self: bool,
+) -> Vec<Vec<usize>> {
+    let (init_r, diag) = init_knn_radius(points, k + 1);
+    points
+        .par_iter()
+        .enumerate()
+        .map(|(i, p)| {
+            let cent = if exclude_self { Some(i) } else { None };
+            knn_search(tree, points, p, cent, k, init_r, diag)
+        })
+        .collect()
+}
+
+pub fn within_neighbors(
+    tree: &KDBush,
+    points: &[(f64, f64)],
+    query: &Vec<usize>,
+    r: f64,
+) -> Vec<Vec<usize>> {
+    query
+        .par_iter()
+        .map(|i| {
+            let p = &points[*i];
+            let mut neighbors: Vec<usize> = vec![];
+            tree.within(p.0, p.1, r, |id| neighbors.push(id));
+            neighbors
+        })
+        .collect()
+}
+
+pub fn annulus_neighbors(
+    tree: &KDBush,
+    points: &Vec<(f64, f64)>,
+    r_min: f64,
+    r_max: f64,
+) -> Vec<Vec<usize>> {
+    let r_min2 = r_min * r_min;
+    points
+        .par_iter()
+        .map(|p| {
+            let mut neighbors: Vec<usize> = vec![];
+            // both bounds are inclusive
+            tree.within(p.0, p.1, r_max, |id| {
+                if sq_dist(p, &points[id]) >= r_min2 {
+                    neighbors.push(id)
+                }
+            });
+            neighbors
+        })
+        .collect()
+}
+
+pub fn within_counts(
+    tree: &KDBush,
+    points: &Vec<(f64, f64)>,
+    r: f64,
+    exclude_self: bool,
+) -> Vec<usize> {
+    points
+        .par_iter()
+        .map(|p| {
+            let mut count: usize = 0;
+            tree.within(p.0, p.1, r, |_| count += 1);
+            // the point itself is always inside the radius
+            if exclude_self {
+                count.saturating_sub(1)
+            } else {
+                count
+            }
+        })
+        .collect()
+}
+
+// double the radius until at least min_n other points are found or r_max is reached
+pub fn adaptive_neighbors(
+    tree: &KDBush,
+    points: &Vec<(f64, f64)>,
+    r_start: f64,
+    r_max: f64,
+    min_n: usize,
+) -> Vec<(Vec<usize>, f64)> {
+    points
+        .par_iter()
+        .map(|p| {
+            let mut r = r_start;
+            loop {
+                let mut neighbors: Vec<usize> = vec![];
+                tree.within(p.0, p.1, r, |id| neighbors.push(id));
+                if neighbors.len() > min_n || r >= r_max {
+                    return (neighbors, r);
+                }
+                r = (r * 2.0).min(r_max);
+            }
+        })
+        .collect()
+}
+
+pub fn within_distances(
+    tree: &KDBush,
+    points: &Vec<(f64, f64)>,
+    r: f64,
+    exclude_self: bool,
+) -> Vec<Vec<(usize, f64)>> {
+    points
+        .par_iter()
+        .enumerate()
+        .map(|(i, p)| {
+            let mut neighbors: Vec<(usize, f64)> = vec![];
+            tree.within(p.0, p.1, r, |id| {
+                if !(exclude_self && id == i) {
+                    neighbors.push((id, sq_dist(p, &points[id]).sqrt()))
+                }
+            });
+            neighbors
+        })
+        .collect()
+}
+
+pub fn cross_within_neighbors(tree: &KDBush, query: &Vec<(f64, f64)>, r: f64) -> Vec<Vec<usize>> {
+    query
+        .par_iter()
+        .map(|p| {
+            let mut neighbors: Vec<usize> = vec![];
+            tree.within(p.0, p.1, r, |id| neighbors.push(id));
+            neighbors
+        })
+        .collect()
+}
+
+pub fn cross_knn_neighbors(
+    tree: &KDBush,
+    points: &Vec<(f64, f64)>,
+    query: &Vec<(f64, f64)>,
+    k: usize,
+) -> Vec<Vec<usize>> {
+    let (init_r, _) = init_knn_radius(points, k);
+    let (minx, miny, maxx, maxy) = points_bbox(points);
+    query
+        .par_iter()
+        .map(|p| {
+            // the query point may lay outside the indexed points,
+            // the search radius should be able to reach the farthest corner
+            let reach = [(minx, miny), (minx, maxy), (maxx, miny), (maxx, maxy)]
+                .iter()
+                .map(|c| sq_dist(p, c))
+                .fold(0.0, f64::max)
+                .sqrt();
+            knn_search(tree, points, p, None, k, init_r, reach)
+        })
+        .collect()
+}
+
+pub fn sort_neighbors<P: Coords>(neighbors: &mut [usize], p: &(f64, f64), points: &P, by: &str) {
+    if by == "index" {
+        neighbors.sort_unstable();
+    } else if by == "distance" {
+        neighbors.sort_by(|a, b| {
+            sq_dist(p, &points.point(*a))
+                .partial_cmp(&sq_dist(p, &points.point(*b)))
+                .unwrap_or(Ordering::Equal)
+                .then(a.cmp(b))
+        });
+    }
+}
+
+// keep only the nearest n neighbors, the order of the kept neighbors is not preserved
+pub fn cap_neighbors<P: Coords>(neighbors: &mut Vec<usize>, p: &(f64, f64), points: &P, n: usize) {
+    if neighbors.len() <= n {
+        return;
+    }
+    let mut dists: Vec<(f64, usize)> = neighbors
+        .iter()
+        .map(|id| (sq_dist(p, &points.point(*id)), *id))
+        .collect();
+    let cmp = |a: &(f64, usize), b: &(f64, usize)| {
+        a.0.partial_cmp(&b.0)
+            .unwrap_or(Ordering::Equal)
+            .then(a.1.cmp(&b.1))
+    };
+    if n > 0 {
+        dists.select_nth_unstable_by(n - 1, cmp);
+    }
+    dists.truncate(n);
+    *neighbors = dists.into_iter().map(|(_, id)| id).collect();
+}
+
+// make the graph undirected, the neighbors are sorted by index
+pub fn symmetrize_graph(neighbors: &Vec<Vec<usize>>, union: bool) -> Vec<Vec<usize>> {
+    let mut result: Vec<Vec<usize>> = if union {
+        let n = neighbors
+            .iter()
+            .flatten()
+            .map(|j| j + 1)
+            .fold(neighbors.len(), usize::max);
+        let mut result = vec![vec![]; n];
+        for (i, neighs) in neighbors.iter().enumerate() {
+            for j in neighs {
+                result[i].push(*j);
+                result[*j].push(i);
+            }
+        }
+        result
+    } else {
+        let sets: Vec<HashSet<usize>> = neighbors
+            .par_iter()
+            .map(|neighs| neighs.iter().cloned().collect())
+            .collect();
+        neighbors
+            .par_iter()
+            .enumerate()
+            .map(|(i, neighs)| {
+                neighs
+                    .iter()
+                    .filter(|j| **j < sets.len() && sets[**j].contains(&i))
+                    .cloned()
+                    .collect()
+            })
+            .collect()
+    };
+    result.par_iter_mut().for_each(|neighs| {
+        neighs.sort_unstable();
+        neighs.dedup();
+    });
+    result
+}
+
+// the first (i, j) that j is a neighbor of i but i is not a neighbor of j
+pub fn first_asymmetric(neighbors: &Vec<Vec<usize>>) -> Option<(usize, usize)> {
+    let sets: Vec<HashSet<usize>> = neighbors
+        .par_iter()
+        .map(|neighs| neighs.iter().cloned().collect())
+        .collect();
+    neighbors
+        .par_iter()
+        .enumerate()
+        .find_map_first(|(i, neighs)| {
+            neighs
+                .iter()
+                .find(|j| **j >= sets.len() || !sets[**j].contains(&i))
+                .map(|j| (i, *j))
+        })
+}
+
+// the degree of each node, self-loops are not counted
+pub fn node_degrees(neighbors: &Vec<Vec<usize>>, directed: bool) -> Vec<usize> {
+    let graph: Vec<Vec<usize>> = if directed {
+        neighbors.to_owned()
+    } else {
+        symmetrize_graph(neighbors, true)
+    };
+    graph
+        .par_iter()
+        .enumerate()
+        .map(|(i, neighs)| {
+            let mut neighs: Vec<usize> = neighs.iter().filter(|j| **j != i).cloned().collect();
+            neighs.sort_unstable();
+            neighs.dedup();
+            neighs.len()
+        })
+        .collect()
+}
+
+// randomly keep a fraction of neighbors of each point, at least one is kept if there is any,
+// the rng of point i is seeded by seed + i so the result doesn't depend on the scheduling
+pub fn subsample_graph(neighbors: &Vec<Vec<usize>>, fraction: f64, seed: u64) -> Vec<Vec<usize>> {
+    neighbors
+        .par_iter()
+        .enumerate()
+        .map(|(i, neighs)| {
+            if neighs.is_empty() {
+                return vec![];
+            }
+            let k = ((neighs.len() as f64 * fraction).round() as usize).max(1);
+            let mut rng = StdRng::seed_from_u64(seed.wrapping_add(i as u64));
+            let mut picked = sample(&mut rng, neighs.len(), k).into_vec();
+            picked.sort_unstable();
+            picked.into_iter().map(|j| neighs[j]).collect()
+        })
+        .collect()
+}
+
+// keep only the selected points, the index is remapped to the kept points
+pub fn induced_subgraph(neighbors: &[Vec<usize>], keep: &[bool]) -> Vec<Vec<usize>> {
+    let mut new_index = vec![usize::MAX; keep.len()];
+    let mut count = 0;
+    for (i, k) in keep.iter().enumerate() {
+        if *k {
+            new_index[i] = count;
+            count += 1;
+        }
+    }
+    let mut result = vec![vec![]; count];
+    for (i, neighs) in neighbors.iter().enumerate() {
+        if keep[i] {
+            result[new_index[i]] = neighs
+                .iter()
+                .filter(|j| keep[**j])
+                .map(|j| new_index[*j])
+                .collect();
+        }
+    }
+    result
+}
+
+// the domain is a torus, query the shifted copies of each point to find neighbors across the edges
+pub fn periodic_neighbors(
+    tree: &KDBush,
+    points: &Vec<(f64, f64)>,
+    r: f64,
+    width: f64,
+    height: f64,
+) -> Vec<Vec<usize>> {
+    points
+        .par_iter()
+        .map(|p| {
+            let mut neighbors: Vec<usize> = vec![];
+            for sx in &[-width, 0.0, width] {
+                for sy in &[-height, 0.0, height] {
+                    tree.within(p.0 + sx, p.1 + sy, r, |id| neighbors.push(id));
+                }
+            }
+            neighbors.sort_unstable();
+            neighbors.dedup();
+            neighbors
+        })
+        .collect()
+}
+
+pub fn kernel_weight(d: f64, bandwidth: f64, kernel: &str) -> f64 {
+    match kernel {
+        "linear" => (1.0 - d / bandwidth).max(0.0),
+        "inverse" => 1.0 / (1.0 + d / bandwidth),
+        _ => (-(d * d) / (2.0 * bandwidth * bandwidth)).exp(),
+    }
+}
+
+// the distance to the k-th nearest neighbor, NaN if there are not enough points
+pub fn kth_neighbor_distances(tree: &KDBush, points: &Vec<(f64, f64)>, k: usize) -> Vec<f64> {
+    if k >= points.len() {
+        return vec![f64::NAN; points.len()];
+    }
+    knn_neighbors(tree, points, k, true)
+        .par_iter()
+        .zip(points.par_iter())
+        .map(|(neighs, p)| match neighs.last() {
+            Some(id) => sq_dist(p, &points[*id]).sqrt(),
+            None => f64::NAN,
+        })
+        .collect()
+}
+
+// the distances from the centers to the targets for Ripley's K and the pair correlation function,
+// the univariate functions have all the points as both the centers and the targets
+pub struct PairDistances {
+    // the sorted distances to the other targets within the search radius and the distance
+    // to the border of the window (infinity without a window) of each center
+    dists: Vec<(Vec<f64>, f64)>,
+    n_targets: f64,
+    // the number of the center-target pairs, n(n - 1) for the univariate functions
+    n_pairs: f64,
+    border: bool,
+}
+
+impl PairDistances {
+    pub fn new(
+        tree: &KDBush,
+        points: &[(f64, f64)],
+        centers: &Vec<usize>,
+        targets: &[bool],
+        r_search: f64,
+        window: Option<(f64, f64, f64, f64)>,
+    ) -> Self {
+        let n_targets = targets.iter().filter(|t| **t).count() as f64;
+        let n_pairs: f64 = centers
+            .iter()
+            .map(|i| {
+                if targets[*i] {
+                    n_targets - 1.0
+                } else {
+                    n_targets
+                }
+            })
+            .sum();
+        let dists = centers
+            .par_iter()
+            .map(|i| {
+                let p = &points[*i];
+                let mut dists: Vec<f64> = vec![];
+                tree.within(p.0, p.1, r_search, |j| {
+                    if j != *i && targets[j] {
+                        dists.push(sq_dist(p, &points[j]).sqrt())
+                    }
+                });
+                dists.sort_by(|a, b| a.partial_cmp(b).unwrap());
+                let border = match window {
+                    Some((xmin, ymin, xmax, ymax)) => {
+                        (p.0 - xmin).min(xmax - p.0).min(p.1 - ymin).min(ymax - p.1)
+                    }
+                    None => f64::INFINITY,
+                };
+                (dists, border)
+            })
+            .collect();
+        PairDistances {
+            dists,
+            n_targets,
+            n_pairs,
+            border: window.is_some(),
+        }
+    }
+
+    // the intensity of the targets is n / area, without a window all the centers are counted against
+    // the number of pairs, with a window only the centers at least r away from its border are counted
+    // (the border method), NaN if there are no such centers or no targets
+    fn estimate<F>(&self, area: f64, r: f64, count: F) -> f64
+    where
+        F: Fn(&Vec<f64>) -> f64,
+    {
+        if self.n_pairs == 0.0 {
+            return f64::NAN;
+        }
+        if !self.border {
+            let total: f64 = self.dists.iter().map(|(d, _)| count(d)).sum();
+            return area * total / self.n_pairs;
+        }
+        let inner: Vec<f64> = self
+            .dists
+            .iter()
+            .filter(|(_, border)| *border >= r)
+            .map(|(d, _)| count(d))
+            .collect();
+        if inner.is_empty() {
+            f64::NAN
+        } else {
+            area / self.n_targets * inner.iter().sum::<f64>() / inner.len() as f64
+        }
+    }
+
+    pub fn ripley_k(&self, radii: &[f64], area: f64) -> Vec<f64> {
+        radii
+            .iter()
+            .map(|r| self.estimate(area, *r, |d| d.partition_point(|x| x <= r) as f64))
+            .collect()
+    }
+
+    // the pairs in each bin over the annulus area, or smoothed by the Epanechnikov kernel
+    // over 2 * pi * r at the bin centers, the bins are from 0 to the last edge
+    pub fn pair_correlation(&self, edges: &[f64], bandwidth: Option<f64>, area: f64) -> Vec<f64> {
+        edges
+            .windows(2)
+            .map(|e| match bandwidth {
+                None => {
+                    let annulus = std::f64::consts::PI * (e[1] * e[1] - e[0] * e[0]);
+                    let count = |d: &Vec<f64>| {
+                        (d.partition_point(|x| *x <= e[1]) - d.partition_point(|x| *x <= e[0]))
+                            as f64
+                    };
+                    self.estimate(area, e[1], count) / annulus
+                }
+                Some(h) => {
+                    let r = (e[0] + e[1]) / 2.0;
+                    let kernel = |d: &Vec<f64>| {
+                        d[d.partition_point(|x| *x <= r - h)..d.partition_point(|x| *x < r + h)]
+                            .iter()
+                            .map(|x| 0.75 / h * (1.0 - ((r - x) / h).powi(2)))
+                            .sum::<f64>()
+                    };
+                    self.estimate(area, r, kernel) / (2.0 * std::f64::consts::PI * r)
+                }
+            })
+            .collect()
+    }
+}
+
+pub fn apply_labels(neighbors: Vec<Vec<usize>>, labels: Option<Vec<usize>>) -> Vec<Vec<usize>> {
+    match labels {
+        Some(labels) => neighbors
+            .into_iter()
+            .map(|neighs| neighs.iter().map(|t| labels[*t]).collect())
+            .collect(),
+        None => neighbors,
+    }
+}
+
+// customize 3d point to insert in to R-tree
+pub struct Point3 {
+    coord: [f64; 3],
+    index: usize,
+}
+
+impl RTreeObject for Point3 {
+    type Envelope = AABB<[f64; 3]>;
+
+    fn envelope(&self) -> Self::Envelope {
+        AABB::from_point(self.coord)
+    }
+}
+
+pub fn sphere_neighbors(points: &Vec<(f64, f64, f64)>, r: f64) -> Vec<Vec<usize>> {
+    let objs: Vec<Point3> = points
+        .iter()
+        .enumerate()
+        .map(|(i, p)| Point3 {
+            coord: [p.0, p.1, p.2],
+            index: i,
+        })
+        .collect();
+    let tree: RTree<Point3> = RTree::bulk_load(objs);
+    let r2 = r * r;
+
+    points
+        .par_iter()
+        .map(|p| {
+            let envelope =
+                AABB::from_corners([p.0 - r, p.1 - r, p.2 - r], [p.0 + r, p.1 + r, p.2 + r]);
+            // the box query is filtered by the exact distance to the center of sphere
+            tree.locate_in_envelope_intersecting(&envelope)
+                .filter(|n| {
+                    let dx = n.coord[0] - p.0;
+                    let dy = n.coord[1] - p.1;
+                    let dz = n.coord[2] - p.2;
+                    dx * dx + dy * dy + dz * dz <= r2
+                })
+                .map(|n| n.index)
+                .collect()
+        })
+        .collect()
+}
+
+// single precision 2d point, keep the coordinates in f32 to save memory
+pub struct PointF32 {
+    coord: [f32; 2],
+    index: usize,
+}
+
+impl RTreeObject for PointF32 {
+    type Envelope = AABB<[f32; 2]>;
+
+    fn envelope(&self) -> Self::Envelope {
+        AABB::from_point(self.coord)
+    }
+}
+
+pub fn f32_tree(points: &[[f32; 2]]) -> RTree<PointF32> {
+    let objs: Vec<PointF32> = points
+        .iter()
+        .enumerate()
+        .map(|(i, p)| PointF32 {
+            coord: *p,
+            index: i,
+        })
+        .collect();
+    RTree::bulk_load(objs)
+}
+
+pub fn within_neighbors_f32(
+    tree: &RTree<PointF32>,
+    points: &[[f32; 2]],
+    query: &Vec<usize>,
+    r: f32,
+) -> Vec<Vec<usize>> {
+    let r2 = r * r;
+    query
+        .par_iter()
+        .map(|i| {
+            let p = points[*i];
+            let envelope = AABB::from_corners([p[0] - r, p[1] - r], [p[0] + r, p[1] + r]);
+            let mut neighbors: Vec<usize> = tree
+                .locate_in_envelope_intersecting(&envelope)
+                .filter(|n| {
+                    let dx = n.coord[0] - p[0];
+                    let dy = n.coord[1] - p[1];
+                    dx * dx + dy * dy <= r2
+                })
+                .map(|n| n.index)
+                .collect();
+            neighbors.sort_unstable();
+            neighbors
+        })
+        .collect()
+}
+
+pub fn neighbors_to_csr(neighbors: &Vec<Vec<usize>>) -> (Vec<usize>, Vec<usize>) {
+    let mut indptr: Vec<usize> = Vec::with_capacity(neighbors.len() + 1);
+    let mut indices: Vec<usize> = Vec::with_capacity(neighbors.iter().map(|n| n.len()).sum());
+    indptr.push(0);
+    for neighs in neighbors {
+        indices.extend(neighs);
+        indptr.push(indices.len());
+    }
+    (indptr, indices)
+}
+
+pub fn csr_to_neighbors(indptr: &[usize], indices: &[usize]) -> Result<Vec<Vec<usize>>, String> {
+    if indptr.is_empty() || indptr[0] != 0 || *indptr.last().unwrap() != indices.len() {
+        return Err(format!(
+            "`indptr` should start with 0 and end with the length of `indices` ({}).",
+            indices.len()
+        ));
+    }
+    let mut neighbors = vec![];
+    for (start, end) in indptr.iter().zip(indptr.iter().skip(1)) {
+        if start > end {
+            return Err("`indptr` should be non-decreasing.".to_string());
+        }
+        neighbors.push(indices[*start..*end].to_vec());
+    }
+    Ok(neighbors)
+}
+
+// undirected edges with i < j, self-loops are removed
+pub fn neighbors_to_edges(neighbors: &[Vec<usize>]) -> (Vec<usize>, Vec<usize>) {
+    let mut edges: Vec<(usize, usize)> = neighbors
+        .iter()
+        .enumerate()
+        .flat_map(|(i, neighs)| {
+            neighs
+                .iter()
+                .filter(move |j| **j != i)
+                .map(move |j| (i.min(*j), i.max(*j)))
+        })
+        .collect();
+    edges.par_sort_unstable();
+    edges.dedup();
+    edges.into_iter().unzip()
+}
+
+pub fn edges_to_graph(
+    source: &[usize],
+    target: &Vec<usize>,
+    n: usize,
+) -> Result<Vec<Vec<usize>>, String> {
+    if source.len() != target.len() {
+        return Err(format!(
+            "The length of source ({}) and target ({}) should be the same.",
+            source.len(),
+            target.len()
+        ));
+    }
+    let mut neighbors = vec![vec![]; n];
+    for (i, j) in source.iter().zip(target) {
+        if *i >= n || *j >= n {
+            return Err(format!(
+                "Edge ({}, {}) is out of range for {} points.",
+                i, j, n
+            ));
+        }
+        if i != j {
+            neighbors[*i].push(*j);
+            neighbors[*j].push(*i);
+        }
+    }
+    neighbors.par_iter_mut().for_each(|neighs| {
+        neighs.sort_unstable();
+        neighs.dedup();
+    });
+    Ok(neighbors)
+}
+
+// directed (row, col) pairs, one for each neighbor
+pub fn neighbors_to_coo(neighbors: &[Vec<usize>]) -> (Vec<usize>, Vec<usize>) {
+    neighbors
+        .iter()
+        .enumerate()
+        .flat_map(|(i, neighs)| neighs.iter().map(move |j| (i, *j)))
+        .unzip()
+}
+
+pub fn coo_to_graph(row: &[usize], col: &Vec<usize>, n: usize) -> Result<Vec<Vec<usize>>, String> {
+    if row.len() != col.len() {
+        return Err(format!(
+            "The length of row ({}) and col ({}) should be the same.",
+            row.len(),
+            col.len()
+        ));
+    }
+    let mut neighbors = vec![vec![]; n];
+    for (i, j) in row.iter().zip(col) {
+        if *i >= n || *j >= n {
+            return Err(format!(
+                "Entry ({}, {}) is out of range for {} points.",
+                i, j, n
+            ));
+        }
+        neighbors[*i].push(*j);
+    }
+    neighbors.par_iter_mut().for_each(|neighs| {
+        neighs.sort_unstable();
+        neighs.dedup();
+    });
+    Ok(neighbors)
+}
+
+// snap a point to the grid cell, hex grid uses pointy-top axial coordinates
+pub fn snap_to_grid(p: &(f64, f64), spacing: f64, hex: bool) -> ((i64, i64), (f64, f64)) {
+    if !hex {
+        let cell = (
+            (p.0 / spacing).round() as i64,
+            (p.1 / spacing).round() as i64,
+        );
+        let center = (cell.0 as f64 * spacing, cell.1 as f64 * spacing);
+        return (cell, center);
+    }
+    let size = spacing / 3f64.sqrt();
+    let q = (3f64.sqrt() / 3.0 * p.0 - p.1 / 3.0) / size;
+    let r = (2.0 / 3.0 * p.1) / size;
+    // cube rounding
+    let s = -q - r;
+    let (mut rq, mut rr, rs) = (q.round(), r.round(), s.round());
+    let (dq, dr, ds) = ((rq - q).abs(), (rr - r).abs(), (rs - s).abs());
+    if dq > dr && dq > ds {
+        rq = -rr - rs;
+    } else if dr > ds {
+        rr = -rq - rs;
+    }
+    let center = (size * 3f64.sqrt() * (rq + rr / 2.0), size * 1.5 * rr);
+    ((rq as i64, rr as i64), center)
+}
+
+pub fn grid_neighbors(cells: &[(i64, i64)], hex: bool) -> Vec<Vec<usize>> {
+    let index: HashMap<(i64, i64), usize> =
+        cells.iter().enumerate().map(|(i, c)| (*c, i)).collect();
+    let directions: &[(i64, i64)] = if hex {
+        &[(1, 0), (1, -1), (0, -1), (-1, 0), (-1, 1), (0, 1)]
+    } else {
+        &[(1, 0), (0, -1), (-1, 0), (0, 1)]
+    };
+    cells
+        .iter()
+        .map(|c| {
+            let mut neighbors: Vec<usize> = directions
+                .iter()
+                .filter_map(|d| index.get(&(c.0 + d.0, c.1 + d.1)).cloned())
+                .collect();
+            neighbors.sort_unstable();
+            neighbors
+        })
+        .collect()
+}
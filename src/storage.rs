@@ -0,0 +1,189 @@
+use crate::neighbors::{csr_to_neighbors, neighbors_to_csr};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Error, ErrorKind, Read, Result, Write};
+
+// file layout, all numbers are little-endian u64:
+// magic (4 bytes) | version (1 byte) | n | nnz | indptr (n + 1) | indices (nnz)
+const MAGIC: &[u8; 4] = b"NBRS";
+const VERSION: u8 = 1;
+const HEADER_SIZE: u64 = 4 + 1 + 8 + 8;
+
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg.to_string())
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> Result<u64> {
+    let mut buf = [0u8; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+pub fn write_neighbors(path: &str, neighbors: &Vec<Vec<usize>>) -> Result<()> {
+    let (indptr, indices) = neighbors_to_csr(neighbors);
+    let mut writer = BufWriter::new(File::create(path)?);
+    writer.write_all(MAGIC)?;
+    writer.write_all(&[VERSION])?;
+    writer.write_all(&(neighbors.len() as u64).to_le_bytes())?;
+    writer.write_all(&(indices.len() as u64).to_le_bytes())?;
+    for v in indptr.iter().chain(indices.iter()) {
+        writer.write_all(&(*v as u64).to_le_bytes())?;
+    }
+    writer.flush()
+}
+
+// format errors are reported as ErrorKind::InvalidData
+pub fn read_neighbors(path: &str) -> Result<Vec<Vec<usize>>> {
+    let file = File::open(path)?;
+    let file_size = file.metadata()?.len();
+    let mut reader = BufReader::new(file);
+
+    if file_size < HEADER_SIZE {
+        return Err(invalid("The file is too short to be a neighbors file."));
+    }
+    let mut magic = [0u8; 4];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(invalid("The file is not a neighbors file."));
+    }
+    let mut version = [0u8; 1];
+    reader.read_exact(&mut version)?;
+    if version[0] != VERSION {
+        return Err(invalid(&format!(
+            "Unsupported neighbors file version {}.",
+            version[0]
+        )));
+    }
+
+    let n = read_u64(&mut reader)?;
+    let nnz = read_u64(&mut reader)?;
+    // check the size before allocating anything
+    let expect_size = n
+        .checked_add(1)
+        .and_then(|v| v.checked_add(nnz))
+        .and_then(|v| v.checked_mul(8))
+        .and_then(|v| v.checked_add(HEADER_SIZE));
+    if expect_size != Some(file_size) {
+        return Err(invalid(
+            "The file size doesn't match its header, it may be corrupted.",
+        ));
+    }
+
+    let mut indptr: Vec<usize> = Vec::with_capacity(n as usize + 1);
+    for _ in 0..(n + 1) {
+        indptr.push(read_u64(&mut reader)? as usize);
+    }
+    let mut indices: Vec<usize> = Vec::with_capacity(nnz as usize);
+    for _ in 0..nnz {
+        indices.push(read_u64(&mut reader)? as usize);
+    }
+
+    csr_to_neighbors(&indptr, &indices).map_err(|msg| invalid(&msg))
+}
+
+// the statistics of a pair in CellCombs.bootstrap, one object per pair in the JSON file
+#[derive(Clone, Serialize, Deserialize)]
+pub struct CombsRecord {
+    pub type1: String,
+    pub type2: String,
+    #[serde(with = "json_float")]
+    pub observed: f64,
+    #[serde(with = "json_float")]
+    pub expected: f64,
+    #[serde(with = "json_float")]
+    pub zscore: f64,
+    #[serde(with = "json_float")]
+    pub pvalue: f64,
+    #[serde(with = "json_float")]
+    pub sig: f64,
+}
+
+const COMBS_COLUMNS: [&str; 7] = [
+    "type1", "type2", "observed", "expected", "zscore", "pvalue", "sig",
+];
+
+impl CombsRecord {
+    pub fn pair(&self) -> (&str, &str) {
+        (self.type1.as_str(), self.type2.as_str())
+    }
+
+    fn values(&self) -> [f64; 5] {
+        [
+            self.observed,
+            self.expected,
+            self.zscore,
+            self.pvalue,
+            self.sig,
+        ]
+    }
+}
+
+// JSON has no NaN or infinity, they are written as the strings "NaN", "Infinity" and "-Infinity"
+// like the JSON mapping of protobuf, null is also read as NaN
+mod json_float {
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum JsonFloat {
+        Num(f64),
+        Str(String),
+    }
+
+    pub fn serialize<S: Serializer>(v: &f64, serializer: S) -> Result<S::Ok, S::Error> {
+        if v.is_nan() {
+            serializer.serialize_str("NaN")
+        } else if v.is_infinite() {
+            serializer.serialize_str(if *v > 0.0 { "Infinity" } else { "-Infinity" })
+        } else {
+            serializer.serialize_f64(*v)
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+        match Option::<JsonFloat>::deserialize(deserializer)? {
+            None => Ok(f64::NAN),
+            Some(JsonFloat::Num(v)) => Ok(v),
+            Some(JsonFloat::Str(s)) => match s.as_str() {
+                "NaN" => Ok(f64::NAN),
+                "Infinity" => Ok(f64::INFINITY),
+                "-Infinity" => Ok(f64::NEG_INFINITY),
+                _ => Err(D::Error::custom(format!("invalid number '{}'", s))),
+            },
+        }
+    }
+}
+
+// a list of records, one object per pair
+pub fn write_combs_json(path: &str, records: &[CombsRecord]) -> Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    serde_json::to_writer_pretty(&mut writer, records)?;
+    writer.write_all(b"\n")?;
+    writer.flush()
+}
+
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+pub fn write_combs_csv(path: &str, records: &[CombsRecord]) -> Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    writeln!(writer, "{}", COMBS_COLUMNS.join(","))?;
+    for r in records.iter() {
+        let mut fields = vec![csv_field(&r.type1), csv_field(&r.type2)];
+        fields.extend(r.values().iter().map(|v| format!("{:?}", v)));
+        writeln!(writer, "{}", fields.join(","))?;
+    }
+    writer.flush()
+}
+
+// format errors are reported as ErrorKind::InvalidData
+pub fn read_combs_json(path: &str) -> Result<Vec<CombsRecord>> {
+    let reader = BufReader::new(File::open(path)?);
+    Ok(serde_json::from_reader(reader)?)
+}
@@ -1,45 +1,125 @@
 use counter::Counter;
-use std::collections::HashMap;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{thread_rng, Rng, SeedableRng};
+use rayon::prelude::*;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
 
-pub fn count_neighbors<'a>(
-    types: &Vec<&'a str>,
+// the mean count of the neighbors for each combination, over the centers that have neighbors of the type,
+// and the number of the centers, types are the index of the cell types,
+// neighbors should be the full neighbors of each cell.
+// If ordered, (a, b) counts the b neighbors of the a centers. If not, (a, b) merges both directions,
+// the b neighbors of the a centers and the a neighbors of the b centers.
+// if weights is given, the weights of the neighbors are summed instead of counting,
+// "frequency" divides by the number of cells in center_types, or all the cells if None
+pub fn count_neighbors(
+    types: &Vec<usize>,
     neighbors: &Vec<Vec<usize>>,
-    cell_combs: &Vec<(&'a str, &'a str)>,
+    weights: Option<&Vec<Vec<f64>>>,
+    cell_combs: &Vec<(usize, usize)>,
     order: bool,
-) -> HashMap<(&'a str, &'a str), f64> {
+    stat: &str,
+    center_types: Option<&[usize]>,
+) -> HashMap<(usize, usize), (f64, usize)> {
     let mut storage = cell_combs
         .iter()
         .map(|comb| (comb.to_owned(), vec![]))
-        .collect::<HashMap<(&str, &str), Vec<usize>>>();
+        .collect::<HashMap<(usize, usize), Vec<f64>>>();
 
     for (k, v) in neighbors.iter().enumerate() {
         let cent_type = types[k];
-        let neigh_type: Counter<_> = { v.iter().map(|i| types[*i]).collect::<Counter<_>>() };
+        let neigh_type: HashMap<usize, f64> = match weights {
+            Some(w) => {
+                let mut sums = HashMap::new();
+                for (i, weight) in v.iter().zip(w[k].iter()) {
+                    *sums.entry(types[*i]).or_insert(0.0) += weight;
+                }
+                sums
+            }
+            None => v
+                .iter()
+                .map(|i| types[*i])
+                .collect::<Counter<_>>()
+                .into_map()
+                .into_iter()
+                .map(|(t, c)| (t, c as f64))
+                .collect(),
+        };
         for (nt, c) in neigh_type.iter() {
             let comb = (cent_type, *nt);
             let reverse_comb = (*nt, cent_type);
-            let count = *c;
-            if order {
-                storage.get_mut(&comb).unwrap().push(count);
-                storage.get_mut(&reverse_comb).unwrap().push(count);
-            } else {
-                match storage.get_mut(&comb) {
-                    None => storage.get_mut(&reverse_comb).unwrap().push(count * 2),
-                    Some(s) => s.push(count * 2),
-                };
+            // the combinations that are not selected are skipped
+            if let Some(s) = storage.get_mut(&comb) {
+                s.push(*c);
+            } else if !order {
+                if let Some(s) = storage.get_mut(&reverse_comb) {
+                    s.push(*c);
+                }
             }
         }
     }
 
-    let mut results: HashMap<(&'a str, &'a str), f64> = HashMap::new();
+    // "frequency" is the fraction of the center cells that have at least one neighbor of the other type,
+    // the unordered pair counts the center cells of both types
+    let type_counts: Counter<usize> = if stat == "frequency" {
+        match center_types {
+            Some(centers) => centers.iter().cloned().collect(),
+            None => types.iter().cloned().collect(),
+        }
+    } else {
+        Counter::new()
+    };
+    let mut results: HashMap<(usize, usize), (f64, usize)> = HashMap::new();
     for (k, v) in storage.iter() {
-        results.insert(k.to_owned(), mean(&v));
+        let value = match stat {
+            "sum" => v.iter().sum::<f64>(),
+            "frequency" => {
+                let mut centers = type_counts[&k.0];
+                if !order && k.0 != k.1 {
+                    centers += type_counts[&k.1];
+                }
+                if centers > 0 {
+                    v.len() as f64 / centers as f64
+                } else {
+                    0.0
+                }
+            }
+            _ => mean_f(&v),
+        };
+        results.insert(k.to_owned(), (value, v.len()));
     }
 
     results
 }
 
-pub fn comb_count_neighbors(x: &Vec<bool>, y: &Vec<bool>, neighbors: &Vec<Vec<usize>>) -> usize {
+// the number of neighbors of the target type of each cell
+pub fn cell_target_counts(types: &[usize], neighbors: &[Vec<usize>], target: usize) -> Vec<f64> {
+    neighbors
+        .iter()
+        .map(|v| v.iter().filter(|i| types[**i] == target).count() as f64)
+        .collect()
+}
+
+// resample the center cells with replacement, the resampled centers are appended after the
+// original cells without neighbors, so the neighbors still point to the original cells
+pub fn resample_centers<R: Rng>(
+    types: &Vec<usize>,
+    neighbors: &[Vec<usize>],
+    rng: &mut R,
+) -> (Vec<usize>, Vec<Vec<usize>>) {
+    let n = types.len();
+    let mut resampled_types = types.to_owned();
+    let mut resampled_neighbors = vec![vec![]; n];
+    for _ in 0..n {
+        let i = rng.gen_range(0..n);
+        resampled_types.push(types[i]);
+        resampled_neighbors.push(neighbors.get(i).cloned().unwrap_or_default());
+    }
+    (resampled_types, resampled_neighbors)
+}
+
+pub fn comb_count_neighbors(x: &[bool], y: &[bool], neighbors: &[Vec<usize>]) -> usize {
     let mut count: usize = 0;
 
     for (k, v) in neighbors.iter().enumerate() {
@@ -54,6 +134,352 @@ pub fn comb_count_neighbors(x: &Vec<bool>, y: &Vec<bool>, neighbors: &Vec<Vec<us
     count
 }
 
+// the count of each type in the neighbors of a cell, types are the index of the type
+pub fn composition_row(types: &[usize], neighs: &Vec<usize>, n_types: usize) -> Vec<f64> {
+    let mut row = vec![0.0; n_types];
+    for j in neighs {
+        row[types[*j]] += 1.0;
+    }
+    row
+}
+
+// the Shannon entropy of the counts in the given log base, NaN if all the counts are 0
+pub fn shannon_entropy(counts: &[f64], base: f64) -> f64 {
+    let total: f64 = counts.iter().sum();
+    if total == 0.0 {
+        return f64::NAN;
+    }
+    -counts
+        .iter()
+        .filter(|c| **c > 0.0)
+        .map(|c| c / total * (c / total).log(base))
+        .sum::<f64>()
+}
+
+fn squared_dist(a: &[f64], b: &[f64]) -> f64 {
+    a.iter().zip(b.iter()).map(|(x, y)| (x - y) * (x - y)).sum()
+}
+
+// the index of the nearest centroid, the first one if tied
+fn nearest_centroid(row: &[f64], centroids: &[Vec<f64>]) -> usize {
+    let mut best = (0, f64::INFINITY);
+    for (c, centroid) in centroids.iter().enumerate() {
+        let d = squared_dist(row, centroid);
+        if d < best.1 {
+            best = (c, d);
+        }
+    }
+    best.0
+}
+
+// k-means++ init, each new centroid is drawn with the probability of the squared distance to
+// the nearest chosen one, or uniformly if all the rows are already chosen
+fn kmeans_init<R: Rng>(rows: &[Vec<f64>], k: usize, rng: &mut R) -> Vec<Vec<f64>> {
+    let mut centroids = vec![rows[rng.gen_range(0..rows.len())].to_owned()];
+    let mut dists: Vec<f64> = rows
+        .iter()
+        .map(|r| squared_dist(r, &centroids[0]))
+        .collect();
+    while centroids.len() < k {
+        let total: f64 = dists.iter().sum();
+        let next = if total > 0.0 {
+            let mut target = rng.gen::<f64>() * total;
+            // the last row with a positive distance in case of the rounding error
+            let mut chosen = 0;
+            for (i, d) in dists.iter().enumerate().filter(|(_, d)| **d > 0.0) {
+                chosen = i;
+                if target < *d {
+                    break;
+                }
+                target -= d;
+            }
+            chosen
+        } else {
+            rng.gen_range(0..rows.len())
+        };
+        centroids.push(rows[next].to_owned());
+        let last = centroids.last().unwrap();
+        dists
+            .iter_mut()
+            .zip(rows.iter())
+            .for_each(|(d, r)| *d = d.min(squared_dist(r, last)));
+    }
+    centroids
+}
+
+// Lloyd's algorithm from the k-means++ init, until no label changes or n_iter is reached,
+// the centroid of an empty cluster stays in place.
+// Return the labels, the centroids and the number of iterations
+pub fn kmeans<R: Rng>(
+    rows: &Vec<Vec<f64>>,
+    k: usize,
+    n_iter: usize,
+    rng: &mut R,
+) -> (Vec<usize>, Vec<Vec<f64>>, usize) {
+    let dim = rows.first().map_or(0, |r| r.len());
+    let mut centroids = kmeans_init(rows, k, rng);
+    let mut labels: Vec<usize> = vec![];
+    let mut iterations = 0;
+    while iterations < n_iter {
+        iterations += 1;
+        let new_labels: Vec<usize> = rows
+            .par_iter()
+            .map(|r| nearest_centroid(r, &centroids))
+            .collect();
+        if new_labels == labels {
+            break;
+        }
+        labels = new_labels;
+        let mut sums = vec![vec![0.0; dim]; k];
+        let mut sizes = vec![0; k];
+        for (r, l) in rows.iter().zip(labels.iter()) {
+            sizes[*l] += 1;
+            sums[*l].iter_mut().zip(r.iter()).for_each(|(s, v)| *s += v);
+        }
+        for ((centroid, sum), size) in centroids.iter_mut().zip(sums).zip(sizes) {
+            if size > 0 {
+                *centroid = sum.into_iter().map(|s| s / size as f64).collect();
+            }
+        }
+    }
+    (labels, centroids, iterations)
+}
+
+// counts of all the marker pairs, counts[a * m + b] is the count of x as marker a and y as marker b,
+// the y status of cell j is taken from cell perm[j]
+pub fn comb_count_matrix(
+    markers: &[Vec<bool>],
+    perm: &[usize],
+    neighbors: &[Vec<usize>],
+) -> Vec<usize> {
+    let m = markers.first().map_or(0, |row| row.len());
+    let mut counts = vec![0; m * m];
+    let mut neigh_counts = vec![0; m];
+    for (i, neighs) in neighbors.iter().enumerate() {
+        if !markers[i].iter().any(|v| *v) {
+            continue;
+        }
+        neigh_counts.iter_mut().for_each(|c| *c = 0);
+        for j in neighs {
+            for (b, v) in markers[perm[*j]].iter().enumerate() {
+                if *v {
+                    neigh_counts[b] += 1;
+                }
+            }
+        }
+        for (a, v) in markers[i].iter().enumerate() {
+            if *v {
+                for b in 0..m {
+                    counts[a * m + b] += neigh_counts[b];
+                }
+            }
+        }
+    }
+    counts
+}
+
+// sum of x[i] * y[j] over the neighbors
+pub fn product_stat(x: &[f64], y: &[f64], neighbors: &[Vec<usize>]) -> f64 {
+    neighbors
+        .iter()
+        .enumerate()
+        .map(|(i, neighs)| x[i] * neighs.iter().map(|j| y[*j]).sum::<f64>())
+        .sum()
+}
+
+// pearson correlation between x[i] and the mean of y of its neighbors,
+// cells without neighbors are not counted
+pub fn pearson_stat(x: &[f64], y: &[f64], neighbors: &[Vec<usize>]) -> f64 {
+    let (a, b): (Vec<f64>, Vec<f64>) = neighbors
+        .iter()
+        .enumerate()
+        .filter(|(_, neighs)| !neighs.is_empty())
+        .map(|(i, neighs)| {
+            let m = neighs.iter().map(|j| y[*j]).sum::<f64>() / neighs.len() as f64;
+            (x[i], m)
+        })
+        .unzip();
+    let ma = mean_f(&a);
+    let mb = mean_f(&b);
+    let mut cov = 0.0;
+    let mut va = 0.0;
+    let mut vb = 0.0;
+    for (i, j) in a.iter().zip(b.iter()) {
+        cov += (i - ma) * (j - mb);
+        va += (i - ma) * (i - ma);
+        vb += (j - mb) * (j - mb);
+    }
+    cov / (va * vb).sqrt()
+}
+
+// Moran's I over the weighted edges (i, j, w_ij)
+pub fn moran_stat(values: &[f64], edges: &[(usize, usize, f64)]) -> f64 {
+    let n = values.len() as f64;
+    let m = mean_f(values);
+    let s0: f64 = edges.iter().map(|e| e.2).sum();
+    let cross: f64 = edges
+        .iter()
+        .map(|(i, j, w)| w * (values[*i] - m) * (values[*j] - m))
+        .sum();
+    let var: f64 = values.iter().map(|v| (v - m) * (v - m)).sum();
+    n / s0 * cross / var
+}
+
+// the sums S0, S1 and S2 of the weights and the kurtosis of the values for the moments of
+// Moran's I and Geary's C under the randomization assumption
+fn weight_moments(values: &[f64], edges: &[(usize, usize, f64)]) -> (f64, f64, f64, f64) {
+    let mut weights: HashMap<(usize, usize), f64> = HashMap::new();
+    let mut row_col = vec![0.0; values.len()];
+    for (i, j, w) in edges {
+        *weights.entry((*i, *j)).or_insert(0.0) += w;
+        row_col[*i] += w;
+        row_col[*j] += w;
+    }
+    let s0: f64 = edges.iter().map(|e| e.2).sum();
+    // (w_ij + w_ji)^2 over all the ordered pairs, the pairs in one direction only are counted twice
+    let s1: f64 = weights
+        .iter()
+        .map(|((i, j), w)| match weights.get(&(*j, *i)) {
+            Some(w_back) => (w + w_back).powi(2),
+            None => 2.0 * w * w,
+        })
+        .sum::<f64>()
+        / 2.0;
+    let s2: f64 = row_col.iter().map(|v| v * v).sum();
+    let n = values.len() as f64;
+    let m = mean_f(values);
+    let m2: f64 = values.iter().map(|v| (v - m).powi(2)).sum();
+    let m4: f64 = values.iter().map(|v| (v - m).powi(4)).sum();
+    (s0, s1, s2, n * m4 / (m2 * m2))
+}
+
+// the expectation and the variance of Moran's I under the randomization assumption
+pub fn moran_moments(values: &[f64], edges: &[(usize, usize, f64)]) -> (f64, f64) {
+    let n = values.len() as f64;
+    let expected = -1.0 / (n - 1.0);
+    let (s0, s1, s2, k) = weight_moments(values, edges);
+    let variance = (n * ((n * n - 3.0 * n + 3.0) * s1 - n * s2 + 3.0 * s0 * s0)
+        - k * ((n * n - n) * s1 - 2.0 * n * s2 + 6.0 * s0 * s0))
+        / ((n - 1.0) * (n - 2.0) * (n - 3.0) * s0 * s0)
+        - expected * expected;
+    (expected, variance)
+}
+
+// Geary's C over the weighted edges (i, j, w_ij)
+pub fn geary_stat(values: &[f64], edges: &[(usize, usize, f64)]) -> f64 {
+    let n = values.len() as f64;
+    let m = mean_f(values);
+    let s0: f64 = edges.iter().map(|e| e.2).sum();
+    let diff: f64 = edges
+        .iter()
+        .map(|(i, j, w)| w * (values[*i] - values[*j]).powi(2))
+        .sum();
+    let var: f64 = values.iter().map(|v| (v - m) * (v - m)).sum();
+    (n - 1.0) * diff / (2.0 * s0 * var)
+}
+
+// the expectation and the variance of Geary's C under the randomization assumption
+pub fn geary_moments(values: &[f64], edges: &[(usize, usize, f64)]) -> (f64, f64) {
+    let n = values.len() as f64;
+    let (s0, s1, s2, k) = weight_moments(values, edges);
+    let variance = ((n - 1.0) * s1 * (n * n - 3.0 * n + 3.0 - (n - 1.0) * k)
+        - 0.25 * (n - 1.0) * s2 * (n * n + 3.0 * n - 6.0 - (n * n - n + 2.0) * k)
+        + s0 * s0 * (n * n - 3.0 - (n - 1.0).powi(2) * k))
+        / (n * (n - 2.0) * (n - 3.0) * s0 * s0);
+    (1.0, variance)
+}
+
+// the join counts of the undirected edges, the count of types (a, b) with a <= b is at a * n_types + b
+pub fn join_count_stat(
+    types: &[usize],
+    sources: &[usize],
+    targets: &[usize],
+    n_types: usize,
+) -> Vec<usize> {
+    let mut counts = vec![0; n_types * n_types];
+    for (i, j) in sources.iter().zip(targets.iter()) {
+        let (a, b) = (types[*i], types[*j]);
+        counts[a.min(b) * n_types + a.max(b)] += 1;
+    }
+    counts
+}
+
+// Newman's assortativity coefficient from the join counts, each edge adds half of a cross count to
+// both e_ab and e_ba, NaN if there is only one type in the edges
+pub fn assortativity_stat(counts: &[usize], n_types: usize) -> f64 {
+    let total = counts.iter().sum::<usize>() as f64;
+    let mut trace = 0.0;
+    let mut ends = vec![0.0; n_types];
+    for a in 0..n_types {
+        for b in a..n_types {
+            let e = counts[a * n_types + b] as f64 / total;
+            if a == b {
+                trace += e;
+                ends[a] += e;
+            } else {
+                ends[a] += e / 2.0;
+                ends[b] += e / 2.0;
+            }
+        }
+    }
+    let expected: f64 = ends.iter().map(|v| v * v).sum();
+    (trace - expected) / (1.0 - expected)
+}
+
+// count the neighbor pairs that one is x and the other is y, each pair is counted once,
+// the neighbors should be symmetric and contain only j >= i
+pub fn comb_count_pairs(x: &[bool], y: &[bool], neighbors: &[Vec<usize>]) -> usize {
+    let mut count: usize = 0;
+
+    for (k, v) in neighbors.iter().enumerate() {
+        for c in v.iter() {
+            if (x[k] && y[*c]) || (y[k] && x[*c]) {
+                count += 1
+            }
+        }
+    }
+    count
+}
+
+// count the x cells that have at least one y neighbor
+pub fn comb_count_cells(x: &[bool], y: &[bool], neighbors: &[Vec<usize>]) -> usize {
+    neighbors
+        .iter()
+        .enumerate()
+        .filter(|(k, v)| x[*k] && v.iter().any(|c| y[*c]))
+        .count()
+}
+
+// sum of w * x[i] * y[j] over the weighted neighbors
+pub fn comb_weight_neighbors(x: &[f64], y: &[f64], neighbors: &[Vec<(usize, f64)>]) -> f64 {
+    let mut total: f64 = 0.0;
+
+    for (k, v) in neighbors.iter().enumerate() {
+        if x[k] != 0.0 {
+            let s: f64 = v.iter().map(|(c, w)| w * y[*c]).sum();
+            total += x[k] * s
+        }
+    }
+    total
+}
+
+// same as remove_rep_neighbors, the weights are kept with the neighbors
+pub fn remove_rep_weighted(
+    rep_neighbors: Vec<Vec<(usize, f64)>>,
+    ignore_self: bool,
+) -> Vec<Vec<(usize, f64)>> {
+    rep_neighbors
+        .into_iter()
+        .enumerate()
+        .map(|(i, neighs)| {
+            neighs
+                .into_iter()
+                .filter(|(cell, _)| if ignore_self { *cell > i } else { *cell >= i })
+                .collect()
+        })
+        .collect()
+}
+
 pub fn remove_rep_neighbors(rep_neighbors: Vec<Vec<usize>>, ignore_self: bool) -> Vec<Vec<usize>> {
     let mut neighbors = vec![];
     for (i, neighs) in rep_neighbors.iter().enumerate() {
@@ -78,6 +504,378 @@ pub fn remove_rep_neighbors(rep_neighbors: Vec<Vec<usize>>, ignore_self: bool) -
     neighbors
 }
 
+// the rng of the i-th permutation, derived from the seed so the result doesn't depend on the scheduling
+pub fn perm_rng(seed: Option<u64>, i: usize) -> StdRng {
+    match seed {
+        Some(s) => StdRng::seed_from_u64(s.wrapping_add(i as u64)),
+        None => StdRng::from_rng(thread_rng()).unwrap(),
+    }
+}
+
+// two-sided, (number of permutations at least as extreme + 1) / (times + 1)
+pub fn empirical_pvalue(real: f64, perm: &[f64], m: f64) -> f64 {
+    let diff = (real - m).abs();
+    let extreme = perm.iter().filter(|v| (**v - m).abs() >= diff).count();
+    (extreme as f64 + 1.0) / (perm.len() as f64 + 1.0)
+}
+
+// the statistics of the permutations against the real value, updated as each permutation completes,
+// the mean and variance by Welford's algorithm, the values are only kept if needed
+pub struct PermStats {
+    pub real: f64,
+    pub n: usize,
+    pub mean: f64,
+    m2: f64,
+    greater: usize,
+    less: usize,
+    pub values: Option<Vec<f64>>,
+    pub histogram: Option<Histogram>,
+}
+
+impl PermStats {
+    pub fn new(real: f64) -> Self {
+        PermStats {
+            real,
+            n: 0,
+            mean: 0.0,
+            m2: 0.0,
+            greater: 0,
+            less: 0,
+            values: None,
+            histogram: None,
+        }
+    }
+
+    pub fn push(&mut self, v: f64) {
+        self.n += 1;
+        let diff = v - self.mean;
+        self.mean += diff / self.n as f64;
+        self.m2 += diff * (v - self.mean);
+        if v >= self.real {
+            self.greater += 1
+        }
+        if v <= self.real {
+            self.less += 1
+        }
+        if let Some(values) = &mut self.values {
+            values.push(v)
+        }
+        if let Some(histogram) = &mut self.histogram {
+            histogram.push(v)
+        }
+    }
+
+    // the population std, the same as std_f
+    pub fn std(&self) -> f64 {
+        if self.n > 0 {
+            (self.m2 / self.n as f64).sqrt()
+        } else {
+            0.0
+        }
+    }
+
+    // the one-sided p-value of the smaller side and the direction, 1.0 for greater and -1.0 for less,
+    // when both sides are the same (eg. all the permutations equal to real) the direction is 0.0
+    pub fn directional_pvalue(&self) -> (f64, f64) {
+        let n = self.n as f64 + 1.0;
+        let p_greater = (self.greater as f64 + 1.0) / n;
+        let p_less = (self.less as f64 + 1.0) / n;
+        if p_greater < p_less {
+            (p_greater, 1.0)
+        } else if p_less < p_greater {
+            (p_less, -1.0)
+        } else {
+            (p_greater, 0.0)
+        }
+    }
+
+    // the two-sided empirical p-value around the mean, NaN if the values are not kept
+    pub fn empirical_pvalue(&self) -> f64 {
+        match &self.values {
+            Some(values) => empirical_pvalue(self.real, values, self.mean),
+            None => f64::NAN,
+        }
+    }
+}
+
+// the distinct values are counted exactly up to this number, then they are counted in the bins
+const EXACT_VALUES: usize = 1024;
+// the number of the fine bins in each output bin, once the values are counted in the bins
+const FINE_BINS: usize = 16;
+
+// a fixed number of equal-width bins from the min to the max of the values pushed so far, like numpy.histogram.
+// The distinct values are counted until there are too many of them, then they are counted in the fine bins,
+// the range of the fine bins starts from the min to the max and is doubled (each two neighboring bins are merged)
+// until a new value is in the range, so the memory is bounded. The fine bins are placed into the output bins
+// by their centers, a value is placed at most one fine bin away from its bin.
+pub struct Histogram {
+    pub min: f64,
+    pub max: f64,
+    bins: usize,
+    values: Option<Vec<(f64, usize)>>,
+    start: f64,
+    end: f64,
+    counts: Vec<usize>,
+}
+
+impl Histogram {
+    pub fn new(bins: usize) -> Self {
+        Histogram {
+            min: f64::NAN,
+            max: f64::NAN,
+            bins,
+            values: Some(vec![]),
+            start: f64::NAN,
+            end: f64::NAN,
+            counts: vec![],
+        }
+    }
+
+    pub fn push(&mut self, v: f64) {
+        if !v.is_finite() {
+            return;
+        }
+        if self.min.is_nan() {
+            self.min = v;
+            self.max = v;
+        }
+        self.min = self.min.min(v);
+        self.max = self.max.max(v);
+        let values = match &mut self.values {
+            Some(values) => values,
+            None => return self.push_fine(v, 1),
+        };
+        match values.binary_search_by(|(x, _)| x.total_cmp(&v)) {
+            Ok(i) => values[i].1 += 1,
+            Err(i) => values.insert(i, (v, 1)),
+        }
+        if values.len() > EXACT_VALUES {
+            let values = self.values.take().unwrap();
+            self.start = self.min;
+            self.end = self.max;
+            self.counts = vec![0; self.bins * FINE_BINS];
+            values.iter().for_each(|(x, n)| self.push_fine(*x, *n));
+        }
+    }
+
+    fn push_fine(&mut self, v: f64, n: usize) {
+        let fine_bins = self.counts.len();
+        while v < self.start {
+            let mut counts = vec![0; fine_bins];
+            for (i, c) in self.counts.iter().enumerate() {
+                counts[(fine_bins + i) / 2] += c;
+            }
+            self.start -= self.end - self.start;
+            self.counts = counts;
+        }
+        while v > self.end {
+            let mut counts = vec![0; fine_bins];
+            for (i, c) in self.counts.iter().enumerate() {
+                counts[i / 2] += c;
+            }
+            self.end += self.end - self.start;
+            self.counts = counts;
+        }
+        let i = ((v - self.start) / (self.end - self.start) * fine_bins as f64).floor();
+        self.counts[(i.max(0.0) as usize).min(fine_bins - 1)] += n;
+    }
+
+    // the counts and the edges of the bins, if all the values are the same,
+    // the range is the value +/- 0.5 like numpy.histogram
+    pub fn result(&self) -> (Vec<usize>, Vec<f64>) {
+        let bins = self.bins;
+        let mut counts = vec![0; bins];
+        let (start, end) = if self.min == self.max {
+            (self.min - 0.5, self.min + 0.5)
+        } else {
+            (self.min, self.max)
+        };
+        let mut place = |v: f64, n: usize| {
+            let i = ((v - start) / (end - start) * bins as f64).floor();
+            counts[(i.max(0.0) as usize).min(bins - 1)] += n;
+        };
+        match &self.values {
+            Some(values) => values.iter().for_each(|(x, n)| place(*x, *n)),
+            None => {
+                let width = (self.end - self.start) / self.counts.len() as f64;
+                for (i, n) in self.counts.iter().enumerate() {
+                    let center = self.start + (i as f64 + 0.5) * width;
+                    place(center.max(self.min).min(self.max), *n);
+                }
+            }
+        }
+        let width = (end - start) / bins as f64;
+        let mut edges: Vec<f64> = (0..bins).map(|i| start + i as f64 * width).collect();
+        edges.push(end);
+        (counts, edges)
+    }
+}
+
+// "fdr_bh" for Benjamini-Hochberg or "bonferroni", the adjusted p-values are capped at 1
+pub fn adjust_pvalues(pvalues: &[f64], method: &str) -> Vec<f64> {
+    // NaN p-values stay in place and are not counted as tests
+    let mut order: Vec<usize> = (0..pvalues.len())
+        .filter(|i| !pvalues[*i].is_nan())
+        .collect();
+    let n = order.len() as f64;
+    if method == "bonferroni" {
+        return pvalues
+            .iter()
+            .map(|p| if p.is_nan() { *p } else { (p * n).min(1.0) })
+            .collect();
+    }
+    order.sort_by(|a, b| pvalues[*a].total_cmp(&pvalues[*b]));
+    let mut adjusted = vec![f64::NAN; pvalues.len()];
+    // the running min from the largest p-value keeps the adjusted p-values monotone
+    let mut running: f64 = 1.0;
+    for (rank, i) in order.iter().enumerate().rev() {
+        running = running.min(pvalues[*i] * n / (rank as f64 + 1.0));
+        adjusted[*i] = running;
+    }
+    adjusted
+}
+
+// the index of cells in each group, ordered by the group label
+pub fn group_members<K: Ord + Copy>(groups: &[K]) -> Vec<Vec<usize>> {
+    let mut members: BTreeMap<K, Vec<usize>> = BTreeMap::new();
+    for (i, g) in groups.iter().enumerate() {
+        members.entry(*g).or_default().push(i);
+    }
+    members.into_values().collect()
+}
+
+// shuffle the values only within each group
+pub fn shuffle_within<T: Clone, R: Rng>(values: &mut [T], members: &Vec<Vec<usize>>, rng: &mut R) {
+    for m in members {
+        let mut group_values: Vec<T> = m.iter().map(|i| values[*i].clone()).collect();
+        group_values.shuffle(rng);
+        for (i, v) in m.iter().zip(group_values) {
+            values[*i] = v;
+        }
+    }
+}
+
+// shuffle x, y or both independently, the vector that is not shuffled is borrowed
+pub fn shuffle_status<'a, T: Clone, R: Rng>(
+    x: &'a [T],
+    y: &'a [T],
+    mode: &str,
+    members: &Option<Vec<Vec<usize>>>,
+    rng: &mut R,
+) -> (Cow<'a, [T]>, Cow<'a, [T]>) {
+    let mut shuffle = |values: &[T]| {
+        let mut values = values.to_vec();
+        match members {
+            Some(m) => shuffle_within(&mut values, m, rng),
+            None => values.shuffle(rng),
+        }
+        values
+    };
+    match mode {
+        "x" => (Cow::Owned(shuffle(x)), Cow::Borrowed(y)),
+        "both" => {
+            let shuffle_x = shuffle(x);
+            (Cow::Owned(shuffle_x), Cow::Owned(shuffle(y)))
+        }
+        _ => (Cow::Borrowed(x), Cow::Owned(shuffle(y))),
+    }
+}
+
+// the lower bound of the 95% confidence interval of the running p-value is above alpha
+pub fn clearly_insignificant(real: f64, perm: &Vec<usize>, alpha: f64) -> bool {
+    let n = perm.len() as f64;
+    let m = mean(perm);
+    let diff = (real - m).abs();
+    let extreme = perm
+        .iter()
+        .filter(|v| (**v as f64 - m).abs() >= diff)
+        .count() as f64;
+    let p = extreme / n;
+    p - 1.96 * (p * (1.0 - p) / n).sqrt() > alpha
+}
+
+// the sum and the sum of squares of counts, it can be reduced in any order
+pub fn count_sums(numbers: &[usize]) -> (usize, u128, u128) {
+    numbers.iter().fold((0, 0, 0), |acc, v| add_count(acc, *v))
+}
+
+pub fn add_count(acc: (usize, u128, u128), v: usize) -> (usize, u128, u128) {
+    (
+        acc.0 + 1,
+        acc.1 + v as u128,
+        acc.2 + (v as u128) * (v as u128),
+    )
+}
+
+// mean and population std from the sums, the variance is exact before the division
+pub fn sums_mean_std(sums: (usize, u128, u128)) -> (f64, f64) {
+    let (n, sum, sum_sq) = sums;
+    if n == 0 {
+        return (0.0, 0.0);
+    }
+    let n = n as u128;
+    let var = (n * sum_sq - sum * sum) as f64 / (n * n) as f64;
+    (sum as f64 / n as f64, var.sqrt())
+}
+
+// the load of each cell as a neighbor of x, sum(a[j] * y[j]) equals comb_weight_neighbors
+pub fn neighbor_loads(x: &[f64], neighbors: &[Vec<(usize, f64)>], n: usize) -> Vec<f64> {
+    let mut loads = vec![0.0; n];
+    for (k, v) in neighbors.iter().enumerate() {
+        if x[k] != 0.0 {
+            for (c, w) in v {
+                loads[*c] += x[k] * w;
+            }
+        }
+    }
+    loads
+}
+
+// the mean and variance of sum(a[j] * y[j]) when y is shuffled within each group,
+// for each group, var = sum((a - mean(a))^2) * sum((y - mean(y))^2) / (n - 1)
+pub fn shuffle_moments(a: &[f64], y: &[f64], members: &Vec<Vec<usize>>) -> (f64, f64) {
+    let mut mean = 0.0;
+    let mut var = 0.0;
+    for m in members {
+        let n = m.len() as f64;
+        let ma = m.iter().map(|i| a[*i]).sum::<f64>() / n;
+        let my = m.iter().map(|i| y[*i]).sum::<f64>() / n;
+        mean += n * ma * my;
+        if m.len() > 1 {
+            let saa: f64 = m.iter().map(|i| (a[*i] - ma) * (a[*i] - ma)).sum();
+            let syy: f64 = m.iter().map(|i| (y[*i] - my) * (y[*i] - my)).sum();
+            var += saa * syy / (n - 1.0);
+        }
+    }
+    (mean, var)
+}
+
+// two-sided p-value of the standard normal distribution
+pub fn normal_pvalue(z: f64) -> f64 {
+    erfc(z.abs() / std::f64::consts::SQRT_2)
+}
+
+// complementary error function with fractional error less than 1.2e-7
+fn erfc(x: f64) -> f64 {
+    let z = x.abs();
+    let t = 1.0 / (1.0 + 0.5 * z);
+    let r = t
+        * (-z * z - 1.26551223
+            + t * (1.00002368
+                + t * (0.37409196
+                    + t * (0.09678418
+                        + t * (-0.18628806
+                            + t * (0.27886807
+                                + t * (-1.13520398
+                                    + t * (1.48851587 + t * (-0.82215223 + t * 0.17087277)))))))))
+            .exp();
+    if x >= 0.0 {
+        r
+    } else {
+        2.0 - r
+    }
+}
+
 pub fn mean(numbers: &Vec<usize>) -> f64 {
     let l = numbers.len();
     if l > 0 {
@@ -88,7 +886,26 @@ pub fn mean(numbers: &Vec<usize>) -> f64 {
     }
 }
 
-pub fn mean_f(numbers: &Vec<f64>) -> f64 {
+// the fraction of the sorted values that are at most each of the thresholds
+pub fn empirical_cdf(sorted: &[f64], thresholds: &[f64]) -> Vec<f64> {
+    thresholds
+        .iter()
+        .map(|t| sorted.partition_point(|v| v <= t) as f64 / sorted.len() as f64)
+        .collect()
+}
+
+// the q-th quantile of the sorted values with linear interpolation
+pub fn quantile(sorted: &[f64], q: f64) -> f64 {
+    if sorted.is_empty() {
+        return f64::NAN;
+    }
+    let pos = q * (sorted.len() - 1) as f64;
+    let lo = pos.floor() as usize;
+    let hi = pos.ceil() as usize;
+    sorted[lo] + (sorted[hi] - sorted[lo]) * (pos - lo as f64)
+}
+
+pub fn mean_f(numbers: &[f64]) -> f64 {
     let l = numbers.len();
     if l > 0 {
         let sum: f64 = numbers.iter().sum();
@@ -98,10 +915,10 @@ pub fn mean_f(numbers: &Vec<f64>) -> f64 {
     }
 }
 
-pub fn std(numbers: &Vec<usize>) -> f64 {
+pub fn std_f(numbers: &Vec<f64>) -> f64 {
     let l = numbers.len();
     if l > 0 {
-        let m = mean(numbers);
+        let m = mean_f(numbers);
         let variance = numbers
             .iter()
             .map(|value| {
@@ -116,20 +933,79 @@ pub fn std(numbers: &Vec<usize>) -> f64 {
     }
 }
 
-pub fn std_f(numbers: &Vec<f64>) -> f64 {
-    let l = numbers.len();
-    if l > 0 {
-        let m = mean_f(numbers);
-        let variance = numbers
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn perm_stats_match_two_pass() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let values: Vec<f64> = (0..1000).map(|_| 1e6 + rng.gen::<f64>()).collect();
+        let mut stats = PermStats::new(1e6 + 0.5);
+        values.iter().for_each(|v| stats.push(*v));
+
+        assert_eq!(stats.n, values.len());
+        assert!((stats.mean - mean_f(&values)).abs() < 1e-9);
+        assert!((stats.std() - std_f(&values)).abs() < 1e-9);
+    }
+
+    // the bins of numpy.histogram over the min to the max
+    fn exact_bins(values: &[f64], bins: usize) -> Vec<usize> {
+        let (min, max) = values
             .iter()
-            .map(|value| {
-                let diff = m - (*value as f64);
-                diff * diff
-            })
-            .sum::<f64>()
-            / l as f64;
-        variance.sqrt()
-    } else {
-        0.0
+            .fold((f64::INFINITY, f64::NEG_INFINITY), |(lo, hi), v| {
+                (lo.min(*v), hi.max(*v))
+            });
+        let mut counts = vec![0; bins];
+        for v in values {
+            let i = ((v - min) / (max - min) * bins as f64).floor() as usize;
+            counts[i.min(bins - 1)] += 1;
+        }
+        counts
+    }
+
+    #[test]
+    fn histogram_bins_cover_min_to_max() {
+        // few distinct values are placed exactly, the range is from the first values and doubled on the way
+        let values: Vec<f64> = [5.0, 6.0, 2.0, 9.0, 4.0, 6.0, 3.0, 12.0, 7.0, 6.0].to_vec();
+        let mut hist = Histogram::new(5);
+        values.iter().for_each(|v| hist.push(*v));
+        let (counts, edges) = hist.result();
+        assert_eq!(edges, vec![2.0, 4.0, 6.0, 8.0, 10.0, 12.0]);
+        assert_eq!(counts, vec![2, 2, 4, 1, 1]);
+        assert_eq!(counts, exact_bins(&values, 5));
+
+        let mut same = Histogram::new(4);
+        (0..3).for_each(|_| same.push(1.0));
+        assert_eq!(
+            same.result(),
+            (vec![0, 0, 3, 0], vec![0.5, 0.75, 1.0, 1.25, 1.5])
+        );
+    }
+
+    #[test]
+    fn histogram_many_values_are_rebinned() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let values: Vec<f64> = (0..20000)
+            .map(|i| rng.gen::<f64>() * (1.0 + i as f64 / 1000.0))
+            .collect();
+        let bins = 10;
+        let mut hist = Histogram::new(bins);
+        values.iter().for_each(|v| hist.push(*v));
+        let (counts, edges) = hist.result();
+        assert_eq!(edges[0], hist.min);
+        assert_eq!(edges[bins], hist.max);
+        assert_eq!(counts.iter().sum::<usize>(), values.len());
+        // only the values near the edges can be placed in the neighboring bins
+        let tolerance = 4.0 * (hist.max - hist.min) / (bins * FINE_BINS) as f64;
+        for (i, (c, e)) in counts.iter().zip(exact_bins(&values, bins)).enumerate() {
+            let near = values
+                .iter()
+                .filter(|v| {
+                    (**v - edges[i]).abs() < tolerance || (**v - edges[i + 1]).abs() < tolerance
+                })
+                .count();
+            assert!(c.abs_diff(e) <= near);
+        }
     }
 }
@@ -1,11 +1,71 @@
 import numpy as np
 from neighborhood_analysis import (CellCombs,
+                                   CombsTable,
                                    get_bbox,
                                    get_point_neighbors,
+                                   get_neighbors_batch,
+                                   get_neighbors_knn,
+                                   get_neighbors_mutual_knn,
+                                   get_neighbors_with_distances,
+                                   get_neighbor_counts,
+                                   nearest_neighbor_distances,
+                                   ripley_k,
+                                   cross_k,
+                                   pair_correlation,
+                                   g_function,
+                                   f_function,
+                                   clark_evans,
+                                   moran_i,
+                                   geary_c,
+                                   local_moran,
+                                   getis_ord,
+                                   join_counts,
+                                   assortativity,
+                                   mixing_score,
+                                   infiltration_score,
+                                   get_neighbors_weighted,
+                                   get_neighbors_annulus,
+                                   get_neighbors_adaptive,
+                                   get_neighbors_periodic,
+                                   get_neighbors_3d,
+                                   get_grid_neighbors,
+                                   get_cross_neighbors,
+                                   get_cross_neighbors_knn,
+                                   get_points_in_box,
+                                   get_points_in_boxes,
                                    get_bbox_neighbors,
+                                   get_neighbors_shape,
+                                   PointIndex,
+                                   PointIndexF32,
+                                   get_point_neighbors_f32,
+                                   edges_to_neighbors,
+                                   neighbors_to_coo,
+                                   symmetrize_neighbors,
+                                   assert_symmetric,
+                                   neighbor_graph_stats,
+                                   subsample_neighbors,
+                                   coo_to_neighbors,
+                                   save_neighbors,
+                                   load_neighbors,
                                    comb_bootstrap,
-                                   neighbor_components)
-from time import time
+                                   comb_bootstrap_matrix,
+                                   adjust_pvalues,
+                                   neighbor_composition,
+                                   neighborhood_entropy,
+                                   neighborhood_clusters,
+                                   expression_bootstrap,
+                                   neighbor_components,
+                                   set_num_threads,
+                                   get_num_threads)
+import csv
+import json
+import os
+import signal
+import warnings
+import tempfile
+import threading
+from concurrent.futures import ThreadPoolExecutor
+from time import perf_counter, sleep, time
 
 types = [str(i) for i in range(30)]
 points = np.random.randint(0, 1000, (10000, 2))
@@ -34,6 +94,571 @@ neighbors = get_point_neighbors(points, 10.0)
 end = time()
 print(f"search point neighbors used {(end - start):.5f}s")
 
+start = time()
+knn_neighbors = get_neighbors_knn(points, 5)
+end = time()
+assert all(len(n) == 5 for n in knn_neighbors)
+assert all(i not in n for i, n in enumerate(knn_neighbors))
+print(f"search knn neighbors used {(end - start):.5f}s")
+
+# 0-1 and 2-3 are close pairs, 4 is far away and picks 3 as its nearest
+five = [(0.0, 0.0), (1.0, 0.0), (5.0, 0.0), (6.0, 0.0), (10.0, 0.0)]
+assert get_neighbors_mutual_knn(five, 1) == [[1], [0], [3], [2], []]
+assert get_neighbors_mutual_knn(five, 1, symmetrize="union") == [[1], [0], [3], [2, 4], [3]]
+
+# the index is built once and queried at every radius
+radii = [10.0, 20.0, 30.0, 50.0]
+index = PointIndex(points)
+multi_neighbors = [index.within(r) for r in radii]
+for r, index_neighbors in zip(radii, multi_neighbors):
+    assert [sorted(n) for n in index_neighbors] == [sorted(n) for n in get_point_neighbors(points, r)]
+
+
+def best_time(run, repeat=3):
+    timings = []
+    for _ in range(repeat):
+        start = perf_counter()
+        run()
+        timings.append(perf_counter() - start)
+    return min(timings)
+
+
+# building the index once is faster than building the tree for each radius, the small radii are dominated
+# by building the tree
+small_radii = [1.0, 2.0, 3.0, 5.0]
+rebuild_time = best_time(lambda: [get_point_neighbors(points, r) for r in small_radii])
+
+
+def index_search():
+    small_index = PointIndex(points)
+    return [small_index.within(r) for r in small_radii]
+
+
+index_time = best_time(index_search)
+print(f"search point neighbors at {len(small_radii)} radii used {rebuild_time:.5f}s, "
+      f"with index used {index_time:.5f}s")
+assert index_time < rebuild_time
+# the queries release the GIL, the threads share one index
+with ThreadPoolExecutor(4) as pool:
+    assert list(pool.map(index.within, radii * 2)) == multi_neighbors * 2
+    assert list(pool.map(lambda k: index.knn(k), [1, 2, 3, 4])) == [index.knn(k) for k in [1, 2, 3, 4]]
+try:
+    PointIndex([])
+    raise AssertionError("An empty index should raise")
+except ValueError:
+    pass
+
+no_self_neighbors = get_point_neighbors(points, 10.0, exclude_self=True)
+no_self_neighbors_sorted = [sorted(n) for n in no_self_neighbors]
+assert [sorted(n) for n in no_self_neighbors] == [sorted(j for j in n if j != i) for i, n in enumerate(neighbors)]
+
+indptr, indices = get_point_neighbors(points, 10.0, format="csr")
+assert indices.dtype == np.uint32
+assert [list(indices[indptr[i]:indptr[i + 1]]) for i in range(len(points))] == neighbors
+
+array_points = np.random.rand(10000, 2) * 1000
+start = time()
+array_neighbors = get_point_neighbors(array_points, 10.0)
+end = time()
+assert array_neighbors == get_point_neighbors([(x, y) for (x, y) in array_points], 10.0)
+print(f"search point neighbors from numpy array used {(end - start):.5f}s")
+assert get_point_neighbors(np.asfortranarray(array_points), 10.0) == array_neighbors
+assert get_point_neighbors(np.hstack([array_points, array_points])[:, :2], 10.0) == array_neighbors
+
+# a C-contiguous float64 array is borrowed, so a read-only array or a view into a larger buffer
+# is searched as is without np.ascontiguousarray, the array is left untouched
+frozen_points = array_points.copy()
+frozen_points.setflags(write=False)
+assert get_point_neighbors(frozen_points, 10.0) == array_neighbors
+assert not frozen_points.flags.writeable and (frozen_points == array_points).all()
+stacked_view = np.vstack([array_points, array_points])[:len(array_points)]
+assert stacked_view.base is not None and stacked_view.flags.c_contiguous
+assert get_point_neighbors(stacked_view, 10.0) == array_neighbors
+assert get_point_neighbors(frozen_points, 10.0, format="csr")[1].tolist() == \
+    get_point_neighbors(array_points, 10.0, format="csr")[1].tolist()
+
+f32_points = array_points.astype(np.float32)
+start = time()
+f32_neighbors = get_point_neighbors_f32(f32_points, 10.0)
+end = time()
+# only the points lying at radius r may differ
+for i, (a, b) in enumerate(zip(array_neighbors, f32_neighbors)):
+    for j in set(a) ^ set(b):
+        assert abs(np.linalg.norm(array_points[i] - array_points[j]) - 10.0) < 1e-3
+assert PointIndexF32(f32_points).within(10.0) == f32_neighbors
+print(f"search point neighbors in float32 used {(end - start):.5f}s")
+
+sorted_neighbors = get_point_neighbors(points, 10.0, sort="index")
+assert sorted_neighbors == get_point_neighbors(points, 10.0, sort="index")
+assert all(n == sorted(n) for n in sorted_neighbors)
+nearest_first = get_point_neighbors(points, 10.0, sort="distance", exclude_self=True)
+assert nearest_first == get_point_neighbors(points, 10.0, sort="distance", exclude_self=True)
+
+dense = [(0.0, 0.0), (1.0, 0.0), (2.0, 0.0), (3.0, 0.0), (50.0, 0.0), (51.0, 0.0)]
+capped = get_point_neighbors(dense, 5.0, exclude_self=True, sort="distance", max_neighbors=2)
+assert capped[0] == [1, 2]
+assert capped[4] == [5]
+
+boundary = [(0.0, 0.0), (2.0, 0.0), (1.0, 1.0), (2.0, 2.0), (1.5, 1.5)]
+assert sorted(get_point_neighbors(boundary, 2.0, metric="euclidean")[0]) == [0, 1, 2]
+assert sorted(get_point_neighbors(boundary, 2.0, metric="manhattan")[0]) == [0, 1, 2]
+assert sorted(get_point_neighbors(boundary, 2.0, metric="chebyshev")[0]) == [0, 1, 2, 3, 4]
+try:
+    get_point_neighbors(boundary, 2.0, metric="cosine")
+    raise AssertionError("Unknown metric should raise")
+except ValueError as e:
+    assert "cosine" in str(e)
+
+for node_size in [8, 255]:
+    start = time()
+    tuned_neighbors = get_point_neighbors(points, 10.0, node_size=node_size, sort="index")
+    end = time()
+    assert tuned_neighbors == sorted_neighbors
+    print(f"search point neighbors with node size {node_size} used {(end - start):.5f}s")
+
+start = time()
+counts, hist = get_neighbor_counts(points, 10.0, histogram=True)
+end = time()
+assert list(counts) == [len(n) for n in neighbors]
+assert hist.sum() == len(points)
+empty_counts, empty_hist = get_neighbor_counts([], 10.0, histogram=True)
+assert len(empty_counts) == 0 and len(empty_hist) == 0
+print(f"count point neighbors used {(end - start):.5f}s")
+
+source, target = get_point_neighbors(points, 10.0, format="edges")
+assert (source < target).all()
+assert edges_to_neighbors((source, target), len(points)) == no_self_neighbors_sorted
+assert edges_to_neighbors(list(zip(source, target)), len(points)) == no_self_neighbors_sorted
+
+row, col, data = neighbors_to_coo(neighbors, len(points))
+assert len(row) == len(col) == len(data) == sum(len(n) for n in neighbors)
+assert coo_to_neighbors(row, col, len(points)) == sorted_neighbors
+_, _, dists = neighbors_to_coo(neighbors, len(points), points=points)
+assert (dists <= 10.0).all()
+# the COO layout carries the number of points, so the trailing cells without neighbors are kept
+assert symmetrize_neighbors((np.array([0]), np.array([1]), np.ones(1), 4)) == [[1], [0], [], []]
+try:
+    symmetrize_neighbors((np.array([0]), np.array([1]), np.ones(1)))
+    raise AssertionError("COO neighbors without n_points should raise")
+except ValueError:
+    pass
+# a list of two or three neighbor lists is never read as a sparse layout
+assert symmetrize_neighbors([[1], [0]]) == [[1], [0]]
+assert symmetrize_neighbors([[1], [0, 2], [1]]) == [[1], [0, 2], [1]]
+
+asymmetric = [[1], [2], [1]]
+assert symmetrize_neighbors(asymmetric) == [[1], [0, 2], [1]]
+assert symmetrize_neighbors(asymmetric, mode="intersection") == [[], [2], [1]]
+assert_symmetric(sorted_neighbors)
+try:
+    assert_symmetric(asymmetric)
+    raise AssertionError("Asymmetric neighbors should raise")
+except ValueError as e:
+    assert "1 is a neighbor of 0" in str(e)
+
+stats = neighbor_graph_stats([[0, 1], [0], [1], []])
+assert stats["n_nodes"] == 4 and stats["n_edges"] == 2 and stats["n_isolated"] == 1
+assert stats["max_degree"] == 2 and stats["mean_degree"] == 1.0 and stats["median_degree"] == 1.0
+assert list(stats["degree_hist"][0]) == [0, 1, 2] and list(stats["degree_hist"][1]) == [1, 2, 1]
+assert neighbor_graph_stats([[1], [], [1]], directed=True)["n_edges"] == 2
+assert neighbor_graph_stats([])["mean_degree"] == 0.0
+
+approx = subsample_neighbors(neighbors, 0.3, seed=0)
+assert approx == subsample_neighbors(neighbors, 0.3, seed=0)
+assert all(set(a) <= set(n) and (len(a) > 0) == (len(n) > 0) for a, n in zip(approx, neighbors))
+assert subsample_neighbors(neighbors, 1.0) == neighbors
+try:
+    subsample_neighbors(neighbors, 0.0)
+    raise AssertionError("Fraction out of range should raise")
+except ValueError:
+    pass
+
+rare = [3, 42, 4096]
+assert get_point_neighbors(points, 10.0, query_indices=rare) == [neighbors[i] for i in rare]
+
+invalid = [(0.0, 0.0), (float("nan"), 1.0), (1.0, 0.0)]
+try:
+    get_point_neighbors(invalid, 5.0)
+    raise AssertionError("NaN coordinates should raise")
+except ValueError:
+    pass
+assert get_point_neighbors(invalid, 5.0, drop_invalid=True, sort="index") == ([[0, 1], [0, 1]], [0, 2])
+assert get_point_neighbors(invalid[1:2] * 2, 5.0, drop_invalid=True) == ([], [])
+
+point_types = list(corr_types)
+cd8_neighbors = get_point_neighbors(points, 10.0, types=point_types, target_types={"1", "2"})
+assert [sorted(n) for n in cd8_neighbors] == [sorted(j for j in n if point_types[j] in {"1", "2"}) for n in neighbors]
+assert get_point_neighbors(points[:3], 10.0, types=["a", "a", "a"], target_types={"b"}) == [[], [], []]
+assert get_point_neighbors([(0.0, 0.0), (0.0, 1.0), (0.0, 2.0)], 1.5, types=["a", "b", "b"], target_types={"b"},
+                           sort="index") == [[1], [1, 2], [1, 2]]
+
+# a large cell and a small cell at the same location
+mixed = [(0.0, 0.0), (0.0, 0.0), (5.0, 0.0)]
+mixed_types = ["tumor", "immune", "immune"]
+radius_by_type = {"tumor": 6.0, "immune": 2.0}
+assert get_point_neighbors(mixed, None, types=mixed_types, radius_by_type=radius_by_type,
+                           sort="index") == [[0, 1, 2], [0, 1], [2]]
+try:
+    get_point_neighbors(mixed, None, types=mixed_types, radius_by_type={"tumor": 6.0})
+    raise AssertionError("Missing type should raise")
+except ValueError as e:
+    assert "immune" in str(e)
+
+with tempfile.TemporaryDirectory() as tmp:
+    path = os.path.join(tmp, "neighbors.bin")
+    save_neighbors(path, neighbors)
+    assert load_neighbors(path) == neighbors
+    with open(path, "r+b") as f:
+        f.truncate(100)
+    try:
+        load_neighbors(path)
+        raise AssertionError("Corrupted file should raise")
+    except ValueError:
+        pass
+
+rois = [np.random.rand(2000, 2) * 500 for _ in range(50)]
+start = time()
+batch_neighbors = get_neighbors_batch(rois, 10.0)
+end = time()
+assert batch_neighbors[7] == get_point_neighbors(rois[7], 10.0)
+print(f"search point neighbors of {len(rois)} ROIs used {(end - start):.5f}s")
+assert get_neighbors_batch([[], [(0.0, 0.0), (1.0, 0.0)]], 2.0) == [[], [[0, 1], [0, 1]]]
+
+stacked = [(1.0, 1.0), (1.0, 1.0), (1.0, 1.0), (3.0, 1.0)]
+with warnings.catch_warnings(record=True) as w:
+    warnings.simplefilter("always")
+    get_point_neighbors(stacked, 1.0, handle_duplicates="warn")
+    assert "[0, 1, 2]" in str(w[0].message)
+merged, mapping = get_point_neighbors(stacked, 5.0, handle_duplicates="merge", sort="index")
+assert mapping == [0, 0, 0, 3]
+assert merged == [[0, 3], [0, 3], [0, 3], [0, 3]]
+merged_no_self, _ = get_point_neighbors(stacked, 5.0, handle_duplicates="merge", exclude_self=True, sort="index")
+assert merged_no_self == [[3], [3], [3], [0]]
+
+sized = [(0.0, 0.0), (4.0, 0.0), (20.0, 0.0)]
+assert get_point_neighbors(sized, [5.0, 1.0, 1.0])[1] == [1]
+assert sorted(get_point_neighbors(sized, [5.0, 1.0, 1.0], use_both=True)[1]) == [0, 1]
+
+dist_neighbors = get_neighbors_with_distances([(0.0, 0.0), (3.0, 4.0), (10.0, 10.0)], 5.0)
+assert sorted(dist_neighbors[0]) == [(0, 0.0), (1, 5.0)]
+assert get_neighbors_with_distances([], 5.0) == []
+
+weighted = get_neighbors_weighted([(0.0, 0.0), (3.0, 4.0)], 5.0, kernel="linear", bandwidth=10.0)
+assert sorted(weighted[0]) == [(0, 1.0), (1, 0.5)]
+assert get_neighbors_weighted([(0.0, 0.0), (3.0, 4.0)], 5.0, include_self=False)[0] == [(1, np.exp(-0.5))]
+assert get_neighbors_weighted([], 5.0) == []
+
+assert list(nearest_neighbor_distances([(0.0, 0.0), (3.0, 4.0), (3.0, 5.0)])) == [5.0, 1.0, 1.0]
+
+# a regular grid of unit intensity, each point has 4 neighbors within [1, sqrt(2)) and 8 within [sqrt(2), 2)
+grid_points = [(float(x), float(y)) for x in range(20) for y in range(20)]
+grid_k, grid_l = ripley_k(grid_points, [0.5, 1.2, 1.5], area=(-0.5, -0.5, 19.5, 19.5))
+assert np.allclose(grid_k, [0.0, 4.0, 8.0])
+assert np.allclose(grid_l, np.sqrt(grid_k / np.pi) - [0.5, 1.2, 1.5])
+grid_by_type = ripley_k(grid_points, [1.2], area=(-0.5, -0.5, 19.5, 19.5), types=["a"] * 200 + ["b"] * 200)
+assert sorted(grid_by_type) == ["a", "b"]
+# complete spatial randomness, K(r) is about pi * r^2 and L(r) - r is about 0
+csr_points = np.random.default_rng(0).random((3000, 2))
+csr_k, csr_l = ripley_k(csr_points, [0.02, 0.05, 0.1], area=(0.0, 0.0, 1.0, 1.0))
+assert np.allclose(csr_k, np.pi * np.array([0.02, 0.05, 0.1]) ** 2, rtol=0.1)
+assert np.all(np.abs(csr_l) < 0.01)
+assert np.allclose(ripley_k(csr_points, [0.05], area=1.0, correction="none")[0], np.pi * 0.05 ** 2, rtol=0.15)
+assert np.isnan(ripley_k(grid_points, [15.0])[0][0])
+# K is NaN with fewer than 2 points, even without an area
+assert all(np.isnan(ripley_k([], [1.0, 2.0])[0])) and all(np.isnan(ripley_k([(0.0, 0.0)], [1.0])[1]))
+assert np.isnan(ripley_k([(0.0, 0.0)], [1.0], types=["a"])["a"][0]).all()
+# the checkerboard, each point has 4 neighbors of the other color within [1, sqrt(2)) and none of its own
+checker_types = ["black" if (x + y) % 2 else "white" for x in range(20) for y in range(20)]
+assert np.allclose(cross_k(grid_points, checker_types, "black", "white", [0.5, 1.2], area=(-0.5, -0.5, 19.5, 19.5)),
+                   [0.0, 8.0])
+assert np.allclose(ripley_k(grid_points, [1.2], area=(-0.5, -0.5, 19.5, 19.5), types=checker_types)["black"][0], 0.0)
+checker_k, checker_lower, checker_upper = cross_k(grid_points, checker_types, "black", "white", [1.2],
+                                                  area=(-0.5, -0.5, 19.5, 19.5), n_sim=50, seed=0)
+assert checker_upper[0] < checker_k[0] and checker_lower[0] <= checker_upper[0]
+assert list(cross_k(grid_points, checker_types, "black", "white", [1.2], n_sim=20, seed=1)[1]) == \
+       list(cross_k(grid_points, checker_types, "black", "white", [1.2], n_sim=20, seed=1)[1])
+
+csr_r, csr_g = pair_correlation(csr_points, 0.1, 10, area=(0.0, 0.0, 1.0, 1.0))
+assert np.allclose(csr_r, np.arange(0.005, 0.1, 0.01))
+assert np.allclose(csr_g, 1.0, atol=0.15)
+csr_smooth = pair_correlation(csr_points, 0.1, 10, bandwidth=0.005, area=(0.0, 0.0, 1.0, 1.0))[1]
+assert np.allclose(csr_smooth[1:], 1.0, atol=0.15)
+# the clusters of 20 points around 50 parents
+cluster_rng = np.random.default_rng(1)
+cluster_points = np.repeat(cluster_rng.random((50, 2)), 20, axis=0) + cluster_rng.normal(0, 0.01, (1000, 2))
+cluster_g = pair_correlation(cluster_points, 0.1, 10)[1]
+assert cluster_g[0] > 2 and cluster_g[0] > cluster_g[-1]
+_, csr_g_types = pair_correlation(csr_points, 0.1, 10, types=["a", "b"] * 1500, area=(0.0, 0.0, 1.0, 1.0))
+assert list(csr_g_types) == [("a", "a"), ("a", "b"), ("b", "b")]
+assert np.allclose(csr_g_types[("a", "b")], 1.0, atol=0.25)
+empty_r, empty_g = pair_correlation([], 0.1, 10)
+assert np.allclose(empty_r, csr_r) and all(np.isnan(empty_g))
+assert np.isnan(pair_correlation([(0.0, 0.0)], 0.1, 10, types=["a"])[1][("a", "a")]).all()
+
+# the nearest neighbor of every grid point is at 1, the test locations at 0.5 spacing are
+# 400 grid points (0), 760 edge midpoints (0.5) and 361 cell centers (sqrt(0.5))
+assert list(g_function(grid_points, [0.5, 0.99, 1.0, 2.0])) == [0.0, 0.0, 1.0, 1.0]
+assert list(g_function(grid_points, [1.2], types=checker_types)["black"]) == [0.0]
+assert list(g_function(grid_points, [1.5], types=checker_types)["white"]) == [1.0]
+assert np.isnan(g_function([(0.0, 0.0)], [1.0])[0])
+assert np.allclose(f_function(grid_points, [0.0, 0.25, 0.6, 0.75], 0.5),
+                   [400 / 1521, 400 / 1521, 1160 / 1521, 1.0])
+assert np.allclose(f_function(grid_points, [0.0], 1.0, window=(0.0, 0.0, 19.0, 19.0)), [1.0])
+
+# the grid is dispersed, the mean nearest neighbor distance is 1 against 0.5 / sqrt(400 / 361)
+grid_ce = clark_evans(grid_points)
+assert np.isclose(grid_ce["R"], 1 / (0.5 * np.sqrt(361 / 400))) and grid_ce["zscore"] > 0 and grid_ce["pvalue"] < 1e-6
+cluster_ce = clark_evans(cluster_points)
+assert cluster_ce["R"] < 0.5 and cluster_ce["zscore"] < 0 and cluster_ce["pvalue"] < 1e-6
+csr_ce = clark_evans(csr_points, area=(0.0, 0.0, 1.0, 1.0), correction="donnelly")
+assert 0.95 < csr_ce["R"] < 1.05 and abs(csr_ce["zscore"]) < 3
+assert csr_ce["expected"] > clark_evans(csr_points, area=1.0)["expected"]
+assert sorted(clark_evans(grid_points, types=checker_types)) == ["black", "white"]
+assert np.isnan(clark_evans([(0.0, 0.0), (1.0, 1.0)], types=["a", "b"])["a"]["R"])
+
+# the rook neighbors of the grid, the checkerboard is negative and the gradient is positive
+grid_rook = get_point_neighbors(grid_points, 1.0)
+checker_moran = moran_i([float(t == "black") for t in checker_types], grid_rook, permutations=99, seed=0)
+assert np.isclose(checker_moran["I"], -1.0) and checker_moran["zscore"] < 0 and checker_moran["perm_pvalue"] == 0.01
+assert np.isclose(checker_moran["expected"], -1 / 399)
+gradient_moran = moran_i([x for x, _ in grid_points], grid_rook)
+assert gradient_moran["I"] > 0.9 and gradient_moran["zscore"] > 0 and gradient_moran["pvalue"] < 1e-6
+try:
+    moran_i([np.nan] + [x for x, _ in grid_points[1:]], grid_rook)
+    assert False
+except ValueError:
+    pass
+assert moran_i([np.nan] + [x for x, _ in grid_points[1:]], grid_rook, ignore_nan=True)["I"] > 0.9
+# Geary's C is the other way around, above 1 for the checkerboard and below 1 for the gradient
+checker_geary = geary_c([float(t == "black") for t in checker_types], grid_rook, permutations=99, seed=0)
+assert checker_geary["C"] > 1 and checker_geary["zscore"] > 0 and checker_geary["perm_pvalue"] == 0.01
+gradient_geary = geary_c([x for x, _ in grid_points], grid_rook)
+assert gradient_geary["C"] < 0.1 and gradient_geary["zscore"] < 0 and gradient_geary["expected"] == 1.0
+for moran_data in [[float(t == "black") for t in checker_types], [x for x, _ in grid_points],
+                   list(np.random.default_rng(2).random(400))]:
+    moran_z = moran_i(moran_data, grid_rook)["zscore"]
+    geary_z = geary_c(moran_data, grid_rook)["zscore"]
+    assert abs(moran_z) < 3 or np.sign(moran_z) == -np.sign(geary_z)
+# every cell of the checkerboard is against its neighbors, the two edges of the gradient are LL and HH
+checker_lisa = local_moran([float(t == "black") for t in checker_types], grid_rook, permutations=99, seed=0)
+assert (checker_lisa["I"] < 0).all() and set(checker_lisa["quadrant"]) == {"HL", "LH"}
+assert np.median(checker_lisa["pvalue"]) < 0.1
+assert np.array_equal(checker_lisa["pvalue"],
+                      local_moran([float(t == "black") for t in checker_types], grid_rook, 99, 0)["pvalue"])
+gradient_lisa = local_moran([x for x, _ in grid_points], grid_rook, permutations=99, seed=0)
+assert {q for (x, _), q in zip(grid_points, gradient_lisa["quadrant"]) if x == 0} == {"LL"}
+assert {q for (x, _), q in zip(grid_points, gradient_lisa["quadrant"]) if x == 19} == {"HH"}
+lonely_lisa = local_moran([1.0, 2.0, 3.0], [[1], [0], []], permutations=9, seed=0)
+assert np.isnan(lonely_lisa["I"][2]) and np.isnan(lonely_lisa["pvalue"][2]) and lonely_lisa["quadrant"][2] is None
+assert not np.isnan(lonely_lisa["I"][0])
+# a hot spot embedded in the noise
+hotspot_values = np.random.default_rng(3).normal(size=400)
+hotspot = np.array([5 <= x < 10 and 5 <= y < 10 for x, y in grid_points])
+hotspot_values[hotspot] += 4
+hotspot_inner = np.array([6 <= x < 9 and 6 <= y < 9 for x, y in grid_points])
+hotspot_far = np.array([not (3 <= x < 12 and 3 <= y < 12) for x, y in grid_points])
+hotspot_gi = getis_ord(list(hotspot_values), grid_rook, adjust="fdr_bh")
+assert (hotspot_gi["zscore"][hotspot_inner] > 3).all() and (hotspot_gi["pvalue_adj"][hotspot_inner] < 0.05).all()
+assert (hotspot_gi["pvalue_adj"][hotspot_far] < 0.05).mean() < 0.05
+assert (hotspot_gi["pvalue_adj"] >= hotspot_gi["pvalue"]).all()
+coldspot_gi = getis_ord(list(-hotspot_values), grid_rook, star=False)
+assert (coldspot_gi["zscore"][hotspot_inner] < -3).all() and "pvalue_adj" not in coldspot_gi
+lonely_gi = getis_ord([1.0, 2.0, 3.0, 4.0], [[1], [0], [], [0]], star=False, adjust="bonferroni")
+assert np.isnan(lonely_gi["zscore"][2]) and np.isnan(lonely_gi["pvalue_adj"][2])
+assert not np.isnan(lonely_gi["pvalue_adj"][0])
+assert not np.isnan(getis_ord([1.0, 2.0, 3.0, 4.0], [[1], [0], [], [0]])["zscore"][2])
+# the two halves of the grid are segregated, the random types are well mixed
+segregated_joins = join_counts(["left" if x < 10 else "right" for x, _ in grid_points], grid_rook, 99, seed=0)
+assert list(segregated_joins) == [("left", "left"), ("left", "right"), ("right", "right")]
+assert segregated_joins[("left", "right")]["observed"] == 20 and segregated_joins[("left", "left")]["observed"] == 370
+assert segregated_joins[("left", "right")]["zscore"] < -3 and segregated_joins[("left", "right")]["pvalue"] == 0.01
+assert segregated_joins[("left", "left")]["zscore"] > 3
+mixed_joins = join_counts(list(np.random.default_rng(4).choice(["a", "b", "c"], 400)), grid_rook, 99, seed=0)
+assert len(mixed_joins) == 6 and all(abs(j["zscore"]) < 4 for j in mixed_joins.values())
+assert sum(j["observed"] for j in mixed_joins.values()) == 760
+# a directed graph is symmetrized, the edge in one direction is counted once
+assert join_counts(["a", "b", "a"], [[1], [], [1]], 9)[("a", "b")]["observed"] == 2
+assert join_counts(["a", "b", "a"], [[1], [0, 2], [1]], 9)[("a", "b")]["observed"] == 2
+# the single b cell never joins another b cell, the b-b count is 0 in all the permutations
+assert np.isnan(join_counts(["a", "b", "a"], [[1], [0, 2], [1]], 9, on_zero_variance="nan")[("b", "b")]["zscore"])
+try:
+    join_counts(["a", "b", "a"], [[1], [0, 2], [1]], 9, on_zero_variance="raise")
+    raise AssertionError("Zero variance should raise")
+except ValueError as e:
+    assert "the same count" in str(e)
+# the two triangles are only connected inside (the self-loop is skipped), the checkerboard only touches
+# the other type
+assert assortativity(["a"] * 3 + ["b"] * 3, [[0, 1], [2], [0], [4], [5], [3, 3]], permutations=0) == {"r": 1.0}
+checker_assort = assortativity(checker_types, grid_rook, 99, seed=0)
+assert np.isclose(checker_assort["r"], -1) and checker_assort["zscore"] < -3 and checker_assort["pvalue"] == 0.01
+assert assortativity(["a", "b"], [[1], [0]], 0)["r"] == -1.0
+# a single type, r is NaN in the data and in all the permutations
+single_assort = assortativity(["a"] * 3, [[1], [2], [0]], 9, on_zero_variance="nan")
+assert np.isnan(single_assort["r"]) and np.isnan(single_assort["zscore"])
+with warnings.catch_warnings(record=True) as caught:
+    warnings.simplefilter("always")
+    assert np.isnan(assortativity(["a"] * 3, [[1], [2], [0]], 9)["zscore"])
+    assert len(caught) == 1
+try:
+    assortativity(["a"] * 3, [[1], [2], [0]], 9, on_zero_variance="raise")
+    raise AssertionError("Undefined z-score should raise")
+except ValueError as e:
+    assert "undefined" in str(e)
+assert np.isclose(assortativity(["left" if x < 10 else "right" for x, _ in grid_points], grid_rook, 0)["r"],
+                  1 - 2 * 20 / 760)
+mixed_assort = assortativity(list(np.random.default_rng(4).choice(["a", "b", "c"], 400)), grid_rook, 99, seed=0)
+assert abs(mixed_assort["r"]) < 0.1 and abs(mixed_assort["zscore"]) < 4
+# 2 tumor-tumor, 2 tumor-immune and 3 immune-immune edges, the self-loop and the repeated edge are not counted
+mix_types = ["tumor"] * 3 + ["immune"] * 3
+mix_neighbors = {0: [1, 3], 1: [0, 2], 2: [4], 3: [4, 5], 4: [5], 5: [5]}
+assert np.isclose(mixing_score(mix_types, mix_neighbors, "tumor", "immune"), 2 / 3)
+assert mixing_score(mix_types, mix_neighbors, "tumor", "immune", normalize="a") == 1.0
+assert np.isclose(mixing_score(mix_types, mix_neighbors, "tumor", "immune", normalize="both"), 2 / 5)
+assert infiltration_score(mix_types, mix_neighbors, "tumor", "immune") == 0.5
+assert np.isclose(infiltration_score(mix_types, mix_neighbors, "immune", "tumor"), 2 / 5)
+with warnings.catch_warnings(record=True) as caught:
+    warnings.simplefilter("always")
+    assert np.isnan(mixing_score(["tumor", "immune", "immune"], [[1, 2]], "tumor", "immune"))
+    assert np.isnan(infiltration_score(["tumor", "immune"], [[], [1]], "tumor", "immune"))
+assert len(caught) == 2
+# A has no edges in any permutation, the z-score is NaN with another warning
+with warnings.catch_warnings(record=True) as caught:
+    warnings.simplefilter("always")
+    assert np.isnan(infiltration_score(["tumor", "immune"], [[], [1]], "tumor", "immune", permutations=5)).all()
+assert len(caught) == 2
+with warnings.catch_warnings(record=True) as caught:
+    warnings.simplefilter("always")
+    assert np.isnan(infiltration_score(["tumor", "immune"], [[], [1]], "tumor", "immune", permutations=5,
+                                       on_zero_variance="nan")).all()
+    # a complete graph, every permutation gives the same score
+    pair_mixing = mixing_score(["tumor", "tumor", "immune", "immune"], [[1, 2, 3], [2, 3], [3], []], "tumor",
+                               "immune", permutations=5, on_zero_variance="nan")
+    assert pair_mixing[0] == 4.0 and np.isnan(pair_mixing[1])
+assert len(caught) == 0
+for zero_call in [lambda: mixing_score(["tumor", "immune", "immune"], [[1, 2]], "tumor", "immune",
+                                       on_zero_variance="raise"),
+                  lambda: mixing_score(["tumor", "tumor", "immune", "immune"], [[1, 2, 3], [2, 3], [3], []],
+                                       "tumor", "immune", permutations=5, on_zero_variance="raise")]:
+    try:
+        zero_call()
+        assert False
+    except ValueError:
+        pass
+halves_mixing, halves_mixing_z = mixing_score(["left" if x < 10 else "right" for x, _ in grid_points], grid_rook,
+                                              "left", "right", permutations=99, seed=0)
+assert np.isclose(halves_mixing, 20 / 370) and halves_mixing_z < -3
+assert mixing_score(checker_types, grid_rook, "black", "white", permutations=20, seed=1) == \
+    mixing_score(checker_types, grid_rook, "black", "white", permutations=20, seed=1)
+try:
+    mixing_score(mix_types, mix_neighbors, "tumor", "stroma")
+    assert False
+except ValueError:
+    pass
+# the left half mixes A and B, the right half mixes C and D, the two halves are the two neighborhoods
+hood_rng = np.random.default_rng(5)
+hood_types = [str(hood_rng.choice(["A", "B"] if x < 10 else ["C", "D"])) for x, _ in grid_points]
+hood_neighbors = get_point_neighbors(grid_points, 2.0)
+hood_labels, hood_centroids, hood_columns = neighborhood_clusters(hood_types, hood_neighbors, 2, seed=0)
+assert sorted(hood_columns) == ["A", "B", "C", "D"] and hood_centroids.shape == (2, 4)
+assert len({l for (x, _), l in zip(grid_points, hood_labels) if x < 9}) == 1
+assert len({l for (x, _), l in zip(grid_points, hood_labels) if x > 10}) == 1
+assert hood_labels[0] != hood_labels[-1]
+assert np.allclose(hood_centroids.sum(axis=1), 1)
+left_centroid = dict(zip(hood_columns, hood_centroids[hood_labels[0]]))
+assert left_centroid["A"] > 0.3 and left_centroid["B"] > 0.3 and left_centroid["C"] < 0.1
+seeded_clusters = neighborhood_clusters(hood_types, hood_neighbors, 3, seed=1)
+repeated_clusters = neighborhood_clusters(hood_types, hood_neighbors, 3, seed=1)
+assert np.array_equal(seeded_clusters[0], repeated_clusters[0])
+assert np.array_equal(seeded_clusters[1], repeated_clusters[1])
+assert list(neighborhood_clusters(["A", "B", "A"], [[1], [0]], 1)[0]) == [0, 0, -1]
+try:
+    neighborhood_clusters(["A", "B", "A"], [[1], [0]], 3)
+    assert False
+except ValueError:
+    pass
+try:
+    neighborhood_clusters(["A", "B", "A"], [[1], [0]], 1, n_iter=0)
+    assert False
+except ValueError:
+    pass
+assert list(nearest_neighbor_distances([(0.0, 0.0), (3.0, 4.0), (3.0, 5.0)], k=2))[1] == 5.0
+assert np.isnan(nearest_neighbor_distances([(0.0, 0.0)])[0])
+assert len(nearest_neighbor_distances([])) == 0
+
+ring = [(0.0, 0.0), (2.0, 0.0), (0.0, 5.0), (6.0, 0.0)]
+assert sorted(get_neighbors_annulus(ring, 2.0, 5.0)[0]) == [1, 2]
+assert [sorted(n) for n in get_neighbors_annulus(points, 0.0, 10.0)] == [sorted(n) for n in neighbors]
+assert get_neighbors_annulus([], 2.0, 5.0) == []
+for annulus_r in [(5.0, 2.0), (-1.0, 5.0), (float("nan"), 5.0), (2.0, float("inf"))]:
+    try:
+        get_neighbors_annulus(ring, *annulus_r)
+        raise AssertionError("Invalid radii should raise")
+    except ValueError as e:
+        assert "r_min < r_max" in str(e)
+
+tumor = [(0.0, 0.0), (100.0, 100.0)]
+immune = [(1.0, 1.0), (99.0, 99.0), (50.0, 50.0)]
+assert get_cross_neighbors(tumor, immune, 5.0) == [[0], [1]]
+assert get_cross_neighbors_knn(tumor, immune, 2) == [[0, 2], [1, 2]]
+assert get_cross_neighbors(tumor, [], 5.0) == [[], []] and get_cross_neighbors([], immune, 5.0) == []
+assert get_cross_neighbors_knn(tumor, [], 0) == [[], []]
+
+roi = [(0.0, 0.0), (5.0, 5.0), (10.0, 10.0), (11.0, 3.0)]
+assert get_points_in_box(roi, 0.0, 0.0, 10.0, 10.0) == [0, 1, 2]
+assert get_points_in_boxes(roi, [(4.0, 4.0, 6.0, 6.0), (10.0, 0.0, 12.0, 3.0)]) == [[1], [3]]
+assert get_points_in_box([], 0.0, 0.0, 10.0, 10.0) == []
+assert get_points_in_boxes([], [(4.0, 4.0, 6.0, 6.0), (10.0, 0.0, 12.0, 3.0)]) == [[], []]
+
+sparse = [(0.0, 0.0), (1.0, 0.0), (2.0, 0.0), (20.0, 0.0), (100.0, 0.0)]
+adaptive, used_r, unmet = get_neighbors_adaptive(sparse, 1.0, 32.0, 2, exclude_self=True)
+assert used_r[:4] == [2.0, 1.0, 2.0, 32.0]
+assert sorted(adaptive[3]) == [0, 1, 2]
+assert unmet == [4]
+assert get_neighbors_adaptive([], 1.0, 32.0, 2) == ([], [], [])
+for adaptive_r in [(0.0, 32.0), (64.0, 32.0), (float("nan"), 32.0), (1.0, float("nan")), (1.0, float("inf"))]:
+    try:
+        get_neighbors_adaptive(sparse, *adaptive_r, 10)
+        raise AssertionError("Invalid radii should raise")
+    except ValueError as e:
+        assert "r_start <= r_max" in str(e)
+
+edges = [(1.0, 50.0), (99.0, 50.0)]
+assert get_neighbors_periodic(edges, 5.0, 100.0, 100.0) == [[0, 1], [0, 1]]
+assert get_neighbors_periodic([], 5.0, 100.0, 100.0) == []
+for periodic_args in [(5.0, float("nan"), 100.0), (5.0, 100.0, float("inf")), (float("nan"), 100.0, 100.0),
+                      (-1.0, 100.0, 100.0)]:
+    try:
+        get_neighbors_periodic(edges, *periodic_args)
+        raise AssertionError("Invalid r or domain should raise")
+    except ValueError:
+        pass
+assert get_point_neighbors(edges, 5.0) == [[0], [1]]
+
+# a center spot surrounded by 6 spots on a hex grid, and a far away spot
+hexagon = [(0.0, 0.0)] + [(np.cos(a), np.sin(a)) for a in np.arange(6) * np.pi / 3] + [(10.0, 0.0)]
+bin_neighbors, point_bins = get_grid_neighbors(hexagon, 1.0, tolerance=0.1)
+assert len(bin_neighbors[point_bins[0]]) == 6
+assert len(bin_neighbors[point_bins[1]]) == 3
+assert bin_neighbors[point_bins[7]] == []
+square_neighbors, _ = get_grid_neighbors([(0.0, 0.0), (1.0, 0.0), (1.0, 1.0)], 1.0, layout="square")
+assert square_neighbors == [[1], [0, 2], [1]]
+
+# a large square, a small square 1 unit to its right, and a triangle 3 units away
+shapes = [[(0.0, 0.0), (10.0, 0.0), (10.0, 10.0), (0.0, 10.0)],
+          [(11.0, 4.0), (12.0, 4.0), (12.0, 5.0), (11.0, 5.0)],
+          [(15.0, 4.0), (16.0, 4.0), (15.5, 5.0)]]
+assert get_neighbors_shape(shapes, 0.5) == [[0, 1], [0, 1], [2]]
+assert get_neighbors_shape(shapes, 1.5) == [[0, 1], [0, 1, 2], [1, 2]]
+
+sphere = [(0.0, 0.0, 0.0), (3.0, 4.0, 0.0), (0.0, 3.0, 4.0001)]
+neighbors_3d = get_neighbors_3d(sphere, 5.0)
+assert sorted(neighbors_3d[0]) == [0, 1]
+points_3d = [(x, y, z) for (x, y, z) in np.random.randint(0, 100, (10000, 3))]
+start = time()
+neighbors_3d = get_neighbors_3d(points_3d, 5.0)
+end = time()
+print(f"search 3d point neighbors used {(end - start):.5f}s")
+
 start = time()
 
 cc = CellCombs(types, False)
@@ -49,6 +674,665 @@ v = comb_bootstrap(X, Y, neighbors, times=1000, ignore_self=True)
 s2 = time()
 print(f"marker co-exp used {(s2 - s1):.5f}s")
 
+assert comb_bootstrap(X, Y, neighbors, times=200, seed=42) == comb_bootstrap(X, Y, neighbors, times=200, seed=42)
+detail = comb_bootstrap(X, Y, neighbors, times=200, seed=42, return_detail=True)
+assert detail["zscore"] == comb_bootstrap(X, Y, neighbors, times=200, seed=42)
+# the options after ignore_self are keyword-only
+try:
+    comb_bootstrap(X, Y, neighbors, 200, False, 42)
+    raise AssertionError("seed should be keyword-only")
+except TypeError:
+    pass
+assert 1 / 201 <= detail["pvalue"] <= 1
+
+# the dict form may miss keys of cells without neighbors
+neighbors_dict = {i: n for i, n in enumerate(neighbors) if len(n) > 0}
+assert comb_bootstrap(X, Y, neighbors_dict, times=200, seed=42) == comb_bootstrap(X, Y, neighbors, times=200, seed=42)
+dict_results = CellCombs(types, False).bootstrap(corr_types, neighbors_dict, times=100, method="zscore", seed=42,
+                                                 return_type="dict")
+list_results = dict(CellCombs(types, False).bootstrap(corr_types, neighbors, times=100, method="zscore", seed=42))
+assert list(dict_results.keys()) == list(list_results.keys())
+assert dict_results == list_results
+
+# the (indptr, indices) pair from format="csr" is accepted as neighbors
+assert comb_bootstrap(X, Y, (indptr, indices), times=200, seed=42) == comb_bootstrap(X, Y, neighbors, times=200, seed=42)
+coo_neighbors = (*neighbors_to_coo(neighbors, len(points)), len(points))
+assert comb_bootstrap(X, Y, coo_neighbors, times=200, seed=42) == comb_bootstrap(X, Y, neighbors, times=200, seed=42)
+csr_results = CellCombs(types, False).bootstrap(corr_types, (indptr, indices), times=100, method="zscore", seed=42)
+assert csr_results == CellCombs(types, False).bootstrap(corr_types, neighbors, times=100, method="zscore", seed=42)
+try:
+    comb_bootstrap(X, Y, (np.array([0, 1, 2]), np.array([1, -1], dtype=np.int64)), times=10)
+    assert False
+except ValueError:
+    pass
+
+Z = [bool(i) for i in np.random.choice([True, False], 10000, p=[0.2, 0.8])]
+marker_matrix = np.array([X, Y, Z]).T
+s1 = time()
+z_matrix = comb_bootstrap_matrix(marker_matrix, neighbors, times=200, seed=42)
+s2 = time()
+assert z_matrix.shape == (3, 3)
+assert z_matrix[0, 0] == comb_bootstrap(X, X, neighbors, times=200, seed=42)
+assert z_matrix[0, 1] == comb_bootstrap(X, Y, neighbors, times=200, seed=42)
+assert z_matrix[2, 1] == comb_bootstrap(Z, Y, neighbors, times=200, seed=42)
+z_dict = comb_bootstrap_matrix(marker_matrix, neighbors, times=200, seed=42, markers=["X", "Y", "Z"])
+assert z_dict[("Z", "Y")] == z_matrix[2, 1]
+# no cell is positive of the last marker, its row and column are undefined as in comb_bootstrap
+empty_matrix = np.array([X, Y, [False] * len(X)]).T
+empty_z = comb_bootstrap_matrix(empty_matrix, neighbors, times=50, seed=42, on_zero_variance="nan")
+assert np.isnan(empty_z[2]).all() and np.isnan(empty_z[:, 2]).all() and not np.isnan(empty_z[:2, :2]).any()
+assert np.isnan(comb_bootstrap(X, [False] * len(X), neighbors, times=50, seed=42, on_zero_variance="nan"))
+try:
+    comb_bootstrap_matrix(empty_matrix, neighbors, times=50, markers=["X", "Y", "E"], on_zero_variance="raise")
+    raise AssertionError("Zero variance should raise")
+except ValueError as e:
+    assert "('X', 'E')" in str(e)
+print(f"marker co-exp matrix used {(s2 - s1):.5f}s")
+
+x_values = np.random.rand(10000).tolist()
+y_values = np.random.rand(10000).tolist()
+s1 = time()
+z, p = expression_bootstrap(x_values, y_values, neighbors, times=200, seed=42)
+s2 = time()
+assert 1 / 201 <= p <= 1
+z, p = expression_bootstrap(x_values, y_values, neighbors, times=200, stat="pearson", seed=42)
+assert 1 / 201 <= p <= 1
+y_values[0] = float("nan")
+try:
+    expression_bootstrap(x_values, y_values, neighbors, times=10)
+    raise AssertionError("NaN values should raise")
+except ValueError:
+    pass
+# the cell with NaN is dropped with its edges, the same as bootstrapping the remaining cells
+nan_x, nan_y = [1.0, 2.0, float("nan"), 3.0, 4.0], [2.0, 1.0, 5.0, 0.0, 3.0]
+nan_chain = [[1], [0, 2], [1, 3], [2, 4], [3]]
+for nan_stat in ["product", "pearson"]:
+    assert expression_bootstrap(nan_x, nan_y, nan_chain, times=50, stat=nan_stat, seed=0, ignore_nan=True) == \
+           expression_bootstrap([1.0, 2.0, 3.0, 4.0], [2.0, 1.0, 0.0, 3.0], [[1], [0], [3], [2]], times=50,
+                                stat=nan_stat, seed=0)
+# a constant y gives the same statistic in all the permutations, the z-score is undefined
+for constant_stat in ["product", "pearson"]:
+    assert np.isnan(expression_bootstrap(nan_x[:2], [1.0, 1.0], [[1], [0]], times=10, stat=constant_stat,
+                                         on_zero_variance="nan")[0])
+    with warnings.catch_warnings(record=True) as caught:
+        warnings.simplefilter("always")
+        assert np.isnan(expression_bootstrap(nan_x[:2], [1.0, 1.0], [[1], [0]], times=10, stat=constant_stat)[0])
+        assert len(caught) == 1
+    try:
+        expression_bootstrap(nan_x[:2], [1.0, 1.0], [[1], [0]], times=10, stat=constant_stat,
+                             on_zero_variance="raise")
+        raise AssertionError("Zero variance should raise")
+    except ValueError as e:
+        assert "undefined" in str(e)
+print(f"expression co-localization used {(s2 - s1):.5f}s")
+
+# only 0 -> 1, the default count depends on which marker is x
+directed_neighbors = [[1], [], [], []]
+dx, dy = [True, False, False, False], [False, True, False, False]
+assert comb_bootstrap(dx, dy, directed_neighbors, times=10, return_detail=True)["observed"] == 1
+assert comb_bootstrap(dy, dx, directed_neighbors, times=10, return_detail=True)["observed"] == 0
+assert comb_bootstrap(dx, dy, directed_neighbors, times=10, return_detail=True, symmetric=True)["observed"] == 1
+assert comb_bootstrap(dy, dx, directed_neighbors, times=10, return_detail=True, symmetric=True)["observed"] == 1
+
+# two ROIs with very different frequencies of x and y, neighbors are within each ROI
+roi_rng = np.random.default_rng(0)
+roi_groups = [0] * 500 + [1] * 500
+roi_x = [bool(v) for v in roi_rng.random(1000) < np.repeat([0.8, 0.05], 500)]
+roi_y = [bool(v) for v in roi_rng.random(1000) < np.repeat([0.8, 0.05], 500)]
+roi_neighbors = [sorted(roi_rng.choice(range(g * 500, g * 500 + 500), 5, replace=False).tolist())
+                 for g in roi_groups]
+global_z = comb_bootstrap(roi_x, roi_y, roi_neighbors, times=200, seed=0)
+stratified_z = comb_bootstrap(roi_x, roi_y, roi_neighbors, times=200, seed=0, groups=roi_groups)
+assert abs(stratified_z) < 5 < global_z
+
+# X and Y are independent, most of the permutations can be skipped unless the p-value is small by chance
+early = comb_bootstrap(X, Y, neighbors, seed=42, adaptive=True, max_times=1000)
+assert early["times"] % 50 == 0
+if comb_bootstrap(X, Y, neighbors, times=1000, seed=42, return_detail=True)["pvalue"] > 0.3:
+    assert early["times"] < 1000
+assert early == comb_bootstrap(X, Y, neighbors, seed=42, adaptive=True, max_times=1000)
+assert comb_bootstrap(roi_x, roi_y, roi_neighbors, seed=0, adaptive=True, max_times=300)["times"] == 300
+
+all_false = [False] * len(X)
+try:
+    comb_bootstrap(X, all_false, neighbors, times=10, on_zero_variance="raise")
+    raise AssertionError("Zero variance should raise")
+except ValueError:
+    pass
+assert np.isnan(comb_bootstrap(X, all_false, neighbors, times=10, on_zero_variance="nan"))
+with warnings.catch_warnings(record=True) as caught:
+    warnings.simplefilter("always")
+    assert np.isnan(comb_bootstrap([True] * 3, [True] * 3, [[0, 1, 2]] * 3, times=10))
+    assert len(caught) == 1
+
+for bad_x, bad_y, bad_neighbors, msg in [([True, False], [True], [[0], [1]], "should be the same"),
+                                         ([True, False], [True, False], [[0, 2], [1]], "Neighbor 2 of cell 0"),
+                                         ([True, False], [True, False], {0: [0], 5: [1]}, "Cell 5")]:
+    try:
+        comb_bootstrap(bad_x, bad_y, bad_neighbors, times=10)
+        raise AssertionError("Invalid input should raise")
+    except ValueError as e:
+        assert msg in str(e)
+
+triangle = [[1, 2], [0, 2], [0, 1]]
+tx, ty = [False, True, True], [True, True, False]
+observed = [comb_bootstrap(tx, ty, triangle, times=10, count=mode, return_detail=True, on_zero_variance="nan")
+            ["observed"] for mode in ["incidence", "pairs", "cells"]]
+assert observed == [0, 3, 2]
+
+z, perm = comb_bootstrap(X, Y, neighbors, times=200, seed=42, return_permutations=True)
+assert len(perm) == 200 and z == comb_bootstrap(X, Y, neighbors, times=200, seed=42)
+assert np.isclose(z, (detail["observed"] - perm.mean()) / perm.std())
+
+ones = [[1.0] * len(n) for n in neighbors]
+weighted_z = comb_bootstrap(X, Y, neighbors, times=200, seed=42, weights=ones)
+assert np.isclose(weighted_z, comb_bootstrap(X, Y, neighbors, times=200, seed=42))
+closeness = [[1.0 / (1.0 + j) for j in range(len(n))] for n in neighbors]
+weighted_pairs = [list(zip(n, w)) for n, w in zip(neighbors, closeness)]
+assert comb_bootstrap(X, Y, weighted_pairs, times=200, seed=42) == \
+       comb_bootstrap(X, Y, neighbors, times=200, seed=42, weights=closeness)
+
+# the GIL is released during the permutations, other python threads keep running in the middle of the call
+def ticks_during(run):
+    stamps = []
+    finished = threading.Event()
+
+    def tick():
+        while not finished.is_set():
+            stamps.append(perf_counter())
+            sleep(0.001)
+
+    ticker = threading.Thread(target=tick)
+    ticker.start()
+    start = perf_counter()
+    run()
+    end = perf_counter()
+    finished.set()
+    ticker.join()
+    # the ends of the call may still hold the GIL for a switch interval
+    margin = (end - start) / 4
+    return sum(start + margin < t < end - margin for t in stamps)
+
+
+assert ticks_during(lambda: comb_bootstrap(X, Y, neighbors, times=20000)) > 0
+assert ticks_during(lambda: cc.bootstrap(corr_types, neighbors, times=2000)) > 0
+
+analytic = comb_bootstrap(X, Y, neighbors, method="analytic", return_detail=True)
+permutation = comb_bootstrap(X, Y, neighbors, times=2000, seed=42, return_detail=True)
+assert abs(analytic["perm_mean"] - permutation["perm_mean"]) < 0.1 * permutation["perm_std"]
+assert abs(analytic["zscore"] - permutation["zscore"]) < 0.3 + 0.05 * abs(permutation["zscore"])
+stratified_analytic = comb_bootstrap(roi_x, roi_y, roi_neighbors, groups=roi_groups, method="analytic")
+assert abs(stratified_analytic - stratified_z) < 0.5 + 0.1 * abs(stratified_z)
+
+# x is rare and clustered in a ring of cells, y is abundant
+ring = [[(i - 1) % 400, (i + 1) % 400] for i in range(400)]
+ring_x = [i < 10 for i in range(400)]
+ring_y = [bool(v) for v in np.random.default_rng(1).random(400) < 0.6]
+nulls = {mode: comb_bootstrap(ring_x, ring_y, ring, times=500, seed=0, shuffle=mode, return_detail=True)
+         for mode in ["y", "x", "both"]}
+assert len({d["perm_std"] for d in nulls.values()}) == 3
+assert nulls["y"] == comb_bootstrap(ring_x, ring_y, ring, times=500, seed=0, return_detail=True)
+# the pairs count is symmetric in x and y, shuffling x is the same as swapping x and y and shuffling y
+assert comb_bootstrap(ring_x, ring_y, ring, times=200, seed=0, count="pairs", shuffle="x") == \
+       comb_bootstrap(ring_y, ring_x, ring, times=200, seed=0, count="pairs")
+analytic_x = comb_bootstrap(ring_x, ring_y, ring, method="analytic", shuffle="x", return_detail=True)
+assert abs(analytic_x["perm_std"] - nulls["x"]["perm_std"]) < 0.1 * nulls["x"]["perm_std"]
+
+# 0/1 counts are the same as the boolean status, the boolean path is unchanged
+int_x, int_y = [int(v) for v in X], [int(v) for v in Y]
+assert np.isclose(comb_bootstrap(int_x, int_y, neighbors, times=200, seed=42),
+                  comb_bootstrap(X, Y, neighbors, times=200, seed=42))
+assert comb_bootstrap(X, Y, neighbors, times=200, seed=42) == z
+counts_x, counts_y = [3, 0, 1], [0, 2, 5]
+assert comb_bootstrap(counts_x, counts_y, triangle, times=10, return_detail=True)["observed"] == 3 * 2 + 3 * 5 + 0 * 5
+assert comb_bootstrap([0.5, 0, 1], counts_y, triangle, times=10, return_detail=True)["observed"] == 0.5 * 7
+try:
+    comb_bootstrap(["a", "b", "c"], counts_y, triangle, times=10)
+    raise AssertionError("Invalid status should raise")
+except TypeError as e:
+    assert "bool, int or float" in str(e)
+
+reported = []
+assert comb_bootstrap(X, Y, neighbors, times=250, seed=42, progress=lambda done, total: reported.append((done, total))) \
+       == comb_bootstrap(X, Y, neighbors, times=250, seed=42)
+assert reported == [(100, 250), (200, 250), (250, 250)]
+reported.clear()
+cc.bootstrap(corr_types, neighbors, times=200, progress=lambda done, total: reported.append(done))
+assert reported == [100, 200]
+
+
+def interrupt(done, total):
+    raise KeyboardInterrupt
+
+
+for run in [lambda: comb_bootstrap(X, Y, neighbors, times=1000, progress=interrupt),
+            lambda: cc.bootstrap(corr_types, neighbors, times=1000, progress=interrupt)]:
+    try:
+        run()
+        raise AssertionError("KeyboardInterrupt should abort the bootstrap")
+    except KeyboardInterrupt:
+        pass
+
+seeded = cc.bootstrap(corr_types, neighbors, times=200, method="zscore", seed=42)
+assert seeded == cc.bootstrap(corr_types, neighbors, times=200, method="zscore", seed=42)
+assert seeded != cc.bootstrap(corr_types, neighbors, times=200, method="zscore", seed=43)
+# dropping self in the search is the same as ignoring self in the bootstrap
+self_free = get_point_neighbors(points, 10.0, exclude_self=True)
+assert cc.bootstrap(corr_types, self_free, times=200, method="zscore", seed=42) == \
+    cc.bootstrap(corr_types, neighbors, times=200, method="zscore", seed=42, ignore_self=True)
+assert cc.bootstrap(corr_types, self_free, times=200, seed=42) == \
+    cc.bootstrap(corr_types, neighbors, times=200, seed=42, ignore_self=True)
+
+assert [k for k, _ in seeded] == sorted(k for k, _ in seeded)
+assert all(isinstance(k, tuple) for k, _ in seeded)
+seeded_dict = cc.bootstrap(corr_types, neighbors, times=200, method="zscore", seed=42, return_type="dict")
+assert seeded_dict == dict(seeded)
+# the running statistics are updated in the order of the permutations, the same with or without progress
+assert cc.bootstrap(corr_types, neighbors, times=250, method="zscore", seed=42, progress=lambda done, total: None) \
+       == cc.bootstrap(corr_types, neighbors, times=250, method="zscore", seed=42)
+
+full = cc.bootstrap(corr_types, neighbors, times=200, method="full", seed=42, return_type="dict")
+# the significance is the same test as the pval method, the p-value is (extreme + 1) / (times + 1)
+seeded_sig = cc.bootstrap(corr_types, neighbors, times=200, seed=42, return_type="dict")
+assert full.keys() == seeded_dict.keys()
+for k, v in full.items():
+    assert np.isclose(v["zscore"], seeded_dict[k])
+    assert 1 / 201 <= v["pvalue"] <= 1 and np.isclose(v["pvalue"] * 201, round(v["pvalue"] * 201))
+    assert v["sig"] == seeded_sig[k]
+
+raw_p = [0.01, 0.04, 0.03, 0.005, 0.5]
+assert np.allclose(adjust_pvalues(raw_p), [0.025, 0.05, 0.05, 0.025, 0.5])
+assert np.allclose(adjust_pvalues(raw_p, method="bonferroni"), [0.05, 0.2, 0.15, 0.025, 1])
+assert np.allclose(adjust_pvalues([0.01, np.nan, 0.04]), [0.02, np.nan, 0.04], equal_nan=True)
+assert np.allclose(adjust_pvalues([0.01, np.nan, 0.04], method="bonferroni"), [0.02, np.nan, 0.08], equal_nan=True)
+adjusted = cc.bootstrap(corr_types, neighbors, times=200, method="full", seed=42, return_type="dict", adjust="fdr_bh")
+assert np.allclose([v["pvalue_adj"] for v in adjusted.values()], adjust_pvalues([v["pvalue"] for v in full.values()]))
+adjusted_sig = cc.bootstrap(corr_types, neighbors, times=200, seed=42, return_type="dict", adjust="fdr_bh")
+for k, v in adjusted.items():
+    assert v["pvalue_adj"] >= v["pvalue"]
+    assert v["sig"] == adjusted_sig[k]
+bonferroni = cc.bootstrap(corr_types, neighbors, times=200, seed=42, adjust="bonferroni")
+assert sum(abs(v) for _, v in bonferroni) <= sum(abs(v) for _, v in cc.bootstrap(corr_types, neighbors, times=200, seed=42))
+
+focal = [(t, types[0]) for t in types]
+restricted = cc.bootstrap(corr_types, neighbors, times=200, method="zscore", seed=42, return_type="dict",
+                          selected_combs=focal)
+assert len(restricted) == len(types)
+assert all(types[0] in k and v == seeded_dict[k] for k, v in restricted.items())
+try:
+    cc.bootstrap(corr_types, neighbors, times=10, selected_combs=[(types[0], "unknown")])
+    raise AssertionError("Unknown type should raise")
+except ValueError as e:
+    assert "unknown" in str(e)
+
+# the attributes are the same as before they are stored as rust types
+assert cc.cell_types == types
+assert cc.cell_combs == [(a, b) for i, a in enumerate(types) for b in types[i:]]
+ordered_cc = CellCombs(["b", "a", "b"], True)
+assert ordered_cc.cell_types == ["b", "a"]
+assert ordered_cc.cell_combs == [("b", "b"), ("b", "a"), ("a", "b"), ("a", "a")]
+
+# each A cell is surrounded by 10 B cells, the B cells are mostly surrounded by B cells
+directed_types = ["A"] * 20 + ["B"] * 200
+directed_combs = [list(range(20 + 10 * i, 30 + 10 * i)) for i in range(20)] + \
+                 [[20 + (j + d) % 200 for d in [1, 2, 198, 199]] + ([j // 10] if j % 10 == 0 else [])
+                  for j in range(200)]
+directed_z = CellCombs(["A", "B"], True).bootstrap(directed_types, directed_combs, times=500, method="zscore",
+                                                   seed=0, return_type="dict")
+assert directed_z[("A", "B")] > 3
+assert directed_z[("A", "B")] - directed_z[("B", "A")] > 3
+
+# hand-computed mean counts on a 6-cell graph, edges 0-1, 0-2, 1-2, 2-3, 3-4, 4-5
+toy_types = ["A", "A", "B", "B", "C", "C"]
+toy_neighbors = [[1, 2], [0, 2], [0, 1, 3], [2, 4], [3, 5], [4]]
+toy = CellCombs(["A", "B", "C"]).bootstrap(toy_types, toy_neighbors, times=10, method="full", seed=0,
+                                           return_type="dict")
+assert {k: v["observed"] for k, v in toy.items()} == {("A", "A"): 1, ("A", "B"): 4 / 3, ("A", "C"): 0,
+                                                      ("B", "B"): 1, ("B", "C"): 1, ("C", "C"): 1}
+assert {k: v["n_centers"] for k, v in toy.items()} == {("A", "A"): 2, ("A", "B"): 3, ("A", "C"): 0,
+                                                       ("B", "B"): 2, ("B", "C"): 2, ("C", "C"): 2}
+toy_ordered = CellCombs(["A", "B", "C"], True).bootstrap(toy_types, toy_neighbors, times=10, method="full",
+                                                         seed=0, return_type="dict")
+assert toy_ordered[("A", "B")]["observed"] == 1 and toy_ordered[("B", "A")]["observed"] == 2
+assert toy_ordered[("A", "B")]["n_centers"] == 2 and toy_ordered[("B", "A")]["n_centers"] == 1
+# the expected count is the mean of the permutations of the same counting
+assert np.isclose(toy[("A", "A")]["zscore"] * toy[("A", "A")]["perm_std"],
+                  toy[("A", "A")]["observed"] - toy[("A", "A")]["perm_mean"])
+# the unordered count doesn't depend on which type comes first in the index
+toy_reversed = CellCombs(["A", "B", "C"]).bootstrap(toy_types[::-1], [[5 - j for j in n] for n in toy_neighbors[::-1]],
+                                                    times=10, method="full", seed=0, return_type="dict")
+assert all(toy_reversed[k]["observed"] == v["observed"] for k, v in toy.items())
+# the sum and the frequency of the center cells with any neighbor of the other type
+toy_sum = CellCombs(["A", "B", "C"]).bootstrap(toy_types, toy_neighbors, times=10, method="full", seed=0,
+                                               return_type="dict", stat="sum")
+assert {k: v["observed"] for k, v in toy_sum.items()} == {("A", "A"): 2, ("A", "B"): 4, ("A", "C"): 0,
+                                                          ("B", "B"): 2, ("B", "C"): 2, ("C", "C"): 2}
+toy_freq = CellCombs(["A", "B", "C"]).bootstrap(toy_types, toy_neighbors, times=10, method="full", seed=0,
+                                                return_type="dict", stat="frequency")
+assert {k: v["observed"] for k, v in toy_freq.items()} == {("A", "A"): 1, ("A", "B"): 0.75, ("A", "C"): 0,
+                                                           ("B", "B"): 1, ("B", "C"): 0.5, ("C", "C"): 1}
+toy_ordered_freq = CellCombs(["A", "B", "C"], True).bootstrap(toy_types, toy_neighbors, times=10, method="full",
+                                                              seed=0, return_type="dict", stat="frequency")
+assert toy_ordered_freq[("A", "B")]["observed"] == 1 and toy_ordered_freq[("B", "A")]["observed"] == 0.5
+# the permutations use the same statistic
+assert all(0 <= v["perm_mean"] <= 1 for v in toy_freq.values())
+try:
+    CellCombs(["A", "B", "C"]).bootstrap(toy_types, toy_neighbors, times=10, stat="median")
+    raise AssertionError("Unknown stat should raise")
+except ValueError:
+    pass
+# cell 3 and 5 are missing from the keys, they are not centers but still the neighbors of cell 2 and 4
+toy_pruned = CellCombs(["A", "B", "C"]).bootstrap(toy_types, {0: [1, 2], 1: [0, 2], 2: [0, 1, 3], 4: [3, 5]},
+                                                  times=10, method="full", seed=0, return_type="dict")
+assert {k: (v["observed"], v["n_centers"]) for k, v in toy_pruned.items()} == {
+    ("A", "A"): (1, 2), ("A", "B"): (4 / 3, 3), ("A", "C"): (0, 0),
+    ("B", "B"): (1, 1), ("B", "C"): (1, 1), ("C", "C"): (1, 1)}
+for bad_neighbors in [{0: [6]}, {6: [0]}]:
+    try:
+        CellCombs(["A", "B", "C"]).bootstrap(toy_types, bad_neighbors, times=10)
+        raise AssertionError("Out of range neighbors should raise")
+    except ValueError:
+        pass
+# the weights of the edges are summed, the unit weights are identical to counting
+toy_unit = {i: [(j, 1.0) for j in n] for i, n in enumerate(toy_neighbors)}
+assert CellCombs(["A", "B", "C"]).bootstrap(toy_types, toy_unit, times=10, method="full", seed=0,
+                                            return_type="dict") == toy
+toy_weighted = {i: [(j, 0.5 if {i, j} == {0, 2} else 1.0) for j in n] for i, n in enumerate(toy_neighbors)}
+toy_w = CellCombs(["A", "B", "C"]).bootstrap(toy_types, toy_weighted, times=10, method="full", seed=0,
+                                             return_type="dict")
+assert toy_w[("A", "B")]["observed"] == 1.0 and toy_w[("A", "A")]["observed"] == 1.0
+assert CellCombs(["A", "B", "C"]).bootstrap(toy_types, toy_weighted, times=10, method="full", seed=0,
+                                            return_type="dict", stat="sum")[("A", "B")]["observed"] == 3.0
+# C has only one cell, the pairs with C are not tested
+rare_types = ["A", "A", "B", "B", "C", "A"]
+rare = CellCombs(["A", "B", "C"]).bootstrap(rare_types, toy_neighbors, times=10, seed=0, return_type="dict",
+                                            min_cells=2)
+assert [k for k, v in rare.items() if np.isnan(v)] == [("A", "C"), ("B", "C"), ("C", "C")]
+rare_full = CellCombs(["A", "B", "C"]).bootstrap(rare_types, toy_neighbors, times=10, method="full", seed=0,
+                                                 return_type="dict", min_cells=2)
+assert rare_full[("A", "B")]["n_cells"] == (3, 2) and rare_full[("A", "C")]["n_cells"] == (3, 1)
+assert np.isnan(rare_full[("A", "C")]["zscore"]) and not np.isnan(rare_full[("A", "B")]["observed"])
+assert list(CellCombs(["A", "B", "C"]).bootstrap(rare_types, toy_neighbors, times=10, seed=0, return_type="dict",
+                                                 min_cells=2, drop_rare=True)) == [("A", "A"), ("A", "B"), ("B", "B")]
+# the expected sum of the A-B edges is 12 directed edges * P(one end is A and the other is B) = 12 * 8 / 30
+toy_fc = CellCombs(["A", "B", "C"]).bootstrap(toy_types, toy_neighbors, times=2000, method="full", seed=0,
+                                              return_type="dict", stat="sum", pseudocount=0)
+assert abs(toy_fc[("A", "B")]["log2_fc"] - np.log2(4 / 3.2)) < 0.05
+assert np.isclose(toy_fc[("A", "B")]["log2_fc"], np.log2(4 / toy_fc[("A", "B")]["perm_mean"]))
+for bad_pseudocount in [-1.0, float("nan"), float("inf")]:
+    try:
+        CellCombs(["A", "B", "C"]).bootstrap(toy_types, toy_neighbors, times=10, method="full",
+                                             pseudocount=bad_pseudocount)
+        raise AssertionError("The pseudocount should be finite and non-negative")
+    except ValueError as e:
+        assert "`pseudocount`" in str(e)
+# cell 5 has a type not in the constructor, it's not a center and not counted as a neighbor
+unknown_types = toy_types[:5] + ["X"]
+try:
+    CellCombs(["A", "B", "C"]).bootstrap(unknown_types, toy_neighbors, times=10)
+    raise AssertionError("Unknown types should raise")
+except ValueError as e:
+    assert "X" in str(e)
+toy_unknown = CellCombs(["A", "B", "C"]).bootstrap(unknown_types, toy_neighbors, times=10, method="full", seed=0,
+                                                   return_type="dict", allow_unknown=True)
+assert (toy_unknown[("C", "C")]["observed"], toy_unknown[("C", "C")]["n_centers"]) == (0, 0)
+assert (toy_unknown[("B", "C")]["observed"], toy_unknown[("B", "C")]["n_centers"]) == (1, 2)
+assert toy_unknown[("B", "C")]["n_cells"] == (2, 1)
+# cell 0 is the only Ki67 positive A cell
+toy_marker = CellCombs(["A", "B", "C"]).bootstrap_marker(toy_types, toy_neighbors, [True] + [False] * 5, "A", "Ki67",
+                                                         times=10, method="full", seed=0, return_type="dict")
+assert len(toy_marker) == 10 and ("A", "B") not in toy_marker
+assert (toy_marker[("A+Ki67", "B")]["observed"], toy_marker[("A+Ki67", "B")]["n_centers"]) == (1, 2)
+assert (toy_marker[("A+Ki67", "A-Ki67")]["observed"], toy_marker[("A+Ki67", "A-Ki67")]["n_centers"]) == (1, 2)
+assert toy_marker[("B", "C")]["observed"] == toy[("B", "C")]["observed"]
+# bootstrap_marker takes the same keyword arguments as bootstrap, the unknown ones raise
+toy_marker_sum = CellCombs(["A", "B", "C"]).bootstrap_marker(toy_types, toy_neighbors, [True] + [False] * 5, "A",
+                                                             "Ki67", times=10, method="full", seed=0, stat="sum",
+                                                             selected_combs=[("A+Ki67", "B")], return_type="dict")
+assert list(toy_marker_sum) == [("A+Ki67", "B")] and toy_marker_sum[("A+Ki67", "B")]["observed"] == 2
+try:
+    CellCombs(["A", "B", "C"]).bootstrap(toy_types, toy_neighbors, times=10, parallel=True)
+    raise AssertionError("Unknown keyword arguments should raise")
+except TypeError as e:
+    assert "parallel" in str(e)
+try:
+    CellCombs(["A", "B", "C"]).bootstrap_marker(toy_types, toy_neighbors, [True] + [False] * 5, "A", "Ki67", 10)
+    raise AssertionError("times of bootstrap_marker should be keyword-only")
+except TypeError:
+    pass
+# D is not in the data, the order of the constructor is not sorted, the result is sorted by the type names
+schema_cc = CellCombs(["D", "C", "A", "B"])
+for schema_method in ["pval", "zscore", "full"]:
+    schema = schema_cc.bootstrap(toy_types, toy_neighbors, times=10, method=schema_method, seed=0,
+                                 on_zero_variance="nan")
+    assert [k for k, _ in schema] == sorted(schema_cc.cell_combs)
+schema_table = schema_cc.bootstrap(toy_types, toy_neighbors, times=10, seed=0, return_type="table",
+                                   on_zero_variance="nan")
+assert [r["pair"] for r in schema_table.records()] == sorted(schema_cc.cell_combs)
+assert {r["pair"]: r["observed"] for r in schema_table.records()}[("D", "A")] == 0
+toy_cc = CellCombs(["A", "B", "C"])
+hist_result, null_hist = toy_cc.bootstrap(toy_types, toy_neighbors, times=50, seed=0, histogram=20,
+                                          return_type="dict")
+assert set(null_hist) == set(hist_result)
+for hist_pair, hist_data in null_hist.items():
+    assert len(hist_data["counts"]) == 20 and len(hist_data["edges"]) == 21
+    assert sum(hist_data["counts"]) == 50
+    if hist_data["min"] < hist_data["max"]:
+        assert hist_data["edges"][0] == hist_data["min"] and hist_data["edges"][-1] == hist_data["max"]
+    else:
+        assert hist_data["edges"][0] == hist_data["min"] - 0.5 and hist_data["edges"][-1] == hist_data["max"] + 0.5
+assert toy_cc.bootstrap(toy_types, toy_neighbors, times=50, seed=0,
+                        return_type="dict") == hist_result
+try:
+    toy_cc.bootstrap(toy_types, toy_neighbors, times=10, histogram=0)
+    assert False
+except ValueError:
+    pass
+
+toy_composition, toy_columns = neighbor_composition(toy_types, toy_neighbors)
+assert toy_columns == ["A", "B", "C"]
+assert toy_composition.tolist() == [[1, 1, 0], [1, 1, 0], [2, 1, 0], [0, 1, 1], [0, 1, 1], [0, 0, 1]]
+toy_fractions, _ = neighbor_composition(toy_types + ["A"], toy_neighbors + [[6]], normalize=True, ignore_self=True,
+                                        on_empty="nan")
+assert np.allclose(toy_fractions[2], [2 / 3, 1 / 3, 0]) and np.isnan(toy_fractions[6]).all()
+toy_entropy = neighborhood_entropy(toy_types, toy_neighbors)
+assert np.allclose(toy_entropy[[0, 2, 5]], [1, -(2 / 3) * np.log2(2 / 3) - (1 / 3) * np.log2(1 / 3), 0])
+assert np.isclose(neighborhood_entropy(toy_types, toy_neighbors, base=np.e)[0], np.log(2))
+assert np.allclose(neighborhood_entropy(toy_types, toy_neighbors, normalized=True), toy_entropy / np.log2(3))
+assert np.isnan(neighborhood_entropy(toy_types + ["A"], toy_neighbors + [[6]], ignore_self=True)[6])
+assert (neighborhood_entropy(["A"] * 3, [[0, 1], [2], [0]], normalized=True) == 0).all()
+# the integer codes of the types give the same result, the columns are the codes as strings
+toy_type_codes = np.array(["ABC".index(t) for t in toy_types], dtype=np.int16)
+coded_composition, coded_columns = neighbor_composition(toy_type_codes, toy_neighbors)
+assert coded_columns == ["0", "1", "2"] and coded_composition.tolist() == toy_composition.tolist()
+assert np.allclose(neighborhood_entropy(toy_type_codes.tolist(), toy_neighbors), toy_entropy)
+assert list(neighborhood_clusters(toy_type_codes, toy_neighbors, 2, seed=0)[0]) == \
+    list(neighborhood_clusters(toy_types, toy_neighbors, 2, seed=0)[0])
+
+# integer codes give the same result as the names
+type_codes = np.array([types.index(t) for t in corr_types], dtype=np.int16)
+coded_cc = CellCombs(np.arange(len(types), dtype=np.int16), categories=types)
+assert coded_cc.cell_types == cc.cell_types and coded_cc.cell_combs == cc.cell_combs
+assert coded_cc.bootstrap(type_codes, neighbors, times=200, method="zscore", seed=42, categories=types) == seeded
+assert CellCombs([2, 0, 2]).cell_types == ["2", "0"]
+try:
+    coded_cc.bootstrap(type_codes, neighbors, times=10, categories=types[:3])
+    raise AssertionError("Codes out of categories should raise")
+except ValueError as e:
+    assert "out of range" in str(e)
+
+# the second ROI is empty, the third has only two types
+roi_types = [corr_types, [], [types[0] if i % 2 else types[1] for i in range(100)]]
+roi_combs = [neighbors, [], [[(i + 1) % 100] for i in range(100)]]
+def same_z(a, b):
+    return a.keys() == b.keys() and all(np.isclose(a[k], b[k], equal_nan=True) for k in a)
+
+
+batch, meta = cc.bootstrap_batch(roi_types, roi_combs, times=200, method="zscore", seed=42, return_type="dict",
+                                 meta=True, on_zero_variance="nan")
+assert len(batch) == 3 and same_z(batch[0], seeded_dict)
+assert same_z(batch[2], cc.bootstrap(roi_types[2], roi_combs[2], times=200, method="zscore", seed=42 + 2 * 200,
+                                     return_type="dict", on_zero_variance="nan"))
+wrapped = cc.bootstrap_batch(roi_types, roi_combs, times=200, method="zscore", seed=2 ** 64 - 1, return_type="dict",
+                             on_zero_variance="nan")
+assert same_z(wrapped[2], cc.bootstrap(roi_types[2], roi_combs[2], times=200, method="zscore", seed=2 * 200 - 1,
+                                       return_type="dict", on_zero_variance="nan"))
+assert all(same_z(b, c) for b, c in zip(cc.bootstrap_batch(roi_types, roi_combs, times=200, method="zscore", seed=42,
+                                                           return_type="dict", parallel=False,
+                                                           on_zero_variance="nan"), batch))
+pair = (types[0], types[1])
+defined = [b[pair] for b in batch if not np.isnan(b[pair])]
+assert np.isclose(meta[pair], sum(defined) / np.sqrt(len(defined)))
+
+# a type in a single cell of a ring, the counts of all the permutations are the same
+lonely_types = ["Z"] + ["A"] * 9
+lonely_ring = [[(i - 1) % 10, (i + 1) % 10] for i in range(10)]
+lonely_cc = CellCombs(["A", "Z"])
+lonely = lonely_cc.bootstrap(lonely_types, lonely_ring, times=20, method="zscore", return_type="dict",
+                             on_zero_variance="nan")
+assert all(np.isnan(z) for z in lonely.values())
+assert np.isnan(lonely_cc.bootstrap(lonely_types, lonely_ring, times=20, method="full", return_type="dict",
+                                    on_zero_variance="nan")[("A", "Z")]["zscore"])
+with warnings.catch_warnings(record=True) as caught:
+    warnings.simplefilter("always")
+    lonely_cc.bootstrap(lonely_types, lonely_ring, times=20, method="zscore")
+    assert len(caught) == 3 and "('A', 'Z')" in "".join(str(c.message) for c in caught)
+try:
+    lonely_cc.bootstrap(lonely_types, lonely_ring, times=20, method="zscore", on_zero_variance="raise")
+    raise AssertionError("Zero variance should raise")
+except ValueError as e:
+    assert "the same count" in str(e)
+
+# when all the permutations tie with the observed count, there is no direction
+assert set(lonely_cc.bootstrap(lonely_types, lonely_ring, times=20, pval=1.0, return_type="dict").values()) == {0.0}
+# two segregated halves of a ring, the smallest p-value of 10 permutations is 1 / 11
+halves_types = ["A"] * 10 + ["B"] * 10
+halves_ring = [[(i - 1) % 20, (i + 1) % 20] for i in range(20)]
+halves_cc = CellCombs(["A", "B"])
+halves = halves_cc.bootstrap(halves_types, halves_ring, times=10, pval=0.1, seed=0, return_type="dict")
+assert halves[("A", "A")] == 1.0 and halves[("A", "B")] == -1.0
+assert set(halves_cc.bootstrap(halves_types, halves_ring, times=10, seed=0, return_type="dict").values()) == {0.0}
+
+# the inner cells of the A half have more A neighbors than expected, the B half less
+halves_scores = halves_cc.cell_scores(halves_types + ["A"], halves_ring + [[]], "A", times=200, seed=0)
+assert halves_scores.shape == (21,) and np.isnan(halves_scores[20])
+assert halves_scores[5] > 0 and halves_scores[15] < 0
+assert np.array_equal(halves_scores, halves_cc.cell_scores(halves_types + ["A"], halves_ring + [[]], "A", times=200,
+                                                           seed=0), equal_nan=True)
+try:
+    halves_cc.cell_scores(halves_types, halves_ring, "C", times=10)
+    raise AssertionError("Unknown target type should raise")
+except ValueError as e:
+    assert "Unknown cell type in `target_type`: C." in str(e)
+# the missing keys are the cells without neighbors
+missing_scores = halves_cc.cell_scores(["A", "B"], {0: [1]}, "B", times=10, seed=0)
+assert missing_scores.shape == (2,) and np.isnan(missing_scores[1])
+
+# two compartments on a chain, E types only in the first and D types only in the second,
+# the global shuffle calls an avoidance that disappears when shuffling within the compartments
+compartment_rng = np.random.RandomState(0)
+compartment_types = list(compartment_rng.choice(["E1", "E2"], 100)) + list(compartment_rng.choice(["D1", "D2"], 100))
+compartment_chain = [[j for j in (i - 1, i + 1) if 0 <= j < 200] for i in range(200)]
+compartment_cc = CellCombs(["D1", "D2", "E1", "E2"])
+global_null = compartment_cc.bootstrap(compartment_types, compartment_chain, times=200, seed=0, return_type="dict",
+                                       stat="sum")
+compartment_blocks = [0] * 100 + [1] * 99 + [2]
+block_null = compartment_cc.bootstrap(compartment_types, compartment_chain, times=200, seed=0, return_type="dict",
+                                      stat="sum", blocks=compartment_blocks)
+assert global_null[("D1", "E1")] == -1.0 and block_null[("D1", "E1")] == 0.0
+# the blocks are the same as the groups when there is a single group
+assert block_null == compartment_cc.bootstrap(compartment_types, compartment_chain, times=200, seed=0,
+                                              return_type="dict", stat="sum", groups=compartment_blocks)
+assert block_null == compartment_cc.bootstrap(compartment_types, compartment_chain, times=200, seed=0,
+                                              return_type="dict", stat="sum", groups=[0] * 200,
+                                              blocks=compartment_blocks)
+for labels in ["groups", "blocks"]:
+    try:
+        compartment_cc.bootstrap(compartment_types, compartment_chain, times=10, **{labels: [0] * 100})
+        raise AssertionError(f"The length of {labels} should match the types")
+    except ValueError as e:
+        assert f"`{labels}`" in str(e)
+
+# all the permutations are the same as the observed count, no fold change
+assert all(v["log2_fc"] == 0 for v in lonely_cc.bootstrap(lonely_types, lonely_ring, times=20, method="full",
+                                                          return_type="dict", on_zero_variance="nan").values())
+
+# the A cells have 2 A neighbors except the 2 at the border, the observed mean is 1.8
+halves_ci = halves_cc.bootstrap_ci(halves_types, halves_ring, n_boot=200, seed=0, return_type="dict")
+assert 1 <= halves_ci[("A", "A")][0] <= 1.8 <= halves_ci[("A", "A")][1] <= 2
+assert halves_ci == halves_cc.bootstrap_ci(halves_types, halves_ring, n_boot=200, seed=0, return_type="dict")
+# every A cell has an A neighbor, the fraction is over the resampled centers only
+assert halves_cc.bootstrap_ci(halves_types, halves_ring, n_boot=200, seed=0, return_type="dict",
+                              stat="frequency")[("A", "A")] == (1.0, 1.0)
+# the single C cell is missing in some resamples
+rare_ci = CellCombs(["A", "B", "C"]).bootstrap_ci(rare_types, toy_neighbors, n_boot=200, seed=0, return_type="dict")
+assert np.isnan(rare_ci[("C", "C")]).all()
+
+# the table has the statistics of the full method, and can be saved and loaded
+table = cc.bootstrap(corr_types, neighbors, times=200, seed=42, return_type="table")
+assert [(r["pair"], r["observed"], r["expected"], r["pvalue"], r["sig"]) for r in table.records()] == \
+       [(k, v["observed"], v["perm_mean"], v["pvalue"], v["sig"]) for k, v in full.items()]
+with tempfile.TemporaryDirectory() as tmp:
+    table.to_json(os.path.join(tmp, "table.json"))
+    assert CombsTable.from_json(os.path.join(tmp, "table.json")).records() == table.records()
+    table.to_csv(os.path.join(tmp, "table.csv"))
+    with open(os.path.join(tmp, "table.csv")) as f:
+        rows = list(csv.reader(f))
+    assert rows[0] == ["type1", "type2", "observed", "expected", "zscore", "pvalue", "sig"]
+    assert len(rows) == len(full) + 1 and float(rows[1][2]) == table.records()[0]["observed"]
+    # JSON has no NaN or infinity, they are kept as strings, null is loaded as NaN
+    infinite = [{"type1": "A", "type2": "B", "observed": 1.0, "expected": 0.0, "zscore": "Infinity",
+                 "pvalue": None, "sig": 1.0},
+                {"type1": "A", "type2": "\U0001F600", "observed": 0, "expected": 1.0, "zscore": "-Infinity",
+                 "pvalue": 0.5, "sig": -1.0}]
+    with open(os.path.join(tmp, "infinite.json"), "w") as f:
+        json.dump(infinite, f, allow_nan=False)
+    infinite_table = CombsTable.from_json(os.path.join(tmp, "infinite.json"))
+    assert [r["zscore"] for r in infinite_table.records()] == [float("inf"), float("-inf")]
+    assert np.isnan(infinite_table.records()[0]["pvalue"])
+    # the non-BMP name is written as a surrogate pair by the json module
+    assert infinite_table.records()[1]["pair"] == ("A", "\U0001F600")
+    infinite_table.to_json(os.path.join(tmp, "infinite.json"))
+    with open(os.path.join(tmp, "infinite.json")) as f:
+        saved = json.load(f)
+    assert [r["zscore"] for r in saved] == ["Infinity", "-Infinity"] and saved[0]["pvalue"] == "NaN"
+    assert saved[1]["type2"] == "\U0001F600"
+
+# the thread pool is already initialized by the parallel functions above
+try:
+    set_num_threads(2)
+    raise AssertionError("Setting the threads after initialization should raise")
+except RuntimeError as e:
+    assert str(get_num_threads()) in str(e)
+try:
+    set_num_threads(0)
+    raise AssertionError("Zero threads should raise")
+except ValueError:
+    pass
+
+# a mocked Ctrl-C during a long run without progress, the run is aborted between the blocks
+def interrupted(run):
+    timer = threading.Timer(0.2, lambda: os.kill(os.getpid(), signal.SIGINT))
+    timer.start()
+    try:
+        run()
+    except KeyboardInterrupt:
+        return True
+    finally:
+        timer.cancel()
+    return False
+
+
+assert interrupted(lambda: comb_bootstrap(X, Y, neighbors, times=10 ** 8))
+assert interrupted(lambda: toy_cc.bootstrap(toy_types, toy_neighbors, times=10 ** 8))
+assert interrupted(lambda: toy_cc.bootstrap_batch([toy_types], [toy_neighbors], times=10 ** 8))
+
+# cell 0 and 1 are their own neighbors, ignore_self only drops the self pairs
+self_neighbors = [[0, 1], [0, 1], [2]]
+self_x, self_y = [True, True, True], [True, True, False]
+assert comb_bootstrap(self_x, self_y, self_neighbors, times=10, return_detail=True)["observed"] == 3
+assert comb_bootstrap(self_x, self_y, self_neighbors, times=10, ignore_self=True, return_detail=True)["observed"] == 1
+
 cents = [i for i in range(len(points))]
 s1 = time()
 ix, col, data = neighbor_components(dict(zip(cents, neighbors)), dict(zip(cents, corr_types)))